@@ -0,0 +1,69 @@
+"""Tiny random Qwen2/Qwen3 checkpoints and the logits `transformers` computes for them
+
+Read by `test_matches_transformers_logits` in `src/model.rs`. Run from this directory
+with torch and transformers installed:
+
+    python generate.py
+
+Every variant directory receives `config.json` and `model.safetensors` from
+`save_pretrained`, and `logits.json` holding the input tokens and the float32 logits
+[seq_len, vocab_size] of one forward pass.
+"""
+
+import json
+from pathlib import Path
+
+import torch
+from transformers import Qwen2Config, Qwen2ForCausalLM, Qwen3Config, Qwen3ForCausalLM
+
+TOKENS = [3, 1, 4, 1, 5, 9, 2, 6, 5, 3, 5, 8]
+
+# Same shape as `Qwen2Config::tiny()`
+COMMON = dict(
+    vocab_size=64,
+    hidden_size=16,
+    intermediate_size=32,
+    num_hidden_layers=2,
+    num_attention_heads=4,
+    num_key_value_heads=2,
+    max_position_embeddings=64,
+    rms_norm_eps=1e-6,
+    rope_theta=10000.0,
+    bos_token_id=1,
+    eos_token_id=2,
+)
+
+VARIANTS = {
+    # Q/K/V biases, untied LM head
+    "qwen2": (Qwen2Config, Qwen2ForCausalLM, dict(tie_word_embeddings=False)),
+    # QK-norm, no biases, heads wider than hidden_size / num_heads, tied LM head
+    "qwen3": (
+        Qwen3Config,
+        Qwen3ForCausalLM,
+        dict(attention_bias=False, head_dim=8, tie_word_embeddings=True),
+    ),
+}
+
+
+def main():
+    root = Path(__file__).parent
+    for seed, (name, (config_class, model_class, overrides)) in enumerate(VARIANTS.items()):
+        torch.manual_seed(seed)
+        config = config_class(**COMMON, **overrides, attn_implementation="eager")
+        model = model_class(config).to(torch.float32).eval()
+        # Default init leaves norms at one and biases at zero; randomize everything so
+        # each of them shows up in the logits
+        with torch.no_grad():
+            for parameter in model.parameters():
+                parameter.normal_(0.0, 0.3)
+
+        directory = root / name
+        model.save_pretrained(directory, safe_serialization=True)
+        with torch.no_grad():
+            logits = model(torch.tensor([TOKENS])).logits[0]
+        with open(directory / "logits.json", "w") as file:
+            json.dump({"tokens": TOKENS, "logits": logits.tolist()}, file)
+
+
+if __name__ == "__main__":
+    main()
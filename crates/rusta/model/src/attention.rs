@@ -0,0 +1,234 @@
+//! Memory-efficient attention for long prefills
+//!
+//! The eager path in [`crate::model::Qwen2Attention`] builds the whole
+//! `[batch, heads, seq, keys]` score matrix and copies every KV head once per query head
+//! it serves. [`tiled_attention`] instead walks the keys in tiles with an online softmax
+//! (as in FlashAttention), so at most one `block_size × block_size` tile of scores per
+//! head is alive at a time, and folds the query heads of a GQA group into the rows of
+//! their shared KV head rather than repeating K and V.
+//!
+//! Packed training sequences hold several samples back to back. Rather than a dense
+//! `[seq, seq]` mask per sequence, their sample boundaries travel as `document_ids` and
+//! [`document_mask`] is built for one tile at a time.
+
+use burn::tensor::{Bool, Int, Tensor, backend::Backend};
+
+/// Stand-in for −∞ in masked scores, finite so rows with no visible key in a tile
+/// yet do not produce `−∞ − (−∞)`
+const MASKED_SCORE: f32 = -1e30;
+
+/// Scaled dot-product attention of `q` over `k`/`v`, computed in tiles
+///
+/// * `q` - `[batch, num_heads, seq_len, head_dim]`
+/// * `k`, `v` - `[batch, num_kv_heads, total_len, head_dim]`, each KV head serving
+///   `num_heads / num_kv_heads` consecutive query heads
+/// * `mask` - `[batch or 1, 1, seq_len, total_len]`, `true` where a query may not
+///   attend to a key; every query must see at least one key
+/// * `past_len` - when the queries are the last `seq_len` of `total_len` positions and
+///   causal, key tiles past the last query of a tile are skipped outright
+/// * `document_ids` - `[batch, seq_len]` sample of each packed query, the keys being the
+///   same positions (`seq_len == total_len`); queries only see keys of their own sample
+///
+/// Returns `[batch, num_heads, seq_len, head_dim]`.
+pub fn tiled_attention<B: Backend>(
+    q: Tensor<B, 4>,
+    k: Tensor<B, 4>,
+    v: Tensor<B, 4>,
+    mask: Option<Tensor<B, 4, Bool>>,
+    past_len: Option<usize>,
+    document_ids: Option<Tensor<B, 2, Int>>,
+    block_size: usize,
+) -> Tensor<B, 4> {
+    assert!(block_size > 0, "attention block size must be positive");
+    let [batch_size, num_heads, seq_len, head_dim] = q.dims();
+    let [_, num_kv_heads, total_len, _] = k.dims();
+    let n_rep = num_heads / num_kv_heads;
+    let scale = (head_dim as f32).sqrt().recip();
+
+    // Group the query heads under their KV head: [batch, kv_heads, n_rep, seq, head_dim]
+    let q = q.reshape([batch_size, num_kv_heads, n_rep, seq_len, head_dim]);
+
+    let mut outputs = Vec::with_capacity(seq_len.div_ceil(block_size));
+    for q_start in (0..seq_len).step_by(block_size) {
+        let q_end = (q_start + block_size).min(seq_len);
+        let q_len = q_end - q_start;
+        let rows = n_rep * q_len;
+        let q_tile = q
+            .clone()
+            .slice([
+                0..batch_size,
+                0..num_kv_heads,
+                0..n_rep,
+                q_start..q_end,
+                0..head_dim,
+            ])
+            .reshape([batch_size, num_kv_heads, rows, head_dim])
+            .mul_scalar(scale);
+
+        // Causal queries never see keys after the last of them
+        let key_end = past_len.map_or(total_len, |past_len| (past_len + q_end).min(total_len));
+
+        let device = q_tile.device();
+        let stats = [batch_size, num_kv_heads, rows, 1];
+        let mut max = Tensor::<B, 4>::full(stats, MASKED_SCORE, &device);
+        let mut denominator = Tensor::<B, 4>::zeros(stats, &device);
+        let mut acc = Tensor::<B, 4>::zeros([batch_size, num_kv_heads, rows, head_dim], &device);
+
+        for k_start in (0..key_end).step_by(block_size) {
+            let k_end = (k_start + block_size).min(key_end);
+            let keys = [0..batch_size, 0..num_kv_heads, k_start..k_end, 0..head_dim];
+            let k_tile = k.clone().slice(keys.clone());
+            let v_tile = v.clone().slice(keys);
+
+            // Repeat a [batch or 1, 1, q_len, k_len] mask for every query head of a group
+            let per_row = |mask: Tensor<B, 4, Bool>| {
+                let [mask_batch, ..] = mask.dims();
+                mask.unsqueeze_dim::<5>(2)
+                    .expand([mask_batch, 1, n_rep, q_len, k_end - k_start])
+                    .reshape([mask_batch, 1, rows, k_end - k_start])
+            };
+            let mut scores = q_tile.clone().matmul(k_tile.swap_dims(2, 3));
+            if let Some(mask) = &mask {
+                let [mask_batch, ..] = mask.dims();
+                let mask_tile =
+                    mask.clone()
+                        .slice([0..mask_batch, 0..1, q_start..q_end, k_start..k_end]);
+                scores = scores.mask_fill(per_row(mask_tile), MASKED_SCORE);
+            }
+            if let Some(document_ids) = &document_ids {
+                let queries = document_ids.clone().slice([0..batch_size, q_start..q_end]);
+                let keys = document_ids.clone().slice([0..batch_size, k_start..k_end]);
+                scores = scores.mask_fill(per_row(document_mask(queries, keys)), MASKED_SCORE);
+            }
+
+            // Rescale what has been accumulated so far to the new running maximum
+            let new_max = max.clone().max_pair(scores.clone().max_dim(3));
+            let correction = (max - new_max.clone()).exp();
+            let weights = (scores - new_max.clone()).exp();
+            denominator = denominator * correction.clone() + weights.clone().sum_dim(3);
+            acc = acc * correction + weights.matmul(v_tile);
+            max = new_max;
+        }
+
+        outputs.push((acc / denominator).reshape([
+            batch_size,
+            num_kv_heads,
+            n_rep,
+            q_len,
+            head_dim,
+        ]));
+    }
+
+    Tensor::cat(outputs, 3).reshape([batch_size, num_heads, seq_len, head_dim])
+}
+
+/// `true` where a query and a key belong to different packed samples
+///
+/// Takes the `[batch, queries]` and `[batch, keys]` document ids and returns a
+/// `[batch, 1, queries, keys]` mask.
+pub fn document_mask<B: Backend>(
+    query_documents: Tensor<B, 2, Int>,
+    key_documents: Tensor<B, 2, Int>,
+) -> Tensor<B, 4, Bool> {
+    let [batch_size, num_queries] = query_documents.dims();
+    let [_, num_keys] = key_documents.dims();
+    let shape = [batch_size, num_queries, num_keys];
+    let queries = query_documents.unsqueeze_dim::<3>(2).expand(shape);
+    let keys = key_documents.unsqueeze_dim::<3>(1).expand(shape);
+    queries.not_equal(keys).unsqueeze_dim(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::backend::NdArray;
+    use burn::tensor::{Distribution, Tolerance, activation::softmax};
+
+    type Backend = NdArray<f32>;
+
+    /// Eager reference: full scores, repeated KV heads, −∞ masking
+    fn eager_attention(
+        q: Tensor<Backend, 4>,
+        k: Tensor<Backend, 4>,
+        v: Tensor<Backend, 4>,
+        mask: Option<Tensor<Backend, 4, Bool>>,
+    ) -> Tensor<Backend, 4> {
+        let [batch_size, num_heads, _, head_dim] = q.dims();
+        let [_, num_kv_heads, total_len, _] = k.dims();
+        let n_rep = num_heads / num_kv_heads;
+        let repeat = |x: Tensor<Backend, 4>| {
+            x.unsqueeze_dim::<5>(2)
+                .expand([batch_size, num_kv_heads, n_rep, total_len, head_dim])
+                .reshape([batch_size, num_heads, total_len, head_dim])
+        };
+        let mut scores = q
+            .matmul(repeat(k).swap_dims(2, 3))
+            .div_scalar((head_dim as f32).sqrt());
+        if let Some(mask) = mask {
+            scores = scores.mask_fill(mask, f32::NEG_INFINITY);
+        }
+        softmax(scores, 3).matmul(repeat(v))
+    }
+
+    #[test]
+    fn test_tiled_attention_matches_eager() {
+        let device = Default::default();
+        let (batch_size, num_heads, num_kv_heads, head_dim) = (2, 6, 2, 8);
+        let random = |heads, len| {
+            Tensor::<Backend, 4>::random(
+                [batch_size, heads, len, head_dim],
+                Distribution::Normal(0.0, 2.0),
+                &device,
+            )
+        };
+
+        // Prefill after a cached prefix, a single decode step, and a windowed prefill
+        for (seq_len, total_len, window) in [(11, 17, None), (1, 17, None), (13, 13, Some(4))] {
+            let past_len = total_len - seq_len;
+            let q = random(num_heads, seq_len);
+            let k = random(num_kv_heads, total_len);
+            let v = random(num_kv_heads, total_len);
+            let mut mask = Vec::new();
+            for query in past_len..total_len {
+                mask.extend(
+                    (0..total_len).map(|key| {
+                        key > query || window.is_some_and(|window| query - key >= window)
+                    }),
+                );
+            }
+            let mask = Tensor::<Backend, 4, Bool>::from_data(
+                burn::tensor::TensorData::new(mask, [1, 1, seq_len, total_len]),
+                &device,
+            );
+
+            let expected = eager_attention(q.clone(), k.clone(), v.clone(), Some(mask.clone()));
+            for block_size in [1, 3, 5, 64] {
+                for causal in [None, Some(past_len)] {
+                    let tiled = tiled_attention(
+                        q.clone(),
+                        k.clone(),
+                        v.clone(),
+                        Some(mask.clone()),
+                        causal,
+                        None,
+                        block_size,
+                    );
+                    tiled
+                        .into_data()
+                        .assert_approx_eq::<f32>(&expected.to_data(), Tolerance::absolute(1e-5));
+                }
+            }
+        }
+
+        // Unmasked attention over every key
+        let (q, k, v) = (
+            random(num_heads, 5),
+            random(num_kv_heads, 9),
+            random(num_kv_heads, 9),
+        );
+        let expected = eager_attention(q.clone(), k.clone(), v.clone(), None);
+        tiled_attention(q, k, v, None, None, None, 4)
+            .into_data()
+            .assert_approx_eq::<f32>(&expected.into_data(), Tolerance::absolute(1e-5));
+    }
+}
@@ -1,11 +1,28 @@
 //! Key-value caching for autoregressive generation
+//!
+//! Three storage layouts back [`crate::KeyValueCache`]: a contiguous
+//! [`AutoregressiveCache`] sized for the whole context window, a [`PagedKvCache`] that
+//! takes fixed-size blocks from a [`PagedKvPool`] on demand, and a [`RollingKvCache`]
+//! that evicts old tokens so generation can run past the context window. The pool is
+//! bounded and can be shared by the caches of many conversations.
 
-use burn::tensor::{backend::Backend, Tensor};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use burn::tensor::{Int, Tensor, TensorData, backend::Backend};
+
+use crate::error::{ModelError, Result};
+use crate::model::Qwen2Config;
 
 /// Autoregressive cache for storing key or value tensors during generation
+///
+/// The backing buffer is allocated on the first append, so building caches for a
+/// large configuration does not reserve the whole context window up front.
 pub struct AutoregressiveCache<B: Backend> {
-    cache: Tensor<B, 4>,
+    cache: Option<Tensor<B, 4>>,
+    shape: [usize; 4],
+    device: B::Device,
     current_len: usize,
+    batch_size: usize,
 }
 
 impl<B: Backend> AutoregressiveCache<B> {
@@ -24,14 +41,12 @@ impl<B: Backend> AutoregressiveCache<B> {
         head_dim: usize,
         device: &B::Device,
     ) -> Self {
-        let cache = Tensor::zeros(
-            [max_batch_size, num_heads, max_seq_len, head_dim],
-            device,
-        );
-
         Self {
-            cache,
+            cache: None,
+            shape: [max_batch_size, num_heads, max_seq_len, head_dim],
+            device: device.clone(),
             current_len: 0,
+            batch_size: 0,
         }
     }
 
@@ -41,22 +56,47 @@ impl<B: Backend> AutoregressiveCache<B> {
     /// * `new_data` - New key or value tensor with shape [batch, num_heads, seq_len, head_dim]
     ///
     /// # Returns
-    /// Full cached tensor with shape [batch, num_heads, current_len + seq_len, head_dim]
-    pub fn forward(&mut self, new_data: Tensor<B, 4>) -> Tensor<B, 4> {
-        let [_batch, _num_heads, new_seq_len, _head_dim] = new_data.dims();
+    /// Full cached tensor with shape [batch, num_heads, current_len + seq_len, head_dim],
+    /// or [`ModelError::CacheOverflow`] if the sequence would exceed `max_seq_len`
+    pub fn forward(&mut self, new_data: Tensor<B, 4>) -> Result<Tensor<B, 4>> {
+        let [batch, num_heads, new_seq_len, head_dim] = new_data.dims();
+        let [max_batch_size, cache_heads, max_seq_len, cache_head_dim] = self.shape;
+        assert!(
+            batch <= max_batch_size && num_heads == cache_heads && head_dim == cache_head_dim,
+            "cache holds [{max_batch_size}, {cache_heads}, _, {cache_head_dim}], \
+             got [{batch}, {num_heads}, _, {head_dim}]"
+        );
 
         // Update the cache with new data
         let end_pos = self.current_len + new_seq_len;
-        self.cache = self
+        if end_pos > max_seq_len {
+            return Err(ModelError::CacheOverflow {
+                requested: end_pos,
+                capacity: max_seq_len,
+            });
+        }
+
+        let cache = self
             .cache
-            .clone()
-            .slice_assign([0..1, 0..1, self.current_len..end_pos, 0..1], new_data.clone());
+            .take()
+            .unwrap_or_else(|| Tensor::zeros(self.shape, &self.device))
+            .slice_assign(
+                [
+                    0..batch,
+                    0..num_heads,
+                    self.current_len..end_pos,
+                    0..head_dim,
+                ],
+                new_data,
+            );
+        self.cache = Some(cache.clone());
 
         // Update current length
         self.current_len = end_pos;
+        self.batch_size = batch;
 
         // Return the active portion of the cache
-        self.cache.clone().slice([0..1, 0..1, 0..end_pos, 0..1])
+        Ok(cache.slice([0..batch, 0..num_heads, 0..end_pos, 0..head_dim]))
     }
 
     /// Get the current cached sequence length
@@ -64,8 +104,1136 @@ impl<B: Backend> AutoregressiveCache<B> {
         self.current_len
     }
 
+    /// Whether nothing has been cached yet
+    pub fn is_empty(&self) -> bool {
+        self.current_len == 0
+    }
+
+    /// Maximum number of positions the cache can hold
+    pub fn capacity(&self) -> usize {
+        self.shape[2]
+    }
+
+    /// The cached sequence [batch, num_heads, current_len, head_dim], if any
+    pub fn tensor(&self) -> Option<Tensor<B, 4>> {
+        let cache = self.cache.as_ref().filter(|_| self.current_len > 0)?;
+        let [_, num_heads, _, head_dim] = self.shape;
+        Some(cache.clone().slice([
+            0..self.batch_size,
+            0..num_heads,
+            0..self.current_len,
+            0..head_dim,
+        ]))
+    }
+
+    /// Drop every cached position from `len` on; later appends overwrite them
+    pub fn truncate(&mut self, len: usize) {
+        self.current_len = self.current_len.min(len);
+    }
+
+    /// An independent copy of the cache that shares the buffer until either one writes
+    ///
+    /// Tensors are copy-on-write, so the first append to a shared buffer copies it.
+    pub fn fork(&self) -> Self {
+        Self {
+            cache: self.cache.clone(),
+            shape: self.shape,
+            device: self.device.clone(),
+            current_len: self.current_len,
+            batch_size: self.batch_size,
+        }
+    }
+
+    /// Rebuild the batch from copies of the sequences at `rows`, in order
+    ///
+    /// A row may be repeated, e.g. to expand one prompt into several continuations,
+    /// or left out to drop its sequence. The selected rows are copied.
+    pub fn fork_sequences(&mut self, rows: &[usize]) {
+        let [max_batch_size, ..] = self.shape;
+        assert!(
+            rows.len() <= max_batch_size && rows.iter().all(|&row| row < self.batch_size),
+            "cannot fork rows {rows:?} of {} into a batch of at most {max_batch_size}",
+            self.batch_size
+        );
+        if let Some(cache) = self.cache.take() {
+            // Unused rows of the buffer are filled with copies of row 0
+            let ids: Vec<i64> = (0..max_batch_size)
+                .map(|i| rows.get(i).map_or(0, |&row| row as i64))
+                .collect();
+            let ids = Tensor::<B, 1, Int>::from_data(
+                TensorData::new(ids, [max_batch_size]),
+                &self.device,
+            );
+            self.cache = Some(cache.select(0, ids));
+        }
+        self.batch_size = rows.len();
+    }
+
+    /// Capture the cached positions so [`Self::restore`] can return to them
+    pub fn snapshot(&self) -> AutoregressiveSnapshot<B> {
+        AutoregressiveSnapshot {
+            cache: self.cache.clone(),
+            current_len: self.current_len,
+            batch_size: self.batch_size,
+        }
+    }
+
+    /// Return to the state captured by `snapshot`, a snapshot of a cache of this shape
+    pub fn restore(&mut self, snapshot: &AutoregressiveSnapshot<B>) {
+        if let Some(cache) = &snapshot.cache {
+            assert_eq!(
+                cache.dims(),
+                self.shape,
+                "snapshot of a cache of another shape"
+            );
+        }
+        self.cache = snapshot.cache.clone();
+        self.current_len = snapshot.current_len;
+        self.batch_size = snapshot.batch_size;
+    }
+
     /// Reset the cache (for new prompts)
     pub fn reset(&mut self) {
         self.current_len = 0;
     }
 }
+
+/// State of an [`AutoregressiveCache`] saved by [`AutoregressiveCache::snapshot`]
+///
+/// The buffer is shared with the cache, so taking a snapshot copies nothing until the
+/// cache is written to again.
+pub struct AutoregressiveSnapshot<B: Backend> {
+    cache: Option<Tensor<B, 4>>,
+    current_len: usize,
+    batch_size: usize,
+}
+
+/// Rolling-buffer key/value cache keeping attention sinks and a recent window
+///
+/// The first `sink_tokens` positions are kept for good, plus the most recent tokens so
+/// that each new query sees `window` tokens including itself; older tokens are
+/// evicted. Keys are stored before RoPE and rotated by their slot in the buffer
+/// (StreamingLLM), so positions never exceed the buffer size and generation can
+/// continue indefinitely.
+pub struct RollingKvCache<B: Backend> {
+    key: Option<Tensor<B, 4>>,
+    value: Option<Tensor<B, 4>>,
+    sink_tokens: usize,
+    window: usize,
+    max_seq_len: usize,
+}
+
+impl<B: Backend> RollingKvCache<B> {
+    /// Create a rolling cache whose buffer fits within `max_seq_len` positions
+    pub fn new(sink_tokens: usize, window: usize, max_seq_len: usize) -> Self {
+        assert!(window > 0, "window must be positive");
+        assert!(
+            sink_tokens + window <= max_seq_len,
+            "{sink_tokens} sink tokens and a window of {window} exceed {max_seq_len} positions"
+        );
+        Self {
+            key: None,
+            value: None,
+            sink_tokens,
+            window,
+            max_seq_len,
+        }
+    }
+
+    /// Append unrotated keys and values and return the buffer including them
+    ///
+    /// Eviction happens after the call, so the returned tensors still hold every
+    /// token the new queries may need; [`crate::model::Qwen2Attention`] masks the
+    /// stale ones.
+    pub fn forward(
+        &mut self,
+        key: Tensor<B, 4>,
+        value: Tensor<B, 4>,
+    ) -> Result<(Tensor<B, 4>, Tensor<B, 4>)> {
+        let new_seq_len = key.dims()[2];
+        self.reserve(new_seq_len)?;
+
+        let append = |cache: Option<Tensor<B, 4>>, new: Tensor<B, 4>| match cache {
+            Some(cache) => Tensor::cat(vec![cache, new], 2),
+            None => new,
+        };
+        let key = append(self.key.take(), key);
+        let value = append(self.value.take(), value);
+
+        self.key = Some(self.evict(key.clone()));
+        self.value = Some(self.evict(value.clone()));
+        Ok((key, value))
+    }
+
+    /// Keep the sinks and the `window - 1` most recent tokens
+    fn evict(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
+        let [batch, num_heads, seq_len, head_dim] = x.dims();
+        let keep = self.sink_tokens + self.window - 1;
+        if seq_len <= keep {
+            return x;
+        }
+
+        let recent = x.clone().slice([
+            0..batch,
+            0..num_heads,
+            seq_len - (self.window - 1)..seq_len,
+            0..head_dim,
+        ]);
+        if self.sink_tokens == 0 {
+            return recent;
+        }
+        let sinks = x.slice([0..batch, 0..num_heads, 0..self.sink_tokens, 0..head_dim]);
+        Tensor::cat(vec![sinks, recent], 2)
+    }
+
+    /// Check that `new_tokens` more positions fit in the buffer
+    pub fn reserve(&self, new_tokens: usize) -> Result<()> {
+        let requested = self.len() + new_tokens;
+        if requested > self.max_seq_len {
+            return Err(ModelError::CacheOverflow {
+                requested,
+                capacity: self.max_seq_len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Number of tokens currently in the buffer
+    pub fn len(&self) -> usize {
+        self.key.as_ref().map_or(0, |key| key.dims()[2])
+    }
+
+    /// Whether nothing has been cached yet
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of positions the buffer can hold during one forward pass
+    pub fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Number of attention-sink tokens
+    pub fn sink_tokens(&self) -> usize {
+        self.sink_tokens
+    }
+
+    /// Tokens each query attends to besides the sinks, itself included
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// The buffered keys and values [batch, num_heads, len, head_dim], if any
+    ///
+    /// Keys are unrotated.
+    pub fn tensors(&self) -> Option<(Tensor<B, 4>, Tensor<B, 4>)> {
+        Some((self.key.clone()?, self.value.clone()?))
+    }
+
+    /// Keep only the first `len` slots of the buffer
+    ///
+    /// Slots count from the start of the buffer, not of the conversation: tokens
+    /// evicted since are not brought back.
+    pub fn truncate(&mut self, len: usize) {
+        if len == 0 {
+            return self.reset();
+        }
+        if len >= self.len() {
+            return;
+        }
+        let keep = |x: Tensor<B, 4>| {
+            let [batch, num_heads, _, head_dim] = x.dims();
+            x.slice([0..batch, 0..num_heads, 0..len, 0..head_dim])
+        };
+        self.key = self.key.take().map(keep);
+        self.value = self.value.take().map(keep);
+    }
+
+    /// Rebuild the batch from copies of the sequences at `rows`, in order
+    pub fn fork_sequences(&mut self, rows: &[usize]) {
+        let ids: Vec<i64> = rows.iter().map(|&row| row as i64).collect();
+        let select = |x: Tensor<B, 4>| {
+            let ids = TensorData::new(ids.clone(), [rows.len()]);
+            let ids = Tensor::<B, 1, Int>::from_data(ids, &x.device());
+            x.select(0, ids)
+        };
+        self.key = self.key.take().map(select);
+        self.value = self.value.take().map(select);
+    }
+
+    /// An independent copy of the cache sharing its buffers until either one writes
+    pub fn fork(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            value: self.value.clone(),
+            sink_tokens: self.sink_tokens,
+            window: self.window,
+            max_seq_len: self.max_seq_len,
+        }
+    }
+
+    /// Reset the cache (for new prompts)
+    pub fn reset(&mut self) {
+        self.key = None;
+        self.value = None;
+    }
+}
+
+/// Bounded pool of KV blocks shared by paged caches
+///
+/// Every layer has its own block storage and free list. Cloning the pool shares it,
+/// so caches created from clones draw from the same memory.
+#[derive(Clone)]
+pub struct PagedKvPool<B: Backend> {
+    layers: Vec<Arc<Mutex<BlockStore<B>>>>,
+    block_size: usize,
+    max_seq_len: usize,
+}
+
+impl<B: Backend> PagedKvPool<B> {
+    /// Create a pool of `num_blocks` blocks of `block_size` positions per layer
+    ///
+    /// Storage is allocated on the first write.
+    pub fn new(
+        config: &Qwen2Config,
+        block_size: usize,
+        num_blocks: usize,
+        device: &B::Device,
+    ) -> Self {
+        assert!(block_size > 0, "block_size must be positive");
+        let head_dim = config.head_dim();
+        let shape = [num_blocks, config.num_key_value_heads, block_size, head_dim];
+
+        let layers = (0..config.num_hidden_layers)
+            .map(|_| {
+                Arc::new(Mutex::new(BlockStore {
+                    key: None,
+                    value: None,
+                    shape,
+                    device: device.clone(),
+                    // Reversed so blocks are handed out in ascending order
+                    free: (0..num_blocks).rev().collect(),
+                    refs: vec![0; num_blocks],
+                }))
+            })
+            .collect();
+
+        Self {
+            layers,
+            block_size,
+            max_seq_len: config.context_length(),
+        }
+    }
+
+    /// Positions per block
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Blocks per layer
+    pub fn num_blocks(&self) -> usize {
+        self.layers.first().map_or(0, |store| lock(store).shape[0])
+    }
+
+    /// Blocks per layer not held by any cache
+    pub fn free_blocks(&self) -> usize {
+        self.layers
+            .first()
+            .map_or(0, |store| lock(store).free.len())
+    }
+
+    /// Create the cache of `layer` for up to `max_batch_size` sequences
+    pub(crate) fn cache(&self, layer: usize, max_batch_size: usize) -> PagedKvCache<B> {
+        PagedKvCache {
+            store: self.layers[layer].clone(),
+            block_size: self.block_size,
+            max_seq_len: self.max_seq_len,
+            max_batch_size,
+            block_tables: Vec::new(),
+            lengths: Vec::new(),
+        }
+    }
+}
+
+/// Key and value blocks of one layer, `[num_blocks, num_heads, block_size, head_dim]`
+struct BlockStore<B: Backend> {
+    key: Option<Tensor<B, 4>>,
+    value: Option<Tensor<B, 4>>,
+    shape: [usize; 4],
+    device: B::Device,
+    free: Vec<usize>,
+    /// Block tables referencing each block; forked caches share blocks
+    refs: Vec<usize>,
+}
+
+impl<B: Backend> BlockStore<B> {
+    fn allocate(&mut self) -> Option<usize> {
+        let block = self.free.pop()?;
+        self.refs[block] = 1;
+        Some(block)
+    }
+
+    fn share(&mut self, block: usize) {
+        self.refs[block] += 1;
+    }
+
+    /// Drop one reference to `block`, returning it to the free list with the last
+    fn release(&mut self, block: usize) {
+        self.refs[block] -= 1;
+        if self.refs[block] == 0 {
+            self.free.push(block);
+        }
+    }
+
+    fn release_all(&mut self, table: Vec<usize>) {
+        table
+            .into_iter()
+            .rev()
+            .for_each(|block| self.release(block));
+    }
+
+    /// Copy the keys and values of block `from` into block `to`
+    fn copy_block(&mut self, from: usize, to: usize) {
+        let [_, num_heads, block_size, head_dim] = self.shape;
+        let copy = |blocks: Tensor<B, 4>| {
+            let source =
+                blocks
+                    .clone()
+                    .slice([from..from + 1, 0..num_heads, 0..block_size, 0..head_dim]);
+            blocks.slice_assign(
+                [to..to + 1, 0..num_heads, 0..block_size, 0..head_dim],
+                source,
+            )
+        };
+        self.key = self.key.take().map(copy);
+        self.value = self.value.take().map(copy);
+    }
+}
+
+fn lock<B: Backend>(store: &Mutex<BlockStore<B>>) -> std::sync::MutexGuard<'_, BlockStore<B>> {
+    store.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Paged key/value cache of one layer
+///
+/// Each sequence of the batch owns a block table mapping its positions to blocks of
+/// the shared [`PagedKvPool`], and has its own length: sequences of a continuous batch
+/// join and leave independently (see [`crate::scheduler`]). Blocks are returned to the
+/// pool on reset and drop. Forks share blocks; a shared block is copied before it is
+/// written to.
+pub struct PagedKvCache<B: Backend> {
+    store: Arc<Mutex<BlockStore<B>>>,
+    block_size: usize,
+    max_seq_len: usize,
+    max_batch_size: usize,
+    block_tables: Vec<Vec<usize>>,
+    lengths: Vec<usize>,
+}
+
+impl<B: Backend> PagedKvCache<B> {
+    /// Take enough blocks from the pool for `new_tokens` more positions per sequence
+    ///
+    /// Either every sequence gets its blocks or none does; running out of blocks or
+    /// positions is reported as [`ModelError::CacheOverflow`], a batch larger than the
+    /// cache's or a new batch size while tokens are cached as [`ModelError::InvalidInput`].
+    pub fn reserve(&mut self, batch_size: usize, new_tokens: usize) -> Result<()> {
+        if batch_size > self.max_batch_size {
+            return Err(ModelError::InvalidInput(format!(
+                "batch of {batch_size} exceeds the cache's {}",
+                self.max_batch_size
+            )));
+        }
+        if self.is_empty() && self.block_tables.len() != batch_size {
+            self.release();
+            self.block_tables = vec![Vec::new(); batch_size];
+            self.lengths = vec![0; batch_size];
+        }
+        if self.block_tables.len() != batch_size {
+            return Err(ModelError::InvalidInput(format!(
+                "batch of {batch_size} while the cache holds {} sequences",
+                self.block_tables.len()
+            )));
+        }
+
+        let end_pos = self.len() + new_tokens;
+        if end_pos > self.max_seq_len {
+            return Err(ModelError::CacheOverflow {
+                requested: end_pos,
+                capacity: self.max_seq_len,
+            });
+        }
+
+        let mut store = lock(&self.store);
+        // New blocks, plus private copies of the shared blocks about to be written
+        let needed: usize = self
+            .block_tables
+            .iter()
+            .zip(&self.lengths)
+            .map(|(table, &len)| {
+                let shared = written_blocks(table, len, new_tokens, self.block_size)
+                    .filter(|&i| store.refs[table[i]] > 1)
+                    .count();
+                let end = (len + new_tokens).div_ceil(self.block_size);
+                end.saturating_sub(table.len()) + shared
+            })
+            .sum();
+        if needed > store.free.len() {
+            let held = self.block_tables.iter().map(Vec::len).min().unwrap_or(0);
+            let reachable = held + store.free.len() / batch_size.max(1);
+            return Err(ModelError::CacheOverflow {
+                requested: end_pos,
+                capacity: (reachable * self.block_size).min(self.max_seq_len),
+            });
+        }
+
+        for (table, &len) in self.block_tables.iter_mut().zip(&self.lengths) {
+            for i in written_blocks(table, len, new_tokens, self.block_size) {
+                let block = table[i];
+                if store.refs[block] > 1 {
+                    let copy = store.allocate().expect("checked free blocks");
+                    store.copy_block(block, copy);
+                    store.release(block);
+                    table[i] = copy;
+                }
+            }
+            while table.len() < (len + new_tokens).div_ceil(self.block_size) {
+                table.push(store.allocate().expect("checked free blocks"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Append new key/value tensors and return the full cached sequences
+    ///
+    /// Row `i` of the input is written after the cached tokens of sequence `i`.
+    ///
+    /// # Arguments
+    /// * `key`, `value` - New tensors with shape [batch, num_heads, seq_len, head_dim]
+    ///
+    /// # Returns
+    /// Full cached tensors with shape [batch, num_heads, len, head_dim], where `len` is
+    /// the longest sequence; shorter sequences are padded with stale entries
+    pub fn forward(
+        &mut self,
+        key: Tensor<B, 4>,
+        value: Tensor<B, 4>,
+    ) -> Result<(Tensor<B, 4>, Tensor<B, 4>)> {
+        let [batch, num_heads, new_seq_len, head_dim] = key.dims();
+        self.reserve(batch, new_seq_len)?;
+
+        let mut store = lock(&self.store);
+        assert!(
+            num_heads == store.shape[1] && head_dim == store.shape[3],
+            "pool holds [_, {}, _, {}] blocks, got [{batch}, {num_heads}, _, {head_dim}]",
+            store.shape[1],
+            store.shape[3]
+        );
+        let mut keys = store
+            .key
+            .take()
+            .unwrap_or_else(|| Tensor::zeros(store.shape, &store.device));
+        let mut values = store
+            .value
+            .take()
+            .unwrap_or_else(|| Tensor::zeros(store.shape, &store.device));
+
+        // Scatter the new positions into their blocks, one contiguous run per block
+        for (row, (table, len)) in self.block_tables.iter().zip(&mut self.lengths).enumerate() {
+            let start_pos = *len;
+            let end_pos = start_pos + new_seq_len;
+            let mut pos = start_pos;
+            while pos < end_pos {
+                let block = table[pos / self.block_size];
+                let offset = pos % self.block_size;
+                let count = (self.block_size - offset).min(end_pos - pos);
+                let src = pos - start_pos;
+
+                let target = [
+                    block..block + 1,
+                    0..num_heads,
+                    offset..offset + count,
+                    0..head_dim,
+                ];
+                let source = [row..row + 1, 0..num_heads, src..src + count, 0..head_dim];
+                keys = keys.slice_assign(target.clone(), key.clone().slice(source.clone()));
+                values = values.slice_assign(target, value.clone().slice(source));
+                pos += count;
+            }
+            *len = end_pos;
+        }
+
+        let gathered = (self.gather(&keys), self.gather(&values));
+        store.key = Some(keys);
+        store.value = Some(values);
+        Ok(gathered)
+    }
+
+    /// Read every sequence's blocks back in position order, padded to the longest
+    fn gather(&self, blocks: &Tensor<B, 4>) -> Tensor<B, 4> {
+        let [_, num_heads, block_size, head_dim] = blocks.dims();
+        let len = self.len();
+        let num_blocks = len.div_ceil(block_size);
+        let rows = self
+            .block_tables
+            .iter()
+            .map(|table| {
+                // Padding positions read block 0; attention masks them out
+                let ids: Vec<i64> = (0..num_blocks)
+                    .map(|i| table.get(i).map_or(0, |&block| block as i64))
+                    .collect();
+                let ids = Tensor::<B, 1, Int>::from_data(
+                    TensorData::new(ids, [num_blocks]),
+                    &blocks.device(),
+                );
+                blocks
+                    .clone()
+                    .select(0, ids)
+                    .swap_dims(0, 1)
+                    .reshape([1, num_heads, num_blocks * block_size, head_dim])
+                    .slice([0..1, 0..num_heads, 0..len, 0..head_dim])
+            })
+            .collect();
+        Tensor::cat(rows, 0)
+    }
+
+    /// Length of the longest cached sequence
+    pub fn len(&self) -> usize {
+        self.lengths.iter().copied().max().unwrap_or(0)
+    }
+
+    /// Whether nothing has been cached yet
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cached length of every sequence of the batch
+    pub fn sequence_lengths(&self) -> &[usize] {
+        &self.lengths
+    }
+
+    /// Maximum number of positions a sequence may reach, pool size permitting
+    pub fn capacity(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// The cached keys and values [batch, num_heads, len, head_dim], if any
+    pub fn tensors(&self) -> Option<(Tensor<B, 4>, Tensor<B, 4>)> {
+        if self.is_empty() {
+            return None;
+        }
+        let store = lock(&self.store);
+        Some((
+            self.gather(store.key.as_ref()?),
+            self.gather(store.value.as_ref()?),
+        ))
+    }
+
+    /// Blocks currently held by all sequences
+    pub fn num_blocks(&self) -> usize {
+        self.block_tables.iter().map(Vec::len).sum()
+    }
+
+    /// Move the sequences of `other`, a cache of the same pool and layer, to the end
+    /// of this batch without copying their blocks
+    pub fn append(&mut self, mut other: PagedKvCache<B>) {
+        assert!(
+            Arc::ptr_eq(&self.store, &other.store),
+            "paged caches belong to different pools or layers"
+        );
+        assert!(
+            self.block_tables.len() + other.block_tables.len() <= self.max_batch_size,
+            "batch would exceed the cache's {}",
+            self.max_batch_size
+        );
+        self.block_tables.append(&mut other.block_tables);
+        self.lengths.append(&mut other.lengths);
+    }
+
+    /// Drop sequence `row` from the batch and return its blocks to the pool
+    pub fn remove(&mut self, row: usize) {
+        let table = self.block_tables.remove(row);
+        self.lengths.remove(row);
+        lock(&self.store).release_all(table);
+    }
+
+    /// Cut every sequence down to at most `len` positions, returning the blocks past
+    /// them to the pool
+    pub fn truncate(&mut self, len: usize) {
+        let mut store = lock(&self.store);
+        for (table, length) in self.block_tables.iter_mut().zip(&mut self.lengths) {
+            *length = (*length).min(len);
+            let kept = length.div_ceil(self.block_size);
+            if table.len() > kept {
+                store.release_all(table.split_off(kept));
+            }
+        }
+    }
+
+    /// Rebuild the batch from the sequences at `rows`, in order
+    ///
+    /// A row may be repeated, e.g. to expand one prompt into several continuations,
+    /// or left out to drop its sequence and release its blocks. Repeated rows share
+    /// their blocks, copying them on write as [`Self::fork`] does.
+    pub fn fork_sequences(&mut self, rows: &[usize]) {
+        assert!(
+            rows.len() <= self.max_batch_size,
+            "batch would exceed the cache's {}",
+            self.max_batch_size
+        );
+        let mut store = lock(&self.store);
+        let block_tables: Vec<Vec<usize>> = rows
+            .iter()
+            .map(|&row| {
+                let table = self.block_tables[row].clone();
+                table.iter().for_each(|&block| store.share(block));
+                table
+            })
+            .collect();
+        self.lengths = rows.iter().map(|&row| self.lengths[row]).collect();
+        for table in std::mem::replace(&mut self.block_tables, block_tables) {
+            store.release_all(table);
+        }
+    }
+
+    /// An independent copy of the cache sharing the blocks of its cached positions
+    ///
+    /// No memory is copied up front: whichever cache first writes to a shared block
+    /// takes a private copy of it from the pool.
+    pub fn fork(&self) -> Self {
+        let mut store = lock(&self.store);
+        let block_tables = self
+            .block_tables
+            .iter()
+            .zip(&self.lengths)
+            .map(|(table, len)| {
+                let table = table[..len.div_ceil(self.block_size)].to_vec();
+                table.iter().for_each(|&block| store.share(block));
+                table
+            })
+            .collect();
+        Self {
+            store: self.store.clone(),
+            block_size: self.block_size,
+            max_seq_len: self.max_seq_len,
+            max_batch_size: self.max_batch_size,
+            block_tables,
+            lengths: self.lengths.clone(),
+        }
+    }
+
+    /// Reset the cache and return its blocks to the pool
+    pub fn reset(&mut self) {
+        self.release();
+    }
+
+    fn release(&mut self) {
+        self.lengths.clear();
+        if self.block_tables.is_empty() {
+            return;
+        }
+        let mut store = lock(&self.store);
+        for table in self.block_tables.drain(..) {
+            store.release_all(table);
+        }
+    }
+}
+
+/// Indices in `table` of the blocks that `new_tokens` positions after `len` write to
+fn written_blocks(
+    table: &[usize],
+    len: usize,
+    new_tokens: usize,
+    block_size: usize,
+) -> std::ops::Range<usize> {
+    if new_tokens == 0 {
+        return 0..0;
+    }
+    let end = (len + new_tokens).div_ceil(block_size).min(table.len());
+    (len / block_size).min(end)..end
+}
+
+impl<B: Backend> Drop for PagedKvCache<B> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{CacheLayout, KeyValueCache};
+    use burn::backend::NdArray;
+    use burn::tensor::{Int, Tolerance};
+
+    type Backend = NdArray<f32>;
+
+    fn tokens(rows: &[&[i64]]) -> Tensor<Backend, 2, Int> {
+        let data: Vec<Tensor<Backend, 2, Int>> = rows
+            .iter()
+            .map(|row| Tensor::<Backend, 1, Int>::from_ints(*row, &Default::default()).unsqueeze())
+            .collect();
+        Tensor::cat(data, 0)
+    }
+
+    #[test]
+    fn test_incremental_decoding_matches_full_forward() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let input = tokens(&[&[3, 14, 15, 9, 26, 5, 35], &[8, 9, 7, 9, 3, 2, 38]]);
+        let [batch_size, seq_len] = input.dims();
+
+        let mut cache = model.init_cache(&config, batch_size, &CacheLayout::Contiguous, &device);
+        let full = model.forward(input.clone(), &mut cache).unwrap();
+
+        // Prefill a prefix, then decode the rest one token at a time
+        let prefix = 3;
+        let mut cache = model.init_cache(&config, batch_size, &CacheLayout::Contiguous, &device);
+        let mut steps = vec![
+            model
+                .forward(input.clone().slice([0..batch_size, 0..prefix]), &mut cache)
+                .unwrap(),
+        ];
+        for pos in prefix..seq_len {
+            let token = input.clone().slice([0..batch_size, pos..pos + 1]);
+            steps.push(model.forward(token, &mut cache).unwrap());
+        }
+        assert_eq!(cache[0].len(), seq_len);
+
+        Tensor::cat(steps, 1)
+            .into_data()
+            .assert_approx_eq::<f32>(&full.into_data(), Tolerance::default());
+    }
+
+    #[test]
+    fn test_paged_cache_matches_contiguous() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let input = tokens(&[&[3, 14, 15, 9, 26, 5, 35], &[8, 9, 7, 9, 3, 2, 38]]);
+        let [batch_size, seq_len] = input.dims();
+
+        let mut cache = model.init_cache(&config, batch_size, &CacheLayout::Contiguous, &device);
+        let expected = model.forward(input.clone(), &mut cache).unwrap();
+
+        // Blocks of 3 positions: the prompt spans block boundaries and the decode
+        // steps open new blocks
+        let pool = PagedKvPool::new(&config, 3, 8, &device);
+        let layout = CacheLayout::Paged(pool.clone());
+        let mut cache = model.init_cache(&config, batch_size, &layout, &device);
+        let prefix = 4;
+        let mut steps = vec![
+            model
+                .forward(input.clone().slice([0..batch_size, 0..prefix]), &mut cache)
+                .unwrap(),
+        ];
+        for pos in prefix..seq_len {
+            let token = input.clone().slice([0..batch_size, pos..pos + 1]);
+            steps.push(model.forward(token, &mut cache).unwrap());
+        }
+
+        Tensor::cat(steps, 1)
+            .into_data()
+            .assert_approx_eq::<f32>(&expected.into_data(), Tolerance::default());
+        // Two sequences of 7 positions hold 3 blocks each
+        assert_eq!(pool.free_blocks(), 2);
+
+        drop(cache);
+        assert_eq!(pool.free_blocks(), 8);
+    }
+
+    #[test]
+    fn test_paged_pool_is_shared_and_bounded() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let pool = PagedKvPool::<Backend>::new(&config, 4, 3, &device);
+        let layout = CacheLayout::Paged(pool.clone());
+
+        let mut first = model.init_cache(&config, 1, &layout, &device);
+        let mut second = model.init_cache(&config, 1, &layout, &device);
+        model
+            .forward(tokens(&[&[1, 2, 3, 4, 5]]), &mut first)
+            .unwrap();
+        assert_eq!(pool.free_blocks(), 1);
+
+        // The second conversation needs two blocks but only one is left
+        let err = model
+            .forward(tokens(&[&[6, 7, 8, 9, 10]]), &mut second)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ModelError::CacheOverflow { requested: 5, .. }
+        ));
+        assert!(second.iter().all(KeyValueCache::is_empty));
+
+        first.iter_mut().for_each(KeyValueCache::reset);
+        assert_eq!(pool.free_blocks(), 3);
+        model
+            .forward(tokens(&[&[6, 7, 8, 9, 10]]), &mut second)
+            .unwrap();
+        assert_eq!(second[0].len(), 5);
+
+        // Batches the cache cannot take are errors, not panics
+        let err = model
+            .forward(tokens(&[&[1], &[2]]), &mut second)
+            .unwrap_err();
+        assert!(matches!(err, ModelError::InvalidInput(_)));
+        let mut third = model.init_cache(&config, 2, &layout, &device);
+        model.forward(tokens(&[&[1]]), &mut third).unwrap();
+        let err = model
+            .forward(tokens(&[&[1], &[2]]), &mut third)
+            .unwrap_err();
+        assert!(matches!(err, ModelError::InvalidInput(_)));
+        assert_eq!((second[0].len(), third[0].len()), (5, 1));
+    }
+
+    #[test]
+    fn test_rolling_cache_matches_contiguous_within_window() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let input = tokens(&[&[3, 14, 15, 9, 26, 5, 35]]);
+        let seq_len = input.dims()[1];
+
+        let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+        let expected = model.forward(input.clone(), &mut cache).unwrap();
+
+        let layout = CacheLayout::Rolling {
+            sink_tokens: 2,
+            window: 16,
+        };
+        let mut cache = model.init_cache(&config, 1, &layout, &device);
+        let mut steps = vec![
+            model
+                .forward(input.clone().slice([0..1, 0..3]), &mut cache)
+                .unwrap(),
+        ];
+        for pos in 3..seq_len {
+            let token = input.clone().slice([0..1, pos..pos + 1]);
+            steps.push(model.forward(token, &mut cache).unwrap());
+        }
+
+        Tensor::cat(steps, 1)
+            .into_data()
+            .assert_approx_eq::<f32>(&expected.into_data(), Tolerance::default());
+    }
+
+    #[test]
+    fn test_rolling_cache_generates_past_context_window() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let layout = CacheLayout::Rolling {
+            sink_tokens: 2,
+            window: 8,
+        };
+        let mut cache = model.init_cache(&config, 1, &layout, &device);
+
+        model
+            .forward(tokens(&[&[1, 2, 3, 4, 5]]), &mut cache)
+            .unwrap();
+        for step in 0..2 * config.max_position_embeddings {
+            let logits = model
+                .forward(tokens(&[&[(step % 60) as i64]]), &mut cache)
+                .unwrap();
+            assert!(
+                logits
+                    .into_data()
+                    .to_vec::<f32>()
+                    .unwrap()
+                    .iter()
+                    .all(|x| x.is_finite())
+            );
+        }
+        // Sinks plus the tokens the next query will see besides itself
+        assert_eq!(cache[0].len(), 2 + 7);
+    }
+
+    #[test]
+    fn test_truncate_then_regenerate_matches() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let prompt = tokens(&[&[3, 14, 15, 9, 26, 5, 35]]);
+        let continuation = tokens(&[&[8, 9, 7, 9]]);
+        let detour = tokens(&[&[2, 38, 11]]);
+
+        let pool = PagedKvPool::new(&config, 3, 16, &device);
+        let layouts = [
+            CacheLayout::Contiguous,
+            CacheLayout::Paged(pool.clone()),
+            CacheLayout::Rolling {
+                sink_tokens: 2,
+                window: 16,
+            },
+        ];
+        for layout in layouts {
+            let mut cache = model.init_cache(&config, 1, &layout, &device);
+            model.forward(prompt.clone(), &mut cache).unwrap();
+            let free_blocks = pool.free_blocks();
+            let expected = model.forward(continuation.clone(), &mut cache).unwrap();
+
+            // Take another path, roll it back, and regenerate the first one
+            cache.iter_mut().for_each(|cache| cache.truncate(7));
+            model.forward(detour.clone(), &mut cache).unwrap();
+            cache.iter_mut().for_each(|cache| cache.truncate(7));
+            assert_eq!(cache[0].len(), 7);
+            assert_eq!(pool.free_blocks(), free_blocks);
+
+            let regenerated = model.forward(continuation.clone(), &mut cache).unwrap();
+            regenerated
+                .into_data()
+                .assert_approx_eq::<f32>(&expected.into_data(), Tolerance::default());
+        }
+        assert_eq!(pool.free_blocks(), 16);
+    }
+
+    #[test]
+    fn test_forked_caches_are_independent() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let prompt = [3, 14, 15, 9, 26, 5, 35];
+        let branches = [[8, 9], [2, 38]];
+
+        // Each branch decoded from scratch
+        let expected = branches.map(|branch| {
+            let input = tokens(&[&[prompt.as_slice(), &branch].concat()]);
+            let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+            let logits = model.forward(input, &mut cache).unwrap();
+            logits.slice([0..1, prompt.len()..prompt.len() + 2])
+        });
+
+        // Blocks of 3 positions: the prompt ends inside its third block
+        let pool = PagedKvPool::new(&config, 3, 8, &device);
+        for layout in [CacheLayout::Contiguous, CacheLayout::Paged(pool.clone())] {
+            let mut cache = model.init_cache(&config, 1, &layout, &device);
+            model.forward(tokens(&[&prompt]), &mut cache).unwrap();
+            let mut fork: Vec<_> = cache.iter().map(KeyValueCache::fork).collect();
+            assert_eq!(fork[0].len(), prompt.len());
+
+            let mut caches = [cache, fork.split_off(0)];
+            for ((cache, branch), expected) in caches.iter_mut().zip(branches).zip(&expected) {
+                model
+                    .forward(tokens(&[&branch]), cache)
+                    .unwrap()
+                    .into_data()
+                    .assert_approx_eq::<f32>(&expected.to_data(), Tolerance::default());
+            }
+        }
+        assert_eq!(pool.free_blocks(), 8);
+
+        // Forking shares the blocks; only the shared block written to is copied
+        let layout = CacheLayout::Paged(pool.clone());
+        let mut cache = model.init_cache(&config, 1, &layout, &device);
+        model.forward(tokens(&[&prompt]), &mut cache).unwrap();
+        let mut fork: Vec<_> = cache.iter().map(KeyValueCache::fork).collect();
+        assert_eq!(pool.free_blocks(), 5);
+        model.forward(tokens(&[&[8]]), &mut fork).unwrap();
+        assert_eq!(pool.free_blocks(), 4);
+        model.forward(tokens(&[&[2]]), &mut cache).unwrap();
+        assert_eq!(pool.free_blocks(), 4);
+
+        // The first two blocks stay with the fork
+        drop(cache);
+        assert_eq!(pool.free_blocks(), 5);
+        drop(fork);
+        assert_eq!(pool.free_blocks(), 8);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let pool = PagedKvPool::new(&config, 4, 8, &device);
+
+        for layout in [CacheLayout::Contiguous, CacheLayout::Paged(pool.clone())] {
+            let mut cache = model.init_cache(&config, 1, &layout, &device);
+            model
+                .forward(tokens(&[&[3, 14, 15, 9, 26]]), &mut cache)
+                .unwrap();
+            let snapshots: Vec<_> = cache.iter().map(KeyValueCache::snapshot).collect();
+            let expected = model.forward(tokens(&[&[5, 35]]), &mut cache).unwrap();
+
+            // Restoring twice shows the snapshot survives the first restore
+            for _ in 0..2 {
+                model.forward(tokens(&[&[8, 9, 7]]), &mut cache).unwrap();
+                cache
+                    .iter_mut()
+                    .zip(&snapshots)
+                    .for_each(|(cache, snapshot)| cache.restore(snapshot));
+                assert_eq!(cache[0].len(), 5);
+                model
+                    .forward(tokens(&[&[5, 35]]), &mut cache)
+                    .unwrap()
+                    .into_data()
+                    .assert_approx_eq::<f32>(&expected.to_data(), Tolerance::default());
+            }
+        }
+        assert_eq!(pool.free_blocks(), 8);
+    }
+
+    #[test]
+    fn test_autoregressive_cache_fork_and_snapshot() {
+        let device = Default::default();
+        let mut cache = AutoregressiveCache::<Backend>::new(1, 2, 8, 2, &device);
+        cache.forward(Tensor::ones([1, 2, 3, 2], &device)).unwrap();
+        let snapshot = cache.snapshot();
+        let mut fork = cache.fork();
+
+        cache
+            .forward(Tensor::full([1, 2, 2, 2], 2.0, &device))
+            .unwrap();
+        fork.forward(Tensor::full([1, 2, 1, 2], 3.0, &device))
+            .unwrap();
+        let values = |cache: &AutoregressiveCache<Backend>| {
+            let data = cache.tensor().unwrap().into_data().to_vec::<f32>().unwrap();
+            data.chunks(2)
+                .take(cache.len())
+                .map(|pair| pair[0])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values(&cache), [1.0, 1.0, 1.0, 2.0, 2.0]);
+        assert_eq!(values(&fork), [1.0, 1.0, 1.0, 3.0]);
+
+        cache.restore(&snapshot);
+        assert_eq!(values(&cache), [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_fork_sequences_reorders_rows() {
+        let device = Default::default();
+        let mut cache = AutoregressiveCache::<Backend>::new(3, 1, 4, 1, &device);
+        let rows = Tensor::<Backend, 1>::from_floats([1.0, 2.0], &device);
+        cache.forward(rows.reshape([2, 1, 1, 1])).unwrap();
+
+        cache.fork_sequences(&[1, 0, 1]);
+        let rows = Tensor::<Backend, 1>::from_floats([3.0, 4.0, 5.0], &device);
+        let cached = cache.forward(rows.reshape([3, 1, 1, 1])).unwrap();
+        assert_eq!(
+            cached.into_data().to_vec::<f32>().unwrap(),
+            [2.0, 3.0, 1.0, 4.0, 2.0, 5.0]
+        );
+    }
+
+    #[test]
+    fn test_overflow_is_an_error() {
+        let device = Default::default();
+        let mut cache = AutoregressiveCache::<Backend>::new(1, 2, 4, 2, &device);
+
+        cache.forward(Tensor::zeros([1, 2, 3, 2], &device)).unwrap();
+        let err = cache
+            .forward(Tensor::zeros([1, 2, 2, 2], &device))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ModelError::CacheOverflow {
+                requested: 5,
+                capacity: 4
+            }
+        ));
+        assert_eq!(cache.len(), 3);
+    }
+}
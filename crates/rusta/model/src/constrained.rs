@@ -0,0 +1,277 @@
+//! Grammar-constrained decoding
+//!
+//! A [`GrammarConstraint`] masks the logits of every step so that only tokens keeping
+//! the output a prefix of the grammar's language can be sampled, and lets the stop
+//! tokens through once the output is complete. With
+//! [`crate::stream::TokenStream::with_constraint`] and [`Grammar::action_block`], the
+//! model can only emit valid ActionBlock JSON.
+//!
+//! The vocabulary is kept in a byte trie: a token is allowed if the matcher survives
+//! its bytes, and a rejected prefix rules out every token that starts with it.
+
+use std::sync::Arc;
+
+use crate::grammar::{Grammar, GrammarMatcher, State};
+
+/// Token byte strings arranged by common prefix
+#[derive(Debug, Clone)]
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+    tokens: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    /// Tokens whose bytes end at this node
+    tokens: Vec<u32>,
+}
+
+impl TokenTrie {
+    /// Index the bytes of every token, by id
+    ///
+    /// Tokens without bytes, such as control tokens, are never allowed by a grammar.
+    pub fn new<T: AsRef<[u8]>>(tokens: impl IntoIterator<Item = T>) -> Self {
+        let tokens: Vec<Vec<u8>> = tokens.into_iter().map(|t| t.as_ref().to_vec()).collect();
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in tokens.iter().enumerate() {
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for &byte in bytes {
+                node = match nodes[node].children.iter().find(|(b, _)| *b == byte) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            nodes[node].tokens.push(id as u32);
+        }
+        Self { nodes, tokens }
+    }
+
+    /// Number of token ids, including those without bytes
+    pub fn vocab_size(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Bytes of token `id`
+    pub fn token_bytes(&self, id: u32) -> Option<&[u8]> {
+        self.tokens.get(id as usize).map(Vec::as_slice)
+    }
+
+    /// Tokens the grammar allows after the text `matcher` has seen, in no set order
+    pub fn allowed_tokens(&self, matcher: &GrammarMatcher) -> Vec<u32> {
+        let mut allowed = Vec::new();
+        self.collect(0, matcher.state(), matcher.grammar(), &mut allowed);
+        allowed
+    }
+
+    fn collect(&self, node: usize, state: &State, grammar: &Grammar, allowed: &mut Vec<u32>) {
+        for &(byte, child) in &self.nodes[node].children {
+            if let Some(next) = state.step(grammar, byte) {
+                allowed.extend(&self.nodes[child].tokens);
+                self.collect(child, &next, grammar, allowed);
+            }
+        }
+    }
+}
+
+/// Per-sequence grammar state of a constrained generation
+#[derive(Debug, Clone)]
+pub struct GrammarConstraint {
+    grammar: Arc<Grammar>,
+    trie: Arc<TokenTrie>,
+    /// One matcher per sequence of the batch, created on first use
+    matchers: Vec<GrammarMatcher>,
+}
+
+impl GrammarConstraint {
+    pub fn new(grammar: Grammar, trie: Arc<TokenTrie>) -> Self {
+        Self {
+            grammar: Arc::new(grammar),
+            trie,
+            matchers: Vec::new(),
+        }
+    }
+
+    /// The matcher of sequence `row`
+    pub fn matcher(&mut self, row: usize) -> &GrammarMatcher {
+        self.matcher_mut(row)
+    }
+
+    fn matcher_mut(&mut self, row: usize) -> &mut GrammarMatcher {
+        while self.matchers.len() <= row {
+            self.matchers.push(self.grammar.matcher());
+        }
+        &mut self.matchers[row]
+    }
+
+    /// Tokens sequence `row` may produce next: those the grammar allows, plus
+    /// `stop_tokens` once its output is complete
+    pub fn allowed_tokens(&mut self, row: usize, stop_tokens: &[u32]) -> Vec<u32> {
+        let trie = self.trie.clone();
+        let matcher = self.matcher_mut(row);
+        let mut allowed = trie.allowed_tokens(matcher);
+        if matcher.is_complete() {
+            allowed.extend(stop_tokens);
+        }
+        allowed
+    }
+
+    /// Set the logits of every token sequence `row` may not produce to −∞
+    ///
+    /// Logits are left unchanged if no token is allowed, which happens only once the
+    /// output is complete and `stop_tokens` is empty.
+    pub fn mask_logits(&mut self, row: usize, logits: &mut [f32], stop_tokens: &[u32]) {
+        let allowed = self.allowed_tokens(row, stop_tokens);
+        if allowed.is_empty() {
+            tracing::warn!("grammar allows no token for sequence {row}");
+            return;
+        }
+        let mut mask = vec![false; logits.len()];
+        for token in allowed {
+            if let Some(slot) = mask.get_mut(token as usize) {
+                *slot = true;
+            }
+        }
+        for (logit, allowed) in logits.iter_mut().zip(mask) {
+            if !allowed {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    /// Advance sequence `row` past `token`, returning whether the grammar allowed it
+    ///
+    /// Tokens without bytes, such as stop tokens, are allowed once the output is
+    /// complete.
+    pub fn accept_token(&mut self, row: usize, token: u32) -> bool {
+        let trie = self.trie.clone();
+        let matcher = self.matcher_mut(row);
+        match trie.token_bytes(token) {
+            Some(bytes) if !bytes.is_empty() => matcher.accept_bytes(bytes),
+            _ => matcher.is_complete(),
+        }
+    }
+
+    /// Whether the output of sequence `row` is a complete sentence of the grammar
+    pub fn is_complete(&mut self, row: usize) -> bool {
+        self.matcher_mut(row).is_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::{generate_stream, token_column};
+    use crate::model::Qwen2Config;
+    use crate::sampling::SamplingConfig;
+    use crate::stopping::{FinishReason, StopConditions};
+    use crate::stream::StreamEvent;
+    use burn::backend::NdArray;
+
+    type Backend = NdArray<f32>;
+
+    /// A byte-level vocabulary for the test model: printable ASCII and newline, a few
+    /// multi-byte pieces, the two bytes of "é", and a control token without bytes
+    fn vocabulary(vocab_size: usize) -> Vec<Vec<u8>> {
+        let pieces = [
+            "{\"",
+            "\":",
+            ",\"",
+            "schema_version",
+            "tool",
+            "args",
+            "0.",
+            "cargo_check",
+            "net_fetch",
+            "é",
+        ];
+        let mut tokens: Vec<Vec<u8>> = (b' '..=b'~').chain(*b"\n").map(|b| vec![b]).collect();
+        tokens.extend(pieces.iter().map(|p| p.as_bytes().to_vec()));
+        tokens.push(vec![0xc3]);
+        tokens.push(vec![0xa9]);
+        tokens.resize(vocab_size - 1, b"zz".to_vec());
+        tokens.push(Vec::new());
+        tokens
+    }
+
+    #[test]
+    fn test_trie_masks_tokens() {
+        let trie = Arc::new(TokenTrie::new(["a", "ab", "abc", "b", "\"", "", "\"a"]));
+        let grammar = Grammar::parse(r#"root ::= "\"" "a"+ "\"""#).unwrap();
+        let mut constraint = GrammarConstraint::new(grammar, trie);
+
+        let sorted = |mut tokens: Vec<u32>| {
+            tokens.sort();
+            tokens
+        };
+        assert_eq!(sorted(constraint.allowed_tokens(0, &[5])), [4, 6]);
+        assert!(constraint.accept_token(0, 6));
+        assert_eq!(sorted(constraint.allowed_tokens(0, &[5])), [0, 4]);
+        assert!(!constraint.accept_token(0, 3));
+
+        let mut logits = vec![1.0; 7];
+        constraint.mask_logits(0, &mut logits, &[5]);
+        assert_eq!(logits[0], 1.0);
+        assert_eq!(logits[1], f32::NEG_INFINITY);
+        assert!(constraint.accept_token(0, 4));
+        assert!(constraint.is_complete(0));
+        assert_eq!(constraint.allowed_tokens(0, &[5]), [5]);
+        assert!(constraint.accept_token(0, 5));
+
+        // Other sequences have their own state
+        assert_eq!(sorted(constraint.allowed_tokens(1, &[5])), [4, 6]);
+    }
+
+    #[test]
+    fn test_constrained_generation_emits_action_block() {
+        let device = Default::default();
+        let config = Qwen2Config {
+            vocab_size: 128,
+            ..Qwen2Config::tiny()
+        };
+        let model = config.init::<Backend>(&device);
+        let tokens = vocabulary(config.vocab_size);
+        let trie = Arc::new(TokenTrie::new(&tokens));
+        let stop_token = config.vocab_size as u32 - 1;
+        let stop = StopConditions::new().with_stop_token_ids([stop_token]);
+
+        // A random model, constrained, still produces schema-valid JSON
+        for seed in 0..3 {
+            let sampling = SamplingConfig::new().with_seed(seed);
+            let constraint = GrammarConstraint::new(Grammar::action_block(), trie.clone());
+            let input = token_column::<Backend>(&[3, 1, 4], &device).swap_dims(0, 1);
+            let events: Vec<StreamEvent> =
+                generate_stream(&model, &config, input, 400, &sampling, &stop, &device)
+                    .with_constraint(constraint)
+                    .collect();
+
+            let mut text = Vec::new();
+            let mut finished = None;
+            for event in events {
+                match event {
+                    StreamEvent::Token { token_id, .. } => {
+                        text.extend(&tokens[token_id as usize]);
+                    }
+                    StreamEvent::Finished { reason, .. } => finished = Some(reason),
+                    StreamEvent::Prefill { .. } => {}
+                }
+            }
+            let text = String::from_utf8(text).unwrap();
+            let mut matcher = Arc::new(Grammar::action_block()).matcher();
+            assert!(matcher.accept_str(&text), "{text}");
+            if finished == Some(FinishReason::StopToken(stop_token)) {
+                let block: serde_json::Value = serde_json::from_str(&text).unwrap();
+                assert_eq!(block["schema_version"], "1");
+                assert!(block["args"].is_object());
+            }
+        }
+    }
+}
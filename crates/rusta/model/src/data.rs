@@ -1,5 +1,1063 @@
 //! Data loading, tokenization, and batching
+//!
+//! [`Qwen2Tokenizer`] is Qwen2's byte-level BPE: text is split on the added tokens
+//! (`<|im_start|>`, `<|endoftext|>`, ...), NFC-normalized, cut into pieces by the Qwen
+//! pre-tokenizer regex, and each piece's bytes are merged by rank. Every byte has a
+//! token, so encoding is lossless and any token sequence decodes to bytes; a
+//! [`DecodeStream`] turns those bytes into text as generation goes, holding back
+//! characters split across tokens.
+//!
+//! For training, [`TrainingSample`]s are read from JSONL, tokenized with the
+//! [`ChatTemplate`] and packed into fixed-length [`PackedSequence`]s by a
+//! [`PackedDataset`]; the [`PackedBatcher`] turns them into next-token targets, a loss
+//! mask covering only what the assistant wrote, and the sample boundaries with which
+//! [`Qwen2ForCausalLM::forward_packed`](crate::model::Qwen2ForCausalLM::forward_packed)
+//! keeps the packed samples from seeing each other.
 
-// TODO: Implement tokenizer (Qwen2 tokenizer)
-// TODO: Implement dataset loading
-// TODO: Implement batching for training
+use std::collections::HashMap;
+use std::path::Path;
+
+use burn::config::Config;
+use burn::data::dataloader::batcher::Batcher;
+use burn::data::dataset::Dataset;
+use burn::tensor::{Bool, Int, Tensor, TensorData, backend::Backend};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::{UnicodeNormalization, is_nfc};
+
+use crate::chat::{ChatMessage, ChatTemplate};
+use crate::constrained::TokenTrie;
+use crate::error::{ModelError, Result};
+use crate::gguf::GgufVocab;
+use crate::inference::token_column;
+use crate::stopping::TokenDecoder;
+
+/// Qwen2's pre-tokenizer pattern without its final `\s+(?!\S)|\s+` alternatives,
+/// which the `regex` crate cannot express; [`pre_tokenize`] applies the lookahead
+const QWEN2_PATTERN: &str = concat!(
+    r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}",
+    r"| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+",
+);
+
+/// llama.cpp token types of added tokens: control (special) and user defined
+const GGUF_CONTROL: i32 = 3;
+const GGUF_USER_DEFINED: i32 = 4;
+
+/// A token matched verbatim before pre-tokenization
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct AddedToken {
+    pub id: u32,
+    pub content: String,
+    /// Control tokens such as `<|im_end|>`, dropped by `skip_special_tokens`
+    #[serde(default)]
+    pub special: bool,
+}
+
+/// Qwen2 byte-level BPE tokenizer
+#[derive(Debug, Clone)]
+pub struct Qwen2Tokenizer {
+    /// Bytes of every token, by id; added tokens decode to their content
+    tokens: Vec<Vec<u8>>,
+    special: Vec<bool>,
+    /// Ids of the regular tokens, by bytes
+    encoder: HashMap<Vec<u8>, u32>,
+    /// `(left, right)` → `(rank, merged)`
+    merges: HashMap<(u32, u32), (u32, u32)>,
+    byte_ids: [u32; 256],
+    added: HashMap<String, u32>,
+    /// Alternation of the added tokens, longest first
+    added_pattern: Option<Regex>,
+    pre_tokenizer: Regex,
+    nfc: bool,
+}
+
+#[derive(Deserialize)]
+struct TokenizerJson {
+    #[serde(default)]
+    added_tokens: Vec<AddedToken>,
+    normalizer: Option<NormalizerJson>,
+    model: BpeJson,
+}
+
+#[derive(Deserialize)]
+struct NormalizerJson {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Deserialize)]
+struct BpeJson {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    vocab: HashMap<String, u32>,
+    merges: Vec<MergeJson>,
+}
+
+/// Older files join a merge's halves with a space, newer ones store a pair
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MergeJson {
+    Joined(String),
+    Pair(String, String),
+}
+
+impl Qwen2Tokenizer {
+    /// Load a HuggingFace `tokenizer.json`
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(|source| ModelError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_json_str(&json)
+    }
+
+    /// Parse the contents of a HuggingFace `tokenizer.json`
+    ///
+    /// The BPE model, its merges, the added tokens and an NFC normalizer are read; the
+    /// pre-tokenizer is always Qwen2's.
+    pub fn from_json_str(json: &str) -> Result<Self> {
+        let file: TokenizerJson = serde_json::from_str(json)
+            .map_err(|err| tokenizer_error(format!("malformed tokenizer.json: {err}")))?;
+        if let Some(kind) = file.model.kind.as_deref()
+            && kind != "BPE"
+        {
+            return Err(tokenizer_error(format!(
+                "expected a BPE model, found {kind:?}"
+            )));
+        }
+        let nfc = match file.normalizer.as_ref().map(|n| n.kind.as_str()) {
+            None => false,
+            Some("NFC") => true,
+            Some(other) => return Err(tokenizer_error(format!("unsupported normalizer {other}"))),
+        };
+        let merges = file
+            .model
+            .merges
+            .into_iter()
+            .map(|merge| match merge {
+                MergeJson::Pair(left, right) => Ok((left, right)),
+                MergeJson::Joined(joined) => split_merge(&joined),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::build(file.model.vocab, merges, file.added_tokens, nfc)
+    }
+
+    /// Build the tokenizer embedded in a GGUF file
+    ///
+    /// Control and user-defined tokens become added tokens, the former special.
+    pub fn from_gguf_vocab(vocab: &GgufVocab) -> Result<Self> {
+        if vocab.model != "gpt2" {
+            return Err(tokenizer_error(format!(
+                "expected a gpt2 (byte-level BPE) vocabulary, found {:?}",
+                vocab.model
+            )));
+        }
+        let mut regular = HashMap::new();
+        let mut added = Vec::new();
+        for (id, token) in vocab.tokens.iter().enumerate() {
+            match vocab.token_types.get(id).copied() {
+                Some(kind @ (GGUF_CONTROL | GGUF_USER_DEFINED)) => added.push(AddedToken {
+                    id: id as u32,
+                    content: token.clone(),
+                    special: kind == GGUF_CONTROL,
+                }),
+                _ => {
+                    regular.insert(token.clone(), id as u32);
+                }
+            }
+        }
+        let merges = vocab
+            .merges
+            .iter()
+            .map(|merge| split_merge(merge))
+            .collect::<Result<Vec<_>>>()?;
+        Self::build(regular, merges, added, true)
+    }
+
+    fn build(
+        vocab: HashMap<String, u32>,
+        merges: Vec<(String, String)>,
+        added_tokens: Vec<AddedToken>,
+        nfc: bool,
+    ) -> Result<Self> {
+        let unicode_bytes = unicode_to_bytes();
+        let to_bytes = |token: &str| {
+            token
+                .chars()
+                .map(|c| unicode_bytes.get(&c).copied())
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| tokenizer_error(format!("{token:?} is not a byte-level token")))
+        };
+
+        let added: HashMap<String, u32> = added_tokens
+            .iter()
+            .map(|token| (token.content.clone(), token.id))
+            .collect();
+        let is_added = |id: u32| added_tokens.iter().any(|token| token.id == id);
+        let vocab_size = vocab
+            .values()
+            .chain(added.values())
+            .max()
+            .map_or(0, |&max| max as usize + 1);
+        let mut tokens = vec![Vec::new(); vocab_size];
+        let mut special = vec![false; vocab_size];
+        let mut encoder = HashMap::with_capacity(vocab.len());
+        for (token, &id) in &vocab {
+            // Added tokens repeated in the vocabulary are matched only verbatim
+            if is_added(id) {
+                continue;
+            }
+            let bytes = to_bytes(token)?;
+            tokens[id as usize] = bytes.clone();
+            encoder.insert(bytes, id);
+        }
+        for token in &added_tokens {
+            tokens[token.id as usize] = token.content.as_bytes().to_vec();
+            special[token.id as usize] = token.special;
+        }
+
+        let mut byte_ids = [0; 256];
+        for (byte, id) in byte_ids.iter_mut().enumerate() {
+            *id = *encoder
+                .get(&[byte as u8][..])
+                .ok_or_else(|| tokenizer_error(format!("no token for byte {byte:#04x}")))?;
+        }
+
+        let id_of = |token: &[u8]| {
+            encoder.get(token).copied().ok_or_else(|| {
+                let token = String::from_utf8_lossy(token);
+                tokenizer_error(format!("merge refers to unknown token {token:?}"))
+            })
+        };
+        let mut merge_ranks = HashMap::with_capacity(merges.len());
+        for (rank, (left, right)) in merges.iter().enumerate() {
+            let (left, right) = (to_bytes(left)?, to_bytes(right)?);
+            let merged = id_of(&[left.as_slice(), &right].concat())?;
+            merge_ranks
+                .entry((id_of(&left)?, id_of(&right)?))
+                .or_insert((rank as u32, merged));
+        }
+
+        let mut contents: Vec<&str> = added.keys().map(String::as_str).collect();
+        contents.sort_by_key(|content| std::cmp::Reverse(content.len()));
+        let added_pattern = (!contents.is_empty())
+            .then(|| {
+                let alternatives: Vec<String> = contents.iter().map(|c| regex::escape(c)).collect();
+                Regex::new(&alternatives.join("|"))
+            })
+            .transpose()
+            .map_err(|err| tokenizer_error(err.to_string()))?;
+
+        Ok(Self {
+            tokens,
+            special,
+            encoder,
+            merges: merge_ranks,
+            byte_ids,
+            added,
+            added_pattern,
+            pre_tokenizer: Regex::new(QWEN2_PATTERN).expect("valid pre-tokenizer pattern"),
+            nfc,
+        })
+    }
+
+    /// Number of token ids, added tokens included
+    pub fn vocab_size(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Id of an added token's content, or of a regular token's text
+    pub fn token_id(&self, token: &str) -> Option<u32> {
+        self.added
+            .get(token)
+            .or_else(|| self.encoder.get(token.as_bytes()))
+            .copied()
+    }
+
+    /// Bytes token `id` decodes to
+    pub fn token_bytes(&self, id: u32) -> Option<&[u8]> {
+        self.tokens.get(id as usize).map(Vec::as_slice)
+    }
+
+    pub fn is_special(&self, id: u32) -> bool {
+        self.special.get(id as usize).copied().unwrap_or(false)
+    }
+
+    /// Encode `text`, matching added tokens such as `<|im_start|>` where they occur
+    pub fn encode(&self, text: &str) -> Vec<u32> {
+        let mut ids = Vec::new();
+        let mut start = 0;
+        if let Some(pattern) = &self.added_pattern {
+            for found in pattern.find_iter(text) {
+                self.encode_ordinary_into(&text[start..found.start()], &mut ids);
+                ids.push(self.added[found.as_str()]);
+                start = found.end();
+            }
+        }
+        self.encode_ordinary_into(&text[start..], &mut ids);
+        ids
+    }
+
+    /// Encode `text` as plain text, so added tokens in it are spelled out byte by byte
+    ///
+    /// Use this for untrusted content that must not be able to inject control tokens.
+    pub fn encode_ordinary(&self, text: &str) -> Vec<u32> {
+        let mut ids = Vec::new();
+        self.encode_ordinary_into(text, &mut ids);
+        ids
+    }
+
+    /// Encode `text` as a batch of one prompt [1, seq_len] for [`crate::inference::generate`]
+    pub fn encode_prompt<B: Backend>(&self, text: &str, device: &B::Device) -> Tensor<B, 2, Int> {
+        token_column(&self.encode(text), device).swap_dims(0, 1)
+    }
+
+    fn encode_ordinary_into(&self, text: &str, ids: &mut Vec<u32>) {
+        if text.is_empty() {
+            return;
+        }
+        let normalized: String;
+        let text = if self.nfc && !is_nfc(text) {
+            normalized = text.nfc().collect();
+            normalized.as_str()
+        } else {
+            text
+        };
+        for piece in pre_tokenize(&self.pre_tokenizer, text) {
+            self.merge_piece(piece.as_bytes(), ids);
+        }
+    }
+
+    /// Apply the merges to the bytes of one pre-tokenized piece, lowest rank first
+    fn merge_piece(&self, bytes: &[u8], ids: &mut Vec<u32>) {
+        let mut parts: Vec<u32> = bytes.iter().map(|&b| self.byte_ids[b as usize]).collect();
+        loop {
+            let best = parts
+                .windows(2)
+                .enumerate()
+                .filter_map(|(i, pair)| {
+                    let &(rank, merged) = self.merges.get(&(pair[0], pair[1]))?;
+                    Some((rank, i, merged))
+                })
+                .min();
+            let Some((_, i, merged)) = best else { break };
+            parts[i] = merged;
+            parts.remove(i + 1);
+        }
+        ids.extend(parts);
+    }
+
+    /// Decode `ids` to text; bytes that are not valid UTF-8 become U+FFFD
+    pub fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> String {
+        String::from_utf8_lossy(&self.decode_bytes(ids, skip_special_tokens)).into_owned()
+    }
+
+    /// Concatenated bytes of `ids`; unknown ids are skipped
+    pub fn decode_bytes(&self, ids: &[u32], skip_special_tokens: bool) -> Vec<u8> {
+        ids.iter()
+            .filter(|&&id| !(skip_special_tokens && self.is_special(id)))
+            .filter_map(|&id| self.token_bytes(id))
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Decode a generation token by token
+    pub fn decode_stream(&self, skip_special_tokens: bool) -> DecodeStream<'_> {
+        DecodeStream {
+            tokenizer: self,
+            skip_special_tokens,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The vocabulary as a [`TokenTrie`] for constrained decoding
+    ///
+    /// Special tokens have no bytes there, so a grammar never allows them; pass the
+    /// stop tokens to the constraint instead.
+    pub fn token_trie(&self) -> TokenTrie {
+        TokenTrie::new(self.tokens.iter().enumerate().map(|(id, bytes)| {
+            if self.special[id] {
+                &[][..]
+            } else {
+                bytes.as_slice()
+            }
+        }))
+    }
+}
+
+impl TokenDecoder for Qwen2Tokenizer {
+    fn decode(&self, tokens: &[u32]) -> String {
+        Qwen2Tokenizer::decode(self, tokens, false)
+    }
+}
+
+/// Incremental decoding that only emits whole characters
+///
+/// A character split across tokens is held back until its last byte arrives, so the
+/// pieces returned by [`DecodeStream::step`] concatenate to the full decoded text.
+#[derive(Debug)]
+pub struct DecodeStream<'a> {
+    tokenizer: &'a Qwen2Tokenizer,
+    skip_special_tokens: bool,
+    pending: Vec<u8>,
+}
+
+impl DecodeStream<'_> {
+    /// The text completed by token `id`, possibly empty
+    pub fn step(&mut self, id: u32) -> String {
+        if self.skip_special_tokens && self.tokenizer.is_special(id) {
+            return String::new();
+        }
+        self.pending
+            .extend_from_slice(self.tokenizer.token_bytes(id).unwrap_or_default());
+        let mut text = String::new();
+        loop {
+            match std::str::from_utf8(&self.pending) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    self.pending.clear();
+                    return text;
+                }
+                Err(err) => {
+                    let valid = err.valid_up_to();
+                    text.push_str(std::str::from_utf8(&self.pending[..valid]).expect("valid"));
+                    match err.error_len() {
+                        // Wait for the rest of the character
+                        None => {
+                            self.pending.drain(..valid);
+                            return text;
+                        }
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            self.pending.drain(..valid + len);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Flush the bytes of an unfinished character, as U+FFFD
+    pub fn finish(&mut self) -> String {
+        let text = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        text
+    }
+}
+
+/// One line of a JSONL training file: a conversation for supervised fine-tuning, or
+/// plain text for pretraining
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TrainingSample {
+    Chat { messages: Vec<ChatMessage> },
+    Text { text: String },
+}
+
+impl TrainingSample {
+    /// Read one sample per non-empty line
+    pub fn read_jsonl(path: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|source| ModelError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|err| ModelError::Dataset {
+                    path: path.to_path_buf(),
+                    line: i + 1,
+                    reason: err.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    /// Token ids and which of them to train on: what the assistant wrote in a
+    /// conversation, every token of a text, which ends with `<|endoftext|>`
+    pub fn tokenize(&self, template: &ChatTemplate) -> TokenizedSample {
+        match self {
+            Self::Chat { messages } => {
+                let (input_ids, loss_mask) = template.encode_with_mask(messages, false);
+                TokenizedSample {
+                    input_ids,
+                    loss_mask,
+                }
+            }
+            Self::Text { text } => {
+                let tokenizer = template.tokenizer();
+                let mut input_ids = tokenizer.encode_ordinary(text);
+                input_ids.extend(tokenizer.token_id("<|endoftext|>"));
+                TokenizedSample {
+                    loss_mask: vec![true; input_ids.len()],
+                    input_ids,
+                }
+            }
+        }
+    }
+}
+
+/// Token ids of one training sample
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenizedSample {
+    pub input_ids: Vec<u32>,
+    /// Whether each token is a training target
+    pub loss_mask: Vec<bool>,
+}
+
+/// How [`PackedDataset`] packs samples into sequences
+#[derive(Config, Debug)]
+pub struct PackingConfig {
+    /// Tokens per sequence; longer samples are truncated
+    pub seq_len: usize,
+    /// Token filling the end of sequences, `<|endoftext|>` by default
+    #[config(default = "crate::stopping::QWEN_END_OF_TEXT")]
+    pub pad_token_id: u32,
+}
+
+/// `seq_len` tokens of whole samples, back to back, followed by padding
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackedSequence {
+    pub input_ids: Vec<u32>,
+    /// Whether each token is a training target; padding never is
+    pub loss_mask: Vec<bool>,
+    /// Index of the sample each token comes from; the padding counts as one more
+    pub document_ids: Vec<u32>,
+}
+
+impl PackedSequence {
+    fn next_document(&self) -> u32 {
+        self.document_ids.last().map_or(0, |&document| document + 1)
+    }
+}
+
+/// Training samples packed into fixed-length sequences
+///
+/// Samples are packed in order, a new sequence starting whenever the next sample
+/// does not fit, so at most one sample's length is wasted on padding per sequence.
+/// Samples without a token to train on, such as conversations whose assistant turns
+/// were truncated away, are dropped.
+#[derive(Debug, Clone)]
+pub struct PackedDataset {
+    sequences: Vec<PackedSequence>,
+}
+
+impl PackedDataset {
+    pub fn pack(
+        samples: impl IntoIterator<Item = TokenizedSample>,
+        config: &PackingConfig,
+    ) -> Self {
+        let seq_len = config.seq_len;
+        let finish = |mut sequence: PackedSequence| {
+            let document = sequence.next_document();
+            sequence.input_ids.resize(seq_len, config.pad_token_id);
+            sequence.loss_mask.resize(seq_len, false);
+            sequence.document_ids.resize(seq_len, document);
+            sequence
+        };
+
+        let mut sequences = Vec::new();
+        let mut current = PackedSequence::default();
+        let (mut truncated, mut dropped) = (0, 0);
+        for mut sample in samples {
+            if sample.input_ids.len() > seq_len {
+                sample.input_ids.truncate(seq_len);
+                sample.loss_mask.truncate(seq_len);
+                truncated += 1;
+            }
+            if !sample.loss_mask.contains(&true) {
+                dropped += 1;
+                continue;
+            }
+            if current.input_ids.len() + sample.input_ids.len() > seq_len {
+                sequences.push(finish(std::mem::take(&mut current)));
+            }
+            let document = current.next_document();
+            let len = current.document_ids.len() + sample.input_ids.len();
+            current.document_ids.resize(len, document);
+            current.input_ids.extend(sample.input_ids);
+            current.loss_mask.extend(sample.loss_mask);
+        }
+        if !current.input_ids.is_empty() {
+            sequences.push(finish(current));
+        }
+        if truncated + dropped > 0 {
+            tracing::warn!(
+                "truncated {truncated} samples to {seq_len} tokens, dropped {dropped} with \
+                 nothing to train on"
+            );
+        }
+        Self { sequences }
+    }
+
+    /// Read, tokenize and pack the samples of a JSONL file
+    pub fn from_jsonl(
+        path: impl AsRef<Path>,
+        template: &ChatTemplate,
+        config: &PackingConfig,
+    ) -> Result<Self> {
+        let samples = TrainingSample::read_jsonl(path)?;
+        Ok(Self::pack(
+            samples.iter().map(|sample| sample.tokenize(template)),
+            config,
+        ))
+    }
+
+    pub fn sequences(&self) -> &[PackedSequence] {
+        &self.sequences
+    }
+}
+
+impl Dataset<PackedSequence> for PackedDataset {
+    fn get(&self, index: usize) -> Option<PackedSequence> {
+        self.sequences.get(index).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.sequences.len()
+    }
+}
+
+/// A batch of packed sequences for next-token prediction
+#[derive(Debug, Clone)]
+pub struct PackedBatch<B: Backend> {
+    /// [batch_size, seq_len]
+    pub input_ids: Tensor<B, 2, Int>,
+    /// The token after each input [batch_size, seq_len]; `0` where the loss is masked
+    pub targets: Tensor<B, 2, Int>,
+    /// Targets to train on [batch_size, seq_len]: trained tokens of the same sample
+    pub loss_mask: Tensor<B, 2, Bool>,
+    /// Sample each token comes from [batch_size, seq_len]; tokens only attend to their
+    /// own sample
+    pub document_ids: Tensor<B, 2, Int>,
+    /// RoPE positions, restarting at 0 with every sample [batch_size, seq_len]
+    pub position_ids: Tensor<B, 2, Int>,
+}
+
+/// Batches [`PackedSequence`]s of equal length
+#[derive(Debug, Clone, Default)]
+pub struct PackedBatcher;
+
+impl<B: Backend> Batcher<B, PackedSequence, PackedBatch<B>> for PackedBatcher {
+    fn batch(&self, items: Vec<PackedSequence>, device: &B::Device) -> PackedBatch<B> {
+        let batch_size = items.len();
+        let seq_len = items.first().map_or(0, |item| item.input_ids.len());
+        let mut input_ids = Vec::with_capacity(batch_size * seq_len);
+        let mut targets = Vec::with_capacity(batch_size * seq_len);
+        let mut loss_mask = Vec::with_capacity(batch_size * seq_len);
+        let mut document_ids = Vec::with_capacity(batch_size * seq_len);
+        let mut position_ids = Vec::with_capacity(batch_size * seq_len);
+        for item in &items {
+            assert_eq!(
+                item.input_ids.len(),
+                seq_len,
+                "packed sequences differ in length"
+            );
+            let documents = &item.document_ids;
+            let mut position: i64 = 0;
+            for t in 0..seq_len {
+                input_ids.push(item.input_ids[t] as i64);
+                let same_document = documents.get(t + 1) == Some(&documents[t]);
+                let trained = same_document && item.loss_mask[t + 1];
+                targets.push(if trained {
+                    item.input_ids[t + 1] as i64
+                } else {
+                    0
+                });
+                loss_mask.push(trained);
+
+                if t > 0 && documents[t] != documents[t - 1] {
+                    position = 0;
+                }
+                position_ids.push(position);
+                position += 1;
+                document_ids.push(documents[t] as i64);
+            }
+        }
+
+        let shape = [batch_size, seq_len];
+        PackedBatch {
+            input_ids: Tensor::from_data(TensorData::new(input_ids, shape), device),
+            targets: Tensor::from_data(TensorData::new(targets, shape), device),
+            loss_mask: Tensor::from_data(TensorData::new(loss_mask, shape), device),
+            document_ids: Tensor::from_data(TensorData::new(document_ids, shape), device),
+            position_ids: Tensor::from_data(TensorData::new(position_ids, shape), device),
+        }
+    }
+}
+
+/// Split text into the pieces BPE merges within
+///
+/// Applies the `\s+(?!\S)` alternative [`QWEN2_PATTERN`] leaves out: a run of spaces
+/// followed by a word gives its last space to the word.
+fn pre_tokenize<'t>(pattern: &Regex, text: &'t str) -> Vec<&'t str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    while let Some(found) = pattern.find_at(text, start) {
+        let piece = found.as_str();
+        let mut end = found.end();
+        let before_word = text[end..]
+            .chars()
+            .next()
+            .is_some_and(|c| !c.is_whitespace());
+        if before_word
+            && piece.chars().all(char::is_whitespace)
+            && !piece.ends_with(['\r', '\n'])
+            && let Some(last) = piece.chars().next_back()
+            && piece.len() > last.len_utf8()
+        {
+            end -= last.len_utf8();
+        }
+        pieces.push(&text[found.start()..end]);
+        start = end;
+    }
+    pieces
+}
+
+/// GPT-2's byte-level alphabet: printable bytes stand for themselves, the others for
+/// code points from U+0100 on
+fn bytes_to_unicode() -> [char; 256] {
+    let mut chars = ['\0'; 256];
+    let mut next = 0x100;
+    for (byte, c) in chars.iter_mut().enumerate() {
+        let printable = matches!(byte as u8, b'!'..=b'~' | 0xa1..=0xac | 0xae..=0xff);
+        *c = if printable {
+            byte as u8 as char
+        } else {
+            let c = char::from_u32(next).expect("code point below U+0200");
+            next += 1;
+            c
+        };
+    }
+    chars
+}
+
+fn unicode_to_bytes() -> HashMap<char, u8> {
+    bytes_to_unicode()
+        .into_iter()
+        .enumerate()
+        .map(|(byte, c)| (c, byte as u8))
+        .collect()
+}
+
+fn split_merge(merge: &str) -> Result<(String, String)> {
+    merge
+        .split_once(' ')
+        .map(|(left, right)| (left.to_string(), right.to_string()))
+        .ok_or_else(|| tokenizer_error(format!("malformed merge {merge:?}")))
+}
+
+fn tokenizer_error(message: impl Into<String>) -> ModelError {
+    ModelError::Tokenizer(message.into())
+}
+
+#[cfg(test)]
+impl Qwen2Tokenizer {
+    /// A tokenizer.json in Qwen2's layout: the 256 byte tokens in GPT-2 order, a few
+    /// merges, and added tokens past the end of the vocabulary
+    pub(crate) fn fixture_json() -> String {
+        let alphabet = bytes_to_unicode();
+        let printable = (0..256).filter(|&b| alphabet[b] as u32 == b as u32);
+        let others = (0..256).filter(|&b| alphabet[b] as u32 != b as u32);
+        let mut vocab: Vec<String> = printable
+            .chain(others)
+            .map(|b| alphabet[b].to_string())
+            .collect();
+        let merges = [
+            "h e", "l l", "he ll", "hell o", "Ġ w", "o r", "Ġw or", "Ġwor l", "Ġworl d", "Ġ Ġ",
+            "Ã ©",
+        ];
+        vocab.extend(merges.iter().map(|merge| merge.replace(' ', "")));
+        let vocab: serde_json::Map<String, serde_json::Value> = vocab
+            .into_iter()
+            .enumerate()
+            .map(|(id, token)| (token, id.into()))
+            .collect();
+        serde_json::json!({
+            "version": "1.0",
+            "added_tokens": [
+                { "id": 300, "content": "<|endoftext|>", "special": true },
+                { "id": 301, "content": "<|im_start|>", "special": true },
+                { "id": 302, "content": "<|im_end|>", "special": true },
+                { "id": 303, "content": "<tool_call>", "special": false },
+                { "id": 304, "content": "</tool_call>", "special": false },
+                { "id": 305, "content": "<tool_response>", "special": false },
+                { "id": 306, "content": "</tool_response>", "special": false },
+            ],
+            "normalizer": { "type": "NFC" },
+            "model": {
+                "type": "BPE",
+                "vocab": vocab,
+                // Newer files store merges as pairs
+                "merges": merges.map(|merge| merge.split(' ').collect::<Vec<_>>()),
+            }
+        })
+        .to_string()
+    }
+
+    /// The tokenizer of [`Self::fixture_json`]
+    pub(crate) fn fixture() -> Self {
+        Self::from_json_str(&Self::fixture_json()).expect("valid fixture")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use crate::stopping::{FinishReason, StopConditions};
+
+    #[test]
+    fn test_pre_tokenize() {
+        let pattern = Regex::new(QWEN2_PATTERN).unwrap();
+        let cases: [(&str, &[&str]); 5] = [
+            ("Hello world", &["Hello", " world"]),
+            ("I'LL  don't", &["I", "'LL", " ", " don", "'t"]),
+            (
+                "x = 123;\n\n  fn",
+                &["x", " =", " ", "1", "2", "3", ";\n\n", " ", " fn"],
+            ),
+            ("a  \n\tb  ", &["a", "  \n", "\tb", "  "]),
+            ("  1 ¿é", &[" ", " ", "1", " ¿", "é"]),
+        ];
+        for (text, pieces) in cases {
+            assert_eq!(pre_tokenize(&pattern, text), pieces, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn test_encode_fixture_ids() {
+        let tokenizer = Qwen2Tokenizer::fixture();
+        assert_eq!(tokenizer.vocab_size(), 307);
+        assert_eq!(tokenizer.token_id("<|im_end|>"), Some(302));
+        assert_eq!(tokenizer.token_id("!"), Some(0));
+
+        let cases: [(&str, &[u32]); 5] = [
+            ("hello world", &[259, 264]),
+            ("<|im_start|>hello<|im_end|>", &[301, 259, 302]),
+            // Spaces pair up before a word takes the last one; "e\u{301}" is NFC "é"
+            ("   world e\u{301}", &[265, 264, 220, 266]),
+            ("\n<tool_call>", &[198, 303]),
+            ("hi\u{0}", &[71, 72, 188]),
+        ];
+        for (text, ids) in cases {
+            assert_eq!(tokenizer.encode(text), ids, "{text:?}");
+        }
+        assert_eq!(tokenizer.decode(&[265, 264, 220, 266], false), "   world é");
+        assert_eq!(tokenizer.decode(&[301, 259, 302], true), "hello");
+        assert_eq!(tokenizer.encode_ordinary("<|im_end|>").len(), 10);
+
+        let text = "fn main() {\n    println!(\"héllo, 世界\");\n}\n<|endoftext|>";
+        assert_eq!(tokenizer.decode(&tokenizer.encode(text), false), text);
+
+        let device = Default::default();
+        let prompt = tokenizer.encode_prompt::<burn::backend::NdArray<f32>>("hello", &device);
+        assert_eq!(prompt.dims(), [1, 1]);
+    }
+
+    #[test]
+    fn test_decode_stream_holds_split_characters() {
+        let tokenizer = Qwen2Tokenizer::fixture();
+        // "世" is three byte tokens; 0xff is never valid UTF-8
+        let ids = tokenizer.encode_ordinary("a世");
+        assert_eq!(ids.len(), 4);
+        let mut stream = tokenizer.decode_stream(true);
+        let pieces: Vec<String> = ids.iter().map(|&id| stream.step(id)).collect();
+        assert_eq!(pieces, ["a", "", "", "世"]);
+        assert_eq!(stream.step(302), "");
+        assert_eq!(stream.step(tokenizer.byte_ids[0xff]), "\u{fffd}");
+        assert_eq!(stream.step(ids[1]), "");
+        assert_eq!(stream.finish(), "\u{fffd}");
+
+        // The tokenizer decodes stop strings and feeds constrained decoding
+        let tokenizer = Arc::new(tokenizer);
+        let stop = StopConditions::new().with_stop_strings(["world"], tokenizer.clone());
+        let generated = tokenizer.encode("hello world");
+        assert_eq!(
+            stop.check(&generated),
+            Some(FinishReason::StopString("world".into()))
+        );
+        let trie = tokenizer.token_trie();
+        assert_eq!(trie.token_bytes(259), Some(&b"hello"[..]));
+        assert_eq!(trie.token_bytes(302), Some(&[][..]));
+        assert_eq!(trie.token_bytes(303), Some(&b"<tool_call>"[..]));
+    }
+
+    #[test]
+    fn test_gguf_vocab_and_errors() {
+        let tokenizer = Qwen2Tokenizer::fixture();
+        let vocab = GgufVocab {
+            model: "gpt2".to_string(),
+            tokens: (0..tokenizer.vocab_size() as u32)
+                .map(|id| match id {
+                    300.. => String::from_utf8(tokenizer.tokens[id as usize].clone()).unwrap(),
+                    _ => tokenizer.tokens[id as usize]
+                        .iter()
+                        .map(|&b| bytes_to_unicode()[b as usize])
+                        .collect(),
+                })
+                .collect(),
+            token_types: (0..tokenizer.vocab_size())
+                .map(|id| match id {
+                    303.. => GGUF_USER_DEFINED,
+                    300.. => GGUF_CONTROL,
+                    _ => 1,
+                })
+                .collect(),
+            merges: vec![
+                "h e".into(),
+                "l l".into(),
+                "he ll".into(),
+                "hell o".into(),
+                "Ġ w".into(),
+                "o r".into(),
+                "Ġw or".into(),
+                "Ġwor l".into(),
+                "Ġworl d".into(),
+            ],
+            bos_token_id: None,
+            eos_token_id: Some(300),
+            padding_token_id: None,
+        };
+        let from_gguf = Qwen2Tokenizer::from_gguf_vocab(&vocab).unwrap();
+        let text = "<|im_start|>hello world<tool_call>";
+        assert_eq!(from_gguf.encode(text), tokenizer.encode(text));
+        assert!(from_gguf.is_special(302) && !from_gguf.is_special(303));
+
+        let bad_merge = Qwen2Tokenizer::fixture_json().replace(r#"["Ã","©"]"#, r#"["Ã","x"]"#);
+        assert!(matches!(
+            Qwen2Tokenizer::from_json_str(&bad_merge),
+            Err(ModelError::Tokenizer(message)) if message.contains("unknown token")
+        ));
+        let wordpiece =
+            Qwen2Tokenizer::fixture_json().replace(r#""type":"BPE""#, r#""type":"WordPiece""#);
+        assert!(Qwen2Tokenizer::from_json_str(&wordpiece).is_err());
+    }
+
+    #[test]
+    fn test_pack_samples() {
+        let sample = |input_ids: &[u32], loss_mask: &[bool]| TokenizedSample {
+            input_ids: input_ids.to_vec(),
+            loss_mask: loss_mask.to_vec(),
+        };
+        let samples = [
+            sample(&[1, 2], &[false, true]),
+            sample(&[3, 4, 5], &[true, true, true]),
+            // Nothing to train on
+            sample(&[6], &[false]),
+            sample(&[7, 8, 9, 10, 11, 12, 13], &[true; 7]),
+            sample(&[14], &[true]),
+        ];
+        let config = PackingConfig::new(6).with_pad_token_id(0);
+        let dataset = PackedDataset::pack(samples, &config);
+
+        let expected = [
+            PackedSequence {
+                input_ids: vec![1, 2, 3, 4, 5, 0],
+                loss_mask: vec![false, true, true, true, true, false],
+                document_ids: vec![0, 0, 1, 1, 1, 2],
+            },
+            // Truncated to the sequence length
+            PackedSequence {
+                input_ids: vec![7, 8, 9, 10, 11, 12],
+                loss_mask: vec![true; 6],
+                document_ids: vec![0; 6],
+            },
+            PackedSequence {
+                input_ids: vec![14, 0, 0, 0, 0, 0],
+                loss_mask: vec![true, false, false, false, false, false],
+                document_ids: vec![0, 1, 1, 1, 1, 1],
+            },
+        ];
+        assert_eq!(dataset.sequences(), expected);
+        assert_eq!(dataset.len(), 3);
+        assert_eq!(dataset.get(1), Some(expected[1].clone()));
+    }
+
+    #[test]
+    fn test_sft_batches_from_jsonl() {
+        type Backend = burn::backend::NdArray<f32>;
+
+        let template = ChatTemplate::new(Arc::new(Qwen2Tokenizer::fixture()))
+            .unwrap()
+            .with_default_system_prompt(None);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sft.jsonl");
+        let lines = [
+            concat!(
+                r#"{"messages": [{"role": "user", "content": "hi"}, "#,
+                r#"{"role": "assistant", "content": "hello"}]}"#,
+            ),
+            "",
+            r#"{"text": "hello world"}"#,
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let samples = TrainingSample::read_jsonl(&path).unwrap();
+        assert_eq!(
+            samples[1],
+            TrainingSample::Text {
+                text: "hello world".into()
+            }
+        );
+        // The fixture spells "user" and "assistant" out byte by byte: 24 tokens, of
+        // which "\n", "hello" and "<|im_end|>" are the assistant's
+        let chat = samples[0].tokenize(&template);
+        assert_eq!(chat.input_ids.len(), 24);
+        assert_eq!(chat.loss_mask.iter().filter(|&&m| m).count(), 3);
+
+        let config = PackingConfig::new(32).with_pad_token_id(0);
+        let dataset = PackedDataset::from_jsonl(&path, &template, &config).unwrap();
+        assert_eq!(dataset.len(), 1);
+        let sequence = dataset.get(0).unwrap();
+        let device = Default::default();
+        let batch: PackedBatch<Backend> = PackedBatcher.batch(vec![sequence; 2], &device);
+        assert_eq!(batch.input_ids.dims(), [2, 32]);
+        assert_eq!(batch.document_ids.dims(), [2, 32]);
+
+        let row = |tensor: Tensor<Backend, 2, Int>| -> Vec<i64> {
+            tensor
+                .slice([0..1, 0..32])
+                .into_data()
+                .convert::<i64>()
+                .to_vec()
+                .unwrap()
+        };
+        // Targets are the next token where it is trained on, within one sample
+        let loss_mask: Vec<bool> = batch
+            .loss_mask
+            .slice([0..1, 0..32])
+            .into_data()
+            .to_vec()
+            .unwrap();
+        let targets = row(batch.targets);
+        let trained: Vec<u32> = (0..32)
+            .filter(|&t| loss_mask[t])
+            .map(|t| targets[t] as u32)
+            .collect();
+        let tokenizer = template.tokenizer();
+        assert_eq!(
+            tokenizer.decode(&trained, false),
+            "\nhello<|im_end|> world<|endoftext|>"
+        );
+
+        // Positions restart with the text sample and the padding
+        let positions = row(batch.position_ids);
+        assert_eq!(positions[..24], (0..24).collect::<Vec<_>>());
+        assert_eq!(positions[24..], [0, 1, 2, 0, 1, 2, 3, 4]);
+        let documents = row(batch.document_ids);
+        assert_eq!(documents, [[0; 24].as_slice(), &[1; 3], &[2; 5]].concat());
+
+        std::fs::write(&path, "{\"text\": \"ok\"}\n{\"messages\": 3}\n").unwrap();
+        assert!(matches!(
+            TrainingSample::read_jsonl(&path),
+            Err(ModelError::Dataset { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,77 @@
+//! Error type shared by model loading and inference
+
+use std::path::PathBuf;
+
+/// Errors raised by `rusta-model`
+///
+/// Variants are kept fine-grained so callers (the CLI, loop-core) can react to each
+/// case, e.g. retrying with a shorter prompt on [`ModelError::CacheOverflow`].
+#[derive(Debug, thiserror::Error)]
+pub enum ModelError {
+    #[error("failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("malformed config.json: {0}")]
+    ConfigParse(#[source] serde_json::Error),
+    #[error("unsupported config field `{field}`: {reason}")]
+    UnsupportedConfig { field: &'static str, reason: String },
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("malformed shard index {}: {source}", path.display())]
+    ShardIndex {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("invalid safetensors file {}: {source}", path.display())]
+    Safetensors {
+        path: PathBuf,
+        #[source]
+        source: safetensors::SafeTensorError,
+    },
+    #[error("invalid GGUF file {}: {reason}", path.display())]
+    Gguf { path: PathBuf, reason: String },
+    /// `missing` lists module paths that received no tensor; `unexpected` lists checkpoint
+    /// tensor names that do not belong to the model.
+    #[error(
+        "checkpoint is missing {} weights: {missing:?} (unexpected: {unexpected:?})",
+        missing.len()
+    )]
+    MissingWeights {
+        missing: Vec<String>,
+        unexpected: Vec<String>,
+    },
+    #[error("checkpoint has {} tensors the model does not use: {unexpected:?}", unexpected.len())]
+    UnexpectedWeights { unexpected: Vec<String> },
+    #[error("tensor `{name}`: expected shape {expected:?}, found {found:?}")]
+    ShapeMismatch {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    #[error("tensor `{name}` has unsupported dtype {dtype}")]
+    UnsupportedDtype { name: String, dtype: String },
+    #[error("failed to convert tensor: {0}")]
+    Tensor(String),
+    #[error("invalid grammar: {0}")]
+    Grammar(String),
+    #[error("tokenizer error: {0}")]
+    Tokenizer(String),
+    #[error("invalid dataset {}, line {line}: {reason}", path.display())]
+    Dataset {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+    #[error("linear layer has neither a dense nor a quantized weight")]
+    MissingWeight,
+    #[error("KV cache overflow: {requested} positions requested, capacity is {capacity}")]
+    CacheOverflow { requested: usize, capacity: usize },
+}
+
+pub type Result<T, E = ModelError> = std::result::Result<T, E>;
@@ -0,0 +1,981 @@
+//! GGUF checkpoint import
+//!
+//! llama.cpp's GGUF format bundles a model's architecture, tokenizer vocabulary and
+//! (usually quantized) weights in a single file. [`GgufFile`] reads the header:
+//! metadata becomes a [`Qwen2Config`] and the tokenizer entries a [`GgufVocab`]. The
+//! weights are renamed to their HuggingFace equivalents, dequantized to f32 and loaded
+//! one transformer layer at a time through the same path as safetensors checkpoints.
+//!
+//! Supported tensor types are F32, F16, BF16, Q8_0 and Q4_K.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use burn::{
+    store::TensorSnapshotError,
+    tensor::{TensorData, backend::Backend, bf16, f16},
+};
+
+use crate::error::{ModelError, Result};
+use crate::model::{Qwen2Config, Qwen2ForCausalLM, check_heads};
+use crate::quant::QuantConfig;
+use crate::rope::{RopeScaling, RopeScalingKind};
+use crate::weights::{CheckpointTensor, WeightLoader};
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+/// Alignment of the tensor data section when `general.alignment` is absent
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+/// A metadata value
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+}
+
+impl GgufValue {
+    /// The value as an unsigned integer, if it is a non-negative integer
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v.into()),
+            GgufValue::U16(v) => Some(v.into()),
+            GgufValue::U32(v) => Some(v.into()),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a float, if it is numeric
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            GgufValue::F32(v) => Some(v.into()),
+            GgufValue::F64(v) => Some(v),
+            GgufValue::I8(v) => Some(v.into()),
+            GgufValue::I16(v) => Some(v.into()),
+            GgufValue::I32(v) => Some(v.into()),
+            GgufValue::I64(v) => Some(v as f64),
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Storage type of a tensor
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgmlType {
+    F32,
+    F16,
+    BF16,
+    /// Blocks of 32 int8 values sharing an f16 scale
+    Q8_0,
+    /// Super-blocks of 256 4-bit values in 8 sub-blocks with 6-bit scales and minimums
+    Q4_K,
+    /// Any other ggml type id; such tensors cannot be read
+    Other(u32),
+}
+
+impl GgmlType {
+    fn from_id(id: u32) -> Self {
+        match id {
+            0 => GgmlType::F32,
+            1 => GgmlType::F16,
+            8 => GgmlType::Q8_0,
+            12 => GgmlType::Q4_K,
+            30 => GgmlType::BF16,
+            other => GgmlType::Other(other),
+        }
+    }
+
+    /// `(values, bytes)` per block
+    fn block_layout(&self) -> Option<(usize, usize)> {
+        match self {
+            GgmlType::F32 => Some((1, 4)),
+            GgmlType::F16 | GgmlType::BF16 => Some((1, 2)),
+            GgmlType::Q8_0 => Some((32, 34)),
+            GgmlType::Q4_K => Some((256, 144)),
+            GgmlType::Other(_) => None,
+        }
+    }
+}
+
+/// Name, shape and location of a tensor
+#[derive(Debug, Clone)]
+pub struct GgufTensorInfo {
+    pub name: String,
+    /// Dimensions in ggml order, innermost first
+    pub dims: Vec<usize>,
+    pub ggml_type: GgmlType,
+    /// Offset from the start of the tensor data section
+    pub offset: u64,
+}
+
+impl GgufTensorInfo {
+    /// Row-major shape, outermost first (a linear weight is `[out, in]`)
+    pub fn shape(&self) -> Vec<usize> {
+        self.dims.iter().rev().copied().collect()
+    }
+
+    pub fn num_elements(&self) -> usize {
+        self.dims.iter().product()
+    }
+
+    /// Bytes of stored data, or `None` for unsupported types and misaligned rows
+    fn size_bytes(&self) -> Option<usize> {
+        let (block_values, block_bytes) = self.ggml_type.block_layout()?;
+        let row = self.dims.first().copied().unwrap_or(1);
+        row.is_multiple_of(block_values)
+            .then(|| self.num_elements() / block_values * block_bytes)
+    }
+}
+
+/// Tokenizer vocabulary embedded in a GGUF file
+#[derive(Debug, Clone)]
+pub struct GgufVocab {
+    /// Tokenizer family, `"gpt2"` for Qwen2's byte-level BPE
+    pub model: String,
+    /// Token strings, indexed by id
+    pub tokens: Vec<String>,
+    /// llama.cpp token types (1 normal, 3 control, 4 user defined, ...), if present
+    pub token_types: Vec<i32>,
+    /// BPE merges as `"left right"`, highest priority first
+    pub merges: Vec<String>,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<u32>,
+    pub padding_token_id: Option<u32>,
+}
+
+impl GgufVocab {
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Id of `token`, by linear search
+    pub fn token_id(&self, token: &str) -> Option<u32> {
+        self.tokens
+            .iter()
+            .position(|t| t == token)
+            .map(|id| id as u32)
+    }
+}
+
+/// Header of a GGUF file: metadata and tensor directory
+#[derive(Debug)]
+pub struct GgufFile {
+    path: PathBuf,
+    version: u32,
+    metadata: HashMap<String, GgufValue>,
+    tensors: Vec<GgufTensorInfo>,
+    /// Absolute offset of the tensor data section
+    data_offset: u64,
+}
+
+impl GgufFile {
+    /// Read the header of the GGUF file at `path`; tensor data is read on demand
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path).map_err(|source| ModelError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let mut reader = HeaderReader {
+            inner: BufReader::new(file),
+            path: &path,
+        };
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != GGUF_MAGIC {
+            return Err(reader.invalid("not a GGUF file"));
+        }
+        let version = reader.u32()?;
+        if !(2..=3).contains(&version) {
+            return Err(reader.invalid(format!("unsupported GGUF version {version}")));
+        }
+
+        let tensor_count = reader.u64()?;
+        let metadata_count = reader.u64()?;
+
+        let mut metadata = HashMap::new();
+        for _ in 0..metadata_count {
+            let key = reader.string()?;
+            let value_type = reader.u32()?;
+            let value = reader.value(value_type)?;
+            metadata.insert(key, value);
+        }
+
+        let mut tensors = Vec::new();
+        for _ in 0..tensor_count {
+            let name = reader.string()?;
+            let num_dims = reader.u32()?;
+            let dims = (0..num_dims)
+                .map(|_| reader.u64().map(|dim| dim as usize))
+                .collect::<Result<Vec<_>>>()?;
+            let ggml_type = GgmlType::from_id(reader.u32()?);
+            let offset = reader.u64()?;
+            tensors.push(GgufTensorInfo {
+                name,
+                dims,
+                ggml_type,
+                offset,
+            });
+        }
+
+        let alignment = metadata
+            .get("general.alignment")
+            .and_then(GgufValue::as_u64)
+            .filter(|&alignment| alignment > 0)
+            .unwrap_or(DEFAULT_ALIGNMENT);
+        let header_end = reader
+            .inner
+            .stream_position()
+            .map_err(|source| ModelError::Io {
+                path: path.clone(),
+                source,
+            })?;
+        let data_offset = header_end.div_ceil(alignment) * alignment;
+
+        Ok(Self {
+            path,
+            version,
+            metadata,
+            tensors,
+            data_offset,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&GgufValue> {
+        self.metadata.get(key)
+    }
+
+    pub fn tensors(&self) -> &[GgufTensorInfo] {
+        &self.tensors
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&GgufTensorInfo> {
+        self.tensors.iter().find(|tensor| tensor.name == name)
+    }
+
+    /// Read and dequantize a tensor to row-major f32 values
+    pub fn read_tensor(&self, info: &GgufTensorInfo) -> Result<Vec<f32>> {
+        read_tensor_data(&self.path, self.data_offset, info)
+    }
+
+    /// Build the model configuration from the `qwen2.*` (or `qwen3.*`) and
+    /// `tokenizer.ggml.*` metadata
+    pub fn qwen2_config(&self) -> Result<Qwen2Config> {
+        let arch = self
+            .metadata_str("general.architecture")
+            .ok_or_else(|| self.invalid("missing general.architecture"))?;
+        if arch != "qwen2" && arch != "qwen3" {
+            return Err(ModelError::UnsupportedConfig {
+                field: "general.architecture",
+                reason: format!("expected \"qwen2\" or \"qwen3\", found {arch:?}"),
+            });
+        }
+        let key = |name: &str| format!("{arch}.{name}");
+        let rope_scaling = match self.metadata_str(&key("rope.scaling.type")) {
+            None | Some("none") => None,
+            Some(kind) => {
+                let kind = match kind {
+                    "linear" => RopeScalingKind::Linear,
+                    "yarn" => RopeScalingKind::Yarn,
+                    other => {
+                        return Err(ModelError::UnsupportedConfig {
+                            field: "rope_scaling",
+                            reason: format!("expected \"linear\" or \"yarn\", found {other:?}"),
+                        });
+                    }
+                };
+                let factor = self
+                    .metadata_f64(&key("rope.scaling.factor"))
+                    .ok_or_else(|| self.invalid("missing rope.scaling.factor"))?;
+                Some(
+                    RopeScaling::new(kind, factor).with_original_max_position_embeddings(
+                        self.metadata_usize(&key("rope.scaling.original_context_length")),
+                    ),
+                )
+            }
+        };
+
+        let hidden_size = self.required_usize(&key("embedding_length"))?;
+        let num_attention_heads = self.required_usize(&key("attention.head_count"))?;
+        let num_key_value_heads = self
+            .metadata_usize(&key("attention.head_count_kv"))
+            .unwrap_or(num_attention_heads);
+        let head_dim = self.metadata_usize(&key("attention.key_length"));
+        check_heads(
+            hidden_size,
+            num_attention_heads,
+            num_key_value_heads,
+            head_dim,
+        )?;
+
+        // The embedding matrix is authoritative; the vocabulary may omit padding rows
+        let vocab_size = match self.tensor("token_embd.weight") {
+            Some(embed) => embed.dims.get(1).copied().unwrap_or(0),
+            None => self.vocab()?.len(),
+        };
+        let eos_token_id = self
+            .metadata_usize("tokenizer.ggml.eos_token_id")
+            .ok_or_else(|| ModelError::InvalidConfig("missing eos_token_id".to_string()))?;
+        let bos_token_id = self
+            .metadata_usize("tokenizer.ggml.bos_token_id")
+            .unwrap_or(eos_token_id);
+
+        let mut config = Qwen2Config::new(
+            vocab_size,
+            hidden_size,
+            self.required_usize(&key("feed_forward_length"))?,
+            self.required_usize(&key("block_count"))?,
+            num_attention_heads,
+            num_key_value_heads,
+            self.required_usize(&key("context_length"))?,
+            "silu".to_string(),
+            bos_token_id,
+            eos_token_id,
+        )
+        .with_tie_word_embeddings(self.tensor("output.weight").is_none())
+        // Architecture variants show in which tensors the first layer has
+        .with_qk_norm(self.tensor("blk.0.attn_q_norm.weight").is_some())
+        .with_attention_bias(self.tensor("blk.0.attn_q.bias").is_some())
+        .with_head_dim(head_dim)
+        .with_rope_scaling(rope_scaling);
+        if let Some(eps) = self.metadata_f64(&key("attention.layer_norm_rms_epsilon")) {
+            config = config.with_rms_norm_eps(eps);
+        }
+        if let Some(theta) = self.metadata_f64(&key("rope.freq_base")) {
+            config = config.with_rope_theta(theta);
+        }
+        Ok(config)
+    }
+
+    /// Read the embedded tokenizer vocabulary
+    pub fn vocab(&self) -> Result<GgufVocab> {
+        let strings = |key: &str| -> Result<Vec<String>> {
+            let Some(values) = self.metadata(key) else {
+                return Ok(Vec::new());
+            };
+            values
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|value| value.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| self.invalid(format!("{key} is not an array of strings")))
+        };
+
+        let tokens = strings("tokenizer.ggml.tokens")?;
+        if tokens.is_empty() {
+            return Err(self.invalid("missing tokenizer.ggml.tokens"));
+        }
+        let token_types = self
+            .metadata("tokenizer.ggml.token_type")
+            .and_then(GgufValue::as_array)
+            .map(|types| {
+                types
+                    .iter()
+                    .map(|t| t.as_f64().map_or(0, |t| t as i32))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let token_id = |key: &str| self.metadata_usize(key).map(|id| id as u32);
+
+        Ok(GgufVocab {
+            model: self
+                .metadata_str("tokenizer.ggml.model")
+                .unwrap_or_default()
+                .to_string(),
+            tokens,
+            token_types,
+            merges: strings("tokenizer.ggml.merges")?,
+            bos_token_id: token_id("tokenizer.ggml.bos_token_id"),
+            eos_token_id: token_id("tokenizer.ggml.eos_token_id"),
+            padding_token_id: token_id("tokenizer.ggml.padding_token_id"),
+        })
+    }
+
+    fn metadata_str(&self, key: &str) -> Option<&str> {
+        self.metadata(key).and_then(GgufValue::as_str)
+    }
+
+    fn metadata_usize(&self, key: &str) -> Option<usize> {
+        self.metadata(key)
+            .and_then(GgufValue::as_u64)
+            .map(|value| value as usize)
+    }
+
+    fn metadata_f64(&self, key: &str) -> Option<f64> {
+        self.metadata(key).and_then(GgufValue::as_f64)
+    }
+
+    fn required_usize(&self, key: &str) -> Result<usize> {
+        self.metadata_usize(key)
+            .ok_or_else(|| self.invalid(format!("missing integer {key}")))
+    }
+
+    fn invalid(&self, reason: impl Into<String>) -> ModelError {
+        ModelError::Gguf {
+            path: self.path.clone(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Load the weights of a GGUF file into `model`
+///
+/// Tensors are grouped by transformer layer and each group is dequantized and applied
+/// before the next is read. With `quantize`, every layer's linear weights are
+/// requantized as soon as they are loaded, so a 4-bit file stays roughly 4-bit in
+/// memory. Missing and unexpected tensors are reported as for safetensors checkpoints.
+pub fn load_gguf<B: Backend>(
+    mut model: Qwen2ForCausalLM<B>,
+    config: &Qwen2Config,
+    file: &GgufFile,
+    quantize: Option<&QuantConfig>,
+) -> Result<Qwen2ForCausalLM<B>> {
+    let mut loader = WeightLoader::new(config, quantize);
+
+    // Layer index (None for the embeddings, final norm and head) -> its tensors
+    let mut groups: BTreeMap<Option<usize>, Vec<CheckpointTensor>> = BTreeMap::new();
+    for info in &file.tensors {
+        let name = hf_name(&info.name);
+        if info.size_bytes().is_none() && name.is_some() {
+            return Err(ModelError::UnsupportedDtype {
+                name: info.name.clone(),
+                dtype: format!("{:?} with {:?} values", info.ggml_type, info.dims),
+            });
+        }
+        let layer = info
+            .name
+            .strip_prefix("blk.")
+            .and_then(|rest| rest.split_once('.'))
+            .and_then(|(layer, _)| layer.parse().ok());
+
+        let path = file.path.clone();
+        let data_offset = file.data_offset;
+        let shape = info.shape();
+        let tensor_info = info.clone();
+        groups.entry(layer).or_default().push(CheckpointTensor {
+            name: name.unwrap_or_else(|| info.name.clone()),
+            shape: shape.clone(),
+            data: Rc::new(move || {
+                let values = read_tensor_data(&path, data_offset, &tensor_info)
+                    .map_err(|e| TensorSnapshotError::IoError(e.to_string()))?;
+                Ok(TensorData::new(values, shape.clone()))
+            }),
+        });
+    }
+
+    for (layer, tensors) in groups {
+        tracing::debug!("loading GGUF tensors of layer {layer:?}");
+        model = loader.apply(model, tensors)?;
+    }
+    loader.finish()?;
+    Ok(model)
+}
+
+/// HuggingFace name of a llama.cpp tensor name, if it is one the model uses
+fn hf_name(name: &str) -> Option<String> {
+    let global = match name {
+        "token_embd.weight" => Some("model.embed_tokens.weight"),
+        "output_norm.weight" => Some("model.norm.weight"),
+        "output.weight" => Some("lm_head.weight"),
+        _ => None,
+    };
+    if let Some(global) = global {
+        return Some(global.to_string());
+    }
+
+    let (layer, rest) = name.strip_prefix("blk.")?.split_once('.')?;
+    layer.parse::<usize>().ok()?;
+    let (module, param) = rest.rsplit_once('.')?;
+    let module = match module {
+        "attn_norm" => "input_layernorm",
+        "ffn_norm" => "post_attention_layernorm",
+        "attn_q" => "self_attn.q_proj",
+        "attn_k" => "self_attn.k_proj",
+        "attn_v" => "self_attn.v_proj",
+        "attn_output" => "self_attn.o_proj",
+        "attn_q_norm" => "self_attn.q_norm",
+        "attn_k_norm" => "self_attn.k_norm",
+        "ffn_gate" => "mlp.gate_proj",
+        "ffn_up" => "mlp.up_proj",
+        "ffn_down" => "mlp.down_proj",
+        _ => return None,
+    };
+    Some(format!("model.layers.{layer}.{module}.{param}"))
+}
+
+fn read_tensor_data(path: &Path, data_offset: u64, info: &GgufTensorInfo) -> Result<Vec<f32>> {
+    let invalid = |reason: String| ModelError::Gguf {
+        path: path.to_path_buf(),
+        reason,
+    };
+    let io = |source| ModelError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let size = info.size_bytes().ok_or_else(|| {
+        invalid(format!(
+            "tensor `{}` has unsupported type {:?} for dims {:?}",
+            info.name, info.ggml_type, info.dims
+        ))
+    })?;
+
+    let mut file = File::open(path).map_err(io)?;
+    file.seek(SeekFrom::Start(data_offset + info.offset))
+        .map_err(io)?;
+    let mut bytes = vec![0; size];
+    file.read_exact(&mut bytes).map_err(io)?;
+
+    Ok(match info.ggml_type {
+        GgmlType::F32 => bytes
+            .as_chunks()
+            .0
+            .iter()
+            .map(|&b| f32::from_le_bytes(b))
+            .collect(),
+        GgmlType::F16 => bytes
+            .as_chunks()
+            .0
+            .iter()
+            .map(|&b| f16::from_le_bytes(b).to_f32())
+            .collect(),
+        GgmlType::BF16 => bytes
+            .as_chunks()
+            .0
+            .iter()
+            .map(|&b| bf16::from_le_bytes(b).to_f32())
+            .collect(),
+        GgmlType::Q8_0 => dequantize_q8_0(&bytes),
+        GgmlType::Q4_K => dequantize_q4_k(&bytes),
+        GgmlType::Other(_) => unreachable!("size_bytes rejects unsupported types"),
+    })
+}
+
+/// Each 34-byte block: f16 scale, then 32 int8 values
+fn dequantize_q8_0(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .as_chunks::<34>()
+        .0
+        .iter()
+        .flat_map(|block| {
+            let scale = f16::from_le_bytes([block[0], block[1]]).to_f32();
+            block[2..].iter().map(move |&q| q as i8 as f32 * scale)
+        })
+        .collect()
+}
+
+/// Each 144-byte super-block: f16 `d` and `dmin`, twelve bytes packing eight 6-bit
+/// scales and minimums, then 128 bytes of 4-bit values; value = d·scale·q − dmin·min
+fn dequantize_q4_k(bytes: &[u8]) -> Vec<f32> {
+    let mut out = Vec::with_capacity(bytes.len() / 144 * 256);
+    for block in bytes.as_chunks::<144>().0 {
+        let d = f16::from_le_bytes([block[0], block[1]]).to_f32();
+        let dmin = f16::from_le_bytes([block[2], block[3]]).to_f32();
+        let scales = &block[4..16];
+        let qs = &block[16..];
+
+        // Four chunks of 64 values: low nibbles of 32 bytes, then their high nibbles
+        for (chunk, q) in qs.as_chunks::<32>().0.iter().enumerate() {
+            for (sub, shift) in [(2 * chunk, 0), (2 * chunk + 1, 4)] {
+                let (scale, min) = q4_k_scale_min(sub, scales);
+                let (scale, min) = (d * scale as f32, dmin * min as f32);
+                out.extend(
+                    q.iter()
+                        .map(|&q| ((q >> shift) & 0x0f) as f32 * scale - min),
+                );
+            }
+        }
+    }
+    out
+}
+
+/// 6-bit scale and minimum of sub-block `j` (ggml's `get_scale_min_k4`)
+fn q4_k_scale_min(j: usize, q: &[u8]) -> (u8, u8) {
+    if j < 4 {
+        (q[j] & 63, q[j + 4] & 63)
+    } else {
+        (
+            (q[j + 4] & 0x0f) | ((q[j - 4] >> 6) << 4),
+            (q[j + 4] >> 4) | ((q[j] >> 6) << 4),
+        )
+    }
+}
+
+/// Little-endian reader over the GGUF header
+struct HeaderReader<'a, R> {
+    inner: R,
+    path: &'a Path,
+}
+
+impl<R: Read> HeaderReader<'_, R> {
+    fn invalid(&self, reason: impl Into<String>) -> ModelError {
+        ModelError::Gguf {
+            path: self.path.to_path_buf(),
+            reason: reason.into(),
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.inner.read_exact(buf).map_err(|source| ModelError::Io {
+            path: self.path.to_path_buf(),
+            source,
+        })
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    /// Length-prefixed array count, bounded so a corrupt header cannot exhaust memory
+    fn len(&mut self) -> Result<usize> {
+        let len = self.u64()?;
+        usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= 1 << 32)
+            .ok_or_else(|| self.invalid(format!("implausible length {len}")))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.len()?;
+        let mut buf = vec![0; len];
+        self.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| self.invalid("string is not valid UTF-8"))
+    }
+
+    fn value(&mut self, value_type: u32) -> Result<GgufValue> {
+        Ok(match value_type {
+            0 => GgufValue::U8(u8::from_le_bytes(self.bytes()?)),
+            1 => GgufValue::I8(i8::from_le_bytes(self.bytes()?)),
+            2 => GgufValue::U16(u16::from_le_bytes(self.bytes()?)),
+            3 => GgufValue::I16(i16::from_le_bytes(self.bytes()?)),
+            4 => GgufValue::U32(self.u32()?),
+            5 => GgufValue::I32(i32::from_le_bytes(self.bytes()?)),
+            6 => GgufValue::F32(f32::from_le_bytes(self.bytes()?)),
+            7 => GgufValue::Bool(self.bytes::<1>()?[0] != 0),
+            8 => GgufValue::String(self.string()?),
+            9 => {
+                let element_type = self.u32()?;
+                let len = self.len()?;
+                let mut values = Vec::with_capacity(len.min(1 << 16));
+                for _ in 0..len {
+                    values.push(self.value(element_type)?);
+                }
+                GgufValue::Array(values)
+            }
+            10 => GgufValue::U64(self.u64()?),
+            11 => GgufValue::I64(i64::from_le_bytes(self.bytes()?)),
+            12 => GgufValue::F64(f64::from_le_bytes(self.bytes()?)),
+            other => return Err(self.invalid(format!("unknown metadata type {other}"))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::token_column;
+    use crate::model::KeyValueCache;
+    use crate::weights::hf_tensor_shapes;
+    use crate::weights::load_safetensors;
+    use crate::weights::tests::{values, write_shard};
+    use burn::backend::NdArray;
+    use burn::tensor::Tolerance;
+    use std::io::Write;
+
+    type Backend = NdArray<f32>;
+
+    fn put_string(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u64).to_le_bytes());
+        out.extend(s.as_bytes());
+    }
+
+    enum Meta {
+        U32(u32),
+        F32(f32),
+        Str(&'static str),
+        Strings(Vec<String>),
+    }
+
+    /// Q8_0 encoding of `values`, whose length is a multiple of 32
+    fn quantize_q8_0(values: &[f32]) -> Vec<u8> {
+        values
+            .chunks(32)
+            .flat_map(|block| {
+                let amax = block.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+                let scale = f16::from_f32(amax / 127.0);
+                let d = scale.to_f32();
+                let mut out = scale.to_le_bytes().to_vec();
+                out.extend(block.iter().map(|v| (v / d).round() as i8 as u8));
+                out
+            })
+            .collect()
+    }
+
+    /// Write a GGUF v3 file with the tensors of `config`
+    fn write_gguf(path: &Path, config: &Qwen2Config, vocab: &[String]) {
+        let metadata = [
+            ("general.architecture", Meta::Str("qwen2")),
+            ("general.alignment", Meta::U32(32)),
+            (
+                "qwen2.context_length",
+                Meta::U32(config.max_position_embeddings as u32),
+            ),
+            (
+                "qwen2.embedding_length",
+                Meta::U32(config.hidden_size as u32),
+            ),
+            (
+                "qwen2.feed_forward_length",
+                Meta::U32(config.intermediate_size as u32),
+            ),
+            (
+                "qwen2.block_count",
+                Meta::U32(config.num_hidden_layers as u32),
+            ),
+            (
+                "qwen2.attention.head_count",
+                Meta::U32(config.num_attention_heads as u32),
+            ),
+            (
+                "qwen2.attention.head_count_kv",
+                Meta::U32(config.num_key_value_heads as u32),
+            ),
+            ("qwen2.attention.layer_norm_rms_epsilon", Meta::F32(1e-6)),
+            ("qwen2.rope.freq_base", Meta::F32(config.rope_theta as f32)),
+            ("tokenizer.ggml.model", Meta::Str("gpt2")),
+            ("tokenizer.ggml.tokens", Meta::Strings(vocab.to_vec())),
+            (
+                "tokenizer.ggml.merges",
+                Meta::Strings(vec!["t 1".to_string()]),
+            ),
+            (
+                "tokenizer.ggml.bos_token_id",
+                Meta::U32(config.bos_token_id as u32),
+            ),
+            (
+                "tokenizer.ggml.eos_token_id",
+                Meta::U32(config.eos_token_id as u32),
+            ),
+        ];
+
+        // llama.cpp names, ggml type and encoded data of every tensor
+        let reverse: Vec<(String, GgmlType, Vec<usize>, Vec<u8>)> = hf_tensor_shapes(config)
+            .into_iter()
+            .enumerate()
+            .map(|(seed, (name, shape))| {
+                let gguf_name = [
+                    ("model.embed_tokens", "token_embd"),
+                    ("model.norm", "output_norm"),
+                    ("lm_head", "output"),
+                    ("model.layers.", "blk."),
+                    ("input_layernorm", "attn_norm"),
+                    ("post_attention_layernorm", "ffn_norm"),
+                    ("self_attn.q_proj", "attn_q"),
+                    ("self_attn.k_proj", "attn_k"),
+                    ("self_attn.v_proj", "attn_v"),
+                    ("self_attn.o_proj", "attn_output"),
+                    ("self_attn.q_norm", "attn_q_norm"),
+                    ("self_attn.k_norm", "attn_k_norm"),
+                    ("mlp.gate_proj", "ffn_gate"),
+                    ("mlp.up_proj", "ffn_up"),
+                    ("mlp.down_proj", "ffn_down"),
+                ]
+                .iter()
+                .fold(name.clone(), |name, (from, to)| name.replace(from, to));
+                assert_eq!(hf_name(&gguf_name), Some(name.clone()));
+
+                let values = values(&shape, seed);
+                let dims: Vec<usize> = shape.iter().rev().copied().collect();
+                let (ggml_type, data) = if name.ends_with("down_proj.weight") {
+                    (GgmlType::Q8_0, quantize_q8_0(&values))
+                } else {
+                    let bytes = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+                    (GgmlType::F32, bytes)
+                };
+                (gguf_name, ggml_type, dims, data)
+            })
+            .collect();
+
+        let mut out = GGUF_MAGIC.to_vec();
+        out.extend(3u32.to_le_bytes());
+        out.extend((reverse.len() as u64).to_le_bytes());
+        out.extend((metadata.len() as u64).to_le_bytes());
+        for (key, value) in &metadata {
+            put_string(&mut out, key);
+            match value {
+                Meta::U32(v) => {
+                    out.extend(4u32.to_le_bytes());
+                    out.extend(v.to_le_bytes());
+                }
+                Meta::F32(v) => {
+                    out.extend(6u32.to_le_bytes());
+                    out.extend(v.to_le_bytes());
+                }
+                Meta::Str(v) => {
+                    out.extend(8u32.to_le_bytes());
+                    put_string(&mut out, v);
+                }
+                Meta::Strings(values) => {
+                    out.extend(9u32.to_le_bytes());
+                    out.extend(8u32.to_le_bytes());
+                    out.extend((values.len() as u64).to_le_bytes());
+                    for v in values {
+                        put_string(&mut out, v);
+                    }
+                }
+            }
+        }
+
+        let mut offset = 0u64;
+        for (name, ggml_type, dims, data) in &reverse {
+            put_string(&mut out, name);
+            out.extend((dims.len() as u32).to_le_bytes());
+            for &dim in dims {
+                out.extend((dim as u64).to_le_bytes());
+            }
+            let type_id: u32 = if *ggml_type == GgmlType::Q8_0 { 8 } else { 0 };
+            out.extend(type_id.to_le_bytes());
+            out.extend(offset.to_le_bytes());
+            offset = (offset + data.len() as u64).div_ceil(32) * 32;
+        }
+        out.resize(out.len().div_ceil(32) * 32, 0);
+        for (_, _, _, data) in &reverse {
+            out.extend(data);
+            out.resize(out.len().div_ceil(32) * 32, 0);
+        }
+
+        File::create(path).unwrap().write_all(&out).unwrap();
+    }
+
+    #[test]
+    fn test_load_gguf_matches_safetensors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Qwen2Config::tiny();
+        let vocab: Vec<String> = (0..config.vocab_size).map(|i| format!("t{i}")).collect();
+        let gguf_path = dir.path().join("model.gguf");
+        write_gguf(&gguf_path, &config, &vocab);
+        write_shard(
+            &dir.path().join("model.safetensors"),
+            &hf_tensor_shapes(&config),
+        );
+
+        let file = GgufFile::open(&gguf_path).unwrap();
+        assert_eq!(file.version(), 3);
+        let parsed = file.qwen2_config().unwrap();
+        assert_eq!(parsed.hidden_size, config.hidden_size);
+        assert_eq!(parsed.num_key_value_heads, config.num_key_value_heads);
+        assert_eq!(parsed.vocab_size, config.vocab_size);
+        assert_eq!(parsed.eos_token_id, config.eos_token_id);
+        assert!(!parsed.tie_word_embeddings);
+
+        let tokenizer = file.vocab().unwrap();
+        assert_eq!(tokenizer.model, "gpt2");
+        assert_eq!(tokenizer.tokens, vocab);
+        assert_eq!(tokenizer.token_id("t7"), Some(7));
+        assert_eq!(tokenizer.merges, ["t 1"]);
+
+        let device = Default::default();
+        let gguf_model = load_gguf(parsed.init::<Backend>(&device), &parsed, &file, None).unwrap();
+        let reference = config.init::<Backend>(&device);
+        let reference = load_safetensors(reference, &config, dir.path(), None).unwrap();
+
+        let logits = |model: &Qwen2ForCausalLM<Backend>| {
+            let input = token_column::<Backend>(&[3, 1, 4, 1, 5], &device).swap_dims(0, 1);
+            let mut cache: Vec<KeyValueCache<Backend>> =
+                model.init_cache(&config, 1, &Default::default(), &device);
+            model.forward(input, &mut cache).unwrap().into_data()
+        };
+        // Only the Q8_0 down projections differ from the f32 reference
+        logits(&gguf_model).assert_approx_eq::<f32>(&logits(&reference), Tolerance::absolute(2e-2));
+    }
+
+    #[test]
+    fn test_dequantize_q4_k() {
+        let mut block = Vec::new();
+        block.extend(f16::from_f32(1.0).to_le_bytes());
+        block.extend(f16::from_f32(0.5).to_le_bytes());
+        // Sub-block scales 1, 2, 3, 4 and minimums 1, 0, 0, 0 in the low six bits; the
+        // upper sub-blocks combine the next four bytes with the top bits of the first eight
+        block.extend([1 | (1 << 6), 2, 3, 4, 1, 0, 0, 0, 0x32, 0, 0, 0]);
+        block.extend([0x21; 128]);
+
+        let values = dequantize_q4_k(&block);
+        assert_eq!(values.len(), 256);
+        // Low nibbles (1) with scale 1, min 1: 1 - 0.5
+        assert_eq!(values[0], 0.5);
+        // High nibbles (2) of the same bytes with scale 2
+        assert_eq!(values[40], 4.0);
+        assert_eq!(values[64], 3.0);
+        assert_eq!(values[100], 8.0);
+        // Sub-block 4: scale 2 | 1 << 4 = 18, min 3
+        assert_eq!(values[130], 16.5);
+        assert_eq!(values[170], 0.0);
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.gguf");
+        std::fs::write(&path, b"GGML\0\0\0\0").unwrap();
+        assert!(matches!(
+            GgufFile::open(&path),
+            Err(ModelError::Gguf { .. })
+        ));
+    }
+}
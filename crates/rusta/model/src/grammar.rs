@@ -0,0 +1,816 @@
+//! Context-free grammars for constrained decoding
+//!
+//! Grammars are written in the GBNF dialect of llama.cpp:
+//!
+//! ```text
+//! root   ::= "{" ws "\"tool\":" ws tool ws "}"
+//! tool   ::= "\"cargo_check\"" | "\"test\""
+//! ws     ::= [ \t\n]{0,8}
+//! ```
+//!
+//! Rules are alternatives (`|`) of sequences of string literals, character classes
+//! (`[a-z]`, `[^"\\]`), `.` (any character), rule references and parenthesized groups,
+//! each optionally repeated with `*`, `+`, `?` or `{m}`, `{m,}`, `{m,n}`. `#` starts a
+//! comment. Generation starts at `root`; left recursion is rejected.
+//!
+//! A [`GrammarMatcher`] tracks every way the text so far can continue, as a set of
+//! stacks of grammar positions, and is advanced one byte at a time so that it can
+//! follow tokens that split a UTF-8 character.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::error::{ModelError, Result};
+
+/// A compiled grammar
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grammar {
+    /// Alternatives of every rule, each a sequence of elements
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    /// One character in (or, negated, outside) the inclusive ranges
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => {
+                ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&c)) != *negated
+            }
+            Element::Rule(_) => false,
+        }
+    }
+
+    /// Whether some code point in `lo..=hi` matches (over-approximated for negated
+    /// classes whose ranges cover it only together)
+    fn matches_any(&self, lo: u32, hi: u32) -> bool {
+        match self {
+            Element::Chars { ranges, negated } => {
+                if *negated {
+                    !ranges
+                        .iter()
+                        .any(|&(a, b)| a as u32 <= lo && hi <= b as u32)
+                } else {
+                    ranges
+                        .iter()
+                        .any(|&(a, b)| a as u32 <= hi && lo <= b as u32)
+                }
+            }
+            Element::Rule(_) => false,
+        }
+    }
+}
+
+impl Grammar {
+    /// Parse GBNF text whose start rule is `root`
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            pos: 0,
+            grammar: Grammar {
+                rules: Vec::new(),
+                names: Vec::new(),
+                root: 0,
+            },
+            ids: HashMap::new(),
+            defined: HashSet::new(),
+        };
+        parser.parse()?;
+        let grammar = parser.grammar;
+
+        if let Some(name) = grammar
+            .names
+            .iter()
+            .find(|name| !parser.defined.contains(*name))
+        {
+            return Err(grammar_error(format!("rule `{name}` is not defined")));
+        }
+        let root = parser.ids.get("root").copied();
+        let root = root.ok_or_else(|| grammar_error("no `root` rule"))?;
+        let grammar = Grammar { root, ..grammar };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+
+    /// Number of rules, including those generated for groups and repetitions
+    pub fn num_rules(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// A matcher at the start of the `root` rule
+    pub fn matcher(self: &Arc<Self>) -> GrammarMatcher {
+        let mut stacks = Vec::new();
+        for alt in 0..self.rules[self.root].len() {
+            let start = Position {
+                rule: self.root,
+                alt,
+                pos: 0,
+            };
+            self.expand(vec![start], &mut stacks);
+        }
+        GrammarMatcher {
+            grammar: self.clone(),
+            state: State {
+                stacks: dedup(stacks),
+                partial: Vec::new(),
+            },
+        }
+    }
+
+    fn add_rule(&mut self, name: String) -> usize {
+        self.rules.push(Vec::new());
+        self.names.push(name);
+        self.rules.len() - 1
+    }
+
+    /// Follow rule references and finished sequences until every stack is empty
+    /// (the text may end) or has a character class on top
+    fn expand(&self, mut stack: Vec<Position>, out: &mut Vec<Vec<Position>>) {
+        loop {
+            let Some(&top) = stack.last() else {
+                out.push(stack);
+                return;
+            };
+            let sequence = &self.rules[top.rule][top.alt];
+            match sequence.get(top.pos) {
+                None => {
+                    stack.pop();
+                }
+                Some(Element::Chars { .. }) => {
+                    out.push(stack);
+                    return;
+                }
+                Some(&Element::Rule(rule)) => {
+                    // Return to the next element, unless the reference ends the sequence
+                    stack.pop();
+                    if top.pos + 1 < sequence.len() {
+                        stack.push(Position {
+                            pos: top.pos + 1,
+                            ..top
+                        });
+                    }
+                    for alt in 0..self.rules[rule].len() {
+                        let mut stack = stack.clone();
+                        stack.push(Position { rule, alt, pos: 0 });
+                        self.expand(stack, out);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Stacks after consuming `c` from each stack whose top accepts it
+    fn advance(&self, stacks: &[Vec<Position>], c: char) -> Vec<Vec<Position>> {
+        let mut out = Vec::new();
+        for stack in stacks {
+            let Some(&top) = stack.last() else { continue };
+            if !self.rules[top.rule][top.alt][top.pos].matches(c) {
+                continue;
+            }
+            let mut stack = stack.clone();
+            stack.pop();
+            stack.push(Position {
+                pos: top.pos + 1,
+                ..top
+            });
+            self.expand(stack, &mut out);
+        }
+        dedup(out)
+    }
+
+    /// Reject rules that can reach themselves without consuming a character, which
+    /// would make [`Self::expand`] loop forever
+    fn check_left_recursion(&self) -> Result<()> {
+        // Rules that can match the empty string, to a fixed point
+        let mut nullable = vec![false; self.rules.len()];
+        let element_nullable = |element: &Element, nullable: &[bool]| match element {
+            Element::Chars { .. } => false,
+            Element::Rule(rule) => nullable[*rule],
+        };
+        loop {
+            let mut changed = false;
+            for (rule, alts) in self.rules.iter().enumerate() {
+                if !nullable[rule]
+                    && alts
+                        .iter()
+                        .any(|seq| seq.iter().all(|e| element_nullable(e, &nullable)))
+                {
+                    nullable[rule] = true;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // Rules each rule may start with: references up to the first non-nullable one
+        let leading: Vec<Vec<usize>> = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut leading = Vec::new();
+                for sequence in alts {
+                    for element in sequence {
+                        if let Element::Rule(rule) = element {
+                            leading.push(*rule);
+                        }
+                        if !element_nullable(element, &nullable) {
+                            break;
+                        }
+                    }
+                }
+                leading
+            })
+            .collect();
+
+        for start in 0..self.rules.len() {
+            let mut seen = vec![false; self.rules.len()];
+            let mut pending = leading[start].clone();
+            while let Some(rule) = pending.pop() {
+                if rule == start {
+                    let name = &self.names[start];
+                    return Err(grammar_error(format!("rule `{name}` is left-recursive")));
+                }
+                if !std::mem::replace(&mut seen[rule], true) {
+                    pending.extend(&leading[rule]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A point in a grammar: element `pos` of alternative `alt` of `rule`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Position {
+    rule: usize,
+    alt: usize,
+    pos: usize,
+}
+
+fn dedup(stacks: Vec<Vec<Position>>) -> Vec<Vec<Position>> {
+    let mut seen = HashSet::new();
+    stacks
+        .into_iter()
+        .filter(|stack| seen.insert(stack.clone()))
+        .collect()
+}
+
+fn grammar_error(reason: impl Into<String>) -> ModelError {
+    ModelError::Grammar(reason.into())
+}
+
+/// Every way the text matched so far can continue
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct State {
+    stacks: Vec<Vec<Position>>,
+    /// Leading bytes of a UTF-8 character not yet complete
+    partial: Vec<u8>,
+}
+
+impl State {
+    /// The state after `byte`, or `None` if no continuation of the grammar allows it
+    pub(crate) fn step(&self, grammar: &Grammar, byte: u8) -> Option<State> {
+        let mut partial = self.partial.clone();
+        partial.push(byte);
+        match std::str::from_utf8(&partial) {
+            Ok(text) => {
+                let c = text.chars().next().expect("one character");
+                let stacks = grammar.advance(&self.stacks, c);
+                (!stacks.is_empty()).then(|| State {
+                    stacks,
+                    partial: Vec::new(),
+                })
+            }
+            // An incomplete character, kept if some character it may become is allowed;
+            // invalid UTF-8 is never accepted
+            Err(err) if err.error_len().is_none() => {
+                let (lo, hi) = code_point_range(&partial);
+                let viable = self.stacks.iter().any(|stack| {
+                    stack.last().is_some_and(|top| {
+                        grammar.rules[top.rule][top.alt][top.pos].matches_any(lo, hi)
+                    })
+                });
+                viable.then(|| State {
+                    stacks: self.stacks.clone(),
+                    partial,
+                })
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.partial.is_empty() && self.stacks.iter().any(Vec::is_empty)
+    }
+}
+
+/// The code points UTF-8 sequences starting with the bytes of `prefix` may encode
+fn code_point_range(prefix: &[u8]) -> (u32, u32) {
+    let first = prefix[0];
+    let (len, bits) = match first {
+        0xc0..=0xdf => (2, first & 0x1f),
+        0xe0..=0xef => (3, first & 0x0f),
+        _ => (4, first & 0x07),
+    };
+    let decode = |fill: u8| {
+        (1..len).fold(bits as u32, |cp, i| {
+            let byte = prefix.get(i).copied().unwrap_or(fill);
+            cp << 6 | (byte & 0x3f) as u32
+        })
+    };
+    (decode(0x80), decode(0xbf))
+}
+
+/// Position of a generation within a [`Grammar`]
+#[derive(Debug, Clone)]
+pub struct GrammarMatcher {
+    grammar: Arc<Grammar>,
+    state: State,
+}
+
+impl GrammarMatcher {
+    /// Consume `bytes` if the grammar allows them, leaving the matcher unchanged if not
+    pub fn accept_bytes(&mut self, bytes: &[u8]) -> bool {
+        let mut state = self.state.clone();
+        for &byte in bytes {
+            match state.step(&self.grammar, byte) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        self.state = state;
+        true
+    }
+
+    /// Consume `text` if the grammar allows it
+    pub fn accept_str(&mut self, text: &str) -> bool {
+        self.accept_bytes(text.as_bytes())
+    }
+
+    /// Whether the text so far is a complete sentence of the grammar
+    pub fn is_complete(&self) -> bool {
+        self.state.is_complete()
+    }
+
+    /// Whether any more text can be accepted
+    pub fn can_continue(&self) -> bool {
+        !self.state.partial.is_empty() || self.state.stacks.iter().any(|stack| !stack.is_empty())
+    }
+
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    pub(crate) fn state(&self) -> &State {
+        &self.state
+    }
+}
+
+/// Recursive-descent parser of GBNF text
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    grammar: Grammar,
+    ids: HashMap<String, usize>,
+    defined: HashSet<String>,
+}
+
+impl Parser {
+    fn parse(&mut self) -> Result<()> {
+        self.skip_space();
+        while self.pos < self.chars.len() {
+            let name = self.name()?;
+            self.skip_space();
+            if !self.eat_str("::=") {
+                return Err(self.error(format!("expected `::=` after `{name}`")));
+            }
+            if !self.defined.insert(name.clone()) {
+                return Err(self.error(format!("rule `{name}` is defined twice")));
+            }
+            let rule = self.rule_id(&name);
+            let alts = self.alternatives(&name)?;
+            self.grammar.rules[rule] = alts;
+            self.skip_space();
+        }
+        Ok(())
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.grammar.add_rule(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// A rule generated for a group or repetition inside rule `parent`
+    fn generated_rule(&mut self, parent: &str, alts: Vec<Vec<Element>>) -> usize {
+        let id = self
+            .grammar
+            .add_rule(format!("{parent}-{}", self.grammar.rules.len()));
+        self.grammar.rules[id] = alts;
+        self.defined.insert(self.grammar.names[id].clone());
+        id
+    }
+
+    fn alternatives(&mut self, rule: &str) -> Result<Vec<Vec<Element>>> {
+        let mut alts = vec![self.sequence(rule)?];
+        while self.eat('|') {
+            alts.push(self.sequence(rule)?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self, rule: &str) -> Result<Vec<Element>> {
+        let mut sequence = Vec::new();
+        loop {
+            self.skip_space();
+            let Some(c) = self.peek() else { break };
+            let element = match c {
+                '"' => {
+                    self.pos += 1;
+                    let literal = self.literal()?;
+                    // A literal is a sequence of single characters
+                    let mut chars: Vec<Element> = literal
+                        .into_iter()
+                        .map(|c| Element::Chars {
+                            ranges: vec![(c, c)],
+                            negated: false,
+                        })
+                        .collect();
+                    if chars.len() > 1 && matches!(self.peek(), Some('*' | '+' | '?' | '{')) {
+                        // An operator repeats the whole literal
+                        Element::Rule(self.generated_rule(rule, vec![chars]))
+                    } else {
+                        let Some(last) = chars.pop() else { continue };
+                        sequence.extend(chars);
+                        last
+                    }
+                }
+                '[' => {
+                    self.pos += 1;
+                    self.char_class()?
+                }
+                '.' => {
+                    self.pos += 1;
+                    Element::Chars {
+                        ranges: Vec::new(),
+                        negated: true,
+                    }
+                }
+                '(' => {
+                    self.pos += 1;
+                    let alts = self.alternatives(rule)?;
+                    self.skip_space();
+                    if !self.eat(')') {
+                        return Err(self.error("expected `)`"));
+                    }
+                    Element::Rule(self.generated_rule(rule, alts))
+                }
+                c if is_name_char(c) => {
+                    if self.at_rule_definition() {
+                        break;
+                    }
+                    let name = self.name()?;
+                    Element::Rule(self.rule_id(&name))
+                }
+                _ => break,
+            };
+            self.repetition(rule, element, &mut sequence)?;
+        }
+        Ok(sequence)
+    }
+
+    /// Append `element` to `sequence` with the repetition operator that follows it
+    fn repetition(
+        &mut self,
+        rule: &str,
+        element: Element,
+        sequence: &mut Vec<Element>,
+    ) -> Result<()> {
+        let bounds = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => (0, Some(0)),
+            _ => {
+                sequence.push(element);
+                return Ok(());
+            }
+        };
+        let (min, max) = match self.next() {
+            Some('{') => self.bounds()?,
+            _ => bounds,
+        };
+
+        sequence.extend(std::iter::repeat_n(element.clone(), min));
+        match max {
+            // x* ::= x x* | ε
+            None => {
+                let id = self.generated_rule(rule, Vec::new());
+                self.grammar.rules[id] = vec![vec![element, Element::Rule(id)], Vec::new()];
+                sequence.push(Element::Rule(id));
+            }
+            // Nested optionals: (x (x ...)?)?
+            Some(max) if max > min => {
+                let mut optional: Option<usize> = None;
+                for _ in min..max {
+                    let mut body = vec![element.clone()];
+                    body.extend(optional.map(Element::Rule));
+                    optional = Some(self.generated_rule(rule, vec![body, Vec::new()]));
+                }
+                sequence.extend(optional.map(Element::Rule));
+            }
+            Some(_) => {}
+        }
+        Ok(())
+    }
+
+    /// The `m}`, `m,}` or `m,n}` of a bounded repetition after its opening brace
+    fn bounds(&mut self) -> Result<(usize, Option<usize>)> {
+        let min = self.number()?;
+        let max = if self.eat(',') {
+            self.skip_space();
+            if self.peek() == Some('}') {
+                None
+            } else {
+                Some(self.number()?)
+            }
+        } else {
+            Some(min)
+        };
+        self.skip_space();
+        if self.peek() != Some('}') {
+            return Err(self.error("expected `}`"));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Err(self.error(format!("empty repetition {{{min},{}}}", max.unwrap())));
+        }
+        self.pos += 1;
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<usize> {
+        self.skip_space();
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits.parse().map_err(|_| self.error("expected a number"))
+    }
+
+    /// The characters of a string literal after its opening quote
+    fn literal(&mut self) -> Result<Vec<char>> {
+        let mut chars = Vec::new();
+        loop {
+            match self.next() {
+                None => return Err(self.error("unterminated string literal")),
+                Some('"') => return Ok(chars),
+                Some('\\') => chars.push(self.escape()?),
+                Some(c) => chars.push(c),
+            }
+        }
+    }
+
+    /// A character class after its opening bracket
+    fn char_class(&mut self) -> Result<Element> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        loop {
+            let lo = match self.next() {
+                None => return Err(self.error("unterminated character class")),
+                Some(']') => return Ok(Element::Chars { ranges, negated }),
+                Some('\\') => self.escape()?,
+                Some(c) => c,
+            };
+            let hi = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                match self.next() {
+                    Some('\\') => self.escape()?,
+                    Some(c) => c,
+                    None => return Err(self.error("unterminated character class")),
+                }
+            } else {
+                lo
+            };
+            ranges.push((lo, hi));
+        }
+    }
+
+    /// The character of an escape sequence after its backslash
+    fn escape(&mut self) -> Result<char> {
+        let c = self
+            .next()
+            .ok_or_else(|| self.error("unterminated escape"))?;
+        let digits = match c {
+            'n' => return Ok('\n'),
+            't' => return Ok('\t'),
+            'r' => return Ok('\r'),
+            'x' => 2,
+            'u' => 4,
+            'U' => 8,
+            _ => return Ok(c),
+        };
+        let hex: String = (0..digits).filter_map(|_| self.next()).collect();
+        u32::from_str_radix(&hex, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| self.error(format!("invalid escape \\{c}{hex}")))
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a rule name"));
+        }
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Whether a name followed by `::=` starts here, ending the current rule
+    fn at_rule_definition(&mut self) -> bool {
+        let start = self.pos;
+        let defines = self.name().is_ok() && {
+            self.skip_space();
+            self.eat_str("::=")
+        };
+        self.pos = start;
+        defines
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+            } else if c.is_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_space();
+        let matched = self.peek() == Some(c);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn eat_str(&mut self, s: &str) -> bool {
+        let matched = s
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.chars.get(self.pos + i) == Some(&c));
+        if matched {
+            self.pos += s.chars().count();
+        }
+        matched
+    }
+
+    fn error(&self, reason: impl std::fmt::Display) -> ModelError {
+        let line = self.chars[..self.pos.min(self.chars.len())]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count();
+        grammar_error(format!("line {}: {reason}", line + 1))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(grammar: &str) -> GrammarMatcher {
+        Arc::new(Grammar::parse(grammar).unwrap()).matcher()
+    }
+
+    fn accepts(grammar: &str, text: &str) -> bool {
+        let mut matcher = matcher(grammar);
+        matcher.accept_str(text) && matcher.is_complete()
+    }
+
+    #[test]
+    fn test_parse_and_match() {
+        let grammar = r#"
+            # Comma-separated integers in brackets
+            root ::= "[" ws ( int ( "," ws int )* )? ws "]"
+            int  ::= "-"? ( "0" | [1-9] [0-9]* )
+            ws   ::= [ \t\n]{0,2}
+        "#;
+        for text in ["[]", "[0]", "[ 1, -20,  3 ]", "[\n-7]"] {
+            assert!(accepts(grammar, text), "{text:?}");
+        }
+        for text in ["[", "[01]", "[1,]", "[1   ]", "[1 2]", "x"] {
+            assert!(!accepts(grammar, text), "{text:?}");
+        }
+
+        // Prefixes are accepted but incomplete; a rejected suffix leaves the matcher as is
+        let mut m = matcher(grammar);
+        assert!(m.accept_str("[1,"));
+        assert!(!m.is_complete());
+        assert!(!m.accept_str("]"));
+        assert!(m.accept_str(" 2]"));
+        assert!(m.is_complete() && !m.can_continue());
+
+        // Operators after a literal repeat all of it
+        let cases: [(&str, &[&str], &[&str]); 4] = [
+            (
+                r#"root ::= "ab"*"#,
+                &["", "ab", "abab"],
+                &["a", "abb", "abbb"],
+            ),
+            (r#"root ::= "ab"+"#, &["ab", "ababab"], &["", "abb", "aba"]),
+            (r#"root ::= "ab"?"#, &["", "ab"], &["a", "b", "abab"]),
+            (
+                r#"root ::= "ab"{2} "c""#,
+                &["ababc"],
+                &["abc", "abbc", "abababc"],
+            ),
+        ];
+        for (grammar, accepted, rejected) in cases {
+            for text in accepted {
+                assert!(accepts(grammar, text), "{grammar}: {text:?}");
+            }
+            for text in rejected {
+                assert!(!accepts(grammar, text), "{grammar}: {text:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_character_classes_and_utf8() {
+        let grammar = r#"root ::= "\"" ( [^"\\] | "\\" . )* "\"" [é-ê]{1,2}"#;
+        assert!(accepts(grammar, r#""a\"b"é"#));
+        assert!(accepts(grammar, "\"ünï\"êé"));
+        assert!(!accepts(grammar, "\"a\"éêé"));
+        assert!(!accepts(grammar, "\"a\"e"));
+
+        // A character split across two tokens
+        let mut m = matcher(grammar);
+        assert!(m.accept_bytes(b"\"\""));
+        let e_acute = "é".as_bytes();
+        assert!(m.accept_bytes(&e_acute[..1]));
+        assert!(!m.is_complete());
+        assert!(!m.accept_bytes(&[0xff]));
+        // `é` and `ê` start with 0xc3; no character starting with 0xc4 is allowed
+        assert!(!m.accept_bytes(&[0xc4]));
+        assert!(m.accept_bytes(&e_acute[1..]));
+        assert!(m.is_complete());
+    }
+
+    #[test]
+    fn test_invalid_grammars() {
+        for (grammar, reason) in [
+            ("root ::= value", "`value` is not defined"),
+            ("item ::= \"a\"", "no `root` rule"),
+            ("root ::= root \"a\" | \"b\"", "left-recursive"),
+            ("root ::= \"a\"? root", "left-recursive"),
+            ("root ::= \"a", "unterminated string"),
+            ("root ::= [a-", "unterminated character class"),
+            ("root ::= \"a\"{3,1}", "empty repetition"),
+            ("root ::= \"a\"\nroot ::= \"b\"", "defined twice"),
+        ] {
+            let err = Grammar::parse(grammar).unwrap_err().to_string();
+            assert!(err.contains(reason), "{grammar:?}: {err}");
+        }
+    }
+}
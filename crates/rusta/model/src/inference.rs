@@ -1,12 +1,16 @@
 //! Model inference and weight loading
 
-use crate::model::{Qwen2Config, Qwen2ForCausalLM, KeyValueCache};
-use burn::{
-    module::Module,
-    record::{FullPrecisionSettings, Recorder},
-    tensor::{backend::Backend, Int, Tensor},
-};
-use burn_import::safetensors::{LoadArgs, SafetensorsFileRecorder};
+use std::path::Path;
+
+use crate::error::Result;
+use crate::gguf::{GgufFile, GgufVocab, load_gguf};
+use crate::model::{CacheLayout, KeyValueCache, Qwen2Config, Qwen2ForCausalLM};
+use crate::quant::QuantConfig;
+use crate::sampling::{Sampler, SamplingConfig};
+use crate::stopping::{FinishReason, StopConditions};
+use crate::stream::TokenStream;
+use crate::weights::load_safetensors;
+use burn::tensor::{Int, Tensor, TensorData, backend::Backend};
 
 /// Load Qwen2 model from Safetensors weights
 ///
@@ -19,26 +23,91 @@ use burn_import::safetensors::{LoadArgs, SafetensorsFileRecorder};
 pub fn load_model<B: Backend>(
     weights_path: &str,
     device: &B::Device,
-) -> Result<Qwen2ForCausalLM<B>, String> {
+) -> Result<Qwen2ForCausalLM<B>> {
     // Initialize model configuration for Strand-Rust-Coder-14B
     let config = Qwen2Config::strand_rust_coder_14b();
+    load_model_with_config(&config, weights_path, device)
+}
 
-    // Create model with random weights
-    let mut model = config.init(device);
+/// Load Qwen2 model from Safetensors weights, quantizing its linear layers on load
+///
+/// Like [`load_model`], but each shard's attention and MLP weights are quantized as
+/// soon as they are read (see [`crate::quant`]).
+pub fn load_model_quantized<B: Backend>(
+    weights_path: &str,
+    quant: &QuantConfig,
+    device: &B::Device,
+) -> Result<Qwen2ForCausalLM<B>> {
+    let config = Qwen2Config::strand_rust_coder_14b();
+    load_quantized_model_with_config(&config, weights_path, quant, device)
+}
 
-    // Load weights from safetensors
-    let load_args = LoadArgs::new(weights_path.into())
-        // PyTorch models need transpose for linear layers
-        .with_debug_print(); // Enable debug printing to see key mapping
+/// Load a Qwen2-family model from a HuggingFace-style model directory
+///
+/// The architecture is read from `config.json`; the weights from either
+/// `model.safetensors.index.json` and its shards or a single `model.safetensors`.
+///
+/// # Returns
+/// The loaded model together with the configuration it was built from
+pub fn load_model_from_dir<B: Backend>(
+    model_dir: impl AsRef<Path>,
+    device: &B::Device,
+) -> Result<(Qwen2ForCausalLM<B>, Qwen2Config)> {
+    let model_dir = model_dir.as_ref();
+    let config = Qwen2Config::from_hf_config_json(model_dir.join("config.json"))?;
+    let model = load_model_with_config(&config, model_dir, device)?;
+    Ok((model, config))
+}
 
-    let record = SafetensorsFileRecorder::<FullPrecisionSettings>::default()
-        .load(load_args, device)
-        .map_err(|e| format!("Failed to load safetensors: {:?}", e))?;
+/// Load a Qwen2-family model and its tokenizer vocabulary from a single GGUF file
+///
+/// The architecture comes from the file's metadata. Quantized tensors are dequantized;
+/// pass `quantize` to requantize the linear layers as they load.
+pub fn load_model_from_gguf<B: Backend>(
+    path: impl AsRef<Path>,
+    quantize: Option<&QuantConfig>,
+    device: &B::Device,
+) -> Result<(Qwen2ForCausalLM<B>, Qwen2Config, GgufVocab)> {
+    let file = GgufFile::open(path)?;
+    let config = file.qwen2_config()?;
+    let vocab = file.vocab()?;
+    let model = load_gguf(config.init(device), &config, &file, quantize)?;
+    Ok((model, config, vocab))
+}
 
-    // Load the weights into the model
-    model = model.load_record(record);
+/// Build the architecture described by `config` and load its safetensors weights
+///
+/// `weights_path` may be a model directory, a shard index or a single safetensors file.
+pub fn load_model_with_config<B: Backend>(
+    config: &Qwen2Config,
+    weights_path: impl AsRef<Path>,
+    device: &B::Device,
+) -> Result<Qwen2ForCausalLM<B>> {
+    // Parameters are initialised lazily, so only the loaded weights are materialised
+    let model = config.init(device);
+    load_safetensors(model, config, weights_path.as_ref(), None)
+}
+
+/// [`load_model_with_config`], quantizing the linear layers of each shard as it loads
+pub fn load_quantized_model_with_config<B: Backend>(
+    config: &Qwen2Config,
+    weights_path: impl AsRef<Path>,
+    quant: &QuantConfig,
+    device: &B::Device,
+) -> Result<Qwen2ForCausalLM<B>> {
+    let model = config.init(device);
+    load_safetensors(model, config, weights_path.as_ref(), Some(quant))
+}
 
-    Ok(model)
+/// Result of [`generate`]
+pub struct GenerationOutput<B: Backend> {
+    /// Prompt followed by the generated tokens [batch_size, seq_len + steps]; sequences
+    /// that finished early are padded with the first stop token
+    pub tokens: Tensor<B, 2, Int>,
+    /// Number of tokens generated by each sequence, stop token included
+    pub num_generated: Vec<usize>,
+    /// Why each sequence stopped
+    pub finish_reasons: Vec<FinishReason>,
 }
 
 /// Generate text from a prompt using the model
@@ -48,78 +117,101 @@ pub fn load_model<B: Backend>(
 /// * `config` - Model configuration
 /// * `input_ids` - Tokenized input [batch_size, seq_len]
 /// * `max_new_tokens` - Maximum number of tokens to generate
-/// * `temperature` - Sampling temperature (1.0 = neutral, lower = more deterministic)
+/// * `sampling` - Sampling parameters (temperature, top-k/top-p/min-p, penalties, seed)
+/// * `stop` - Stop token ids and stop strings, checked per sequence
 /// * `device` - Device to run inference on
 ///
 /// # Returns
-/// Generated token IDs [batch_size, seq_len + max_new_tokens]
+/// Generated token IDs [batch_size, seq_len + steps] and per-sequence finish reasons;
+/// generation ends once every sequence has stopped or after `max_new_tokens` steps
 pub fn generate<B: Backend>(
     model: &Qwen2ForCausalLM<B>,
     config: &Qwen2Config,
     input_ids: Tensor<B, 2, Int>,
     max_new_tokens: usize,
-    temperature: f32,
+    sampling: &SamplingConfig,
+    stop: &StopConditions,
     device: &B::Device,
-) -> Tensor<B, 2, Int> {
-    let [batch_size, _initial_seq_len] = input_ids.dims();
-
-    // Initialize KV cache
-    let mut cache = model.init_cache(config, batch_size, device);
-
-    // Start with the input tokens
-    let mut generated = input_ids.clone();
-
-    // Generate tokens autoregressively
-    for _ in 0..max_new_tokens {
-        // Get the last token (or all tokens on first pass)
-        let input = if cache[0].len() == 0 {
-            // First pass: use full input
-            generated.clone()
-        } else {
-            // Subsequent passes: only use last generated token
-            let seq_len = generated.dims()[1];
-            generated.clone().slice([0..batch_size, seq_len - 1..seq_len])
-        };
-
-        // Forward pass
-        let logits = model.forward(input, &mut cache);
-
-        // Get logits for the last position [batch_size, 1, vocab_size] -> [batch_size, vocab_size]
-        let [_b, seq_len, vocab_size] = logits.dims();
-        let last_logits = logits.slice([0..batch_size, seq_len - 1..seq_len, 0..vocab_size]);
-        let last_logits = last_logits.squeeze::<2>(); // Squeeze to [batch_size, vocab_size]
-
-        // Apply temperature scaling
-        let scaled_logits = last_logits.div_scalar(temperature);
-
-        // Sample next token (greedy for now - TODO: add top-p, top-k sampling)
-        let next_token = scaled_logits.argmax(1);
+) -> GenerationOutput<B> {
+    generate_stream(
+        model,
+        config,
+        input_ids,
+        max_new_tokens,
+        sampling,
+        stop,
+        device,
+    )
+    .into_output()
+}
 
-        // Append to generated sequence
-        generated = Tensor::cat(vec![generated, next_token.unsqueeze()], 1);
+/// Generate tokens lazily, one [`StreamEvent`](crate::stream::StreamEvent) at a time
+///
+/// Takes the same arguments as [`generate`]. Each step of the KV-cache loop runs when
+/// the consumer asks for the next event, so dropping or cancelling the stream stops
+/// the computation.
+pub fn generate_stream<'a, B: Backend>(
+    model: &'a Qwen2ForCausalLM<B>,
+    config: &Qwen2Config,
+    input_ids: Tensor<B, 2, Int>,
+    max_new_tokens: usize,
+    sampling: &SamplingConfig,
+    stop: &StopConditions,
+    device: &B::Device,
+) -> TokenStream<'a, B> {
+    TokenStream::new(
+        model,
+        config,
+        input_ids,
+        max_new_tokens,
+        Sampler::new(sampling.clone()),
+        stop.clone(),
+        device,
+    )
+}
 
-        // TODO: Check for EOS token and break early
-    }
+/// Copy a [batch_size, seq_len] token tensor to the host, one row per sequence
+pub fn token_rows<B: Backend>(tokens: Tensor<B, 2, Int>) -> Vec<Vec<u32>> {
+    let [_batch_size, seq_len] = tokens.dims();
+    let values = tokens
+        .into_data()
+        .convert::<i64>()
+        .to_vec::<i64>()
+        .expect("integer token ids");
+    values
+        .chunks(seq_len.max(1))
+        .map(|row| row.iter().map(|&t| t as u32).collect())
+        .collect()
+}
 
-    generated
+/// Build a [batch_size, 1] token tensor from one token per sequence
+pub fn token_column<B: Backend>(tokens: &[u32], device: &B::Device) -> Tensor<B, 2, Int> {
+    let values: Vec<i64> = tokens.iter().map(|&t| t as i64).collect();
+    Tensor::<B, 1, Int>::from_data(TensorData::new(values, [tokens.len()]), device).unsqueeze_dim(1)
 }
 
 /// Initialize a KV cache for inference
 pub fn init_cache<B: Backend>(
     config: &Qwen2Config,
     batch_size: usize,
+    layout: &CacheLayout<B>,
     device: &B::Device,
 ) -> Vec<KeyValueCache<B>> {
-    let head_dim = config.hidden_size / config.num_attention_heads;
+    let head_dim = config.head_dim();
     (0..config.num_hidden_layers)
-        .map(|_| {
-            KeyValueCache::new(
+        .map(|layer| match layout {
+            CacheLayout::Contiguous => KeyValueCache::new(
                 batch_size,
                 config.num_key_value_heads,
-                config.max_position_embeddings,
+                config.context_length(),
                 head_dim,
                 device,
-            )
+            ),
+            CacheLayout::Paged(pool) => KeyValueCache::paged(pool, layer, batch_size),
+            CacheLayout::Rolling {
+                sink_tokens,
+                window,
+            } => KeyValueCache::rolling(*sink_tokens, *window, config.context_length()),
         })
         .collect()
 }
@@ -138,7 +230,138 @@ mod tests {
         let model = config.init::<Backend>(&device);
 
         // Test that model initializes without panic
-        let cache = model.init_cache(&config, 1, &device);
+        let cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
         assert_eq!(cache.len(), config.num_hidden_layers);
     }
+
+    #[test]
+    fn test_rolling_cache_streams_past_the_context() {
+        use crate::stream::StreamEvent;
+
+        let device = Default::default();
+        let config = Qwen2Config {
+            max_position_embeddings: 12,
+            ..Qwen2Config::tiny()
+        };
+        let model = config.init::<Backend>(&device);
+        let sampling = SamplingConfig::greedy();
+        let stop = StopConditions::new();
+        let run = || {
+            let input = token_column::<Backend>(&[5, 9, 13, 2], &device).swap_dims(0, 1);
+            generate_stream(&model, &config, input, 20, &sampling, &stop, &device)
+        };
+
+        // The contiguous cache runs out of positions
+        let contiguous = run().into_output();
+        assert!(contiguous.num_generated[0] < 20);
+
+        // Four prompt tokens and twenty generated ones outgrow both the context and the
+        // two sinks plus six-token window of the rolling buffer
+        let layout = CacheLayout::Rolling {
+            sink_tokens: 2,
+            window: 6,
+        };
+        let events: Vec<StreamEvent> = run().with_cache_layout(&layout).collect();
+        let tokens = events
+            .iter()
+            .filter(|event| matches!(event, StreamEvent::Token { .. }))
+            .count();
+        assert_eq!(tokens, 20);
+        assert_eq!(
+            events.last(),
+            Some(&StreamEvent::Finished {
+                sequence: 0,
+                reason: FinishReason::Length,
+            })
+        );
+    }
+
+    #[test]
+    fn test_finished_sequences_leave_the_batch() {
+        use crate::stream::StreamEvent;
+
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let sampling = SamplingConfig::greedy();
+        let run = |prompts: &[&[u32]], stop: &StopConditions| {
+            let rows = prompts
+                .iter()
+                .map(|prompt| token_column::<Backend>(prompt, &device).swap_dims(0, 1))
+                .collect();
+            generate_stream(
+                &model,
+                &config,
+                Tensor::cat(rows, 0),
+                6,
+                &sampling,
+                stop,
+                &device,
+            )
+        };
+
+        // Sequence 0 stops on its first token, sequence 1 runs to the length limit
+        let first = vec![5, 9, 13, 2];
+        let output = run(&[&first], &StopConditions::new()).into_output();
+        let stop = StopConditions::new().with_stop_token_ids([token_rows(output.tokens)[0][4]]);
+        let second = (0..config.vocab_size as u32)
+            .map(|start| {
+                (start..start + 4)
+                    .map(|t| t % config.vocab_size as u32)
+                    .collect()
+            })
+            .find(|prompt: &Vec<u32>| {
+                let output = run(&[prompt], &stop).into_output();
+                output.finish_reasons == [FinishReason::Length]
+            })
+            .expect("a prompt that never produces the stop token");
+
+        let mut stream = run(&[&first, &second], &stop);
+        while let Some(event) = stream.next() {
+            if let StreamEvent::Finished { sequence: 0, .. } = event {
+                assert_eq!(stream.running_sequences(), [1]);
+            }
+        }
+        let batched = stream.into_output();
+        assert_eq!(batched.num_generated, [1, 6]);
+
+        let batched_rows = token_rows(batched.tokens);
+        for (row, prompt) in batched_rows.iter().zip([first, second]) {
+            let solo = run(&[&prompt], &stop).into_output();
+            let solo_row = &token_rows(solo.tokens)[0];
+            assert_eq!(&row[..solo_row.len()], solo_row.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_chunked_prefill_streams_progress() {
+        use crate::stream::StreamEvent;
+
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let prompt = [5, 9, 13, 2, 7, 30, 11];
+        let sampling = SamplingConfig::greedy();
+        let stop = StopConditions::new();
+        let run = || {
+            let input = token_column::<Backend>(&prompt, &device).swap_dims(0, 1);
+            generate_stream(&model, &config, input, 4, &sampling, &stop, &device)
+        };
+
+        let events: Vec<StreamEvent> = run().with_prefill_chunk_size(3).collect();
+        let progress: Vec<(usize, usize)> = events
+            .iter()
+            .filter_map(|event| match event {
+                StreamEvent::Prefill { processed, total } => Some((*processed, *total)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(progress, [(3, 7), (6, 7), (7, 7)]);
+        // Progress is reported before the first token
+        assert!(matches!(events[3], StreamEvent::Token { sequence: 0, .. }));
+
+        let chunked = run().with_prefill_chunk_size(3).into_output();
+        let whole = run().into_output();
+        assert_eq!(token_rows(chunked.tokens), token_rows(whole.tokens));
+    }
 }
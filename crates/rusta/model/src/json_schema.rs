@@ -0,0 +1,534 @@
+//! JSON Schema to grammar conversion
+//!
+//! [`JsonSchemaGrammar`] turns the schemas under `schemas/` into GBNF (see
+//! [`crate::grammar`]) that only generates JSON documents of the schema's shape.
+//! Supported keywords: `type` (one or several), `properties`, `required`,
+//! `additionalProperties`, `items`, `enum`, `const`, `anyOf`, `oneOf`, `$ref` to
+//! `#/$defs/...` or to a registered document, and numeric `minimum: 0` (no sign) and
+//! `maximum: 1` on top of it (`[0, 1]`); other numeric bounds are rejected. Other keywords
+//! (`format`, `pattern`, lengths) are not enforced.
+//!
+//! Properties are generated in a fixed order: the required ones in the order `required`
+//! lists them, then any of the optional ones by name. Objects with `properties` admit
+//! no other keys.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::error::{ModelError, Result};
+use crate::grammar::Grammar;
+
+/// `schemas/action_block.schema.json`
+pub const ACTION_BLOCK_SCHEMA: &str = include_str!("../../../../schemas/action_block.schema.json");
+/// `schemas/patch_bundle.schema.json`
+pub const PATCH_BUNDLE_SCHEMA: &str = include_str!("../../../../schemas/patch_bundle.schema.json");
+/// `schemas/docspan.schema.json`, which PatchBundle anchors refer to
+pub const DOCSPAN_SCHEMA: &str = include_str!("../../../../schemas/docspan.schema.json");
+
+/// Rules shared by every converted schema, added when first used
+const PRIMITIVES: &[(&str, &str)] = &[
+    ("ws", r#"[ \t\n]{0,20}"#),
+    ("value", "object | array | string | number | boolean | null"),
+    (
+        "object",
+        r#""{" ws ( string ws ":" ws value ( ws "," ws string ws ":" ws value )* )? ws "}""#,
+    ),
+    ("array", r#""[" ws ( value ( ws "," ws value )* )? ws "]""#),
+    ("string", r#""\"" char* "\"""#),
+    (
+        "char",
+        r#"[^"\\\x00-\x1f] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} )"#,
+    ),
+    ("natural", r#""0" | [1-9] [0-9]{0,15}"#),
+    ("integer", r#""-"? natural"#),
+    ("fraction", r#""." [0-9]{1,16}"#),
+    ("exponent", r#"[eE] [-+]? [0-9]{1,3}"#),
+    ("number", r#""-"? natural fraction? exponent?"#),
+    ("nonnegative-number", "natural fraction? exponent?"),
+    ("unit-number", r#""0" fraction? | "1" ( "." "0"{1,16} )?"#),
+    ("boolean", r#""true" | "false""#),
+    ("null", r#""null""#),
+];
+
+/// Converts JSON schemas to grammars
+#[derive(Debug, Clone, Default)]
+pub struct JsonSchemaGrammar {
+    /// Documents that `$ref` may name, by file name
+    documents: HashMap<String, Value>,
+}
+
+impl JsonSchemaGrammar {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve `$ref: "<name>"` to `schema`
+    pub fn with_document(mut self, name: impl Into<String>, schema: Value) -> Self {
+        self.documents.insert(name.into(), schema);
+        self
+    }
+
+    /// The converter for the repository's schemas, with their cross-references
+    pub fn repository() -> Self {
+        let docspan = serde_json::from_str(DOCSPAN_SCHEMA).expect("valid DocSpan schema");
+        Self::new().with_document("docspan.schema.json", docspan)
+    }
+
+    /// GBNF text generating the documents `schema` describes
+    pub fn to_gbnf(&self, schema: &Value) -> Result<String> {
+        let mut converter = Converter {
+            documents: &self.documents,
+            rules: Vec::new(),
+            refs: HashMap::new(),
+        };
+        let root = converter.visit(schema, schema, "root")?;
+        converter.add_rule("root", root);
+
+        let mut gbnf = String::new();
+        for (name, body) in &converter.rules {
+            gbnf.push_str(&format!("{name} ::= {body}\n"));
+        }
+        // Primitives the rules refer to, and the ones those refer to in turn
+        let mut used = vec![false; PRIMITIVES.len()];
+        let unused = |used: &[bool], gbnf: &str| {
+            (0..PRIMITIVES.len()).find(|&i| !used[i] && refers(gbnf, PRIMITIVES[i].0))
+        };
+        while let Some(i) = unused(&used, &gbnf) {
+            used[i] = true;
+            let (name, body) = PRIMITIVES[i];
+            gbnf.push_str(&format!("{name} ::= {body}\n"));
+        }
+        Ok(gbnf)
+    }
+
+    /// Compile `schema` to a grammar
+    pub fn compile(&self, schema: &Value) -> Result<Grammar> {
+        Grammar::parse(&self.to_gbnf(schema)?)
+    }
+
+    /// Compile the schema in `text`
+    pub fn compile_str(&self, text: &str) -> Result<Grammar> {
+        let schema = serde_json::from_str(text)
+            .map_err(|err| ModelError::Grammar(format!("malformed JSON schema: {err}")))?;
+        self.compile(&schema)
+    }
+}
+
+impl Grammar {
+    /// Grammar of ActionBlock tool calls
+    pub fn action_block() -> Self {
+        JsonSchemaGrammar::repository()
+            .compile_str(ACTION_BLOCK_SCHEMA)
+            .expect("ActionBlock schema compiles")
+    }
+
+    /// Grammar of PatchBundle records
+    pub fn patch_bundle() -> Self {
+        JsonSchemaGrammar::repository()
+            .compile_str(PATCH_BUNDLE_SCHEMA)
+            .expect("PatchBundle schema compiles")
+    }
+}
+
+struct Converter<'a> {
+    documents: &'a HashMap<String, Value>,
+    /// Rules in definition order
+    rules: Vec<(String, String)>,
+    /// Rule of every `$ref` converted so far
+    refs: HashMap<String, String>,
+}
+
+impl Converter<'_> {
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let mut unique = name.to_string();
+        let mut suffix = 1;
+        while self.rules.iter().any(|(rule, _)| *rule == unique) || is_primitive(&unique) {
+            suffix += 1;
+            unique = format!("{name}{suffix}");
+        }
+        self.rules.push((unique.clone(), body));
+        unique
+    }
+
+    /// A GBNF expression for `schema`, a part of `document`; `name` prefixes the rules
+    /// created for its parts
+    fn visit(&mut self, schema: &Value, document: &Value, name: &str) -> Result<String> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Object(schema) => schema,
+            _ => return Err(unsupported(name, "schemas must be objects or `true`")),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.reference(reference, document);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(json_literal(value));
+        }
+        if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+            let values: Vec<String> = values.iter().map(json_literal).collect();
+            return Ok(group(&values));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(options) = schema.get(keyword).and_then(Value::as_array) {
+                let options = options
+                    .iter()
+                    .enumerate()
+                    .map(|(i, option)| self.visit(option, document, &format!("{name}-{i}")))
+                    .collect::<Result<Vec<_>>>()?;
+                return Ok(group(&options));
+            }
+        }
+
+        match schema.get("type") {
+            None => Ok("value".to_string()),
+            Some(Value::String(kind)) => self.typed(kind, schema, document, name),
+            Some(Value::Array(kinds)) => {
+                let options = kinds
+                    .iter()
+                    .map(|kind| {
+                        let kind = kind.as_str().ok_or_else(|| unsupported(name, "type"))?;
+                        self.typed(kind, schema, document, name)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(group(&options))
+            }
+            Some(_) => Err(unsupported(name, "`type` must be a string or an array")),
+        }
+    }
+
+    fn typed(
+        &mut self,
+        kind: &str,
+        schema: &serde_json::Map<String, Value>,
+        document: &Value,
+        name: &str,
+    ) -> Result<String> {
+        // Only the ranges the base rules enforce: no bound, `minimum: 0` and [0, 1]
+        let minimum = schema.get("minimum").map(|minimum| minimum.as_f64());
+        let maximum = schema.get("maximum").map(|maximum| maximum.as_f64());
+        let range = match (minimum, maximum) {
+            (None, None) => NumberRange::Any,
+            (Some(Some(0.0)), None) => NumberRange::NonNegative,
+            (Some(Some(0.0)), Some(Some(1.0))) if kind == "number" => NumberRange::Unit,
+            _ if matches!(kind, "integer" | "number") => {
+                return Err(unsupported(
+                    name,
+                    "numeric bounds other than `minimum: 0` and `minimum: 0, maximum: 1`",
+                ));
+            }
+            _ => NumberRange::Any,
+        };
+        Ok(match kind {
+            "object" => return self.object(schema, document, name),
+            "array" => {
+                let items = match schema.get("items") {
+                    Some(items) => self.visit(items, document, &format!("{name}-item"))?,
+                    None => "value".to_string(),
+                };
+                let items = self.add_rule(&format!("{name}-item"), items);
+                format!(r#""[" ws ( {items} ( ws "," ws {items} )* )? ws "]""#)
+            }
+            "string" => "string".to_string(),
+            "integer" if range == NumberRange::NonNegative => "natural".to_string(),
+            "integer" => "integer".to_string(),
+            "number" => match range {
+                NumberRange::Any => "number",
+                NumberRange::NonNegative => "nonnegative-number",
+                NumberRange::Unit => "unit-number",
+            }
+            .to_string(),
+            "boolean" => "boolean".to_string(),
+            "null" => "null".to_string(),
+            _ => return Err(unsupported(name, &format!("type `{kind}`"))),
+        })
+    }
+
+    fn object(
+        &mut self,
+        schema: &serde_json::Map<String, Value>,
+        document: &Value,
+        name: &str,
+    ) -> Result<String> {
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return Ok(match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => r#""{" ws "}""#.to_string(),
+                _ => "object".to_string(),
+            });
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        // Required properties in `required` order, then the optional ones by name
+        let mut keys: Vec<&str> = required
+            .iter()
+            .copied()
+            .filter(|key| properties.contains_key(*key))
+            .collect();
+        keys.extend(
+            properties
+                .keys()
+                .map(String::as_str)
+                .filter(|key| !required.contains(key)),
+        );
+
+        let mut members = Vec::with_capacity(keys.len());
+        for key in keys {
+            let rule_name = format!("{name}-{}", sanitize(key));
+            let value = self.visit(&properties[key], document, &rule_name)?;
+            let value = self.add_rule(&rule_name, value);
+            let key_literal = json_literal(&Value::from(key));
+            members.push((
+                format!(r#"{key_literal} ws ":" ws {value}"#),
+                required.contains(&key),
+            ));
+        }
+
+        // `rest[i]`: members from `i` on, after at least one member was written
+        let mut rest = vec![r#""""#.to_string(); members.len() + 1];
+        for i in (0..members.len()).rev() {
+            let (member, required) = &members[i];
+            let body = if *required {
+                format!(r#"ws "," ws {member} {}"#, rest[i + 1])
+            } else {
+                format!(r#"( ws "," ws {member} )? {}"#, rest[i + 1])
+            };
+            rest[i] = self.add_rule(&format!("{name}-rest"), body);
+        }
+        // `first`: members from `i` on, none written yet
+        let mut first = r#""""#.to_string();
+        for i in (0..members.len()).rev() {
+            let (member, required) = &members[i];
+            let with_member = format!("{member} {}", rest[i + 1]);
+            first = if *required {
+                with_member
+            } else {
+                format!("( {with_member} | {first} )")
+            };
+        }
+        Ok(format!(r#""{{" ws {first} ws "}}""#))
+    }
+
+    fn reference(&mut self, reference: &str, document: &Value) -> Result<String> {
+        if let Some(rule) = self.refs.get(reference) {
+            return Ok(rule.clone());
+        }
+        let (target, target_document, name) = if let Some(pointer) = reference.strip_prefix('#') {
+            let target = document
+                .pointer(pointer)
+                .ok_or_else(|| unsupported(reference, "unresolved reference"))?;
+            let name = pointer.rsplit('/').next().unwrap_or("ref");
+            (target, document, sanitize(name))
+        } else {
+            let target = self
+                .documents
+                .get(reference)
+                .ok_or_else(|| unsupported(reference, "unknown document"))?;
+            let name = reference
+                .trim_end_matches(".json")
+                .trim_end_matches(".schema");
+            (target, target, sanitize(name))
+        };
+
+        // Register the rule before converting its body, so that it may refer to itself
+        let rule = self.add_rule(&name, String::new());
+        self.refs.insert(reference.to_string(), rule.clone());
+        let body = self.visit(target, target_document, &rule)?;
+        let index = self
+            .rules
+            .iter()
+            .position(|(name, _)| *name == rule)
+            .expect("added");
+        self.rules[index].1 = body;
+        Ok(rule)
+    }
+}
+
+/// The numeric bounds [`Converter`] can express
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NumberRange {
+    Any,
+    /// `minimum: 0`
+    NonNegative,
+    /// `minimum: 0, maximum: 1`
+    Unit,
+}
+
+fn unsupported(at: &str, reason: &str) -> ModelError {
+    ModelError::Grammar(format!("unsupported JSON schema at `{at}`: {reason}"))
+}
+
+/// Whether `gbnf` mentions the rule `name` outside a definition of it (string
+/// literals may produce false positives, which only add an unused rule)
+fn refers(gbnf: &str, name: &str) -> bool {
+    gbnf.match_indices(name).any(|(start, _)| {
+        let end = start + name.len();
+        let boundary = |c: Option<char>| !c.is_some_and(|c| c.is_ascii_alphanumeric() || c == '-');
+        boundary(gbnf[..start].chars().next_back())
+            && boundary(gbnf[end..].chars().next())
+            && !gbnf[end..].trim_start().starts_with("::=")
+    })
+}
+
+fn is_primitive(name: &str) -> bool {
+    PRIMITIVES.iter().any(|(primitive, _)| *primitive == name)
+}
+
+/// `name` as a rule name: lowercase letters, digits and dashes
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// A GBNF string literal matching the JSON serialization of `value`
+fn json_literal(value: &Value) -> String {
+    let json = value.to_string();
+    let mut literal = String::with_capacity(json.len() + 2);
+    literal.push('"');
+    for c in json.chars() {
+        match c {
+            '"' | '\\' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            '\n' => literal.push_str("\\n"),
+            _ => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn group(options: &[String]) -> String {
+    match options {
+        [single] => single.clone(),
+        _ => format!("( {} )", options.join(" | ")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn accepts(grammar: &Arc<Grammar>, text: &str) -> bool {
+        let mut matcher = grammar.matcher();
+        matcher.accept_str(text) && matcher.is_complete()
+    }
+
+    #[test]
+    fn test_action_block_grammar() {
+        let grammar = Arc::new(Grammar::action_block());
+        for text in [
+            r#"{"schema_version":"1","id":"a1","tool":"cargo_check","args":{}}"#,
+            concat!(
+                "{\n  \"schema_version\": \"1\",\n  \"id\": \"a\\\"2\",\n",
+                "  \"tool\": \"ra_type_of\",\n  \"args\": {\"file\": \"src/lib.rs\", ",
+                "\"line\": 3, \"flags\": [true, null, -1.5e3]},\n",
+                "  \"anchors\": [{\"doc_id\": \"d\", \"start\": 0, \"end\": 12, ",
+                "\"sha256\": \"ab\"}],\n  \"confidence\": 0.85,\n  \"k_budget\": 4\n}"
+            ),
+        ] {
+            assert!(accepts(&grammar, text), "{text}");
+            serde_json::from_str::<Value>(text).unwrap();
+        }
+
+        for text in [
+            // Unknown tool, missing `args`, extra key, out-of-range confidence, other order,
+            // wrong const, negative budget
+            r#"{"schema_version":"1","id":"a","tool":"rm_rf","args":{}}"#,
+            r#"{"schema_version":"1","id":"a","tool":"test"}"#,
+            r#"{"schema_version":"1","id":"a","tool":"test","args":{},"extra":1}"#,
+            r#"{"schema_version":"1","id":"a","tool":"test","args":{},"confidence":1.5}"#,
+            r#"{"id":"a","schema_version":"1","tool":"test","args":{}}"#,
+            r#"{"schema_version":"2","id":"a","tool":"test","args":{}}"#,
+            r#"{"schema_version":"1","id":"a","tool":"test","args":{},"k_budget":-1}"#,
+        ] {
+            assert!(!accepts(&grammar, text), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_patch_bundle_grammar() {
+        let grammar = Arc::new(Grammar::patch_bundle());
+        let text = concat!(
+            r#"{"schema_version":"1","id":"p","diff":"--- a\n+++ b\n","#,
+            r#""anchors":[{"schema_version":"1","doc_id":"d","start":1,"end":2,"sha256":"x"}],"#,
+            r#""confidence":1.0,"risk":"low"}"#
+        );
+        assert!(accepts(&grammar, text));
+        assert!(!accepts(
+            &grammar,
+            r#"{"schema_version":"1","id":"p","diff":"","risk":"none"}"#
+        ));
+        // Anchors are DocSpans, which need their own schema_version
+        let anchor = r#""anchors":[{"doc_id":"d","start":1,"end":2,"sha256":"x"}]"#;
+        let text = format!(r#"{{"schema_version":"1","id":"p","diff":"",{anchor}}}"#);
+        assert!(!accepts(&grammar, &text));
+    }
+
+    #[test]
+    fn test_schema_keywords() {
+        let schema = serde_json::json!({
+            "$defs": { "node": {
+                "type": "object",
+                "properties": {
+                    "children": { "type": "array", "items": { "$ref": "#/$defs/node" } }
+                },
+                "additionalProperties": false
+            }},
+            "type": "object",
+            "properties": {
+                "a": { "type": ["integer", "null"] },
+                "b": { "anyOf": [{ "const": [1, "x"] }, { "type": "boolean" }] },
+                "tree": { "$ref": "#/$defs/node" }
+            },
+            "required": ["b"]
+        });
+        let grammar = Arc::new(JsonSchemaGrammar::new().compile(&schema).unwrap());
+        for text in [
+            r#"{"b":true}"#,
+            r#"{"b":[1,"x"],"a":null}"#,
+            r#"{ "b": false , "a": -3, "tree": {"children": [{}, {"children": []}]} }"#,
+        ] {
+            assert!(accepts(&grammar, text), "{text}");
+        }
+        for text in [
+            r#"{}"#,
+            r#"{"b":true,"a":1.5}"#,
+            r#"{"b":true,"tree":{"x":1}}"#,
+        ] {
+            assert!(!accepts(&grammar, text), "{text}");
+        }
+
+        let err = JsonSchemaGrammar::new()
+            .compile(&serde_json::json!({ "$ref": "other.schema.json" }))
+            .unwrap_err();
+        assert!(err.to_string().contains("unknown document"), "{err}");
+
+        // Bounds the base rules cannot enforce are rejected rather than loosened
+        for bounds in [
+            serde_json::json!({ "type": "integer", "minimum": 5 }),
+            serde_json::json!({ "type": "number", "minimum": 0, "maximum": 0.5 }),
+            serde_json::json!({ "type": "number", "maximum": 1 }),
+            serde_json::json!({ "type": "integer", "minimum": 0, "maximum": 1 }),
+        ] {
+            let err = JsonSchemaGrammar::new().compile(&bounds).unwrap_err();
+            assert!(
+                err.to_string().contains("numeric bounds"),
+                "{bounds}: {err}"
+            );
+        }
+    }
+}
@@ -1,8 +1,49 @@
+// burn's `Config` derive expands to `field: field` initialisers
+#![allow(clippy::redundant_field_names)]
+
+pub mod attention;
 pub mod cache;
+pub mod chat;
+pub mod constrained;
 pub mod data;
+pub mod error;
+pub mod gguf;
+pub mod grammar;
 pub mod inference;
+pub mod json_schema;
 pub mod model;
+pub mod prefix_cache;
+pub mod quant;
+pub mod rope;
+pub mod sampling;
+pub mod scheduler;
+pub mod search;
+pub mod speculative;
+pub mod stopping;
+pub mod stream;
 pub mod training;
+pub mod weights;
 
 // Re-export main types
-pub use model::{Qwen2Config, Qwen2ForCausalLM, Qwen2Model, KeyValueCache};
+pub use cache::PagedKvPool;
+pub use chat::{ChatMessage, ChatTemplate};
+pub use constrained::{GrammarConstraint, TokenTrie};
+pub use data::{
+    DecodeStream, PackedBatch, PackedBatcher, PackedDataset, PackingConfig, Qwen2Tokenizer,
+    TrainingSample,
+};
+pub use error::ModelError;
+pub use grammar::{Grammar, GrammarMatcher};
+pub use json_schema::JsonSchemaGrammar;
+pub use model::{
+    CacheLayout, KeyValueCache, KvSnapshot, Qwen2Config, Qwen2ForCausalLM, Qwen2Model,
+};
+pub use prefix_cache::PrefixCache;
+pub use quant::{QuantConfig, QuantFormat};
+pub use rope::{RopeScaling, RopeScalingKind};
+pub use sampling::SamplingConfig;
+pub use scheduler::{GenerationRequest, Scheduler};
+pub use search::{BeamSearchConfig, NBestGenerator, ScoredSequence};
+pub use speculative::{SpeculativeConfig, SpeculativeDecoder};
+pub use stopping::{FinishReason, StopConditions};
+pub use stream::{StreamEvent, TokenStream};
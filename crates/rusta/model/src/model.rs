@@ -3,17 +3,26 @@
 //! This module contains the complete implementation of the Qwen2.5 transformer
 //! architecture, designed for the Strand-Rust-Coder-14B-v1 model.
 
+use std::path::Path;
+
 use burn::{
     config::Config,
     module::Module,
     nn::{
-        Embedding, EmbeddingConfig, Linear, LinearConfig, RmsNorm, RmsNormConfig,
-        RotaryEncoding, RotaryEncodingConfig, SwiGlu, SwiGluConfig,
+        Embedding, EmbeddingConfig, Linear, LinearConfig, RmsNorm, RmsNormConfig, RotaryEncoding,
+    },
+    tensor::{
+        Bool, Int, Tensor, TensorData, activation::softmax, backend::Backend, module::linear,
     },
-    tensor::{activation::softmax, backend::Backend, Bool, Int, Tensor},
 };
 
-use crate::cache::AutoregressiveCache;
+use serde::Deserialize;
+
+use crate::attention::{document_mask, tiled_attention};
+use crate::cache::{AutoregressiveCache, PagedKvCache, PagedKvPool, RollingKvCache};
+use crate::error::{ModelError, Result};
+use crate::quant::{QuantConfig, QuantLinear, QuantSwiGlu, QuantizedWeight};
+use crate::rope::{RopeScaling, rotary_encoding};
 
 // ============================================================================
 // Configuration
@@ -38,6 +47,117 @@ pub struct Qwen2Config {
     pub eos_token_id: usize,
     #[config(default = "false")]
     pub tie_word_embeddings: bool,
+    /// Whether upper layers restrict attention to `sliding_window` tokens
+    #[config(default = "false")]
+    pub use_sliding_window: bool,
+    /// Tokens each query attends to, itself included
+    pub sliding_window: Option<usize>,
+    /// Layers at or above this index use sliding-window attention (all when unset)
+    pub max_window_layers: Option<usize>,
+    /// RMS-normalise every query and key head before RoPE (Qwen3)
+    #[config(default = "false")]
+    pub qk_norm: bool,
+    /// Bias on the query, key and value projections (Qwen2 and Qwen2.5)
+    #[config(default = "true")]
+    pub attention_bias: bool,
+    /// Width of each attention head, when not `hidden_size / num_attention_heads`
+    pub head_dim: Option<usize>,
+    /// Rotary frequency scaling for contexts beyond the trained one
+    pub rope_scaling: Option<RopeScaling>,
+    /// Positions to support, overriding the context implied by `rope_scaling`
+    pub max_context_length: Option<usize>,
+    /// Compute attention in tiles of this many queries and keys with an online softmax
+    /// instead of materialising all scores (eager when unset)
+    pub attention_block_size: Option<usize>,
+}
+
+/// Token id fields may be a single id or a list of ids in HuggingFace configs
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HfTokenIds {
+    One(usize),
+    Many(Vec<usize>),
+}
+
+impl HfTokenIds {
+    fn first(&self) -> Option<usize> {
+        match self {
+            HfTokenIds::One(id) => Some(*id),
+            HfTokenIds::Many(ids) => ids.first().copied(),
+        }
+    }
+}
+
+/// Subset of the HuggingFace `Qwen2Config` schema that we understand
+#[derive(Deserialize)]
+struct HfQwen2Config {
+    model_type: Option<String>,
+    vocab_size: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_hidden_layers: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: Option<usize>,
+    max_position_embeddings: usize,
+    #[serde(default = "default_rms_norm_eps")]
+    rms_norm_eps: f64,
+    #[serde(default = "default_rope_theta")]
+    rope_theta: f64,
+    #[serde(default = "default_hidden_act")]
+    hidden_act: String,
+    bos_token_id: Option<HfTokenIds>,
+    eos_token_id: Option<HfTokenIds>,
+    #[serde(default)]
+    tie_word_embeddings: bool,
+    #[serde(default)]
+    use_sliding_window: bool,
+    sliding_window: Option<usize>,
+    max_window_layers: Option<usize>,
+    rope_scaling: Option<serde_json::Value>,
+    head_dim: Option<usize>,
+    attention_bias: Option<bool>,
+}
+
+fn default_rms_norm_eps() -> f64 {
+    1e-6
+}
+
+fn default_rope_theta() -> f64 {
+    10000.0
+}
+
+fn default_hidden_act() -> String {
+    "silu".to_string()
+}
+
+/// Attention heads must split the hidden size evenly (unless their width is given) and be
+/// shared evenly by the KV heads
+pub(crate) fn check_heads(
+    hidden_size: usize,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: Option<usize>,
+) -> Result<()> {
+    if head_dim == Some(0) {
+        return Err(ModelError::InvalidConfig(
+            "head_dim must be positive".to_string(),
+        ));
+    }
+    if num_attention_heads == 0
+        || (head_dim.is_none() && !hidden_size.is_multiple_of(num_attention_heads))
+    {
+        return Err(ModelError::InvalidConfig(format!(
+            "hidden_size {hidden_size} is not divisible by num_attention_heads \
+             {num_attention_heads}"
+        )));
+    }
+    if num_key_value_heads == 0 || !num_attention_heads.is_multiple_of(num_key_value_heads) {
+        return Err(ModelError::InvalidConfig(format!(
+            "num_attention_heads {num_attention_heads} is not divisible by \
+             num_key_value_heads {num_key_value_heads}"
+        )));
+    }
+    Ok(())
 }
 
 impl Qwen2Config {
@@ -57,7 +177,168 @@ impl Qwen2Config {
             bos_token_id: 151643,
             eos_token_id: 151645,
             tie_word_embeddings: false,
+            use_sliding_window: false,
+            sliding_window: Some(131072),
+            max_window_layers: Some(48),
+            qk_norm: false,
+            attention_bias: true,
+            head_dim: None,
+            rope_scaling: None,
+            max_context_length: None,
+            attention_block_size: None,
+        }
+    }
+
+    /// Width of each attention head
+    pub fn head_dim(&self) -> usize {
+        self.head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads)
+    }
+
+    /// Positions the RoPE tables and caches cover: `max_context_length` if set,
+    /// otherwise the scaled context of `rope_scaling` or `max_position_embeddings`
+    pub fn context_length(&self) -> usize {
+        self.max_context_length.unwrap_or_else(|| {
+            self.rope_scaling
+                .as_ref()
+                .map_or(self.max_position_embeddings, |scaling| {
+                    scaling.scaled_context(self.max_position_embeddings)
+                })
+        })
+    }
+
+    /// Read the architecture from a HuggingFace `config.json`
+    ///
+    /// Fields we cannot honour yet (non-SiLU activations, RoPE scaling types other than
+    /// linear, dynamic and YaRN) are rejected with [`ModelError::UnsupportedConfig`] rather
+    /// than ignored.
+    pub fn from_hf_config_json(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let json = std::fs::read_to_string(path).map_err(|source| ModelError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::from_hf_config_str(&json)
+    }
+
+    /// Serialize to a HuggingFace `config.json`, the inverse of [`Self::from_hf_config_str`]
+    ///
+    /// QK-normalised models are written as Qwen3. `torch_dtype` names the dtype the
+    /// accompanying weights are stored in.
+    pub fn to_hf_config_json(&self, torch_dtype: &str) -> String {
+        let (architecture, model_type) = if self.qk_norm {
+            ("Qwen3ForCausalLM", "qwen3")
+        } else {
+            ("Qwen2ForCausalLM", "qwen2")
+        };
+        let config = serde_json::json!({
+            "architectures": [architecture],
+            "model_type": model_type,
+            "vocab_size": self.vocab_size,
+            "hidden_size": self.hidden_size,
+            "intermediate_size": self.intermediate_size,
+            "num_hidden_layers": self.num_hidden_layers,
+            "num_attention_heads": self.num_attention_heads,
+            "num_key_value_heads": self.num_key_value_heads,
+            "max_position_embeddings": self.max_position_embeddings,
+            "rms_norm_eps": self.rms_norm_eps,
+            "rope_theta": self.rope_theta,
+            "hidden_act": self.hidden_act,
+            "bos_token_id": self.bos_token_id,
+            "eos_token_id": self.eos_token_id,
+            "tie_word_embeddings": self.tie_word_embeddings,
+            "use_sliding_window": self.use_sliding_window,
+            "sliding_window": self.sliding_window,
+            "max_window_layers": self.max_window_layers,
+            "head_dim": self.head_dim(),
+            "attention_bias": self.attention_bias,
+            "rope_scaling": self.rope_scaling.as_ref().map(RopeScaling::to_hf_value),
+            "torch_dtype": torch_dtype,
+        });
+        serde_json::to_string_pretty(&config).expect("JSON values serialize")
+    }
+
+    /// Parse the contents of a HuggingFace `config.json`
+    pub fn from_hf_config_str(json: &str) -> Result<Self> {
+        let hf: HfQwen2Config = serde_json::from_str(json).map_err(ModelError::ConfigParse)?;
+
+        // Qwen3 adds QK-norm and drops the projection biases; the rest is shared
+        let qk_norm = match hf.model_type.as_deref() {
+            None | Some("qwen2") => false,
+            Some("qwen3") => true,
+            Some(model_type) => {
+                return Err(ModelError::UnsupportedConfig {
+                    field: "model_type",
+                    reason: format!("expected \"qwen2\" or \"qwen3\", found {model_type:?}"),
+                });
+            }
+        };
+        let attention_bias = hf.attention_bias.unwrap_or(!qk_norm);
+        if qk_norm && attention_bias {
+            return Err(ModelError::UnsupportedConfig {
+                field: "attention_bias",
+                reason: "Qwen3 output projection biases are not implemented".to_string(),
+            });
+        }
+        if hf.hidden_act != "silu" {
+            return Err(ModelError::UnsupportedConfig {
+                field: "hidden_act",
+                reason: format!("only \"silu\" is implemented, found {:?}", hf.hidden_act),
+            });
         }
+        let rope_scaling = match &hf.rope_scaling {
+            Some(value) => RopeScaling::from_hf_value(value)?,
+            None => None,
+        };
+        if hf.use_sliding_window && !hf.sliding_window.is_some_and(|window| window > 0) {
+            return Err(ModelError::InvalidConfig(
+                "use_sliding_window requires a positive sliding_window".to_string(),
+            ));
+        }
+
+        let num_key_value_heads = hf.num_key_value_heads.unwrap_or(hf.num_attention_heads);
+        check_heads(
+            hf.hidden_size,
+            hf.num_attention_heads,
+            num_key_value_heads,
+            hf.head_dim,
+        )?;
+
+        let eos_token_id = hf
+            .eos_token_id
+            .as_ref()
+            .and_then(HfTokenIds::first)
+            .ok_or_else(|| ModelError::InvalidConfig("missing eos_token_id".to_string()))?;
+        let bos_token_id = hf
+            .bos_token_id
+            .as_ref()
+            .and_then(HfTokenIds::first)
+            .unwrap_or(eos_token_id);
+
+        Ok(Self {
+            vocab_size: hf.vocab_size,
+            hidden_size: hf.hidden_size,
+            intermediate_size: hf.intermediate_size,
+            num_hidden_layers: hf.num_hidden_layers,
+            num_attention_heads: hf.num_attention_heads,
+            num_key_value_heads,
+            max_position_embeddings: hf.max_position_embeddings,
+            rms_norm_eps: hf.rms_norm_eps,
+            rope_theta: hf.rope_theta,
+            hidden_act: hf.hidden_act,
+            bos_token_id,
+            eos_token_id,
+            tie_word_embeddings: hf.tie_word_embeddings,
+            use_sliding_window: hf.use_sliding_window,
+            sliding_window: hf.sliding_window,
+            max_window_layers: hf.max_window_layers,
+            qk_norm,
+            attention_bias,
+            head_dim: hf.head_dim,
+            rope_scaling,
+            max_context_length: None,
+            attention_block_size: None,
+        })
     }
 
     /// Initialize the full Qwen2 model for causal language modeling
@@ -73,16 +354,35 @@ impl Qwen2Config {
             self.rope_theta,
             self.max_position_embeddings,
         )
+        .with_sliding_window(self.sliding_window.filter(|_| self.use_sliding_window))
+        .with_max_window_layers(self.max_window_layers.unwrap_or(0))
+        .with_head_dim(Some(self.head_dim()))
+        .with_qk_norm(self.qk_norm)
+        .with_attention_bias(self.attention_bias)
+        .with_rope_scaling(self.rope_scaling.clone())
+        .with_context_length(Some(self.context_length()))
+        .with_attention_block_size(self.attention_block_size)
         .init(device);
 
-        let lm_head = LinearConfig::new(self.hidden_size, self.vocab_size)
-            .with_bias(false)
-            .init(device);
+        // Tied models project onto the vocabulary with the embedding matrix
+        let lm_head = (!self.tie_word_embeddings).then(|| {
+            LinearConfig::new(self.hidden_size, self.vocab_size)
+                .with_bias(false)
+                .init(device)
+        });
 
         Qwen2ForCausalLM { model, lm_head }
     }
 }
 
+#[cfg(test)]
+impl Qwen2Config {
+    /// A tiny configuration for unit tests (head_dim 4, GQA with 2 KV heads)
+    pub(crate) fn tiny() -> Self {
+        Self::new(64, 16, 32, 2, 4, 2, 64, "silu".to_string(), 1, 2)
+    }
+}
+
 // ============================================================================
 // Model Components
 // ============================================================================
@@ -99,6 +399,21 @@ pub struct Qwen2ModelConfig {
     pub rms_norm_eps: f64,
     pub rope_theta: f64,
     pub max_position_embeddings: usize,
+    /// Attention window of the layers from `max_window_layers` up
+    pub sliding_window: Option<usize>,
+    #[config(default = "0")]
+    pub max_window_layers: usize,
+    /// `hidden_size / num_attention_heads` when unset
+    pub head_dim: Option<usize>,
+    #[config(default = "false")]
+    pub qk_norm: bool,
+    #[config(default = "true")]
+    pub attention_bias: bool,
+    pub rope_scaling: Option<RopeScaling>,
+    /// Positions RoPE covers, `max_position_embeddings` when unset
+    pub context_length: Option<usize>,
+    /// Tile size of memory-efficient attention, eager attention when unset
+    pub attention_block_size: Option<usize>,
 }
 
 impl Qwen2ModelConfig {
@@ -106,7 +421,8 @@ impl Qwen2ModelConfig {
         let embed_tokens = EmbeddingConfig::new(self.vocab_size, self.hidden_size).init(device);
 
         let layers = (0..self.num_hidden_layers)
-            .map(|_| {
+            .map(|layer| {
+                let windowed = layer >= self.max_window_layers;
                 Qwen2DecoderLayerConfig::new(
                     self.hidden_size,
                     self.intermediate_size,
@@ -114,6 +430,11 @@ impl Qwen2ModelConfig {
                     self.num_key_value_heads,
                     self.rms_norm_eps,
                 )
+                .with_sliding_window(self.sliding_window.filter(|_| windowed))
+                .with_head_dim(self.head_dim)
+                .with_qk_norm(self.qk_norm)
+                .with_attention_bias(self.attention_bias)
+                .with_attention_block_size(self.attention_block_size)
                 .init(device)
             })
             .collect();
@@ -123,10 +444,17 @@ impl Qwen2ModelConfig {
             .init(device);
 
         // Initialize RoPE encoding once for all layers
-        let head_dim = self.hidden_size / self.num_attention_heads;
-        let rope = RotaryEncodingConfig::new(self.max_position_embeddings, head_dim)
-            .with_theta(self.rope_theta as f32)
-            .init(device);
+        let head_dim = self
+            .head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads);
+        let rope = rotary_encoding(
+            head_dim,
+            self.rope_theta,
+            self.max_position_embeddings,
+            self.context_length.unwrap_or(self.max_position_embeddings),
+            self.rope_scaling.as_ref(),
+            device,
+        );
 
         Qwen2Model {
             embed_tokens,
@@ -150,15 +478,36 @@ impl<B: Backend> Qwen2Model<B> {
     pub fn forward(
         &self,
         input_ids: Tensor<B, 2, Int>,
-        cache: &mut Vec<KeyValueCache<B>>,
-    ) -> Tensor<B, 3> {
+        cache: &mut [KeyValueCache<B>],
+    ) -> Result<Tensor<B, 3>> {
+        // Claim room in every layer up front so a full cache fails before any work
+        let [batch_size, seq_len] = input_ids.dims();
+        for kv_cache in cache.iter_mut() {
+            kv_cache.reserve(batch_size, seq_len)?;
+        }
+
         let mut hidden_states = self.embed_tokens.forward(input_ids);
 
         for (layer, kv_cache) in self.layers.iter().zip(cache.iter_mut()) {
-            hidden_states = layer.forward(hidden_states, kv_cache, &self.rope);
+            hidden_states = layer.forward(hidden_states, kv_cache, &self.rope)?;
         }
 
-        self.norm.forward(hidden_states)
+        Ok(self.norm.forward(hidden_states))
+    }
+
+    /// [`Self::forward`] over packed sequences, see [`Qwen2ForCausalLM::forward_packed`]
+    pub fn forward_packed(
+        &self,
+        input_ids: Tensor<B, 2, Int>,
+        document_ids: &Tensor<B, 2, Int>,
+        position_ids: &Tensor<B, 2, Int>,
+    ) -> Result<Tensor<B, 3>> {
+        let mut hidden_states = self.embed_tokens.forward(input_ids);
+        for layer in &self.layers {
+            hidden_states =
+                layer.forward_packed(hidden_states, document_ids, position_ids, &self.rope)?;
+        }
+        Ok(self.norm.forward(hidden_states))
     }
 }
 
@@ -170,6 +519,13 @@ pub struct Qwen2DecoderLayerConfig {
     pub num_attention_heads: usize,
     pub num_key_value_heads: usize,
     pub rms_norm_eps: f64,
+    pub sliding_window: Option<usize>,
+    pub head_dim: Option<usize>,
+    #[config(default = "false")]
+    pub qk_norm: bool,
+    #[config(default = "true")]
+    pub attention_bias: bool,
+    pub attention_block_size: Option<usize>,
 }
 
 impl Qwen2DecoderLayerConfig {
@@ -180,6 +536,11 @@ impl Qwen2DecoderLayerConfig {
             self.num_key_value_heads,
             self.rms_norm_eps,
         )
+        .with_sliding_window(self.sliding_window)
+        .with_head_dim(self.head_dim)
+        .with_qk_norm(self.qk_norm)
+        .with_attention_bias(self.attention_bias)
+        .with_attention_block_size(self.attention_block_size)
         .init(device);
 
         let mlp = Qwen2MLPConfig::new(self.hidden_size, self.intermediate_size).init(device);
@@ -216,18 +577,73 @@ impl<B: Backend> Qwen2DecoderLayer<B> {
         hidden_states: Tensor<B, 3>,
         cache: &mut KeyValueCache<B>,
         rope: &RotaryEncoding<B>,
-    ) -> Tensor<B, 3> {
+    ) -> Result<Tensor<B, 3>> {
+        self.forward_with(hidden_states, |hidden_states| {
+            self.self_attn.forward(hidden_states, cache, rope)
+        })
+    }
+
+    /// [`Self::forward`] over packed sequences, see [`Qwen2ForCausalLM::forward_packed`]
+    pub fn forward_packed(
+        &self,
+        hidden_states: Tensor<B, 3>,
+        document_ids: &Tensor<B, 2, Int>,
+        position_ids: &Tensor<B, 2, Int>,
+        rope: &RotaryEncoding<B>,
+    ) -> Result<Tensor<B, 3>> {
+        self.forward_with(hidden_states, |hidden_states| {
+            self.self_attn
+                .forward_packed(hidden_states, document_ids, position_ids, rope)
+        })
+    }
+
+    /// The block around `attend`, the self-attention of the normalized hidden states
+    fn forward_with(
+        &self,
+        hidden_states: Tensor<B, 3>,
+        attend: impl FnOnce(Tensor<B, 3>) -> Result<Tensor<B, 3>>,
+    ) -> Result<Tensor<B, 3>> {
         // Self-attention with residual connection
         let residual = hidden_states.clone();
         let hidden_states = self.input_layernorm.forward(hidden_states);
-        let hidden_states = self.self_attn.forward(hidden_states, cache, rope);
+        let hidden_states = attend(hidden_states)?;
         let hidden_states = residual + hidden_states;
 
         // Feed-forward with residual connection
         let residual = hidden_states.clone();
         let hidden_states = self.post_attention_layernorm.forward(hidden_states);
-        let hidden_states = self.mlp.forward(hidden_states);
-        residual + hidden_states
+        let hidden_states = self.mlp.forward(hidden_states)?;
+        Ok(residual + hidden_states)
+    }
+
+    /// The quantizable linear layers, keyed by their path within the layer
+    fn linears(&self) -> [(&'static str, &QuantLinear<B>); 7] {
+        let attn = &self.self_attn;
+        let mlp = &self.mlp;
+        [
+            ("self_attn.q_proj", &attn.q_proj),
+            ("self_attn.k_proj", &attn.k_proj),
+            ("self_attn.v_proj", &attn.v_proj),
+            ("self_attn.o_proj", &attn.o_proj),
+            ("mlp.swiglu.linear_inner", &mlp.swiglu.linear_inner),
+            ("mlp.swiglu.linear_outer", &mlp.swiglu.linear_outer),
+            ("mlp.down_proj", &mlp.down_proj),
+        ]
+    }
+
+    /// Mutable counterpart of [`Self::linears`]
+    fn linears_mut(&mut self) -> [(&'static str, &mut QuantLinear<B>); 7] {
+        let attn = &mut self.self_attn;
+        let mlp = &mut self.mlp;
+        [
+            ("self_attn.q_proj", &mut attn.q_proj),
+            ("self_attn.k_proj", &mut attn.k_proj),
+            ("self_attn.v_proj", &mut attn.v_proj),
+            ("self_attn.o_proj", &mut attn.o_proj),
+            ("mlp.swiglu.linear_inner", &mut mlp.swiglu.linear_inner),
+            ("mlp.swiglu.linear_outer", &mut mlp.swiglu.linear_outer),
+            ("mlp.down_proj", &mut mlp.down_proj),
+        ]
     }
 }
 
@@ -238,36 +654,55 @@ pub struct Qwen2AttentionConfig {
     pub num_attention_heads: usize,
     pub num_key_value_heads: usize,
     pub rms_norm_eps: f64,
+    /// Restrict each query to this many most recent tokens, itself included
+    pub sliding_window: Option<usize>,
+    /// `hidden_size / num_attention_heads` when unset
+    pub head_dim: Option<usize>,
+    /// RMS-normalise each query and key head (Qwen3)
+    #[config(default = "false")]
+    pub qk_norm: bool,
+    /// Bias on the query, key and value projections
+    #[config(default = "true")]
+    pub attention_bias: bool,
+    /// Tile size of memory-efficient attention, eager attention when unset
+    pub attention_block_size: Option<usize>,
 }
 
 impl Qwen2AttentionConfig {
     pub fn init<B: Backend>(&self, device: &B::Device) -> Qwen2Attention<B> {
-        let head_dim = self.hidden_size / self.num_attention_heads;
+        let head_dim = self
+            .head_dim
+            .unwrap_or(self.hidden_size / self.num_attention_heads);
 
         let q_proj = LinearConfig::new(self.hidden_size, self.num_attention_heads * head_dim)
-            .with_bias(true)
-            .init(device);
+            .with_bias(self.attention_bias)
+            .init(device)
+            .into();
 
         let k_proj = LinearConfig::new(self.hidden_size, self.num_key_value_heads * head_dim)
-            .with_bias(true)
-            .init(device);
+            .with_bias(self.attention_bias)
+            .init(device)
+            .into();
 
         let v_proj = LinearConfig::new(self.hidden_size, self.num_key_value_heads * head_dim)
-            .with_bias(true)
-            .init(device);
+            .with_bias(self.attention_bias)
+            .init(device)
+            .into();
 
         let o_proj = LinearConfig::new(self.num_attention_heads * head_dim, self.hidden_size)
             .with_bias(false)
-            .init(device);
-
-        // Q/K normalization for training stability (Qwen2.5-specific)
-        let q_norm = RmsNormConfig::new(head_dim)
-            .with_epsilon(self.rms_norm_eps)
-            .init(device);
-
-        let k_norm = RmsNormConfig::new(head_dim)
-            .with_epsilon(self.rms_norm_eps)
-            .init(device);
+            .init(device)
+            .into();
+
+        // Per-head Q/K normalization (Qwen3)
+        let qk_norm = || {
+            self.qk_norm.then(|| {
+                RmsNormConfig::new(head_dim)
+                    .with_epsilon(self.rms_norm_eps)
+                    .init(device)
+            })
+        };
+        let (q_norm, k_norm) = (qk_norm(), qk_norm());
 
         Qwen2Attention {
             q_proj,
@@ -279,22 +714,27 @@ impl Qwen2AttentionConfig {
             num_heads: self.num_attention_heads,
             num_key_value_heads: self.num_key_value_heads,
             head_dim,
+            sliding_window: self.sliding_window,
+            block_size: self.attention_block_size,
         }
     }
 }
 
-/// Qwen2 multi-head attention with Q/K normalization
+/// Qwen2 multi-head attention with grouped KV heads and optional Q/K normalization
 #[derive(Module, Debug)]
 pub struct Qwen2Attention<B: Backend> {
-    q_proj: Linear<B>,
-    k_proj: Linear<B>,
-    v_proj: Linear<B>,
-    o_proj: Linear<B>,
-    q_norm: RmsNorm<B>,
-    k_norm: RmsNorm<B>,
+    q_proj: QuantLinear<B>,
+    k_proj: QuantLinear<B>,
+    v_proj: QuantLinear<B>,
+    o_proj: QuantLinear<B>,
+    q_norm: Option<RmsNorm<B>>,
+    k_norm: Option<RmsNorm<B>>,
     num_heads: usize,
     num_key_value_heads: usize,
     head_dim: usize,
+    sliding_window: Option<usize>,
+    /// Tile size of [`tiled_attention`], eager attention when unset
+    block_size: Option<usize>,
 }
 
 impl<B: Backend> Qwen2Attention<B> {
@@ -303,37 +743,150 @@ impl<B: Backend> Qwen2Attention<B> {
         hidden_states: Tensor<B, 3>,
         cache: &mut KeyValueCache<B>,
         rope: &RotaryEncoding<B>,
-    ) -> Tensor<B, 3> {
+    ) -> Result<Tensor<B, 3>> {
         let device = hidden_states.device();
-        let [batch_size, seq_len, hidden_size] = hidden_states.dims();
+        let [batch_size, seq_len, _] = hidden_states.dims();
+
+        // Fail before RoPE, whose tables end at the cache capacity
+        cache.reserve(batch_size, seq_len)?;
+        let cache_seq_len = cache.len();
+
+        let [q, k, v] = self.project(hidden_states)?;
+
+        // Sequences of a continuous batch may sit at different positions
+        let ragged_lengths = cache
+            .sequence_lengths()
+            .filter(|lengths| lengths.iter().any(|&len| len != cache_seq_len))
+            .map(<[usize]>::to_vec);
+
+        // `past_len` is set when the queries are the causal tail of the keys
+        let (q, k, v, mask, past_len) = if let Some((sink_tokens, window)) = cache.rolling_window()
+        {
+            // Rolling buffers hold unrotated keys, which take their slot as position
+            let (k, v) = cache.forward(k, v)?;
+            let total_len = k.dims()[2];
+            let q = rope.apply(q, total_len - seq_len);
+            let k = rope.apply(k, 0);
+            let mask = attention_mask::<B>(seq_len, total_len, Some(window), sink_tokens, &device);
+            let past_len = Some(total_len - seq_len);
+            (q, k, v, mask.map(|mask| mask.unsqueeze::<4>()), past_len)
+        } else if let Some(past_lengths) = ragged_lengths {
+            let q = rotate_rows(rope, q, &past_lengths);
+            let k = rotate_rows(rope, k, &past_lengths);
+            let (k, v) = cache.forward(k, v)?;
+            let total_len = k.dims()[2];
+            let mask = ragged_attention_mask::<B>(
+                &past_lengths,
+                seq_len,
+                total_len,
+                self.sliding_window,
+                &device,
+            );
+            (q, k, v, Some(mask), None)
+        } else {
+            // Apply RoPE
+            let q = rope.apply(q, cache_seq_len);
+            let k = rope.apply(k, cache_seq_len);
+
+            // Update KV cache
+            let (k, v) = cache.forward(k, v)?;
+            let total_len = k.dims()[2];
+            let mask = attention_mask::<B>(seq_len, total_len, self.sliding_window, 0, &device);
+            let past_len = Some(cache_seq_len);
+            (q, k, v, mask.map(|mask| mask.unsqueeze::<4>()), past_len)
+        };
+
+        let attn_output = match self.block_size {
+            Some(block_size) => tiled_attention(q, k, v, mask, past_len, None, block_size),
+            None => self.eager_attention(q, k, v, mask),
+        };
+        let attn_output = attn_output.swap_dims(1, 2).reshape([
+            batch_size,
+            seq_len,
+            self.num_heads * self.head_dim,
+        ]);
+
+        self.o_proj.forward(attn_output)
+    }
+
+    /// Causal self-attention within each packed sample, see
+    /// [`Qwen2ForCausalLM::forward_packed`]
+    pub fn forward_packed(
+        &self,
+        hidden_states: Tensor<B, 3>,
+        document_ids: &Tensor<B, 2, Int>,
+        position_ids: &Tensor<B, 2, Int>,
+        rope: &RotaryEncoding<B>,
+    ) -> Result<Tensor<B, 3>> {
+        let device = hidden_states.device();
+        let [batch_size, seq_len, _] = hidden_states.dims();
+
+        let [q, k, v] = self.project(hidden_states)?;
+        let q = rotate_positions(rope, q, position_ids.clone());
+        let k = rotate_positions(rope, k, position_ids.clone());
+
+        // Sample boundaries are masked tile by tile, or all at once by the eager path
+        let causal = attention_mask::<B>(seq_len, seq_len, self.sliding_window, 0, &device)
+            .map(|mask| mask.unsqueeze::<4>());
+        let attn_output = match self.block_size {
+            Some(block_size) => {
+                let document_ids = Some(document_ids.clone());
+                tiled_attention(q, k, v, causal, Some(0), document_ids, block_size)
+            }
+            None => {
+                let mask = document_mask(document_ids.clone(), document_ids.clone());
+                let mask = match causal {
+                    Some(causal) => mask.bool_or(causal.expand([batch_size, 1, seq_len, seq_len])),
+                    None => mask,
+                };
+                self.eager_attention(q, k, v, Some(mask))
+            }
+        };
+        let attn_output = attn_output.swap_dims(1, 2).reshape([
+            batch_size,
+            seq_len,
+            self.num_heads * self.head_dim,
+        ]);
+
+        self.o_proj.forward(attn_output)
+    }
+
+    /// Project to normalized Q, K and V [batch, heads, seq, head_dim]
+    fn project(&self, hidden_states: Tensor<B, 3>) -> Result<[Tensor<B, 4>; 3]> {
+        let [batch_size, seq_len, _] = hidden_states.dims();
 
         // Project to Q, K, V
-        let q = self.q_proj.forward(hidden_states.clone());
-        let k = self.k_proj.forward(hidden_states.clone());
-        let v = self.v_proj.forward(hidden_states);
+        let q = self.q_proj.forward(hidden_states.clone())?;
+        let k = self.k_proj.forward(hidden_states.clone())?;
+        let v = self.v_proj.forward(hidden_states)?;
 
         // Reshape to [batch, seq, num_heads, head_dim]
         let q = q.reshape([batch_size, seq_len, self.num_heads, self.head_dim]);
         let k = k.reshape([batch_size, seq_len, self.num_key_value_heads, self.head_dim]);
         let v = v.reshape([batch_size, seq_len, self.num_key_value_heads, self.head_dim]);
 
-        // Apply Q/K normalization (Qwen2.5-specific for training stability)
-        let q = self.q_norm.forward(q);
-        let k = self.k_norm.forward(k);
+        // Normalize each query and key head (Qwen3)
+        let q = match &self.q_norm {
+            Some(norm) => norm.forward(q),
+            None => q,
+        };
+        let k = match &self.k_norm {
+            Some(norm) => norm.forward(k),
+            None => k,
+        };
 
         // Swap to [batch, num_heads, seq, head_dim]
-        let q = q.swap_dims(1, 2);
-        let k = k.swap_dims(1, 2);
-        let v = v.swap_dims(1, 2);
-
-        // Apply RoPE
-        let cache_seq_len = cache.len();
-        let q = rope.apply(q, cache_seq_len);
-        let k = rope.apply(k, cache_seq_len);
-
-        // Update KV cache
-        let (k, v) = cache.forward(k, v);
+        Ok([q.swap_dims(1, 2), k.swap_dims(1, 2), v.swap_dims(1, 2)])
+    }
 
+    /// Attention over the full score matrix, with K/V heads repeated for GQA
+    fn eager_attention(
+        &self,
+        q: Tensor<B, 4>,
+        k: Tensor<B, 4>,
+        v: Tensor<B, 4>,
+        mask: Option<Tensor<B, 4, Bool>>,
+    ) -> Tensor<B, 4> {
         // Repeat K/V heads for GQA (if num_kv_heads < num_heads)
         let k = self.repeat_kv(k);
         let v = self.repeat_kv(v);
@@ -343,26 +896,15 @@ impl<B: Backend> Qwen2Attention<B> {
             .matmul(k.swap_dims(2, 3))
             .div_scalar((self.head_dim as f32).sqrt());
 
-        // Apply causal mask for sequences longer than 1
-        if seq_len > 1 {
-            let cache_seq_len = cache.len();
-            let mask = Tensor::<B, 2, Bool>::tril_mask(
-                [seq_len, cache_seq_len],
-                (cache_seq_len - seq_len) as i64,
-                &device,
-            );
-            scores = scores.mask_fill(mask.unsqueeze::<4>(), f32::NEG_INFINITY);
+        // Apply causal and sliding-window masks
+        if let Some(mask) = mask {
+            scores = scores.mask_fill(mask, f32::NEG_INFINITY);
         }
 
         let attn_weights = softmax(scores, 3);
 
         // Apply attention to values
-        let attn_output = attn_weights.matmul(v);
-        let attn_output = attn_output
-            .swap_dims(1, 2)
-            .reshape([batch_size, seq_len, hidden_size]);
-
-        self.o_proj.forward(attn_output)
+        attn_weights.matmul(v)
     }
 
     /// Repeat key/value heads for grouped query attention
@@ -379,6 +921,116 @@ impl<B: Backend> Qwen2Attention<B> {
     }
 }
 
+/// Mask for `seq_len` queries over `total_len` keys, the queries being the last
+/// `seq_len` of them; `true` marks keys a query may not attend to
+///
+/// Keys at least `window` positions behind a query are masked too, except for the
+/// first `sink_tokens` keys. Returns `None` when nothing needs masking.
+fn attention_mask<B: Backend>(
+    seq_len: usize,
+    total_len: usize,
+    window: Option<usize>,
+    sink_tokens: usize,
+    device: &B::Device,
+) -> Option<Tensor<B, 2, Bool>> {
+    let past_len = (total_len - seq_len) as i64;
+    let causal = (seq_len > 1)
+        .then(|| Tensor::<B, 2, Bool>::tril_mask([seq_len, total_len], past_len, device));
+
+    // Only a buffer longer than the window holds stale keys
+    let Some(window) = window.filter(|&window| window < total_len) else {
+        return causal;
+    };
+    let mut stale =
+        Tensor::<B, 2, Bool>::triu_mask([seq_len, total_len], past_len - window as i64 + 1, device);
+    if sink_tokens > 0 {
+        let not_sink = Tensor::<B, 1, Int>::arange(0..total_len as i64, device)
+            .greater_equal_elem(sink_tokens as i64)
+            .unsqueeze::<2>()
+            .expand([seq_len, total_len]);
+        stale = stale.bool_and(not_sink);
+    }
+
+    Some(match causal {
+        Some(causal) => causal.bool_or(stale),
+        None => stale,
+    })
+}
+
+/// [`attention_mask`] for a batch whose sequences have `past_lengths` cached tokens
+///
+/// Returns a [batch, 1, seq_len, total_len] mask that also hides the padding after
+/// shorter sequences.
+fn ragged_attention_mask<B: Backend>(
+    past_lengths: &[usize],
+    seq_len: usize,
+    total_len: usize,
+    window: Option<usize>,
+    device: &B::Device,
+) -> Tensor<B, 4, Bool> {
+    let mut mask = Vec::with_capacity(past_lengths.len() * seq_len * total_len);
+    for &past_len in past_lengths {
+        for query in past_len..past_len + seq_len {
+            mask.extend(
+                (0..total_len)
+                    .map(|key| key > query || window.is_some_and(|window| query - key >= window)),
+            );
+        }
+    }
+    Tensor::from_bool(
+        TensorData::new(mask, [past_lengths.len(), 1, seq_len, total_len]),
+        device,
+    )
+}
+
+/// Apply RoPE to each row of a [batch, num_heads, seq_len, head_dim] tensor, starting
+/// at that row's position
+fn rotate_rows<B: Backend>(
+    rope: &RotaryEncoding<B>,
+    x: Tensor<B, 4>,
+    start_positions: &[usize],
+) -> Tensor<B, 4> {
+    let [_, num_heads, seq_len, head_dim] = x.dims();
+    let rows = start_positions
+        .iter()
+        .enumerate()
+        .map(|(row, &start)| {
+            let x = x
+                .clone()
+                .slice([row..row + 1, 0..num_heads, 0..seq_len, 0..head_dim]);
+            rope.apply(x, start)
+        })
+        .collect();
+    Tensor::cat(rows, 0)
+}
+
+/// Apply RoPE to a [batch, num_heads, seq_len, head_dim] tensor, rotating every token
+/// by its entry of `position_ids` [batch, seq_len]
+fn rotate_positions<B: Backend>(
+    rope: &RotaryEncoding<B>,
+    x: Tensor<B, 4>,
+    position_ids: Tensor<B, 2, Int>,
+) -> Tensor<B, 4> {
+    let [batch_size, num_heads, seq_len, head_dim] = x.dims();
+    let device = x.device();
+
+    // `RotaryEncoding::apply` with the table rows gathered by position
+    let sign = Tensor::<B, 2>::from_floats([[1.0, 0.0, 0.0, 1.0], [0.0, -1.0, 1.0, 0.0]], &device);
+    let frequencies = rope
+        .freq_complex
+        .clone()
+        .select(0, position_ids.reshape([batch_size * seq_len]))
+        .reshape([batch_size, 1, seq_len, head_dim, 2]);
+    let rotated = x
+        .reshape([batch_size * num_heads * seq_len, head_dim / 2, 2])
+        .matmul(sign.unsqueeze())
+        .reshape([batch_size, num_heads, seq_len, head_dim, 2])
+        * frequencies;
+    rotated
+        .sum_dim(4)
+        .reshape([batch_size, num_heads, seq_len, head_dim])
+}
+
 /// Configuration for Qwen2 MLP
 #[derive(Config, Debug)]
 pub struct Qwen2MLPConfig {
@@ -388,14 +1040,12 @@ pub struct Qwen2MLPConfig {
 
 impl Qwen2MLPConfig {
     pub fn init<B: Backend>(&self, device: &B::Device) -> Qwen2MLP<B> {
-        // Use Burn's built-in SwiGLU
-        let swiglu = SwiGluConfig::new(self.hidden_size, self.intermediate_size)
-            .with_bias(false)
-            .init(device);
+        let swiglu = QuantSwiGlu::new(self.hidden_size, self.intermediate_size, device);
 
         let down_proj = LinearConfig::new(self.intermediate_size, self.hidden_size)
             .with_bias(false)
-            .init(device);
+            .init(device)
+            .into();
 
         Qwen2MLP { swiglu, down_proj }
     }
@@ -404,20 +1054,59 @@ impl Qwen2MLPConfig {
 /// Qwen2 MLP with SwiGLU activation
 #[derive(Module, Debug)]
 pub struct Qwen2MLP<B: Backend> {
-    swiglu: SwiGlu<B>,
-    down_proj: Linear<B>,
+    swiglu: QuantSwiGlu<B>,
+    down_proj: QuantLinear<B>,
 }
 
 impl<B: Backend> Qwen2MLP<B> {
-    pub fn forward(&self, hidden_states: Tensor<B, 3>) -> Tensor<B, 3> {
-        self.down_proj.forward(self.swiglu.forward(hidden_states))
+    pub fn forward(&self, hidden_states: Tensor<B, 3>) -> Result<Tensor<B, 3>> {
+        self.down_proj.forward(self.swiglu.forward(hidden_states)?)
     }
 }
 
 /// Key-value cache for autoregressive generation
 pub struct KeyValueCache<B: Backend> {
-    key: AutoregressiveCache<B>,
-    value: AutoregressiveCache<B>,
+    storage: KvStorage<B>,
+}
+
+enum KvStorage<B: Backend> {
+    Contiguous {
+        key: AutoregressiveCache<B>,
+        value: AutoregressiveCache<B>,
+    },
+    Paged(PagedKvCache<B>),
+    Rolling(RollingKvCache<B>),
+}
+
+impl<B: Backend> KvStorage<B> {
+    fn fork(&self) -> Self {
+        match self {
+            KvStorage::Contiguous { key, value } => KvStorage::Contiguous {
+                key: key.fork(),
+                value: value.fork(),
+            },
+            KvStorage::Paged(cache) => KvStorage::Paged(cache.fork()),
+            KvStorage::Rolling(cache) => KvStorage::Rolling(cache.fork()),
+        }
+    }
+}
+
+/// State of a [`KeyValueCache`] saved by [`KeyValueCache::snapshot`]
+pub struct KvSnapshot<B: Backend> {
+    storage: KvStorage<B>,
+}
+
+/// How [`Qwen2ForCausalLM::init_cache`] lays out the KV cache
+#[derive(Clone, Default)]
+pub enum CacheLayout<B: Backend> {
+    /// One buffer per layer sized for [`Qwen2Config::context_length`]
+    #[default]
+    Contiguous,
+    /// Blocks taken on demand from a shared, bounded pool
+    Paged(PagedKvPool<B>),
+    /// A rolling buffer of `sink_tokens` attention sinks plus a recent window, which
+    /// never overflows; every layer attends to `window` tokens besides the sinks
+    Rolling { sink_tokens: usize, window: usize },
 }
 
 impl<B: Backend> KeyValueCache<B> {
@@ -430,14 +1119,96 @@ impl<B: Backend> KeyValueCache<B> {
         device: &B::Device,
     ) -> Self {
         Self {
-            key: AutoregressiveCache::new(max_batch_size, num_heads, max_seq_len, head_dim, device),
-            value: AutoregressiveCache::new(
-                max_batch_size,
-                num_heads,
-                max_seq_len,
-                head_dim,
-                device,
-            ),
+            storage: KvStorage::Contiguous {
+                key: AutoregressiveCache::new(
+                    max_batch_size,
+                    num_heads,
+                    max_seq_len,
+                    head_dim,
+                    device,
+                ),
+                value: AutoregressiveCache::new(
+                    max_batch_size,
+                    num_heads,
+                    max_seq_len,
+                    head_dim,
+                    device,
+                ),
+            },
+        }
+    }
+
+    /// Create a paged cache for `layer` backed by `pool`
+    pub fn paged(pool: &PagedKvPool<B>, layer: usize, max_batch_size: usize) -> Self {
+        Self {
+            storage: KvStorage::Paged(pool.cache(layer, max_batch_size)),
+        }
+    }
+
+    /// Create a rolling-buffer cache bounded by `max_seq_len` positions
+    pub fn rolling(sink_tokens: usize, window: usize, max_seq_len: usize) -> Self {
+        Self {
+            storage: KvStorage::Rolling(RollingKvCache::new(sink_tokens, window, max_seq_len)),
+        }
+    }
+
+    /// Cached length of every sequence, for caches whose sequences may differ in length
+    pub fn sequence_lengths(&self) -> Option<&[usize]> {
+        match &self.storage {
+            KvStorage::Paged(cache) => Some(cache.sequence_lengths()),
+            _ => None,
+        }
+    }
+
+    /// Move the sequences of `other` to the end of this batch
+    ///
+    /// Both caches must be paged caches of the same pool and layer; no blocks are copied.
+    pub(crate) fn append(&mut self, other: KeyValueCache<B>) {
+        match (&mut self.storage, other.storage) {
+            (KvStorage::Paged(cache), KvStorage::Paged(other)) => cache.append(other),
+            _ => panic!("only paged caches can exchange sequences"),
+        }
+    }
+
+    /// Drop sequence `row` from the batch, releasing its memory
+    pub(crate) fn remove_sequence(&mut self, row: usize) {
+        match &mut self.storage {
+            KvStorage::Paged(cache) => cache.remove(row),
+            _ => panic!("only paged caches can drop single sequences"),
+        }
+    }
+
+    /// `(sink_tokens, window)` of a rolling-buffer cache
+    pub fn rolling_window(&self) -> Option<(usize, usize)> {
+        match &self.storage {
+            KvStorage::Rolling(cache) => Some((cache.sink_tokens(), cache.window())),
+            _ => None,
+        }
+    }
+
+    /// Whether the stored keys are already rotated by RoPE
+    ///
+    /// Rolling buffers keep unrotated keys and rotate them by slot on every step, so
+    /// their contents cannot be moved to another layout or the other way round.
+    pub fn rotated_keys(&self) -> bool {
+        !matches!(self.storage, KvStorage::Rolling(_))
+    }
+
+    /// Make room for `new_tokens` more positions without writing anything
+    pub fn reserve(&mut self, batch_size: usize, new_tokens: usize) -> Result<()> {
+        match &mut self.storage {
+            KvStorage::Contiguous { key, .. } => {
+                let requested = key.len() + new_tokens;
+                if requested > key.capacity() {
+                    return Err(ModelError::CacheOverflow {
+                        requested,
+                        capacity: key.capacity(),
+                    });
+                }
+                Ok(())
+            }
+            KvStorage::Paged(cache) => cache.reserve(batch_size, new_tokens),
+            KvStorage::Rolling(cache) => cache.reserve(new_tokens),
         }
     }
 
@@ -446,22 +1217,126 @@ impl<B: Backend> KeyValueCache<B> {
         &mut self,
         key: Tensor<B, 4>,
         value: Tensor<B, 4>,
-    ) -> (Tensor<B, 4>, Tensor<B, 4>) {
-        let k = self.key.forward(key);
-        let v = self.value.forward(value);
-        (k, v)
+    ) -> Result<(Tensor<B, 4>, Tensor<B, 4>)> {
+        match &mut self.storage {
+            KvStorage::Contiguous {
+                key: key_cache,
+                value: value_cache,
+            } => {
+                let k = key_cache.forward(key)?;
+                let v = value_cache.forward(value)?;
+                Ok((k, v))
+            }
+            KvStorage::Paged(cache) => cache.forward(key, value),
+            KvStorage::Rolling(cache) => cache.forward(key, value),
+        }
     }
 
     /// Get the current cached sequence length
     pub fn len(&self) -> usize {
-        self.key.len()
+        match &self.storage {
+            KvStorage::Contiguous { key, .. } => key.len(),
+            KvStorage::Paged(cache) => cache.len(),
+            KvStorage::Rolling(cache) => cache.len(),
+        }
+    }
+
+    /// Whether nothing has been cached yet
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cached keys and values [batch, num_heads, len, head_dim], if any
+    pub fn tensors(&self) -> Option<(Tensor<B, 4>, Tensor<B, 4>)> {
+        match &self.storage {
+            KvStorage::Contiguous { key, value } => Some((key.tensor()?, value.tensor()?)),
+            KvStorage::Paged(cache) => cache.tensors(),
+            KvStorage::Rolling(cache) => cache.tensors(),
+        }
+    }
+
+    /// Maximum number of positions the cache can hold
+    pub fn capacity(&self) -> usize {
+        match &self.storage {
+            KvStorage::Contiguous { key, .. } => key.capacity(),
+            KvStorage::Paged(cache) => cache.capacity(),
+            KvStorage::Rolling(cache) => cache.capacity(),
+        }
+    }
+
+    /// Keep only the first `len` positions, e.g. to roll back rejected draft tokens
+    ///
+    /// Paged caches cut every sequence down to at most `len` and return the freed
+    /// blocks to the pool; rolling caches keep the first `len` slots of their buffer.
+    pub fn truncate(&mut self, len: usize) {
+        match &mut self.storage {
+            KvStorage::Contiguous { key, value } => {
+                key.truncate(len);
+                value.truncate(len);
+            }
+            KvStorage::Paged(cache) => cache.truncate(len),
+            KvStorage::Rolling(cache) => cache.truncate(len),
+        }
+    }
+
+    /// An independent copy of the cache, e.g. for one branch of a beam search
+    ///
+    /// Nothing is copied up front: the two caches share memory until either one
+    /// writes to it, and a paged fork draws its private blocks from the same pool.
+    pub fn fork(&self) -> Self {
+        Self {
+            storage: self.storage.fork(),
+        }
+    }
+
+    /// Rebuild the batch from the sequences at `rows`, in order
+    ///
+    /// Repeating a row forks its sequence, e.g. to expand one prefilled prompt into
+    /// several continuations or to follow the surviving beams of a beam search; rows
+    /// left out are dropped. Paged caches share the blocks copy-on-write, the other
+    /// layouts copy the selected rows.
+    pub fn fork_sequences(&mut self, rows: &[usize]) {
+        match &mut self.storage {
+            KvStorage::Contiguous { key, value } => {
+                key.fork_sequences(rows);
+                value.fork_sequences(rows);
+            }
+            KvStorage::Paged(cache) => cache.fork_sequences(rows),
+            KvStorage::Rolling(cache) => cache.fork_sequences(rows),
+        }
+    }
+
+    /// Capture the cached positions so [`Self::restore`] can return to them
+    ///
+    /// A snapshot of a paged cache holds on to its blocks until it is dropped.
+    pub fn snapshot(&self) -> KvSnapshot<B> {
+        KvSnapshot {
+            storage: self.storage.fork(),
+        }
+    }
+
+    /// Return to the state captured by `snapshot`, which stays usable
+    ///
+    /// # Panics
+    /// If the snapshot was taken from a cache of another layout.
+    pub fn restore(&mut self, snapshot: &KvSnapshot<B>) {
+        assert!(
+            std::mem::discriminant(&self.storage) == std::mem::discriminant(&snapshot.storage),
+            "snapshot of a cache of another layout"
+        );
+        self.storage = snapshot.storage.fork();
     }
 
     /// Reset the cache (for new prompts)
-    #[allow(dead_code)]
     pub fn reset(&mut self) {
-        self.key.reset();
-        self.value.reset();
+        match &mut self.storage {
+            KvStorage::Contiguous { key, value } => {
+                key.reset();
+                value.reset();
+            }
+            KvStorage::Paged(cache) => cache.reset(),
+            KvStorage::Rolling(cache) => cache.reset(),
+        }
     }
 }
 
@@ -469,22 +1344,112 @@ impl<B: Backend> KeyValueCache<B> {
 // Top-level Model
 // ============================================================================
 
+/// Prompt tokens per forward pass in [`Qwen2ForCausalLM::prefill`] and streaming
+/// generation
+pub const DEFAULT_PREFILL_CHUNK_SIZE: usize = 512;
+
 /// Qwen2 model for causal language modeling
 #[derive(Module, Debug)]
 pub struct Qwen2ForCausalLM<B: Backend> {
     model: Qwen2Model<B>,
-    lm_head: Linear<B>,
+    /// `None` when the head is tied to the token embeddings
+    lm_head: Option<Linear<B>>,
 }
 
 impl<B: Backend> Qwen2ForCausalLM<B> {
     /// Forward pass for next-token prediction
+    ///
+    /// Returns [`ModelError::CacheOverflow`] if the input does not fit in `cache`; the
+    /// cache is left unchanged in that case.
     pub fn forward(
         &self,
         input_ids: Tensor<B, 2, Int>,
-        cache: &mut Vec<KeyValueCache<B>>,
-    ) -> Tensor<B, 3> {
-        let hidden_states = self.model.forward(input_ids, cache);
-        self.lm_head.forward(hidden_states)
+        cache: &mut [KeyValueCache<B>],
+    ) -> Result<Tensor<B, 3>> {
+        let hidden_states = self.model.forward(input_ids, cache)?;
+        Ok(self.project_vocab(hidden_states))
+    }
+
+    /// Forward pass over packed training sequences, as batched by
+    /// [`PackedBatcher`](crate::data::PackedBatcher), without a cache
+    ///
+    /// Tokens attend causally within their own sample (`document_ids` [batch_size,
+    /// seq_len]) and are rotated by their position in it (`position_ids`), so each
+    /// sample's logits match a forward pass over that sample alone.
+    pub fn forward_packed(
+        &self,
+        input_ids: Tensor<B, 2, Int>,
+        document_ids: &Tensor<B, 2, Int>,
+        position_ids: &Tensor<B, 2, Int>,
+    ) -> Result<Tensor<B, 3>> {
+        let hidden_states = self
+            .model
+            .forward_packed(input_ids, document_ids, position_ids)?;
+        Ok(self.project_vocab(hidden_states))
+    }
+
+    /// Forward pass returning only the logits of the last position [batch_size, vocab_size]
+    ///
+    /// Skips the vocabulary projection of every other position, which dominates the
+    /// memory of a long prompt.
+    pub fn forward_last(
+        &self,
+        input_ids: Tensor<B, 2, Int>,
+        cache: &mut [KeyValueCache<B>],
+    ) -> Result<Tensor<B, 2>> {
+        let hidden_states = self.model.forward(input_ids, cache)?;
+        let [batch_size, seq_len, hidden_size] = hidden_states.dims();
+        let last = hidden_states.slice([0..batch_size, seq_len - 1..seq_len, 0..hidden_size]);
+        Ok(self.project_vocab(last).squeeze_dim(1))
+    }
+
+    /// Feed a prompt through `cache` in chunks of at most `chunk_size` tokens
+    ///
+    /// Each chunk attends causally to the cache filled by the previous ones, so the
+    /// result matches a single [`Self::forward_last`] while the activations of only one
+    /// chunk are alive at a time. `on_chunk` receives the number of prompt tokens
+    /// processed after every chunk. Returns the logits of the last prompt position
+    /// [batch_size, vocab_size]; as with `forward`, a prompt that does not fit leaves the
+    /// cache unchanged (rolling caches accept prompts of any length).
+    pub fn prefill(
+        &self,
+        input_ids: Tensor<B, 2, Int>,
+        cache: &mut [KeyValueCache<B>],
+        chunk_size: usize,
+        mut on_chunk: impl FnMut(usize),
+    ) -> Result<Tensor<B, 2>> {
+        let [batch_size, seq_len] = input_ids.dims();
+        if chunk_size == 0 {
+            return Err(ModelError::InvalidConfig(
+                "prefill chunk size must be positive".to_string(),
+            ));
+        }
+        // Rolling buffers take any number of chunks; other caches must hold the prompt
+        for kv_cache in cache.iter_mut() {
+            if kv_cache.rolling_window().is_none() {
+                kv_cache.reserve(batch_size, seq_len)?;
+            }
+        }
+
+        let mut logits = None;
+        for start in (0..seq_len).step_by(chunk_size) {
+            let end = (start + chunk_size).min(seq_len);
+            let chunk = input_ids.clone().slice([0..batch_size, start..end]);
+            logits = Some(self.forward_last(chunk, cache)?);
+            on_chunk(end);
+        }
+        logits.ok_or_else(|| ModelError::InvalidInput("empty prompt".to_string()))
+    }
+
+    /// Project hidden states onto the vocabulary
+    fn project_vocab<const D: usize>(&self, hidden_states: Tensor<B, D>) -> Tensor<B, D> {
+        match &self.lm_head {
+            Some(lm_head) => lm_head.forward(hidden_states),
+            None => {
+                let embedding = self.model.embed_tokens.weight.val();
+                linear(hidden_states, embedding.transpose(), None)
+            }
+        }
     }
 
     /// Initialize KV cache for autoregressive generation
@@ -492,19 +1457,669 @@ impl<B: Backend> Qwen2ForCausalLM<B> {
         &self,
         config: &Qwen2Config,
         max_batch_size: usize,
+        layout: &CacheLayout<B>,
         device: &B::Device,
     ) -> Vec<KeyValueCache<B>> {
-        let head_dim = config.hidden_size / config.num_attention_heads;
-        (0..config.num_hidden_layers)
-            .map(|_| {
-                KeyValueCache::new(
-                    max_batch_size,
-                    config.num_key_value_heads,
-                    config.max_position_embeddings,
-                    head_dim,
-                    device,
-                )
+        crate::inference::init_cache(config, max_batch_size, layout, device)
+    }
+
+    /// Quantize the attention projections and MLP weights of every layer
+    ///
+    /// See [`crate::quant`]; the embeddings, norms and LM head stay in full precision.
+    pub fn quantize(self, config: &QuantConfig) -> Self {
+        self.quantize_where(config, |_| true)
+    }
+
+    /// Weight path and quantized weight of every quantized linear layer
+    pub(crate) fn quantized_weights(&self) -> Vec<(String, &QuantizedWeight<B>)> {
+        self.model
+            .layers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, layer)| {
+                layer
+                    .linears()
+                    .into_iter()
+                    .filter_map(move |(name, linear)| {
+                        let weight = linear.quantized()?;
+                        Some((format!("model.layers.{index}.{name}.weight"), weight))
+                    })
             })
             .collect()
     }
+
+    /// Quantize the linear layers whose weight path (e.g.
+    /// `model.layers.0.self_attn.q_proj.weight`) satisfies `filter`
+    pub(crate) fn quantize_where(
+        mut self,
+        config: &QuantConfig,
+        filter: impl Fn(&str) -> bool,
+    ) -> Self {
+        for (index, layer) in self.model.layers.iter_mut().enumerate() {
+            for (name, linear) in layer.linears_mut() {
+                if filter(&format!("model.layers.{index}.{name}.weight")) {
+                    linear.quantize(config);
+                }
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rope::RopeScalingKind;
+
+    const QWEN25_0_5B_CONFIG: &str = r#"{
+        "architectures": ["Qwen2ForCausalLM"],
+        "attention_dropout": 0.0,
+        "bos_token_id": 151643,
+        "eos_token_id": 151645,
+        "hidden_act": "silu",
+        "hidden_size": 896,
+        "initializer_range": 0.02,
+        "intermediate_size": 4864,
+        "max_position_embeddings": 32768,
+        "max_window_layers": 21,
+        "model_type": "qwen2",
+        "num_attention_heads": 14,
+        "num_hidden_layers": 24,
+        "num_key_value_heads": 2,
+        "rms_norm_eps": 1e-06,
+        "rope_theta": 1000000.0,
+        "sliding_window": 32768,
+        "tie_word_embeddings": true,
+        "torch_dtype": "bfloat16",
+        "use_cache": true,
+        "use_sliding_window": false,
+        "vocab_size": 151936
+    }"#;
+
+    #[test]
+    fn test_parse_hf_config() {
+        let config = Qwen2Config::from_hf_config_str(QWEN25_0_5B_CONFIG).unwrap();
+
+        assert_eq!(config.hidden_size, 896);
+        assert_eq!(config.num_hidden_layers, 24);
+        assert_eq!(config.num_attention_heads, 14);
+        assert_eq!(config.num_key_value_heads, 2);
+        assert_eq!(config.rope_theta, 1000000.0);
+        assert_eq!(config.eos_token_id, 151645);
+        assert!(config.tie_word_embeddings);
+        assert_eq!(config.sliding_window, Some(32768));
+        assert_eq!(config.max_window_layers, Some(21));
+    }
+
+    #[test]
+    fn test_parse_hf_config_rejects_unsupported_fields() {
+        let json = QWEN25_0_5B_CONFIG.replace("\"silu\"", "\"gelu\"");
+        let err = Qwen2Config::from_hf_config_str(&json).unwrap_err();
+        assert!(matches!(
+            err,
+            ModelError::UnsupportedConfig {
+                field: "hidden_act",
+                ..
+            }
+        ));
+
+        let json = QWEN25_0_5B_CONFIG
+            .replace("\"sliding_window\": 32768", "\"sliding_window\": null")
+            .replace(
+                "\"use_sliding_window\": false",
+                "\"use_sliding_window\": true",
+            );
+        let err = Qwen2Config::from_hf_config_str(&json).unwrap_err();
+        assert!(matches!(err, ModelError::InvalidConfig(_)));
+    }
+
+    #[test]
+    fn test_parse_hf_config_rope_scaling() {
+        let yarn = r#""rope_scaling": {
+            "factor": 4.0,
+            "original_max_position_embeddings": 32768,
+            "type": "yarn"
+        },"#;
+        let json = QWEN25_0_5B_CONFIG.replace("\"rope_theta\"", &format!("{yarn} \"rope_theta\""));
+        let config = Qwen2Config::from_hf_config_str(&json).unwrap();
+        let scaling = config.rope_scaling.as_ref().expect("rope scaling");
+        assert_eq!(scaling.kind, RopeScalingKind::Yarn);
+        assert_eq!(config.context_length(), 131072);
+        let round_trip = Qwen2Config::from_hf_config_str(&config.to_hf_config_json("float32"));
+        assert_eq!(round_trip.unwrap().rope_scaling, config.rope_scaling);
+        assert_eq!(
+            config.with_max_context_length(Some(65536)).context_length(),
+            65536
+        );
+
+        let json = json.replace("\"yarn\"", "\"longrope\"");
+        let err = Qwen2Config::from_hf_config_str(&json).unwrap_err();
+        assert!(matches!(
+            err,
+            ModelError::UnsupportedConfig {
+                field: "rope_scaling",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_hf_config_sliding_window() {
+        let json = QWEN25_0_5B_CONFIG.replace(
+            "\"use_sliding_window\": false",
+            "\"use_sliding_window\": true",
+        );
+        let config = Qwen2Config::from_hf_config_str(&json).unwrap();
+        assert!(config.use_sliding_window);
+        assert_eq!(config.sliding_window, Some(32768));
+    }
+
+    #[test]
+    fn test_parse_hf_config_variants() {
+        let config = Qwen2Config::from_hf_config_str(QWEN25_0_5B_CONFIG).unwrap();
+        assert!(!config.qk_norm);
+        assert!(config.attention_bias);
+        assert_eq!(config.head_dim(), 64);
+
+        // Qwen3: QK-norm, no projection biases, heads wider than hidden / num_heads
+        let qwen3 = QWEN25_0_5B_CONFIG
+            .replace("\"qwen2\"", "\"qwen3\"")
+            .replace("\"vocab_size\"", "\"head_dim\": 128, \"vocab_size\"");
+        let config = Qwen2Config::from_hf_config_str(&qwen3).unwrap();
+        assert!(config.qk_norm);
+        assert!(!config.attention_bias);
+        assert_eq!(config.head_dim(), 128);
+        let round_trip = Qwen2Config::from_hf_config_str(&config.to_hf_config_json("bfloat16"));
+        assert_eq!(round_trip.unwrap().head_dim, Some(128));
+
+        let json = qwen3.replace("\"vocab_size\"", "\"attention_bias\": true, \"vocab_size\"");
+        let err = Qwen2Config::from_hf_config_str(&json).unwrap_err();
+        assert!(matches!(
+            err,
+            ModelError::UnsupportedConfig {
+                field: "attention_bias",
+                ..
+            }
+        ));
+    }
+
+    /// Plain f32 implementation of the HuggingFace Qwen2/Qwen3 forward pass over
+    /// checkpoint-layout `weights`, independent of the Burn modules and weight loader
+    fn reference_logits(
+        config: &Qwen2Config,
+        weights: &std::collections::HashMap<String, Vec<f32>>,
+        tokens: &[usize],
+    ) -> Vec<f32> {
+        let w = |name: &str| weights[name].as_slice();
+        let (h, hd) = (config.hidden_size, config.head_dim());
+        let (nh, nkv) = (config.num_attention_heads, config.num_key_value_heads);
+        let eps = config.rms_norm_eps as f32;
+
+        // x W^T + b for a `[out, in]` weight
+        let project = |x: &[f32], weight: &[f32], bias: Option<&[f32]>| -> Vec<f32> {
+            weight
+                .chunks(x.len())
+                .enumerate()
+                .map(|(o, row)| {
+                    let dot: f32 = x.iter().zip(row).map(|(a, b)| a * b).sum();
+                    dot + bias.map_or(0.0, |bias| bias[o])
+                })
+                .collect()
+        };
+        let rms_norm = |x: &[f32], weight: &[f32]| -> Vec<f32> {
+            let rms = (x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32 + eps).sqrt();
+            x.iter().zip(weight).map(|(v, w)| v / rms * w).collect()
+        };
+        // "Rotate half" RoPE of one head at `pos`
+        let rope = |x: &mut [f32], pos: usize| {
+            let half = hd / 2;
+            for i in 0..half {
+                let freq = (config.rope_theta as f32).powf(-((2 * i) as f32) / hd as f32);
+                let (sin, cos) = (pos as f32 * freq).sin_cos();
+                let (a, b) = (x[i], x[i + half]);
+                x[i] = a * cos - b * sin;
+                x[i + half] = b * cos + a * sin;
+            }
+        };
+
+        let embed = w("model.embed_tokens.weight");
+        let mut x: Vec<Vec<f32>> = tokens
+            .iter()
+            .map(|&t| embed[t * h..(t + 1) * h].to_vec())
+            .collect();
+        for layer in 0..config.num_hidden_layers {
+            let p = |name: &str| format!("model.layers.{layer}.{name}");
+            let optional = |name: &str| weights.get(&p(name)).map(Vec::as_slice);
+
+            let (mut qs, mut ks, mut vs) = (Vec::new(), Vec::new(), Vec::new());
+            for (pos, x) in x.iter().enumerate() {
+                let hidden = rms_norm(x, w(&p("input_layernorm.weight")));
+                let mut q = project(
+                    &hidden,
+                    w(&p("self_attn.q_proj.weight")),
+                    optional("self_attn.q_proj.bias"),
+                );
+                let mut k = project(
+                    &hidden,
+                    w(&p("self_attn.k_proj.weight")),
+                    optional("self_attn.k_proj.bias"),
+                );
+                let v = project(
+                    &hidden,
+                    w(&p("self_attn.v_proj.weight")),
+                    optional("self_attn.v_proj.bias"),
+                );
+                let norms = [
+                    (&mut q, "self_attn.q_norm.weight"),
+                    (&mut k, "self_attn.k_norm.weight"),
+                ];
+                for (x, norm) in norms {
+                    for head in x.chunks_mut(hd) {
+                        if let Some(norm) = optional(norm) {
+                            let normed = rms_norm(head, norm);
+                            head.copy_from_slice(&normed);
+                        }
+                        rope(head, pos);
+                    }
+                }
+                qs.push(q);
+                ks.push(k);
+                vs.push(v);
+            }
+
+            for pos in 0..x.len() {
+                let mut attn = vec![0.0; nh * hd];
+                for head in 0..nh {
+                    let kv = head / (nh / nkv) * hd;
+                    let q = &qs[pos][head * hd..(head + 1) * hd];
+                    let scores: Vec<f32> = (0..=pos)
+                        .map(|j| {
+                            let k = &ks[j][kv..kv + hd];
+                            let dot: f32 = q.iter().zip(k).map(|(a, b)| a * b).sum();
+                            dot / (hd as f32).sqrt()
+                        })
+                        .collect();
+                    let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    let exp: Vec<f32> = scores.iter().map(|s| (s - max).exp()).collect();
+                    let total: f32 = exp.iter().sum();
+                    for (j, e) in exp.iter().enumerate() {
+                        for d in 0..hd {
+                            attn[head * hd + d] += e / total * vs[j][kv + d];
+                        }
+                    }
+                }
+                let out = project(&attn, w(&p("self_attn.o_proj.weight")), None);
+                x[pos].iter_mut().zip(out).for_each(|(x, o)| *x += o);
+
+                let hidden = rms_norm(&x[pos], w(&p("post_attention_layernorm.weight")));
+                let gate = project(&hidden, w(&p("mlp.gate_proj.weight")), None);
+                let up = project(&hidden, w(&p("mlp.up_proj.weight")), None);
+                let act: Vec<f32> = gate
+                    .iter()
+                    .zip(&up)
+                    .map(|(g, u)| g / (1.0 + (-g).exp()) * u)
+                    .collect();
+                let down = project(&act, w(&p("mlp.down_proj.weight")), None);
+                x[pos].iter_mut().zip(down).for_each(|(x, d)| *x += d);
+            }
+        }
+
+        let head = weights
+            .get("lm_head.weight")
+            .unwrap_or(&weights["model.embed_tokens.weight"]);
+        x.iter()
+            .flat_map(|x| project(&rms_norm(x, w("model.norm.weight")), head, None))
+            .collect()
+    }
+
+    /// Checks the weight layout and forward pass against [`reference_logits`], a second
+    /// implementation in this crate; [`test_matches_transformers_logits`] compares with
+    /// `transformers` itself
+    #[test]
+    fn test_architecture_variants_match_reference() {
+        use crate::weights::hf_tensor_shapes;
+        use crate::weights::tests::{values, write_shard};
+        type Backend = burn::backend::NdArray<f32>;
+
+        let qwen2 = Qwen2Config::tiny();
+        let qwen3 = Qwen2Config::tiny()
+            .with_qk_norm(true)
+            .with_attention_bias(false)
+            .with_head_dim(Some(8))
+            .with_tie_word_embeddings(true);
+        let tokens = [3, 1, 4, 1, 5, 9, 2, 6];
+        let device = Default::default();
+
+        for config in [qwen2, qwen3] {
+            let dir = tempfile::tempdir().unwrap();
+            let tensors = hf_tensor_shapes(&config);
+            write_shard(&dir.path().join(crate::weights::SAFETENSORS_FILE), &tensors);
+            let weights = tensors
+                .iter()
+                .enumerate()
+                .map(|(seed, (name, shape))| (name.clone(), values(shape, seed)))
+                .collect();
+            let expected = reference_logits(&config, &weights, &tokens);
+
+            let model =
+                crate::inference::load_model_with_config::<Backend>(&config, dir.path(), &device)
+                    .unwrap();
+            let ids: Vec<u32> = tokens.iter().map(|&t| t as u32).collect();
+            let input = crate::inference::token_column::<Backend>(&ids, &device).swap_dims(0, 1);
+            let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+            let logits = model.forward(input, &mut cache).unwrap();
+
+            let expected = TensorData::new(expected, [1, tokens.len(), config.vocab_size]);
+            logits
+                .into_data()
+                .assert_approx_eq::<f32>(&expected, burn::tensor::Tolerance::rel_abs(1e-3, 1e-3));
+        }
+    }
+
+    /// Logits `transformers` computed for a checkpoint of `fixtures/hf`
+    #[derive(serde::Deserialize)]
+    struct HfLogits {
+        tokens: Vec<u32>,
+        /// [seq_len, vocab_size]
+        logits: Vec<Vec<f32>>,
+    }
+
+    /// Tiny random checkpoints written by `fixtures/hf/generate.py`: `qwen2` has biases
+    /// and an untied head, `qwen3` QK-norm, no biases, a `head_dim` override and a tied
+    /// head
+    #[test]
+    #[ignore = "needs the checkpoints and logits written by fixtures/hf/generate.py"]
+    fn test_matches_transformers_logits() {
+        type Backend = burn::backend::NdArray<f32>;
+        let device = Default::default();
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/hf");
+
+        for name in ["qwen2", "qwen3"] {
+            let dir = fixtures.join(name);
+            let config = Qwen2Config::from_hf_config_json(dir.join("config.json")).unwrap();
+            assert_eq!(config.qk_norm, name == "qwen3");
+            let model = crate::inference::load_model_with_config::<Backend>(&config, &dir, &device)
+                .unwrap();
+            let json = std::fs::read_to_string(dir.join("logits.json")).unwrap();
+            let expected: HfLogits = serde_json::from_str(&json).unwrap();
+
+            let input = crate::inference::token_column::<Backend>(&expected.tokens, &device);
+            let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+            let logits = model.forward(input.swap_dims(0, 1), &mut cache).unwrap();
+
+            let shape = [1, expected.tokens.len(), config.vocab_size];
+            let expected = TensorData::new(expected.logits.concat(), shape);
+            logits
+                .into_data()
+                .assert_approx_eq::<f32>(&expected, burn::tensor::Tolerance::rel_abs(1e-4, 1e-4));
+        }
+    }
+
+    #[test]
+    fn test_attention_mask() {
+        type Backend = burn::backend::NdArray<f32>;
+        let mask = |seq_len, total_len, window, sink_tokens| {
+            attention_mask::<Backend>(seq_len, total_len, window, sink_tokens, &Default::default())
+                .map(|mask| mask.into_data().to_vec::<bool>().unwrap())
+        };
+
+        // One query needs no causal mask
+        assert_eq!(mask(1, 4, None, 0), None);
+        assert_eq!(mask(1, 4, Some(4), 0), None);
+        // Window of 2: the query sees itself and its predecessor
+        assert_eq!(mask(1, 4, Some(2), 0), Some(vec![true, true, false, false]));
+        // ... plus the sink
+        assert_eq!(
+            mask(1, 4, Some(2), 1),
+            Some(vec![false, true, false, false])
+        );
+        #[rustfmt::skip]
+        assert_eq!(
+            mask(2, 4, Some(2), 0),
+            Some(vec![
+                true, false, false, true,
+                true, true, false, false,
+            ])
+        );
+    }
+
+    #[test]
+    fn test_sliding_window_decoding_matches_full_forward() {
+        type Backend = burn::backend::NdArray<f32>;
+        let device = Default::default();
+        let config = Qwen2Config::tiny()
+            .with_use_sliding_window(true)
+            .with_sliding_window(Some(3))
+            .with_max_window_layers(Some(1));
+        let model = config.init::<Backend>(&device);
+        let input = Tensor::<Backend, 1, Int>::from_ints([3, 14, 15, 9, 26, 5, 35], &device)
+            .unsqueeze::<2>();
+        let seq_len = input.dims()[1];
+
+        let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+        let full = model.forward(input.clone(), &mut cache).unwrap();
+
+        let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+        let steps = (0..seq_len)
+            .map(|pos| {
+                let token = input.clone().slice([0..1, pos..pos + 1]);
+                model.forward(token, &mut cache).unwrap()
+            })
+            .collect();
+        Tensor::cat(steps, 1)
+            .into_data()
+            .assert_approx_eq::<f32>(&full.clone().into_data(), Default::default());
+
+        // The window changes the logits once the sequence outgrows it
+        let unwindowed = Qwen2Config::tiny()
+            .init::<Backend>(&device)
+            .load_record(model.into_record());
+        let mut cache = unwindowed.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+        let reference = unwindowed.forward(input, &mut cache).unwrap();
+        let diff = (full - reference).abs().max().into_scalar();
+        assert!(diff > 1e-4);
+    }
+
+    #[test]
+    fn test_scaled_rope_extends_context() {
+        type Backend = burn::backend::NdArray<f32>;
+        let device = Default::default();
+        let tokens: Vec<i64> = (0..24).map(|i| (i * 7 + 3) % 64).collect();
+        let input =
+            Tensor::<Backend, 1, Int>::from_ints(tokens.as_slice(), &device).unsqueeze::<2>();
+        let trained = Qwen2Config {
+            max_position_embeddings: 16,
+            ..Qwen2Config::tiny()
+        };
+
+        // Without scaling the cache ends at the trained context
+        let model = trained.init::<Backend>(&device);
+        let mut cache = model.init_cache(&trained, 1, &CacheLayout::Contiguous, &device);
+        assert!(model.forward(input.clone(), &mut cache).is_err());
+
+        for kind in [
+            RopeScalingKind::Linear,
+            RopeScalingKind::DynamicNtk,
+            RopeScalingKind::Yarn,
+        ] {
+            let config = trained
+                .clone()
+                .with_rope_scaling(Some(RopeScaling::new(kind, 2.0)));
+            assert_eq!(config.context_length(), 32);
+            let model = config.init::<Backend>(&device);
+
+            let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+            let full = model.forward(input.clone(), &mut cache).unwrap();
+
+            // Prefill then decode: positions past the trained context match the full pass
+            let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+            let mut steps = vec![model.forward(input.clone().slice([0..1, 0..20]), &mut cache)];
+            for pos in 20..tokens.len() {
+                let token = input.clone().slice([0..1, pos..pos + 1]);
+                steps.push(model.forward(token, &mut cache));
+            }
+            let steps = steps.into_iter().collect::<Result<Vec<_>>>().unwrap();
+            Tensor::cat(steps, 1)
+                .into_data()
+                .assert_approx_eq::<f32>(&full.into_data(), Default::default());
+        }
+    }
+
+    #[test]
+    fn test_tiled_attention_matches_eager_forward() {
+        type Backend = burn::backend::NdArray<f32>;
+        let device = Default::default();
+        let eager_config = Qwen2Config::tiny()
+            .with_use_sliding_window(true)
+            .with_sliding_window(Some(5))
+            .with_max_window_layers(Some(1));
+        let eager = eager_config.init::<Backend>(&device);
+        let tiled_config = eager_config.clone().with_attention_block_size(Some(3));
+        let tiled = tiled_config
+            .init::<Backend>(&device)
+            .load_record(eager.clone().into_record());
+        let tokens = [3, 14, 15, 9, 26, 5, 35, 8, 9, 7];
+        let input = Tensor::<Backend, 1, Int>::from_ints(tokens, &device).unsqueeze::<2>();
+
+        let layouts = [
+            CacheLayout::Contiguous,
+            CacheLayout::Rolling {
+                sink_tokens: 1,
+                window: 4,
+            },
+        ];
+        for layout in layouts {
+            // A long prefill followed by single-token decoding steps
+            let run = |model: &Qwen2ForCausalLM<Backend>, config: &Qwen2Config| {
+                let mut cache = model.init_cache(config, 1, &layout, &device);
+                let mut steps = vec![model.forward(input.clone().slice([0..1, 0..7]), &mut cache)];
+                for pos in 7..tokens.len() {
+                    let token = input.clone().slice([0..1, pos..pos + 1]);
+                    steps.push(model.forward(token, &mut cache));
+                }
+                let steps = steps.into_iter().collect::<Result<Vec<_>>>().unwrap();
+                Tensor::cat(steps, 1).into_data()
+            };
+            run(&tiled, &tiled_config).assert_approx_eq::<f32>(
+                &run(&eager, &eager_config),
+                burn::tensor::Tolerance::absolute(1e-5),
+            );
+        }
+    }
+
+    #[test]
+    fn test_packed_forward_matches_separate_samples() {
+        use crate::data::TokenizedSample;
+        use crate::data::{PackedBatch, PackedBatcher, PackedDataset, PackingConfig};
+        use burn::data::dataloader::batcher::Batcher;
+        type Backend = burn::backend::NdArray<f32>;
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let eager = config.init::<Backend>(&device);
+        let tiled_config = config.clone().with_attention_block_size(Some(3));
+        let tiled = tiled_config
+            .init::<Backend>(&device)
+            .load_record(eager.clone().into_record());
+
+        // Two samples and padding in the first sequence, one sample in the second
+        let samples: [&[u32]; 3] = [&[3, 14, 15, 9, 26], &[5, 35, 8], &[9, 7, 9, 3, 2, 38, 4]];
+        let samples = samples.map(|ids| TokenizedSample {
+            input_ids: ids.to_vec(),
+            loss_mask: vec![true; ids.len()],
+        });
+        let packing = PackingConfig::new(12).with_pad_token_id(0);
+        let dataset = PackedDataset::pack(samples.clone(), &packing);
+        assert_eq!(dataset.sequences().len(), 2);
+        let batch: PackedBatch<Backend> =
+            PackedBatcher.batch(dataset.sequences().to_vec(), &device);
+
+        for (model, config) in [(&eager, &config), (&tiled, &tiled_config)] {
+            let packed = model
+                .forward_packed(
+                    batch.input_ids.clone(),
+                    &batch.document_ids,
+                    &batch.position_ids,
+                )
+                .unwrap();
+            let [_, _, vocab_size] = packed.dims();
+            // (row, first position, sample)
+            let placements = [
+                (0, 0, &samples[0]),
+                (0, 5, &samples[1]),
+                (1, 0, &samples[2]),
+            ];
+            for (row, start, sample) in placements {
+                let tokens = &sample.input_ids;
+                let input = crate::inference::token_column::<Backend>(tokens, &device);
+                let input = input.swap_dims(0, 1);
+                let mut cache = model.init_cache(config, 1, &CacheLayout::Contiguous, &device);
+                let alone = model.forward(input, &mut cache).unwrap();
+                let end = start + tokens.len();
+                packed
+                    .clone()
+                    .slice([row..row + 1, start..end, 0..vocab_size])
+                    .into_data()
+                    .assert_approx_eq::<f32>(
+                        &alone.into_data(),
+                        burn::tensor::Tolerance::absolute(1e-5),
+                    );
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunked_prefill_matches_forward() {
+        type Backend = burn::backend::NdArray<f32>;
+        let device = Default::default();
+        let config = Qwen2Config::tiny().with_attention_block_size(Some(2));
+        let model = config.init::<Backend>(&device);
+        let tokens = [3, 14, 15, 9, 26, 5, 35];
+        let input = Tensor::<Backend, 1, Int>::from_ints(tokens, &device).unsqueeze::<2>();
+
+        let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+        let full = model.forward(input.clone(), &mut cache).unwrap();
+        let expected = full
+            .slice([0..1, 6..7, 0..config.vocab_size])
+            .squeeze_dim::<2>(1);
+
+        for chunk_size in [1, 3, 7, 64] {
+            let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+            let mut progress = Vec::new();
+            let logits = model
+                .prefill(input.clone(), &mut cache, chunk_size, |done| {
+                    progress.push(done)
+                })
+                .unwrap();
+            logits
+                .into_data()
+                .assert_approx_eq::<f32>(&expected.to_data(), Default::default());
+            assert_eq!(progress.last(), Some(&tokens.len()));
+            assert_eq!(progress.len(), tokens.len().div_ceil(chunk_size));
+            assert_eq!(cache[0].len(), tokens.len());
+        }
+
+        let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+        let empty = Tensor::<Backend, 2, Int>::empty([1, 0], &device);
+        let err = model.prefill(empty, &mut cache, 2, |_| {}).unwrap_err();
+        assert!(matches!(err, ModelError::InvalidInput(_)));
+
+        // A prompt that cannot fit fails before touching the cache
+        let short = Qwen2Config::tiny().with_max_context_length(Some(5));
+        let mut cache = model.init_cache(&short, 1, &CacheLayout::Contiguous, &device);
+        let err = model
+            .prefill(input.clone(), &mut cache, 2, |_| {})
+            .unwrap_err();
+        assert!(matches!(err, ModelError::CacheOverflow { .. }));
+        assert_eq!(cache[0].len(), 0);
+
+        // Rolling buffers take prompts longer than they can hold at once
+        let rolling = CacheLayout::Rolling {
+            sink_tokens: 1,
+            window: 2,
+        };
+        let mut cache = model.init_cache(&short, 1, &rolling, &device);
+        assert!(model.forward(input.clone(), &mut cache).is_err());
+        let mut cache = model.init_cache(&short, 1, &rolling, &device);
+        model.prefill(input, &mut cache, 2, |_| {}).unwrap();
+    }
 }
@@ -0,0 +1,387 @@
+//! KV-cache reuse across requests that share a prompt prefix
+//!
+//! The agent loop resends the same system prompt and repository context on every turn.
+//! [`PrefixCache`] keeps the keys and values computed for earlier token sequences, keyed
+//! by a blake3 hash of the token prefix, so a new request only runs the model over the
+//! tokens past the longest prefix already seen. Entries are evicted least recently used
+//! first once their total size exceeds the memory budget.
+
+use std::collections::HashMap;
+
+use burn::tensor::{Tensor, backend::Backend};
+
+use crate::error::Result;
+use crate::model::KeyValueCache;
+
+/// Prefix lengths are indexed at multiples of this many tokens by default
+const DEFAULT_GRANULARITY: usize = 16;
+
+/// LRU store of KV-cache snapshots, looked up by token prefix
+pub struct PrefixCache<B: Backend> {
+    entries: HashMap<u64, Entry<B>>,
+    /// Hash of a token prefix -> entries starting with that prefix
+    index: HashMap<blake3::Hash, Vec<u64>>,
+    granularity: usize,
+    budget_bytes: usize,
+    used_bytes: usize,
+    next_id: u64,
+    clock: u64,
+}
+
+struct Entry<B: Backend> {
+    tokens: Vec<u32>,
+    /// Keys and values of every layer [1, num_heads, tokens.len(), head_dim]
+    layers: Vec<(Tensor<B, 4>, Tensor<B, 4>)>,
+    /// Whether the keys are rotated (see [`KeyValueCache::rotated_keys`]); entries only
+    /// restore into caches storing keys the same way
+    rotated_keys: bool,
+    /// Index keys pointing at this entry
+    hashes: Vec<blake3::Hash>,
+    bytes: usize,
+    last_used: u64,
+}
+
+impl<B: Backend> PrefixCache<B> {
+    /// Create an empty cache holding at most `budget_bytes` of keys and values
+    pub fn new(budget_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            index: HashMap::new(),
+            granularity: DEFAULT_GRANULARITY,
+            budget_bytes,
+            used_bytes: 0,
+            next_id: 0,
+            clock: 0,
+        }
+    }
+
+    /// Index stored sequences at multiples of `granularity` tokens
+    ///
+    /// A request can resume from any indexed length of a stored sequence, so a smaller
+    /// granularity reuses more of a partially matching entry at a small hashing cost.
+    pub fn with_granularity(mut self, granularity: usize) -> Self {
+        self.granularity = granularity.max(1);
+        self
+    }
+
+    /// Number of stored sequences
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Bytes of keys and values currently stored
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes
+    }
+
+    pub fn budget_bytes(&self) -> usize {
+        self.budget_bytes
+    }
+
+    /// Length of the longest stored prefix of `tokens`, leaving at least one token
+    /// for the model to process, whichever cache layout it was stored from
+    pub fn longest_prefix(&self, tokens: &[u32]) -> usize {
+        self.find(tokens, None).map_or(0, |(_, len)| len)
+    }
+
+    /// Fill an empty single-sequence `cache` with the longest stored prefix of `tokens`
+    ///
+    /// Returns how many tokens were restored; the caller feeds the model the rest.
+    /// Entries stored from a cache keeping its keys differently (rolling buffers versus
+    /// the other layouts) are skipped.
+    pub fn restore(&mut self, tokens: &[u32], cache: &mut [KeyValueCache<B>]) -> Result<usize> {
+        debug_assert!(cache.iter().all(KeyValueCache::is_empty));
+        let rotated_keys = cache.iter().all(KeyValueCache::rotated_keys);
+        let Some((id, len)) = self.find(tokens, Some(rotated_keys)) else {
+            return Ok(0);
+        };
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(&id).expect("indexed entry");
+        entry.last_used = self.clock;
+
+        for (layer, (key, value)) in cache.iter_mut().zip(&entry.layers) {
+            let [batch, num_heads, _, head_dim] = key.dims();
+            let range = [0..batch, 0..num_heads, 0..len, 0..head_dim];
+            layer.forward(key.clone().slice(range.clone()), value.clone().slice(range))?;
+        }
+        Ok(len)
+    }
+
+    /// Store the state of a single-sequence `cache` that has processed exactly `tokens`
+    ///
+    /// Stored sequences that `tokens` extends are replaced by the new entry.
+    pub fn insert(&mut self, tokens: &[u32], cache: &[KeyValueCache<B>]) {
+        if tokens.is_empty() || cache.iter().any(|layer| layer.len() != tokens.len()) {
+            return;
+        }
+        let Some(layers) = cache
+            .iter()
+            .map(KeyValueCache::tensors)
+            .collect::<Option<Vec<_>>>()
+        else {
+            return;
+        };
+        if layers.iter().any(|(key, _)| key.dims()[0] != 1) {
+            return;
+        }
+        let rotated_keys = cache.iter().all(KeyValueCache::rotated_keys);
+
+        let bytes = layers
+            .iter()
+            .map(|(key, value)| {
+                (key.shape().num_elements() + value.shape().num_elements())
+                    * std::mem::size_of::<B::FloatElem>()
+            })
+            .sum();
+        if bytes > self.budget_bytes {
+            return;
+        }
+
+        // Replace entries covering a prefix of (or exactly) this sequence, keeping
+        // their lengths resumable
+        let mut lengths: Vec<usize> = (self.granularity..tokens.len())
+            .step_by(self.granularity)
+            .chain([tokens.len()])
+            .collect();
+        let superseded: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| {
+                entry.rotated_keys == rotated_keys && tokens.starts_with(&entry.tokens)
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in superseded {
+            lengths.push(self.entries[&id].tokens.len());
+            self.remove(id);
+        }
+        lengths.sort_unstable();
+        lengths.dedup();
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.clock += 1;
+
+        let hashes: Vec<blake3::Hash> = prefix_hashes(tokens)
+            .filter(|(len, _)| lengths.binary_search(len).is_ok())
+            .map(|(_, hash)| hash)
+            .collect();
+        for hash in &hashes {
+            self.index.entry(*hash).or_default().push(id);
+        }
+
+        self.used_bytes += bytes;
+        self.entries.insert(
+            id,
+            Entry {
+                tokens: tokens.to_vec(),
+                layers,
+                rotated_keys,
+                hashes,
+                bytes,
+                last_used: self.clock,
+            },
+        );
+        self.evict();
+    }
+
+    /// Drop every stored sequence
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+        self.used_bytes = 0;
+    }
+
+    /// Entry and length of the longest stored prefix of `tokens[..tokens.len() - 1]`,
+    /// among the entries whose keys are rotated as `rotated_keys` says if given
+    fn find(&self, tokens: &[u32], rotated_keys: Option<bool>) -> Option<(u64, usize)> {
+        let limit = tokens.len().saturating_sub(1);
+        let matches = |entry: &Entry<B>, len: usize| {
+            entry.tokens.starts_with(&tokens[..len])
+                && rotated_keys.is_none_or(|rotated_keys| entry.rotated_keys == rotated_keys)
+        };
+        prefix_hashes(&tokens[..limit])
+            .filter_map(|(len, hash)| {
+                // Most recently used of the entries that really match
+                let id = self.index.get(&hash)?.iter().copied().max_by_key(|id| {
+                    let entry = &self.entries[id];
+                    (matches(entry, len), entry.last_used)
+                })?;
+                matches(&self.entries[&id], len).then_some((id, len))
+            })
+            .last()
+    }
+
+    fn remove(&mut self, id: u64) {
+        let Some(entry) = self.entries.remove(&id) else {
+            return;
+        };
+        self.used_bytes -= entry.bytes;
+        for hash in entry.hashes {
+            if let Some(ids) = self.index.get_mut(&hash) {
+                ids.retain(|&other| other != id);
+                if ids.is_empty() {
+                    self.index.remove(&hash);
+                }
+            }
+        }
+    }
+
+    /// Evict least recently used entries until the budget is met
+    fn evict(&mut self) {
+        while self.used_bytes > self.budget_bytes {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(&id, _)| id)
+            else {
+                break;
+            };
+            let len = self.entries[&oldest].tokens.len();
+            tracing::debug!("evicting cached prefix of {len} tokens");
+            self.remove(oldest);
+        }
+    }
+}
+
+/// `(n, hash of tokens[..n])` for every `n` in `1..=tokens.len()`
+fn prefix_hashes(tokens: &[u32]) -> impl Iterator<Item = (usize, blake3::Hash)> + '_ {
+    let mut hasher = blake3::Hasher::new();
+    tokens.iter().enumerate().map(move |(i, token)| {
+        hasher.update(&token.to_le_bytes());
+        (i + 1, hasher.finalize())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::{generate_stream, token_column};
+    use crate::model::{CacheLayout, Qwen2Config};
+    use crate::sampling::SamplingConfig;
+    use crate::stopping::StopConditions;
+    use burn::backend::NdArray;
+
+    type Backend = NdArray<f32>;
+
+    fn prompt(tokens: &[u32]) -> Tensor<Backend, 2, burn::tensor::Int> {
+        token_column::<Backend>(tokens, &Default::default()).swap_dims(0, 1)
+    }
+
+    #[test]
+    fn test_resumed_generation_matches_uncached() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let sampling = SamplingConfig::greedy();
+        let stop = StopConditions::new();
+        let mut prefix_cache = PrefixCache::new(1 << 20).with_granularity(4);
+
+        let run = |tokens: &[u32], max_new_tokens| {
+            let input = prompt(tokens);
+            generate_stream(
+                &model,
+                &config,
+                input,
+                max_new_tokens,
+                &sampling,
+                &stop,
+                &device,
+            )
+        };
+
+        // First turn fills the prefix cache
+        let first_turn = [5, 9, 13, 2, 7, 30, 11];
+        let mut stream = run(&first_turn, 4).with_prefix_cache(&mut prefix_cache);
+        stream.by_ref().for_each(drop);
+        stream.save_prefix(&mut prefix_cache);
+        let mut history = first_turn.to_vec();
+        history.extend(stream.generated(0));
+        assert_eq!(prefix_cache.len(), 1);
+
+        // The second turn extends the whole first conversation
+        let mut second_turn = history.clone();
+        second_turn.extend([40, 41, 42]);
+        assert_eq!(prefix_cache.longest_prefix(&second_turn), history.len() - 1);
+
+        let cached = run(&second_turn, 5)
+            .with_prefix_cache(&mut prefix_cache)
+            .into_output();
+        let uncached = run(&second_turn, 5).into_output();
+        assert_eq!(
+            crate::inference::token_rows(cached.tokens),
+            crate::inference::token_rows(uncached.tokens)
+        );
+    }
+
+    #[test]
+    fn test_partial_match_and_lru_budget() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+
+        let fill = |tokens: &[u32]| {
+            let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+            model.forward(prompt(tokens), &mut cache).unwrap();
+            cache
+        };
+
+        let a = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let b = [20, 21, 22, 23, 24, 25, 26, 27, 28, 29];
+        let mut prefix_cache = PrefixCache::new(usize::MAX).with_granularity(4);
+        prefix_cache.insert(&a, &fill(&a));
+        let entry_bytes = prefix_cache.used_bytes();
+
+        // Diverges after 6 tokens: resumes from the last indexed length before that
+        assert_eq!(prefix_cache.longest_prefix(&[1, 2, 3, 4, 5, 6, 0, 0]), 4);
+        assert_eq!(prefix_cache.longest_prefix(&[2, 3]), 0);
+
+        let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+        let restored = prefix_cache
+            .restore(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11], &mut cache)
+            .unwrap();
+        assert_eq!(restored, 10);
+        assert_eq!(cache[0].len(), 10);
+
+        // Rolling buffers hold unrotated keys: neither kind restores into the other
+        let rolling = CacheLayout::Rolling {
+            sink_tokens: 1,
+            window: 32,
+        };
+        let mut rolling_cache = model.init_cache(&config, 1, &rolling, &device);
+        assert_eq!(
+            prefix_cache
+                .restore(&[1, 2, 3, 4, 5, 6], &mut rolling_cache)
+                .unwrap(),
+            0
+        );
+        assert!(rolling_cache[0].is_empty());
+        model.forward(prompt(&b), &mut rolling_cache).unwrap();
+        prefix_cache.insert(&b, &rolling_cache);
+        assert_eq!(prefix_cache.len(), 2);
+        let mut cache = model.init_cache(&config, 1, &CacheLayout::Contiguous, &device);
+        assert_eq!(
+            prefix_cache
+                .restore(&[20, 21, 22, 23, 24, 25], &mut cache)
+                .unwrap(),
+            0
+        );
+        let mut rolling_cache = model.init_cache(&config, 1, &rolling, &device);
+        let restored = prefix_cache.restore(&[20, 21, 22, 23, 24, 25], &mut rolling_cache);
+        assert_eq!(restored.unwrap(), 4);
+
+        // Room for a single entry: inserting `b` evicts `a`
+        let mut prefix_cache = PrefixCache::new(entry_bytes).with_granularity(4);
+        prefix_cache.insert(&a, &fill(&a));
+        prefix_cache.insert(&b, &fill(&b));
+        assert_eq!(prefix_cache.len(), 1);
+        assert_eq!(prefix_cache.longest_prefix(&[1, 2, 3, 4, 5]), 0);
+        assert_eq!(prefix_cache.longest_prefix(&[20, 21, 22, 23, 24]), 4);
+    }
+}
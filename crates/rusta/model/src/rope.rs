@@ -0,0 +1,423 @@
+//! Scaled rotary position embeddings for long contexts
+//!
+//! Qwen2.5 checkpoints are trained with a 32K RoPE and extended to 128K tokens by
+//! rescaling the rotary frequencies (the `rope_scaling` block of `config.json`). Burn's
+//! [`RotaryEncoding`] only knows a single `theta`, so [`rotary_encoding`] computes the
+//! cos/sin table itself whenever a [`RopeScaling`] is configured and installs it in an
+//! otherwise standard encoding.
+
+use burn::{
+    config::Config,
+    nn::{RotaryEncoding, RotaryEncodingConfig},
+    tensor::{Tensor, TensorData, backend::Backend},
+};
+
+use crate::error::{ModelError, Result};
+
+/// How rotary frequencies are adjusted past the trained context
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum RopeScalingKind {
+    /// Position interpolation: every frequency divided by `factor`
+    Linear,
+    /// NTK-aware base growth once a sequence outgrows the trained context
+    ///
+    /// Intentionally differs from HuggingFace on prefills longer than the trained
+    /// context: `transformers` rotates every position of a forward pass with the base of
+    /// the pass's total length, so a prompt's rotations depend on how it is chunked. Here
+    /// position `p` always uses the base of a `p + 1` token sequence, which is what HF
+    /// computes when decoding that position on its own. Cached keys never need rotating
+    /// again and one-shot, chunked and token-by-token prefill agree; a long prefill's
+    /// logits match HF's at its last position only.
+    DynamicNtk,
+    /// YaRN: interpolate low frequencies, keep high ones, and sharpen attention
+    Yarn,
+}
+
+/// RoPE scaling parameters, mirroring HuggingFace's `rope_scaling`
+#[derive(Config, Debug, PartialEq)]
+pub struct RopeScaling {
+    pub kind: RopeScalingKind,
+    /// Ratio of the extended context to the trained one
+    pub factor: f64,
+    /// Context the checkpoint was trained with (`max_position_embeddings` when unset)
+    pub original_max_position_embeddings: Option<usize>,
+    /// YaRN: rotations per trained context above which frequencies are left untouched
+    #[config(default = "32.0")]
+    pub beta_fast: f64,
+    /// YaRN: rotations per trained context below which frequencies are fully interpolated
+    #[config(default = "1.0")]
+    pub beta_slow: f64,
+    /// YaRN: scale applied to queries and keys (`0.1 ln(factor) + 1` when unset)
+    pub attention_factor: Option<f64>,
+}
+
+impl RopeScaling {
+    /// Parse a HuggingFace `rope_scaling` object (`None` for `null` or the default type)
+    pub fn from_hf_value(value: &serde_json::Value) -> Result<Option<Self>> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        let unsupported = |reason: String| ModelError::UnsupportedConfig {
+            field: "rope_scaling",
+            reason,
+        };
+        // Older configs spell the key "type"
+        let kind = value
+            .get("rope_type")
+            .or_else(|| value.get("type"))
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| unsupported("missing rope_type".to_string()))?;
+        let kind = match kind {
+            "default" => return Ok(None),
+            "linear" => RopeScalingKind::Linear,
+            "dynamic" => RopeScalingKind::DynamicNtk,
+            "yarn" => RopeScalingKind::Yarn,
+            other => {
+                return Err(unsupported(format!(
+                    "expected \"linear\", \"dynamic\" or \"yarn\", found {other:?}"
+                )));
+            }
+        };
+        let float = |key: &str| value.get(key).and_then(serde_json::Value::as_f64);
+        let factor = float("factor").ok_or_else(|| unsupported("missing factor".to_string()))?;
+        if factor < 1.0 {
+            return Err(unsupported(format!(
+                "factor must be at least 1, found {factor}"
+            )));
+        }
+
+        let mut scaling = Self::new(kind, factor)
+            .with_original_max_position_embeddings(
+                value
+                    .get("original_max_position_embeddings")
+                    .and_then(serde_json::Value::as_u64)
+                    .map(|len| len as usize),
+            )
+            .with_attention_factor(float("attention_factor"));
+        if let Some(beta_fast) = float("beta_fast") {
+            scaling = scaling.with_beta_fast(beta_fast);
+        }
+        if let Some(beta_slow) = float("beta_slow") {
+            scaling = scaling.with_beta_slow(beta_slow);
+        }
+        Ok(Some(scaling))
+    }
+
+    /// Serialize to a HuggingFace `rope_scaling` object
+    pub fn to_hf_value(&self) -> serde_json::Value {
+        let kind = match self.kind {
+            RopeScalingKind::Linear => "linear",
+            RopeScalingKind::DynamicNtk => "dynamic",
+            RopeScalingKind::Yarn => "yarn",
+        };
+        let mut value = serde_json::json!({
+            "rope_type": kind,
+            "factor": self.factor,
+        });
+        if let Some(len) = self.original_max_position_embeddings {
+            value["original_max_position_embeddings"] = len.into();
+        }
+        if self.kind == RopeScalingKind::Yarn {
+            value["beta_fast"] = self.beta_fast.into();
+            value["beta_slow"] = self.beta_slow.into();
+            if let Some(attention_factor) = self.attention_factor {
+                value["attention_factor"] = attention_factor.into();
+            }
+        }
+        value
+    }
+
+    /// Trained context length
+    pub fn original_context(&self, max_position_embeddings: usize) -> usize {
+        self.original_max_position_embeddings
+            .unwrap_or(max_position_embeddings)
+    }
+
+    /// Context the scaled embeddings are meant to reach
+    pub fn scaled_context(&self, max_position_embeddings: usize) -> usize {
+        let scaled = self.original_context(max_position_embeddings) as f64 * self.factor;
+        (scaled as usize).max(max_position_embeddings)
+    }
+
+    /// Inverse frequencies of the `head_dim / 2` rotary pairs for a sequence reaching
+    /// `seq_len` tokens
+    fn inverse_frequencies(
+        &self,
+        head_dim: usize,
+        theta: f64,
+        max_position_embeddings: usize,
+        seq_len: usize,
+    ) -> Vec<f64> {
+        let original = self.original_context(max_position_embeddings) as f64;
+        let dim = head_dim as f64;
+        let base_frequencies = |base: f64| -> Vec<f64> {
+            (0..head_dim / 2)
+                .map(|i| base.powf(-((2 * i) as f64) / dim))
+                .collect()
+        };
+
+        match self.kind {
+            RopeScalingKind::Linear => base_frequencies(theta)
+                .into_iter()
+                .map(|freq| freq / self.factor)
+                .collect(),
+            RopeScalingKind::DynamicNtk => {
+                let seq_len = (seq_len as f64).max(original);
+                let growth = self.factor * seq_len / original - (self.factor - 1.0);
+                base_frequencies(theta * growth.powf(dim / (dim - 2.0)))
+            }
+            RopeScalingKind::Yarn => {
+                // Pair index completing `rotations` turns over the trained context
+                let correction_dim = |rotations: f64| {
+                    dim * (original / (rotations * 2.0 * std::f64::consts::PI)).ln()
+                        / (2.0 * theta.ln())
+                };
+                let low = correction_dim(self.beta_fast).floor().max(0.0);
+                let high = correction_dim(self.beta_slow).ceil().min(dim - 1.0);
+                // Avoid a zero-width ramp
+                let high = if low == high { high + 0.001 } else { high };
+
+                base_frequencies(theta)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, freq)| {
+                        let ramp = ((i as f64 - low) / (high - low)).clamp(0.0, 1.0);
+                        let extrapolation = 1.0 - ramp;
+                        freq / self.factor * (1.0 - extrapolation) + freq * extrapolation
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Scale YaRN applies to the rotated queries and keys
+    fn attention_scale(&self) -> f64 {
+        match self.kind {
+            RopeScalingKind::Yarn => self.attention_factor.unwrap_or(if self.factor > 1.0 {
+                0.1 * self.factor.ln() + 1.0
+            } else {
+                1.0
+            }),
+            RopeScalingKind::Linear | RopeScalingKind::DynamicNtk => 1.0,
+        }
+    }
+}
+
+/// Build the rotary encoding for `context_length` positions
+///
+/// Dynamic NTK scaling depends on the sequence length; position `p` is rotated with the
+/// frequencies of a `p + 1` token sequence (see [`RopeScalingKind::DynamicNtk`]).
+pub fn rotary_encoding<B: Backend>(
+    head_dim: usize,
+    theta: f64,
+    max_position_embeddings: usize,
+    context_length: usize,
+    scaling: Option<&RopeScaling>,
+    device: &B::Device,
+) -> RotaryEncoding<B> {
+    let mut rope = RotaryEncodingConfig::new(context_length, head_dim)
+        .with_theta(theta as f32)
+        .init(device);
+    let Some(scaling) = scaling else {
+        return rope;
+    };
+
+    let scale = scaling.attention_scale();
+    let static_frequencies = (scaling.kind != RopeScalingKind::DynamicNtk)
+        .then(|| scaling.inverse_frequencies(head_dim, theta, max_position_embeddings, 0));
+    // [position, pair, (cos, sin)] with each pair's entry repeated for both its features
+    let mut table = Vec::with_capacity(context_length * head_dim * 2);
+    for position in 0..context_length {
+        let frequencies = match &static_frequencies {
+            Some(frequencies) => frequencies.clone(),
+            None => {
+                scaling.inverse_frequencies(head_dim, theta, max_position_embeddings, position + 1)
+            }
+        };
+        for freq in frequencies {
+            let (sin, cos) = (position as f64 * freq).sin_cos();
+            let pair = [(cos * scale) as f32, (sin * scale) as f32];
+            table.extend_from_slice(&pair);
+            table.extend_from_slice(&pair);
+        }
+    }
+    rope.freq_complex = Tensor::from_data(
+        TensorData::new(table, [context_length, head_dim, 2]),
+        device,
+    );
+    // Only `shift` reads `theta`, which we never call; keep it consistent regardless
+    if let Some(frequencies) = static_frequencies {
+        let frequencies: Vec<f32> = frequencies.into_iter().map(|freq| freq as f32).collect();
+        rope.theta = Tensor::from_data(TensorData::new(frequencies, [head_dim / 2]), device);
+    }
+    rope
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::backend::NdArray;
+    use burn::tensor::Tolerance;
+
+    type Backend = NdArray<f32>;
+
+    #[test]
+    fn test_parse_hf_rope_scaling() {
+        let value = serde_json::json!({
+            "factor": 4.0,
+            "original_max_position_embeddings": 32768,
+            "type": "yarn"
+        });
+        let scaling = RopeScaling::from_hf_value(&value).unwrap().unwrap();
+        assert_eq!(scaling.kind, RopeScalingKind::Yarn);
+        assert_eq!(scaling.scaled_context(32768), 131072);
+        assert_eq!(
+            RopeScaling::from_hf_value(&scaling.to_hf_value()).unwrap(),
+            Some(scaling)
+        );
+
+        let default = serde_json::json!({ "rope_type": "default" });
+        assert_eq!(RopeScaling::from_hf_value(&default).unwrap(), None);
+        assert_eq!(
+            RopeScaling::from_hf_value(&serde_json::Value::Null).unwrap(),
+            None
+        );
+        let longrope = serde_json::json!({ "rope_type": "longrope", "factor": 4.0 });
+        assert!(RopeScaling::from_hf_value(&longrope).is_err());
+    }
+
+    #[test]
+    fn test_scaled_frequencies() {
+        let (head_dim, theta, trained) = (64, 10000.0, 1024);
+        let base = RopeScaling::new(RopeScalingKind::Linear, 1.0)
+            .inverse_frequencies(head_dim, theta, trained, 0);
+
+        let linear = RopeScaling::new(RopeScalingKind::Linear, 4.0)
+            .inverse_frequencies(head_dim, theta, trained, 0);
+        for (scaled, freq) in linear.iter().zip(&base) {
+            assert!((scaled * 4.0 - freq).abs() < 1e-12);
+        }
+
+        // Dynamic NTK leaves the trained context alone and lowers frequencies beyond it
+        let ntk = RopeScaling::new(RopeScalingKind::DynamicNtk, 4.0);
+        assert_eq!(
+            ntk.inverse_frequencies(head_dim, theta, trained, trained),
+            base
+        );
+        let long = ntk.inverse_frequencies(head_dim, theta, trained, 4 * trained);
+        assert_eq!(long[0], base[0]);
+        assert!(
+            long.iter()
+                .zip(&base)
+                .skip(1)
+                .all(|(long, base)| long < base)
+        );
+
+        // YaRN keeps the fastest pairs, interpolates the slowest and ramps in between
+        let yarn = RopeScaling::new(RopeScalingKind::Yarn, 4.0)
+            .inverse_frequencies(head_dim, theta, trained, 0);
+        assert_eq!(yarn[0], base[0]);
+        let last = head_dim / 2 - 1;
+        assert!((yarn[last] * 4.0 - base[last]).abs() < 1e-12);
+        let ratios: Vec<f64> = yarn
+            .iter()
+            .zip(&base)
+            .map(|(yarn, base)| base / yarn)
+            .collect();
+        assert!(ratios.windows(2).all(|pair| pair[0] <= pair[1] + 1e-12));
+    }
+
+    #[test]
+    fn test_dynamic_ntk_against_hf() {
+        let device = Default::default();
+        let (head_dim, theta, trained, factor) = (8, 10000.0, 16, 4.0);
+        let prefill = 40;
+
+        // `_compute_dynamic_ntk_parameters` from transformers for a pass reaching `seq_len`
+        let hf_frequencies = |seq_len: usize| -> Vec<f64> {
+            let seq_len = seq_len.max(trained) as f64;
+            let dim = head_dim as f64;
+            let growth = factor * seq_len / trained as f64 - (factor - 1.0);
+            let base: f64 = theta * growth.powf(dim / (dim - 2.0));
+            (0..head_dim)
+                .step_by(2)
+                .map(|i| 1.0 / base.powf(i as f64 / dim))
+                .collect()
+        };
+        // Our table's [head_dim, (cos, sin)] layout, each pair repeated
+        let hf_row = |frequencies: Vec<f64>, position: usize| -> Vec<f32> {
+            frequencies
+                .into_iter()
+                .flat_map(|freq| {
+                    let (sin, cos) = (position as f64 * freq).sin_cos();
+                    [cos as f32, sin as f32, cos as f32, sin as f32]
+                })
+                .collect()
+        };
+        let max_error = |a: &[f32], b: &[f32]| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| (a - b).abs())
+                .fold(0.0, f32::max)
+        };
+
+        let ntk = RopeScaling::new(RopeScalingKind::DynamicNtk, factor);
+        let rope =
+            rotary_encoding::<Backend>(head_dim, theta, trained, prefill, Some(&ntk), &device);
+        let table = rope.freq_complex.into_data().to_vec::<f32>().unwrap();
+        let row = |position: usize| &table[position * head_dim * 2..][..head_dim * 2];
+
+        // The last position of the prefill is rotated exactly as HF rotates the whole pass
+        let last = prefill - 1;
+        assert!(max_error(row(last), &hf_row(hf_frequencies(prefill), last)) < 1e-5);
+        // Every position uses the base HF picks when decoding it, unscaled in the trained
+        // context
+        for position in 0..prefill {
+            let expected = hf_row(hf_frequencies(position + 1), position);
+            assert!(
+                max_error(row(position), &expected) < 1e-5,
+                "position {position}"
+            );
+        }
+        // Earlier positions of a long prefill are where HF differs (the documented deviation)
+        let hf_prefill = hf_row(hf_frequencies(prefill), trained);
+        assert!(max_error(row(trained), &hf_prefill) > 1e-3);
+    }
+
+    #[test]
+    fn test_rotary_encoding_tables() {
+        let device = Default::default();
+        let (head_dim, theta) = (8, 10000.0);
+        let x = Tensor::<Backend, 4>::ones([1, 1, 6, head_dim], &device);
+
+        // A factor of one is the plain encoding
+        let plain = rotary_encoding::<Backend>(head_dim, theta, 4, 6, None, &device);
+        let identity = RopeScaling::new(RopeScalingKind::Linear, 1.0);
+        let scaled = rotary_encoding::<Backend>(head_dim, theta, 4, 6, Some(&identity), &device);
+        scaled
+            .apply(x.clone(), 0)
+            .into_data()
+            .assert_approx_eq::<f32>(&plain.apply(x.clone(), 0).into_data(), Tolerance::default());
+
+        // Linear scaling puts position 2p where the plain encoding puts p
+        let linear = RopeScaling::new(RopeScalingKind::Linear, 2.0);
+        let scaled = rotary_encoding::<Backend>(head_dim, theta, 4, 6, Some(&linear), &device);
+        let even = scaled
+            .apply(x.clone(), 0)
+            .slice([0..1, 0..1, 4..5, 0..head_dim]);
+        let plain = plain
+            .apply(x.clone(), 0)
+            .slice([0..1, 0..1, 2..3, 0..head_dim]);
+        even.into_data()
+            .assert_approx_eq::<f32>(&plain.into_data(), Tolerance::default());
+
+        // YaRN scales every rotated vector by its attention factor
+        let yarn = RopeScaling::new(RopeScalingKind::Yarn, 4.0);
+        let scaled = rotary_encoding::<Backend>(head_dim, theta, 4, 6, Some(&yarn), &device);
+        let norm = scaled.apply(x, 0).powi_scalar(2).sum_dim(3).sqrt();
+        let expected = (head_dim as f64).sqrt() * (0.1 * 4f64.ln() + 1.0);
+        let expected = Tensor::<Backend, 4>::full([1, 1, 6, 1], expected, &device);
+        norm.into_data()
+            .assert_approx_eq::<f32>(&expected.into_data(), Tolerance::default());
+    }
+}
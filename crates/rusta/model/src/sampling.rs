@@ -0,0 +1,335 @@
+//! Next-token sampling
+//!
+//! Logits are post-processed on the host in a fixed order: repetition, frequency and
+//! presence penalties, temperature, then the top-k, top-p and min-p filters. Randomness
+//! comes from a seeded ChaCha stream, so a given seed reproduces the same tokens on every
+//! platform (the `D2` determinism level in `configs/default.toml`).
+
+use burn::{
+    config::Config,
+    tensor::{Tensor, backend::Backend},
+};
+use rand_chacha::{
+    ChaCha8Rng,
+    rand_core::{RngCore, SeedableRng},
+};
+
+/// Configuration of the sampling stack
+#[derive(Config, Debug)]
+pub struct SamplingConfig {
+    /// Softmax temperature; `0.0` selects greedy decoding
+    #[config(default = "1.0")]
+    pub temperature: f32,
+    /// Keep only the `k` most likely tokens
+    pub top_k: Option<usize>,
+    /// Keep the smallest set of tokens whose cumulative probability reaches `p`
+    pub top_p: Option<f32>,
+    /// Drop tokens less likely than `min_p` times the most likely token
+    pub min_p: Option<f32>,
+    /// Multiplicative penalty on tokens already present (HF semantics, `1.0` disables)
+    #[config(default = "1.0")]
+    pub repetition_penalty: f32,
+    /// Subtracted from a token's logit once per previous occurrence
+    #[config(default = "0.0")]
+    pub frequency_penalty: f32,
+    /// Subtracted from a token's logit if it occurred at all
+    #[config(default = "0.0")]
+    pub presence_penalty: f32,
+    #[config(default = "0")]
+    pub seed: u64,
+}
+
+impl SamplingConfig {
+    /// Deterministic argmax decoding
+    pub fn greedy() -> Self {
+        Self::new().with_temperature(0.0)
+    }
+
+    /// Whether this configuration always picks the most likely token
+    pub fn is_greedy(&self) -> bool {
+        self.temperature <= 0.0 || self.top_k == Some(1)
+    }
+}
+
+/// Stateful sampler holding the random stream for one generation call
+pub struct Sampler {
+    config: SamplingConfig,
+    rng: ChaCha8Rng,
+}
+
+impl Sampler {
+    pub fn new(config: SamplingConfig) -> Self {
+        let rng = ChaCha8Rng::seed_from_u64(config.seed);
+        Self { config, rng }
+    }
+
+    pub fn config(&self) -> &SamplingConfig {
+        &self.config
+    }
+
+    /// Sample one token per batch row
+    ///
+    /// # Arguments
+    /// * `logits` - Next-token logits [batch_size, vocab_size]
+    /// * `history` - Tokens seen so far by each sequence, used for the penalties
+    pub fn sample_batch<B: Backend>(
+        &mut self,
+        logits: Tensor<B, 2>,
+        history: &[Vec<u32>],
+    ) -> Vec<u32> {
+        logits_rows(logits)
+            .into_iter()
+            .enumerate()
+            .map(|(row, mut row_logits)| {
+                let row_history = history.get(row).map(Vec::as_slice).unwrap_or(&[]);
+                self.sample(&mut row_logits, row_history)
+            })
+            .collect()
+    }
+
+    /// Sample the next token for a single sequence; `logits` is modified in place
+    pub fn sample(&mut self, logits: &mut [f32], history: &[u32]) -> u32 {
+        self.apply_penalties(logits, history);
+
+        if self.config.is_greedy() {
+            return argmax(logits);
+        }
+
+        let probs = self.filtered_probs(logits);
+        self.draw(&probs)
+    }
+
+    /// The distribution [`Self::sample`] draws from, as `(token, probability)` pairs
+    /// sorted by decreasing probability; greedy configurations put all mass on the
+    /// argmax. `logits` is modified in place.
+    pub fn distribution(&self, logits: &mut [f32], history: &[u32]) -> Vec<(u32, f32)> {
+        self.apply_penalties(logits, history);
+
+        if self.config.is_greedy() {
+            return vec![(argmax(logits), 1.0)];
+        }
+        self.filtered_probs(logits)
+    }
+
+    fn apply_penalties(&self, logits: &mut [f32], history: &[u32]) {
+        let config = &self.config;
+        if history.is_empty()
+            || (config.repetition_penalty == 1.0
+                && config.frequency_penalty == 0.0
+                && config.presence_penalty == 0.0)
+        {
+            return;
+        }
+
+        let mut counts = std::collections::HashMap::<u32, usize>::new();
+        for &token in history {
+            *counts.entry(token).or_default() += 1;
+        }
+
+        for (token, count) in counts {
+            let Some(logit) = logits.get_mut(token as usize) else {
+                continue;
+            };
+            if *logit > 0.0 {
+                *logit /= config.repetition_penalty;
+            } else {
+                *logit *= config.repetition_penalty;
+            }
+            *logit -= config.frequency_penalty * count as f32 + config.presence_penalty;
+        }
+    }
+
+    /// Temperature-scaled probabilities with the top-k, top-p and min-p filters applied,
+    /// as `(token, probability)` pairs sorted by decreasing probability
+    ///
+    /// Never empty: when no logit yields a finite probability (every logit `-inf` or NaN,
+    /// or an infinite maximum) all mass goes to the argmax.
+    fn filtered_probs(&self, logits: &[f32]) -> Vec<(u32, f32)> {
+        let config = &self.config;
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+
+        let mut probs: Vec<(u32, f32)> = logits
+            .iter()
+            .enumerate()
+            .map(|(token, &logit)| (token as u32, ((logit - max) / config.temperature).exp()))
+            .filter(|(_, p)| *p > 0.0)
+            .collect();
+        if probs.is_empty() {
+            tracing::warn!("no finite next-token logits, falling back to argmax");
+            return vec![(argmax(logits), 1.0)];
+        }
+        probs.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        if let Some(k) = config.top_k {
+            probs.truncate(k.max(1));
+        }
+
+        normalize(&mut probs);
+
+        if let Some(top_p) = config.top_p {
+            let mut cumulative = 0.0;
+            let keep = probs
+                .iter()
+                .position(|(_, p)| {
+                    cumulative += p;
+                    cumulative >= top_p
+                })
+                .map_or(probs.len(), |i| i + 1);
+            probs.truncate(keep);
+        }
+
+        if let (Some(min_p), Some(&(_, top))) = (config.min_p, probs.first()) {
+            let threshold = top * min_p;
+            probs.retain(|(_, p)| *p >= threshold);
+        }
+
+        normalize(&mut probs);
+        probs
+    }
+
+    /// Draw a token from `(token, probability)` pairs summing to one
+    ///
+    /// # Panics
+    /// If `probs` is empty; [`Self::distribution`] never returns an empty distribution.
+    pub fn draw(&mut self, probs: &[(u32, f32)]) -> u32 {
+        let u = self.uniform();
+
+        let mut cumulative = 0.0f64;
+        for &(token, p) in probs {
+            cumulative += p as f64;
+            if u < cumulative {
+                return token;
+            }
+        }
+        // Rounding can leave the total just below `u`
+        probs
+            .last()
+            .expect("cannot draw from an empty distribution")
+            .0
+    }
+
+    /// Next number of the random stream, uniform in [0, 1)
+    pub fn uniform(&mut self) -> f64 {
+        // 53 random bits give a uniform f64 in [0, 1)
+        (self.rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Copy [batch_size, vocab_size] logits to the host, one row per sequence
+pub fn logits_rows<B: Backend>(logits: Tensor<B, 2>) -> Vec<Vec<f32>> {
+    let [_batch_size, vocab_size] = logits.dims();
+    let values = logits
+        .into_data()
+        .convert::<f32>()
+        .to_vec::<f32>()
+        .expect("float logits");
+    values
+        .chunks(vocab_size.max(1))
+        .map(<[f32]>::to_vec)
+        .collect()
+}
+
+fn normalize(probs: &mut [(u32, f32)]) {
+    let total: f32 = probs.iter().map(|(_, p)| p).sum();
+    if total > 0.0 {
+        probs.iter_mut().for_each(|(_, p)| *p /= total);
+    }
+}
+
+/// Index of the largest logit (the first one on ties)
+pub fn argmax(logits: &[f32]) -> u32 {
+    logits
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (i, &logit)| {
+            if logit > best.1 { (i, logit) } else { best }
+        })
+        .0 as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_greedy_picks_argmax() {
+        let mut sampler = Sampler::new(SamplingConfig::greedy());
+        assert_eq!(sampler.sample(&mut [0.1, 2.0, -1.0, 1.9], &[]), 1);
+    }
+
+    #[test]
+    fn test_seed_reproduces_samples() {
+        let config = SamplingConfig::new()
+            .with_temperature(1.5)
+            .with_top_p(Some(0.95))
+            .with_seed(42);
+        let logits: Vec<f32> = (0..32).map(|i| (i % 7) as f32 * 0.3).collect();
+
+        let run = |config: &SamplingConfig| {
+            let mut sampler = Sampler::new(config.clone());
+            (0..16)
+                .map(|_| sampler.sample(&mut logits.clone(), &[]))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(&config), run(&config));
+        assert_ne!(run(&config), run(&config.clone().with_seed(7)));
+    }
+
+    #[test]
+    fn test_filters_restrict_candidates() {
+        let logits = [4.0, 3.9, 0.0, -2.0];
+
+        let sampler = Sampler::new(SamplingConfig::new().with_top_k(Some(2)));
+        let tokens: Vec<u32> = sampler
+            .filtered_probs(&logits)
+            .iter()
+            .map(|t| t.0)
+            .collect();
+        assert_eq!(tokens, vec![0, 1]);
+
+        let sampler = Sampler::new(SamplingConfig::new().with_min_p(Some(0.5)));
+        let tokens: Vec<u32> = sampler
+            .filtered_probs(&logits)
+            .iter()
+            .map(|t| t.0)
+            .collect();
+        assert_eq!(tokens, vec![0, 1]);
+
+        let sampler = Sampler::new(SamplingConfig::new().with_top_p(Some(0.4)));
+        let tokens: Vec<u32> = sampler
+            .filtered_probs(&logits)
+            .iter()
+            .map(|t| t.0)
+            .collect();
+        assert_eq!(tokens, vec![0]);
+    }
+
+    #[test]
+    fn test_non_finite_logits_fall_back_to_argmax() {
+        let sampler = Sampler::new(SamplingConfig::new().with_min_p(Some(0.1)));
+        let mut logits = [f32::NEG_INFINITY; 8];
+        assert_eq!(sampler.filtered_probs(&logits), vec![(0, 1.0)]);
+
+        logits[5] = f32::INFINITY;
+        assert_eq!(sampler.filtered_probs(&logits), vec![(5, 1.0)]);
+
+        let mut sampler = Sampler::new(SamplingConfig::new().with_top_p(Some(0.9)));
+        let mut logits = [f32::NAN, f32::NAN, 1.0, f32::NAN];
+        assert_eq!(sampler.sample(&mut logits, &[]), 2);
+        assert_eq!(sampler.sample(&mut [f32::NAN; 4], &[]), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "empty distribution")]
+    fn test_draw_rejects_empty_distribution() {
+        Sampler::new(SamplingConfig::new()).draw(&[]);
+    }
+
+    #[test]
+    fn test_repetition_penalty_discourages_history() {
+        let config = SamplingConfig::greedy().with_repetition_penalty(2.0);
+        let mut sampler = Sampler::new(config);
+        assert_eq!(sampler.sample(&mut [2.0, 1.5], &[0]), 1);
+    }
+}
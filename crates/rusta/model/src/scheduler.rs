@@ -0,0 +1,507 @@
+//! Continuous batching
+//!
+//! [`Scheduler`] serves many generation requests from one [`PagedKvPool`]. A request is
+//! admitted as soon as a batch slot and enough blocks are free, even while others are
+//! decoding: its prompt is prefilled on its own and its blocks are then moved into the
+//! running batch. Every [`Scheduler::step`] runs a single batched decode pass in which
+//! each sequence keeps its own position, and retires the sequences that finished.
+//!
+//! Admission only reserves blocks for the prompt, so the pool can run dry while the
+//! batch decodes. The newest sequence is then preempted: its blocks are released and it
+//! goes back to the front of the queue with its tokens, to be prefilled again once
+//! blocks are free.
+
+use std::collections::VecDeque;
+
+use burn::tensor::backend::Backend;
+
+use crate::cache::PagedKvPool;
+use crate::error::ModelError;
+use crate::inference::token_column;
+use crate::model::{
+    CacheLayout, DEFAULT_PREFILL_CHUNK_SIZE, KeyValueCache, Qwen2Config, Qwen2ForCausalLM,
+};
+use crate::sampling::{Sampler, SamplingConfig, logits_rows};
+use crate::stopping::{FinishReason, StopConditions};
+use crate::stream::StreamEvent;
+
+/// A prompt to complete, with its own sampling and stop settings
+#[derive(Debug, Clone)]
+pub struct GenerationRequest {
+    pub prompt: Vec<u32>,
+    pub max_new_tokens: usize,
+    pub sampling: SamplingConfig,
+    pub stop: StopConditions,
+}
+
+impl GenerationRequest {
+    /// Sample with the default [`SamplingConfig`] and no stop conditions
+    pub fn new(prompt: Vec<u32>, max_new_tokens: usize) -> Self {
+        Self {
+            prompt,
+            max_new_tokens,
+            sampling: SamplingConfig::new(),
+            stop: StopConditions::new(),
+        }
+    }
+
+    pub fn with_sampling(mut self, sampling: SamplingConfig) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    pub fn with_stop(mut self, stop: StopConditions) -> Self {
+        self.stop = stop;
+        self
+    }
+}
+
+/// A sequence of the running batch
+struct Sequence {
+    id: usize,
+    sampler: Sampler,
+    stop: StopConditions,
+    /// Prompt plus generated tokens; the last one has not been fed to the model yet
+    history: Vec<u32>,
+    prompt_len: usize,
+    max_new_tokens: usize,
+}
+
+impl Sequence {
+    fn new(id: usize, request: GenerationRequest) -> Self {
+        Self {
+            id,
+            sampler: Sampler::new(request.sampling),
+            stop: request.stop,
+            prompt_len: request.prompt.len(),
+            history: request.prompt,
+            max_new_tokens: request.max_new_tokens,
+        }
+    }
+
+    /// Sample the next token from `logits`, returning why the sequence stops, if it does
+    fn sample(
+        &mut self,
+        mut logits: Vec<f32>,
+        events: &mut Vec<StreamEvent>,
+    ) -> Option<FinishReason> {
+        let token_id = self.sampler.sample(&mut logits, &self.history);
+        self.history.push(token_id);
+        events.push(StreamEvent::Token {
+            sequence: self.id,
+            token_id,
+            logprob: None,
+        });
+
+        let generated = &self.history[self.prompt_len..];
+        self.stop
+            .check(generated)
+            .or_else(|| (generated.len() >= self.max_new_tokens).then_some(FinishReason::Length))
+    }
+}
+
+/// A request waiting for a batch slot
+enum Waiting {
+    New(usize, GenerationRequest),
+    /// Preempted for lack of blocks; its cache is recomputed from its tokens
+    Preempted(Box<Sequence>),
+}
+
+impl Waiting {
+    fn id(&self) -> usize {
+        match self {
+            Waiting::New(id, _) => *id,
+            Waiting::Preempted(sequence) => sequence.id,
+        }
+    }
+
+    /// Tokens to prefill on admission
+    fn len(&self) -> usize {
+        match self {
+            Waiting::New(_, request) => request.prompt.len(),
+            Waiting::Preempted(sequence) => sequence.history.len(),
+        }
+    }
+}
+
+/// Continuous-batching generation over a shared paged KV pool
+///
+/// Events carry the id returned by [`Scheduler::submit`] as their `sequence`.
+pub struct Scheduler<'a, B: Backend> {
+    model: &'a Qwen2ForCausalLM<B>,
+    config: Qwen2Config,
+    pool: PagedKvPool<B>,
+    /// One row per running sequence
+    cache: Vec<KeyValueCache<B>>,
+    running: Vec<Sequence>,
+    waiting: VecDeque<Waiting>,
+    max_running: usize,
+    next_id: usize,
+    pending: Vec<StreamEvent>,
+    device: B::Device,
+}
+
+impl<'a, B: Backend> Scheduler<'a, B> {
+    /// Schedule at most `max_running` sequences at a time on `pool`
+    pub fn new(
+        model: &'a Qwen2ForCausalLM<B>,
+        config: &Qwen2Config,
+        pool: PagedKvPool<B>,
+        max_running: usize,
+        device: &B::Device,
+    ) -> Self {
+        let layout = CacheLayout::Paged(pool.clone());
+        let cache = model.init_cache(config, max_running, &layout, device);
+        Self {
+            model,
+            config: config.clone(),
+            pool,
+            cache,
+            running: Vec::new(),
+            waiting: VecDeque::new(),
+            max_running,
+            next_id: 0,
+            pending: Vec::new(),
+            device: device.clone(),
+        }
+    }
+
+    /// Queue a request; it joins the batch on a later [`Scheduler::step`]
+    pub fn submit(&mut self, request: GenerationRequest) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push_back(Waiting::New(id, request));
+        id
+    }
+
+    /// Stop request `id`, whether waiting or running
+    ///
+    /// Returns `false` if it already finished. The [`FinishReason::Cancelled`] event is
+    /// reported by the next step.
+    pub fn cancel(&mut self, id: usize) -> bool {
+        if let Some(index) = self.waiting.iter().position(|waiting| waiting.id() == id) {
+            self.waiting.remove(index);
+            self.pending.push(StreamEvent::Finished {
+                sequence: id,
+                reason: FinishReason::Cancelled,
+            });
+            return true;
+        }
+        match self.running.iter().position(|sequence| sequence.id == id) {
+            Some(row) => {
+                self.retire(row, FinishReason::Cancelled);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn num_running(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Whether every request has finished
+    pub fn is_idle(&self) -> bool {
+        self.running.is_empty() && self.waiting.is_empty() && self.pending.is_empty()
+    }
+
+    /// Decode one token for every running sequence, then admit waiting requests
+    ///
+    /// Newly admitted requests produce their first token from the prefill, so every
+    /// sequence advances by exactly one token per step.
+    pub fn step(&mut self) -> Vec<StreamEvent> {
+        self.decode();
+        self.admit();
+        std::mem::take(&mut self.pending)
+    }
+
+    fn decode(&mut self) {
+        // Sequences whose positions are exhausted cannot take another token
+        let capacity = self.config.context_length();
+        for row in (0..self.running.len()).rev() {
+            if self.running[row].history.len() > capacity {
+                self.retire(row, FinishReason::Length);
+            }
+        }
+
+        let logits = loop {
+            if self.running.is_empty() {
+                return;
+            }
+            let last: Vec<u32> = self
+                .running
+                .iter()
+                .map(|sequence| *sequence.history.last().expect("non-empty history"))
+                .collect();
+            let input = token_column::<B>(&last, &self.device);
+
+            match self.model.forward_last(input, &mut self.cache) {
+                Ok(logits) => break logits,
+                // The pool ran out of blocks: requeue the newest sequence, unless it is
+                // the only one and will never fit
+                Err(err @ ModelError::CacheOverflow { .. }) if self.running.len() > 1 => {
+                    let row = self.running.len() - 1;
+                    tracing::debug!("preempting request {}: {err}", self.running[row].id);
+                    for layer in &mut self.cache {
+                        layer.remove_sequence(row);
+                    }
+                    let sequence = self.running.remove(row);
+                    self.waiting
+                        .push_front(Waiting::Preempted(Box::new(sequence)));
+                }
+                Err(err @ ModelError::CacheOverflow { .. }) => {
+                    tracing::warn!("retiring request {}: {err}", self.running[0].id);
+                    self.retire(0, FinishReason::Length);
+                }
+                // Nothing to gain from retrying a smaller batch
+                Err(err) => {
+                    tracing::warn!("retiring the running batch: {err}");
+                    for row in (0..self.running.len()).rev() {
+                        self.retire(row, FinishReason::Length);
+                    }
+                }
+            }
+        };
+
+        let mut finished = Vec::new();
+        let rows = self.running.iter_mut().zip(logits_rows(logits));
+        for (row, (sequence, row_logits)) in rows.enumerate() {
+            if let Some(reason) = sequence.sample(row_logits, &mut self.pending) {
+                finished.push((row, reason));
+            }
+        }
+        for (row, reason) in finished.into_iter().rev() {
+            self.retire(row, reason);
+        }
+    }
+
+    fn admit(&mut self) {
+        while self.running.len() < self.max_running {
+            let Some(waiting) = self.waiting.front() else {
+                break;
+            };
+            // Wait for running sequences to free blocks, unless there are none to wait for
+            let blocks = (waiting.len() + 1).div_ceil(self.pool.block_size());
+            if blocks > self.pool.free_blocks() && !self.running.is_empty() {
+                break;
+            }
+
+            match self.waiting.pop_front().expect("checked front") {
+                Waiting::New(id, request) => {
+                    if request.prompt.is_empty() || request.max_new_tokens == 0 {
+                        self.pending.push(StreamEvent::Finished {
+                            sequence: id,
+                            reason: FinishReason::Length,
+                        });
+                        continue;
+                    }
+                    self.prefill(Sequence::new(id, request));
+                }
+                Waiting::Preempted(sequence) => self.prefill(*sequence),
+            }
+        }
+    }
+
+    /// Run every token of `sequence` not yet sampled from, sample its next token and
+    /// move it into the batch
+    fn prefill(&mut self, mut sequence: Sequence) {
+        let id = sequence.id;
+        let layout = CacheLayout::Paged(self.pool.clone());
+        let mut cache = self
+            .model
+            .init_cache(&self.config, 1, &layout, &self.device);
+        let input = token_column::<B>(&sequence.history, &self.device).swap_dims(0, 1);
+        let chunk_size = DEFAULT_PREFILL_CHUNK_SIZE;
+        let last_logits = match self.model.prefill(input, &mut cache, chunk_size, |_| {}) {
+            Ok(logits) => logits,
+            Err(err) => {
+                tracing::warn!("cannot admit request {id}: {err}");
+                self.pending.push(StreamEvent::Finished {
+                    sequence: id,
+                    reason: FinishReason::Length,
+                });
+                return;
+            }
+        };
+
+        let row_logits = logits_rows(last_logits).pop().expect("one row");
+        if let Some(reason) = sequence.sample(row_logits, &mut self.pending) {
+            self.pending.push(StreamEvent::Finished {
+                sequence: id,
+                reason,
+            });
+            return;
+        }
+
+        for (layer, new) in self.cache.iter_mut().zip(cache) {
+            layer.append(new);
+        }
+        self.running.push(sequence);
+    }
+
+    fn retire(&mut self, row: usize, reason: FinishReason) {
+        for layer in &mut self.cache {
+            layer.remove_sequence(row);
+        }
+        let sequence = self.running.remove(row);
+        self.pending.push(StreamEvent::Finished {
+            sequence: sequence.id,
+            reason,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::{generate, token_rows};
+    use burn::backend::NdArray;
+    use std::collections::HashMap;
+
+    type Backend = NdArray<f32>;
+
+    #[test]
+    fn test_continuous_batching_matches_single_generation() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let pool = PagedKvPool::new(&config, 4, 32, &device);
+        let mut scheduler = Scheduler::new(&model, &config, pool.clone(), 2, &device);
+
+        let requests = [
+            GenerationRequest::new(vec![5, 9, 13, 2, 7], 6),
+            GenerationRequest::new(vec![40, 3, 22], 5),
+            GenerationRequest::new(vec![1, 2, 3, 4, 5, 6, 7], 4),
+        ]
+        .map(|request| request.with_sampling(SamplingConfig::greedy()));
+
+        let mut generated: HashMap<usize, Vec<u32>> = HashMap::new();
+        let mut finished = HashMap::new();
+        let mut ids = vec![scheduler.submit(requests[0].clone())];
+        let mut steps = 0;
+        while !scheduler.is_idle() || ids.len() < requests.len() {
+            // Later requests arrive while the first one is decoding; the third has to
+            // wait for a free slot
+            if steps == 2 {
+                ids.push(scheduler.submit(requests[1].clone()));
+                ids.push(scheduler.submit(requests[2].clone()));
+            }
+            for event in scheduler.step() {
+                match event {
+                    StreamEvent::Token {
+                        sequence, token_id, ..
+                    } => generated.entry(sequence).or_default().push(token_id),
+                    StreamEvent::Finished { sequence, reason } => {
+                        finished.insert(sequence, reason);
+                    }
+                    StreamEvent::Prefill { .. } => {}
+                }
+            }
+            steps += 1;
+        }
+
+        for (id, request) in ids.iter().zip(&requests) {
+            let input = token_column::<Backend>(&request.prompt, &device).swap_dims(0, 1);
+            let output = generate(
+                &model,
+                &config,
+                input,
+                request.max_new_tokens,
+                &request.sampling,
+                &request.stop,
+                &device,
+            );
+            let expected = token_rows(output.tokens).remove(0)[request.prompt.len()..].to_vec();
+            assert_eq!(generated[id], expected, "request {id}");
+            assert_eq!(finished[id], FinishReason::Length);
+        }
+        assert_eq!(pool.free_blocks(), pool.num_blocks());
+    }
+
+    #[test]
+    fn test_exhausted_pool_preempts_and_resumes() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        // Each request needs four blocks of four positions to finish; six are shared
+        let pool = PagedKvPool::new(&config, 4, 6, &device);
+        let mut scheduler = Scheduler::new(&model, &config, pool.clone(), 2, &device);
+
+        let requests = [
+            GenerationRequest::new(vec![5, 9, 13, 2, 7], 10),
+            GenerationRequest::new(vec![40, 3, 22, 8, 1], 10),
+        ]
+        .map(|request| request.with_sampling(SamplingConfig::greedy()));
+        let ids = requests.clone().map(|request| scheduler.submit(request));
+
+        let mut generated: HashMap<usize, Vec<u32>> = HashMap::new();
+        let mut finished = HashMap::new();
+        let mut preempted = false;
+        while !scheduler.is_idle() {
+            for event in scheduler.step() {
+                match event {
+                    StreamEvent::Token {
+                        sequence, token_id, ..
+                    } => generated.entry(sequence).or_default().push(token_id),
+                    StreamEvent::Finished { sequence, reason } => {
+                        finished.insert(sequence, reason);
+                    }
+                    StreamEvent::Prefill { .. } => {}
+                }
+            }
+            preempted |= scheduler.num_waiting() > 0;
+        }
+        assert!(preempted);
+
+        // The preempted request picks up where it left off
+        for (id, request) in ids.iter().zip(&requests) {
+            let input = token_column::<Backend>(&request.prompt, &device).swap_dims(0, 1);
+            let output = generate(
+                &model,
+                &config,
+                input,
+                request.max_new_tokens,
+                &request.sampling,
+                &request.stop,
+                &device,
+            );
+            let expected = token_rows(output.tokens).remove(0)[request.prompt.len()..].to_vec();
+            assert_eq!(generated[id], expected, "request {id}");
+            assert_eq!(finished[id], FinishReason::Length);
+        }
+        assert_eq!(pool.free_blocks(), pool.num_blocks());
+    }
+
+    #[test]
+    fn test_cancel_waiting_and_running_requests() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let pool = PagedKvPool::new(&config, 4, 32, &device);
+        let mut scheduler = Scheduler::new(&model, &config, pool.clone(), 1, &device);
+
+        let running = scheduler.submit(GenerationRequest::new(vec![1, 2, 3], 10));
+        let waiting = scheduler.submit(GenerationRequest::new(vec![4, 5, 6], 10));
+        scheduler.step();
+        assert_eq!((scheduler.num_running(), scheduler.num_waiting()), (1, 1));
+
+        assert!(scheduler.cancel(waiting));
+        assert!(scheduler.cancel(running));
+        assert!(!scheduler.cancel(running));
+        let events = scheduler.step();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| matches!(
+            event,
+            StreamEvent::Finished {
+                reason: FinishReason::Cancelled,
+                ..
+            }
+        )));
+        assert!(scheduler.is_idle());
+        assert_eq!(pool.free_blocks(), pool.num_blocks());
+    }
+}
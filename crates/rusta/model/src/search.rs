@@ -0,0 +1,483 @@
+//! Beam search and n-best generation
+//!
+//! The agent loop ranks alternative patches by confidence, so both strategies return
+//! several continuations of one prompt with their per-token log-probabilities under
+//! the model. The prompt is prefilled once; its KV cache is then forked into one row
+//! per candidate with [`KeyValueCache::fork_sequences`], backed by a paged pool so
+//! that candidates share the blocks of their common prefix.
+
+use burn::{
+    config::Config,
+    tensor::{Int, Tensor, backend::Backend},
+};
+
+use crate::cache::PagedKvPool;
+use crate::error::{ModelError, Result};
+use crate::inference::token_column;
+use crate::model::{
+    CacheLayout, DEFAULT_PREFILL_CHUNK_SIZE, KeyValueCache, Qwen2Config, Qwen2ForCausalLM,
+};
+use crate::sampling::{Sampler, SamplingConfig, logits_rows};
+use crate::stopping::{FinishReason, StopConditions};
+
+/// Positions per block of the pool candidates share
+const BLOCK_SIZE: usize = 16;
+
+/// Configuration of beam search
+#[derive(Config, Debug)]
+pub struct BeamSearchConfig {
+    /// Hypotheses kept at every step
+    #[config(default = "4")]
+    pub num_beams: usize,
+    /// Exponent `α` of the length normalization `score = logprob / length^α`; values
+    /// above zero favour longer sequences, below zero shorter ones
+    #[config(default = "1.0")]
+    pub length_penalty: f32,
+    /// Finished hypotheses returned, best first, at most `num_beams`
+    #[config(default = "1")]
+    pub num_return_sequences: usize,
+}
+
+/// One generated continuation with its log-probabilities
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoredSequence {
+    /// Generated tokens, prompt excluded, stop token included
+    pub tokens: Vec<u32>,
+    /// Natural-log probability of each token under the model, before any penalty,
+    /// temperature or filter of the sampling stack
+    pub token_logprobs: Vec<f32>,
+    /// Sum of `token_logprobs`
+    pub cumulative_logprob: f32,
+    /// Ranking score: the length-normalized log-probability
+    pub score: f32,
+    pub finish_reason: FinishReason,
+}
+
+impl ScoredSequence {
+    /// Geometric mean of the token probabilities, in `[0, 1]`, as reported in the
+    /// `confidence` field of ActionBlock records
+    pub fn confidence(&self) -> f32 {
+        if self.tokens.is_empty() {
+            return 0.0;
+        }
+        (self.cumulative_logprob / self.tokens.len() as f32).exp()
+    }
+
+    fn empty() -> Self {
+        Self {
+            tokens: Vec::new(),
+            token_logprobs: Vec::new(),
+            cumulative_logprob: 0.0,
+            score: 0.0,
+            finish_reason: FinishReason::Length,
+        }
+    }
+
+    /// This sequence followed by `token`, scored with `length_penalty`
+    fn extend(&self, token: u32, logprob: f32, length_penalty: f32) -> Self {
+        let mut tokens = self.tokens.clone();
+        tokens.push(token);
+        let mut token_logprobs = self.token_logprobs.clone();
+        token_logprobs.push(logprob);
+        let cumulative_logprob = self.cumulative_logprob + logprob;
+        Self {
+            score: cumulative_logprob / (tokens.len() as f32).powf(length_penalty),
+            tokens,
+            token_logprobs,
+            cumulative_logprob,
+            finish_reason: FinishReason::Length,
+        }
+    }
+}
+
+/// Generates several ranked continuations of a prompt
+pub struct NBestGenerator<'a, B: Backend> {
+    model: &'a Qwen2ForCausalLM<B>,
+    config: &'a Qwen2Config,
+    device: B::Device,
+}
+
+impl<'a, B: Backend> NBestGenerator<'a, B> {
+    pub fn new(
+        model: &'a Qwen2ForCausalLM<B>,
+        config: &'a Qwen2Config,
+        device: &B::Device,
+    ) -> Self {
+        Self {
+            model,
+            config,
+            device: device.clone(),
+        }
+    }
+
+    /// Beam search for the `num_return_sequences` most likely continuations
+    ///
+    /// Every step extends each beam by its `2 × num_beams` most likely tokens and keeps
+    /// the best `num_beams` unfinished candidates; candidates ending in a stop
+    /// condition are set aside when they rank among the first `num_beams`. Search ends
+    /// after `max_new_tokens` tokens, or once `num_beams` hypotheses have finished and
+    /// no live beam scores better than the worst of them (Hugging Face's default
+    /// stopping rule).
+    pub fn beam_search(
+        &self,
+        prompt: &[u32],
+        max_new_tokens: usize,
+        beam: &BeamSearchConfig,
+        stop: &StopConditions,
+    ) -> Result<Vec<ScoredSequence>> {
+        if beam.num_beams == 0 {
+            return Err(ModelError::InvalidConfig(
+                "num_beams must be positive".to_string(),
+            ));
+        }
+        if max_new_tokens == 0 {
+            let count = beam.num_return_sequences.min(beam.num_beams);
+            return Ok(vec![ScoredSequence::empty(); count]);
+        }
+        let num_beams = beam.num_beams;
+        let (mut cache, logits) = self.prefill(prompt, num_beams, max_new_tokens)?;
+        let mut logits = logits_rows(logits);
+
+        let mut beams = vec![ScoredSequence::empty()];
+        let mut finished: Vec<ScoredSequence> = Vec::new();
+        while !beams.is_empty() {
+            // The most likely extensions of every beam, best first
+            let mut candidates = Vec::new();
+            for (row, (parent, logits)) in beams.iter().zip(&logits).enumerate() {
+                let logprobs = log_softmax(logits);
+                for token in top_tokens(&logprobs, 2 * num_beams) {
+                    let cumulative = parent.cumulative_logprob + logprobs[token as usize];
+                    candidates.push((row, token, logprobs[token as usize], cumulative));
+                }
+            }
+            candidates.sort_by(|a, b| b.3.total_cmp(&a.3));
+
+            let mut parents = Vec::with_capacity(num_beams);
+            let mut next = Vec::with_capacity(num_beams);
+            for (rank, (row, token, logprob, _)) in candidates.into_iter().enumerate() {
+                if next.len() == num_beams {
+                    break;
+                }
+                let sequence = beams[row].extend(token, logprob, beam.length_penalty);
+                match stop.check(&sequence.tokens) {
+                    Some(reason) if rank < num_beams => {
+                        finished.push(ScoredSequence {
+                            finish_reason: reason,
+                            ..sequence
+                        });
+                    }
+                    Some(_) => {}
+                    None => {
+                        parents.push(row);
+                        next.push(sequence);
+                    }
+                }
+            }
+            beams = next;
+
+            finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+            let best_live = beams.iter().map(|beam| beam.score).fold(f32::MIN, f32::max);
+            let done = finished.len() >= num_beams && finished[num_beams - 1].score >= best_live;
+            let generated = beams.first().map_or(0, |beam| beam.tokens.len());
+            if done || generated >= max_new_tokens {
+                break;
+            }
+
+            // Follow the surviving beams and score their next token
+            cache
+                .iter_mut()
+                .for_each(|cache| cache.fork_sequences(&parents));
+            let last: Vec<u32> = beams
+                .iter()
+                .map(|beam| *beam.tokens.last().unwrap())
+                .collect();
+            match self
+                .model
+                .forward_last(token_column(&last, &self.device), &mut cache)
+            {
+                Ok(next_logits) => logits = logits_rows(next_logits),
+                Err(err) => {
+                    tracing::warn!("stopping beam search: {err}");
+                    break;
+                }
+            }
+        }
+
+        // Beams still running when the search ended compete with the finished ones
+        finished.extend(beams);
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(beam.num_return_sequences.min(num_beams));
+        Ok(finished)
+    }
+
+    /// Sample `n` continuations in one batch, ranked by their mean log-probability
+    ///
+    /// Tokens are drawn with `sampling`; a sequence that stops leaves the batch while
+    /// the others continue. The same seed reproduces the same sequences.
+    pub fn sample(
+        &self,
+        prompt: &[u32],
+        n: usize,
+        max_new_tokens: usize,
+        sampling: &SamplingConfig,
+        stop: &StopConditions,
+    ) -> Result<Vec<ScoredSequence>> {
+        if n == 0 || max_new_tokens == 0 {
+            return Ok(vec![ScoredSequence::empty(); n]);
+        }
+        let (mut cache, logits) = self.prefill(prompt, n, max_new_tokens)?;
+        let first = logits_rows(logits).pop().expect("one row");
+        cache
+            .iter_mut()
+            .for_each(|cache| cache.fork_sequences(&vec![0; n]));
+
+        let mut sampler = Sampler::new(sampling.clone());
+        let mut sequences = vec![ScoredSequence::empty(); n];
+        // Sequences still generating, with the logits of their next token
+        let mut live: Vec<(usize, Vec<f32>)> = (0..n).map(|i| (i, first.clone())).collect();
+        while !live.is_empty() {
+            let mut rows = Vec::with_capacity(live.len());
+            for (row, (i, logits)) in live.iter_mut().enumerate() {
+                let logprobs = log_softmax(logits);
+                let history = [prompt, sequences[*i].tokens.as_slice()].concat();
+                let token = sampler.sample(logits, &history);
+                let sequence = &mut sequences[*i];
+                *sequence = sequence.extend(token, logprobs[token as usize], 1.0);
+
+                if let Some(reason) = stop.check(&sequence.tokens) {
+                    sequence.finish_reason = reason;
+                } else if sequence.tokens.len() < max_new_tokens {
+                    rows.push(row);
+                }
+            }
+            if rows.is_empty() {
+                break;
+            }
+
+            live = rows.iter().map(|&row| live[row].clone()).collect();
+            cache
+                .iter_mut()
+                .for_each(|cache| cache.fork_sequences(&rows));
+            let last: Vec<u32> = live
+                .iter()
+                .map(|(i, _)| *sequences[*i].tokens.last().unwrap())
+                .collect();
+            match self
+                .model
+                .forward_last(token_column(&last, &self.device), &mut cache)
+            {
+                Ok(next_logits) => {
+                    for ((_, logits), next) in live.iter_mut().zip(logits_rows(next_logits)) {
+                        *logits = next;
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!("stopping sampling: {err}");
+                    break;
+                }
+            }
+        }
+
+        sequences.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(sequences)
+    }
+
+    /// Prefill `prompt` into a paged cache with room for `batch_size` continuations of
+    /// up to `max_new_tokens`, returning the cache and the logits [1, vocab_size] of
+    /// the first generated token
+    fn prefill(
+        &self,
+        prompt: &[u32],
+        batch_size: usize,
+        max_new_tokens: usize,
+    ) -> Result<(Vec<KeyValueCache<B>>, Tensor<B, 2>)> {
+        if prompt.is_empty() {
+            return Err(ModelError::InvalidInput("empty prompt".to_string()));
+        }
+        let max_len = (prompt.len() + max_new_tokens).min(self.config.context_length());
+        // Every sequence may copy one shared block on write
+        let num_blocks = batch_size * (max_len.div_ceil(BLOCK_SIZE) + 1);
+        let pool = PagedKvPool::new(self.config, BLOCK_SIZE, num_blocks, &self.device);
+        let layout = CacheLayout::Paged(pool);
+        let mut cache = self
+            .model
+            .init_cache(self.config, batch_size, &layout, &self.device);
+
+        let input: Tensor<B, 2, Int> = token_column::<B>(prompt, &self.device).swap_dims(0, 1);
+        let chunk_size = DEFAULT_PREFILL_CHUNK_SIZE;
+        let logits = self.model.prefill(input, &mut cache, chunk_size, |_| {})?;
+        Ok((cache, logits))
+    }
+}
+
+/// Natural-log probabilities of `logits`
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_total = logits.iter().map(|&x| (x - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|&x| x - log_total).collect()
+}
+
+/// The `k` highest-scoring token ids, best first
+fn top_tokens(scores: &[f32], k: usize) -> Vec<u32> {
+    let mut tokens: Vec<u32> = (0..scores.len() as u32).collect();
+    let by_score = |a: &u32, b: &u32| scores[*b as usize].total_cmp(&scores[*a as usize]);
+    let k = k.min(tokens.len());
+    if k < tokens.len() {
+        tokens.select_nth_unstable_by(k, by_score);
+        tokens.truncate(k);
+    }
+    tokens.sort_by(by_score);
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::{generate, token_rows};
+    use burn::backend::NdArray;
+
+    type Backend = NdArray<f32>;
+
+    /// Log-probabilities of `tokens` after `prompt` from a single full forward pass
+    fn reference_logprobs(
+        model: &Qwen2ForCausalLM<Backend>,
+        config: &Qwen2Config,
+        prompt: &[u32],
+        tokens: &[u32],
+    ) -> Vec<f32> {
+        let device = Default::default();
+        let sequence = [prompt, tokens].concat();
+        let input = token_column::<Backend>(&sequence, &device).swap_dims(0, 1);
+        let mut cache = model.init_cache(config, 1, &CacheLayout::Contiguous, &device);
+        let logits = model.forward(input, &mut cache).unwrap();
+        let rows = logits_rows(logits.reshape([sequence.len(), config.vocab_size]));
+        tokens
+            .iter()
+            .enumerate()
+            .map(|(i, &token)| log_softmax(&rows[prompt.len() - 1 + i])[token as usize])
+            .collect()
+    }
+
+    fn assert_scores_match(
+        model: &Qwen2ForCausalLM<Backend>,
+        config: &Qwen2Config,
+        prompt: &[u32],
+        sequence: &ScoredSequence,
+    ) {
+        let expected = reference_logprobs(model, config, prompt, &sequence.tokens);
+        for (actual, expected) in sequence.token_logprobs.iter().zip(&expected) {
+            assert!((actual - expected).abs() < 1e-4, "{actual} != {expected}");
+        }
+        let total: f32 = expected.iter().sum();
+        assert!((sequence.cumulative_logprob - total).abs() < 1e-3);
+        assert!((0.0..=1.0).contains(&sequence.confidence()));
+    }
+
+    #[test]
+    fn test_beam_search() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let generator = NBestGenerator::new(&model, &config, &device);
+        let prompt = [5, 9, 13, 2, 7];
+        let stop = StopConditions::new();
+
+        // A single beam is greedy decoding
+        let input = token_column::<Backend>(&prompt, &device).swap_dims(0, 1);
+        let sampling = SamplingConfig::greedy();
+        let greedy = generate(&model, &config, input, 6, &sampling, &stop, &device);
+        let greedy = token_rows(greedy.tokens).remove(0)[prompt.len()..].to_vec();
+        let beam = BeamSearchConfig::new().with_num_beams(1);
+        let best = generator.beam_search(&prompt, 6, &beam, &stop).unwrap();
+        assert_eq!(best.len(), 1);
+        assert_eq!(best[0].tokens, greedy);
+        assert_scores_match(&model, &config, &prompt, &best[0]);
+
+        // Distinct hypotheses, best first, each scored as a full forward pass scores it
+        let beam = BeamSearchConfig::new()
+            .with_num_beams(4)
+            .with_num_return_sequences(4);
+        let hypotheses = generator.beam_search(&prompt, 6, &beam, &stop).unwrap();
+        assert_eq!(hypotheses.len(), 4);
+        for (i, hypothesis) in hypotheses.iter().enumerate() {
+            assert_eq!(hypothesis.tokens.len(), 6);
+            assert_eq!(hypothesis.finish_reason, FinishReason::Length);
+            assert!(
+                hypotheses[..i]
+                    .iter()
+                    .all(|other| other.tokens != hypothesis.tokens)
+            );
+            assert!(
+                hypotheses[..i]
+                    .iter()
+                    .all(|other| other.score >= hypothesis.score)
+            );
+            assert_scores_match(&model, &config, &prompt, hypothesis);
+        }
+        let empty = generator.beam_search(&prompt, 0, &beam, &stop).unwrap();
+        assert_eq!(empty.len(), 4);
+        assert!(empty.iter().all(|hypothesis| hypothesis.tokens.is_empty()));
+        let err = generator.beam_search(&[], 6, &beam, &stop).unwrap_err();
+        assert!(matches!(err, ModelError::InvalidInput(_)));
+
+        // A stop token finishes the hypotheses that produce it
+        let stop = StopConditions::new().with_stop_token_ids([hypotheses[0].tokens[2]]);
+        let beam = beam.with_length_penalty(0.0);
+        let hypotheses = generator.beam_search(&prompt, 6, &beam, &stop).unwrap();
+        assert!(
+            hypotheses
+                .iter()
+                .any(|h| matches!(h.finish_reason, FinishReason::StopToken(_)))
+        );
+        for hypothesis in &hypotheses {
+            assert_eq!(hypothesis.score, hypothesis.cumulative_logprob);
+            assert_scores_match(&model, &config, &prompt, hypothesis);
+        }
+    }
+
+    #[test]
+    fn test_sample_n() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let model = config.init::<Backend>(&device);
+        let generator = NBestGenerator::new(&model, &config, &device);
+        let prompt = [5, 9, 13, 2, 7];
+        let sampling = SamplingConfig::new().with_seed(7);
+        let stop = StopConditions::new();
+
+        let samples = generator.sample(&prompt, 5, 8, &sampling, &stop).unwrap();
+        assert_eq!(samples.len(), 5);
+        assert!(
+            samples
+                .windows(2)
+                .all(|pair| pair[0].score >= pair[1].score)
+        );
+        for sample in &samples {
+            assert_eq!(sample.tokens.len(), 8);
+            assert_scores_match(&model, &config, &prompt, sample);
+        }
+        assert_eq!(
+            generator.sample(&prompt, 5, 8, &sampling, &stop).unwrap(),
+            samples
+        );
+
+        // Sequences that stop leave the batch while the others keep generating
+        let stop = StopConditions::new().with_stop_token_ids([samples[0].tokens[3]]);
+        let samples = generator.sample(&prompt, 5, 8, &sampling, &stop).unwrap();
+        assert!(
+            samples
+                .iter()
+                .any(|s| matches!(s.finish_reason, FinishReason::StopToken(_)))
+        );
+        for sample in &samples {
+            match sample.finish_reason {
+                FinishReason::StopToken(token) => {
+                    assert_eq!(sample.tokens.last(), Some(&token));
+                    assert!(sample.tokens.len() <= 8);
+                }
+                _ => assert_eq!(sample.tokens.len(), 8),
+            }
+            assert_scores_match(&model, &config, &prompt, sample);
+        }
+    }
+}
@@ -0,0 +1,348 @@
+//! Speculative decoding with a small draft model
+//!
+//! A small checkpoint sharing the target's tokenizer (Qwen2.5-0.5B for the 14B model)
+//! proposes `num_draft_tokens` tokens one at a time; the target scores all of them in a
+//! single forward pass and keeps the longest prefix it agrees with, plus one token of
+//! its own. Drafts are accepted with probability `min(1, p / q)` and a rejection is
+//! resampled from `max(0, p - q)` (Leviathan et al., 2023), so the output follows the
+//! target's distribution exactly; under greedy decoding it is token-for-token what
+//! [`crate::inference::generate`] produces. Rejected positions are rolled back with
+//! [`crate::KeyValueCache::truncate`].
+
+use burn::{
+    config::Config,
+    tensor::{Int, Tensor, backend::Backend},
+};
+
+use crate::error::{ModelError, Result};
+use crate::inference::token_column;
+use crate::model::{CacheLayout, DEFAULT_PREFILL_CHUNK_SIZE, Qwen2Config, Qwen2ForCausalLM};
+use crate::sampling::{Sampler, SamplingConfig, logits_rows};
+use crate::stopping::{FinishReason, StopConditions};
+
+/// Configuration of speculative decoding
+#[derive(Config, Debug)]
+pub struct SpeculativeConfig {
+    /// Tokens the draft model proposes per target forward pass
+    #[config(default = "4")]
+    pub num_draft_tokens: usize,
+}
+
+/// Result of [`SpeculativeDecoder::generate`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeculativeOutput {
+    /// Generated tokens, prompt excluded, stop token included
+    pub tokens: Vec<u32>,
+    pub finish_reason: FinishReason,
+    /// Draft tokens proposed to the target
+    pub drafted: usize,
+    /// Draft tokens the target accepted
+    pub accepted: usize,
+    /// Target forward passes after the prompt, one per generated token without drafting
+    pub target_forwards: usize,
+}
+
+impl SpeculativeOutput {
+    /// Fraction of the drafted tokens the target accepted
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.drafted == 0 {
+            0.0
+        } else {
+            self.accepted as f64 / self.drafted as f64
+        }
+    }
+}
+
+/// A target model paired with the draft model that speculates for it
+pub struct SpeculativeDecoder<'a, B: Backend> {
+    target: &'a Qwen2ForCausalLM<B>,
+    target_config: &'a Qwen2Config,
+    draft: &'a Qwen2ForCausalLM<B>,
+    draft_config: &'a Qwen2Config,
+    config: SpeculativeConfig,
+}
+
+impl<'a, B: Backend> SpeculativeDecoder<'a, B> {
+    /// Pair `target` with `draft`, whose token ids must mean the same as the target's
+    pub fn new(
+        target: &'a Qwen2ForCausalLM<B>,
+        target_config: &'a Qwen2Config,
+        draft: &'a Qwen2ForCausalLM<B>,
+        draft_config: &'a Qwen2Config,
+        config: SpeculativeConfig,
+    ) -> Self {
+        Self {
+            target,
+            target_config,
+            draft,
+            draft_config,
+            config,
+        }
+    }
+
+    /// Generate up to `max_new_tokens` tokens after `prompt`
+    ///
+    /// Returns an error if the prompt is empty or does not fit in either model's
+    /// context; running out of context later ends the generation with
+    /// [`FinishReason::Length`].
+    pub fn generate(
+        &self,
+        prompt: &[u32],
+        max_new_tokens: usize,
+        sampling: &SamplingConfig,
+        stop: &StopConditions,
+        device: &B::Device,
+    ) -> Result<SpeculativeOutput> {
+        let Some((_, prefix)) = prompt.split_last() else {
+            return Err(ModelError::InvalidInput("empty prompt".to_string()));
+        };
+        let layout = CacheLayout::Contiguous;
+        let mut target_cache = self
+            .target
+            .init_cache(self.target_config, 1, &layout, device);
+        let mut draft_cache = self.draft.init_cache(self.draft_config, 1, &layout, device);
+
+        // Both caches hold everything but the last token, which each round feeds
+        if !prefix.is_empty() {
+            let chunk_size = DEFAULT_PREFILL_CHUNK_SIZE;
+            let input = token_row::<B>(prefix, device);
+            self.target
+                .prefill(input.clone(), &mut target_cache, chunk_size, |_| {})?;
+            self.draft
+                .prefill(input, &mut draft_cache, chunk_size, |_| {})?;
+        }
+
+        let mut target_sampler = Sampler::new(sampling.clone());
+        let mut draft_sampler = Sampler::new(sampling.clone());
+        let mut history = prompt.to_vec();
+        let mut output = SpeculativeOutput {
+            tokens: Vec::new(),
+            finish_reason: FinishReason::Length,
+            drafted: 0,
+            accepted: 0,
+            target_forwards: 0,
+        };
+
+        output.finish_reason = loop {
+            let generated = history.len() - prompt.len();
+            if generated >= max_new_tokens {
+                break FinishReason::Length;
+            }
+            // The target adds one token of its own, and both caches must hold the drafts
+            let num_draft = self
+                .config
+                .num_draft_tokens
+                .min(max_new_tokens - generated - 1)
+                .min(target_cache[0].capacity().saturating_sub(history.len()))
+                .min((draft_cache[0].capacity() + 1).saturating_sub(history.len()));
+
+            // Draft autoregressively, feeding whatever the draft cache has not seen yet
+            let mut context = history.clone();
+            let mut draft_probs = Vec::with_capacity(num_draft);
+            for _ in 0..num_draft {
+                let input = token_row::<B>(&context[draft_cache[0].len()..], device);
+                let logits = match self.draft.forward_last(input, &mut draft_cache) {
+                    Ok(logits) => logits,
+                    Err(err) => {
+                        tracing::debug!("drafting fewer tokens: {err}");
+                        break;
+                    }
+                };
+                let mut logits = logits_rows(logits).pop().expect("one row");
+                let probs = draft_sampler.distribution(&mut logits, &context);
+                context.push(draft_sampler.draw(&probs));
+                draft_probs.push(probs);
+            }
+            let drafts = context[history.len()..].to_vec();
+
+            // Score the last committed token and every draft in one target pass
+            let input = token_row::<B>(&context[target_cache[0].len()..], device);
+            let logits = match self.target.forward(input, &mut target_cache) {
+                Ok(logits) => logits,
+                Err(err) => {
+                    tracing::warn!("stopping generation: {err}");
+                    break FinishReason::Length;
+                }
+            };
+            output.target_forwards += 1;
+            output.drafted += drafts.len();
+            let [_, seq_len, vocab_size] = logits.dims();
+            let positions = drafts.len() + 1;
+            let rows = logits_rows(
+                logits
+                    .slice([0..1, seq_len - positions..seq_len, 0..vocab_size])
+                    .reshape([positions, vocab_size]),
+            );
+
+            let mut committed = Vec::with_capacity(positions);
+            for (i, mut row) in rows.into_iter().enumerate() {
+                let probs = target_sampler.distribution(&mut row, &context[..history.len() + i]);
+                let Some(&draft) = drafts.get(i) else {
+                    // Every draft was accepted: the last position yields a bonus token
+                    committed.push(target_sampler.draw(&probs));
+                    break;
+                };
+                let (p, q) = (
+                    probability(&probs, draft),
+                    probability(&draft_probs[i], draft),
+                );
+                if target_sampler.uniform() < (p / q) as f64 {
+                    committed.push(draft);
+                    output.accepted += 1;
+                } else {
+                    committed.push(target_sampler.draw(&residual(&probs, &draft_probs[i])));
+                    break;
+                }
+            }
+
+            let mut finished = None;
+            for token in committed {
+                history.push(token);
+                let generated = &history[prompt.len()..];
+                finished = stop.check(generated);
+                if finished.is_some() {
+                    break;
+                }
+            }
+
+            // Roll both caches back to the committed tokens, minus the one fed next
+            let keep = history.len() - 1;
+            target_cache
+                .iter_mut()
+                .for_each(|cache| cache.truncate(keep));
+            draft_cache
+                .iter_mut()
+                .for_each(|cache| cache.truncate(keep));
+
+            if let Some(reason) = finished {
+                break reason;
+            }
+        };
+
+        output.tokens = history.split_off(prompt.len());
+        Ok(output)
+    }
+}
+
+/// A [1, seq_len] token tensor
+fn token_row<B: Backend>(tokens: &[u32], device: &B::Device) -> Tensor<B, 2, Int> {
+    token_column::<B>(tokens, device).swap_dims(0, 1)
+}
+
+/// Probability of `token` in sparse `(token, probability)` pairs
+fn probability(probs: &[(u32, f32)], token: u32) -> f32 {
+    probs
+        .iter()
+        .find(|(candidate, _)| *candidate == token)
+        .map_or(0.0, |(_, p)| *p)
+}
+
+/// `max(0, p - q)`, renormalized: where the target puts more mass than the draft
+fn residual(target: &[(u32, f32)], draft: &[(u32, f32)]) -> Vec<(u32, f32)> {
+    let mut probs: Vec<(u32, f32)> = target
+        .iter()
+        .map(|&(token, p)| (token, (p - probability(draft, token)).max(0.0)))
+        .filter(|(_, p)| *p > 0.0)
+        .collect();
+    let total: f32 = probs.iter().map(|(_, p)| p).sum();
+    if total <= 0.0 {
+        // Only rounding can empty the residual; fall back to the target itself
+        return target.to_vec();
+    }
+    probs.iter_mut().for_each(|(_, p)| *p /= total);
+    probs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::{generate, token_rows};
+    use burn::backend::NdArray;
+    use burn::module::Module;
+
+    type Backend = NdArray<f32>;
+
+    #[test]
+    fn test_greedy_speculation_matches_target() {
+        let device = Default::default();
+        let config = Qwen2Config::tiny();
+        let target = config.init::<Backend>(&device);
+        let prompt = [5, 9, 13, 2, 7];
+        let sampling = SamplingConfig::greedy();
+        let stop = StopConditions::new();
+
+        let input = token_row::<Backend>(&prompt, &device);
+        let expected = generate(&target, &config, input, 12, &sampling, &stop, &device);
+        let expected = token_rows(expected.tokens).remove(0)[prompt.len()..].to_vec();
+
+        // A draft identical to the target is always right
+        let twin = config
+            .init::<Backend>(&device)
+            .load_record(target.clone().into_record());
+        let decoder = SpeculativeDecoder::new(
+            &target,
+            &config,
+            &twin,
+            &config,
+            SpeculativeConfig::new().with_num_draft_tokens(3),
+        );
+        let output = decoder
+            .generate(&prompt, 12, &sampling, &stop, &device)
+            .unwrap();
+        assert_eq!(output.tokens, expected);
+        assert_eq!(output.acceptance_rate(), 1.0);
+        assert_eq!(output.target_forwards, 3);
+
+        // An unrelated draft (one layer) is mostly wrong, which must not change the output
+        let draft_config = Qwen2Config {
+            num_hidden_layers: 1,
+            ..Qwen2Config::tiny()
+        };
+        let draft = draft_config.init::<Backend>(&device);
+        for num_draft_tokens in [1, 4, 20] {
+            let decoder = SpeculativeDecoder::new(
+                &target,
+                &config,
+                &draft,
+                &draft_config,
+                SpeculativeConfig::new().with_num_draft_tokens(num_draft_tokens),
+            );
+            let output = decoder
+                .generate(&prompt, 12, &sampling, &stop, &device)
+                .unwrap();
+            assert_eq!(output.tokens, expected, "{num_draft_tokens} draft tokens");
+            assert_eq!(output.finish_reason, FinishReason::Length);
+            assert!(output.acceptance_rate() <= 1.0);
+            assert_eq!(
+                output.target_forwards,
+                expected.len() - output.accepted,
+                "every pass adds one target token"
+            );
+        }
+
+        // Stopping mid-round discards the tokens after the stop token
+        let stop = StopConditions::new().with_stop_token_ids([expected[5]]);
+        let first_stop = expected.iter().position(|&t| t == expected[5]).unwrap();
+        let output = decoder
+            .generate(&prompt, 12, &sampling, &stop, &device)
+            .unwrap();
+        assert_eq!(output.tokens, expected[..=first_stop]);
+        assert_eq!(output.finish_reason, FinishReason::StopToken(expected[5]));
+
+        let err = decoder
+            .generate(&[], 12, &sampling, &stop, &device)
+            .unwrap_err();
+        assert!(matches!(err, ModelError::InvalidInput(_)));
+    }
+
+    #[test]
+    fn test_residual_distribution() {
+        let target = [(0, 0.5), (1, 0.3), (2, 0.2)];
+        let draft = [(1, 0.6), (0, 0.4)];
+        let residual = residual(&target, &draft);
+        // Only tokens the draft under-weights remain: 0 by 0.1 and 2 by 0.2
+        assert_eq!(residual.len(), 2);
+        assert!((probability(&residual, 0) - 1.0 / 3.0).abs() < 1e-6);
+        assert!((probability(&residual, 2) - 2.0 / 3.0).abs() < 1e-6);
+    }
+}
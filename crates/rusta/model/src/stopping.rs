@@ -0,0 +1,146 @@
+//! Stop conditions for generation
+
+use std::sync::Arc;
+
+use crate::model::Qwen2Config;
+
+/// `<|endoftext|>` in the Qwen2 vocabulary
+pub const QWEN_END_OF_TEXT: u32 = 151643;
+/// `<|im_end|>`, which closes every ChatML turn
+pub const QWEN_IM_END: u32 = 151645;
+
+/// Why a sequence stopped generating
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// Produced one of the stop token ids
+    StopToken(u32),
+    /// The decoded continuation contains one of the stop strings
+    StopString(String),
+    /// Reached `max_new_tokens` or the end of the KV cache
+    Length,
+    /// The consumer stopped the generation
+    Cancelled,
+}
+
+/// Turns token ids back into text, so stop strings can be matched
+pub trait TokenDecoder {
+    fn decode(&self, tokens: &[u32]) -> String;
+}
+
+/// When a sequence should stop: stop token ids and decoded stop strings
+#[derive(Clone, Default)]
+pub struct StopConditions {
+    stop_token_ids: Vec<u32>,
+    stop_strings: Vec<String>,
+    decoder: Option<Arc<dyn TokenDecoder + Send + Sync>>,
+}
+
+impl StopConditions {
+    /// No stop conditions: generation always runs to `max_new_tokens`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop on the model's `eos_token_id`
+    pub fn from_config(config: &Qwen2Config) -> Self {
+        Self::new().with_stop_token_ids([config.eos_token_id as u32])
+    }
+
+    /// Stop on either of the tokens Qwen chat models end a turn with
+    pub fn qwen_chat() -> Self {
+        Self::new().with_stop_token_ids([QWEN_IM_END, QWEN_END_OF_TEXT])
+    }
+
+    pub fn with_stop_token_ids(mut self, ids: impl IntoIterator<Item = u32>) -> Self {
+        for id in ids {
+            if !self.stop_token_ids.contains(&id) {
+                self.stop_token_ids.push(id);
+            }
+        }
+        self
+    }
+
+    /// Stop once the decoded continuation contains any of `strings`
+    pub fn with_stop_strings(
+        mut self,
+        strings: impl IntoIterator<Item = impl Into<String>>,
+        decoder: Arc<dyn TokenDecoder + Send + Sync>,
+    ) -> Self {
+        self.stop_strings.extend(
+            strings
+                .into_iter()
+                .map(Into::into)
+                .filter(|s| !s.is_empty()),
+        );
+        self.decoder = Some(decoder);
+        self
+    }
+
+    pub fn stop_token_ids(&self) -> &[u32] {
+        &self.stop_token_ids
+    }
+
+    /// Token used to pad sequences that finished before the rest of their batch
+    pub fn pad_token_id(&self) -> Option<u32> {
+        self.stop_token_ids.first().copied()
+    }
+
+    /// Check the continuation generated so far (prompt excluded)
+    pub fn check(&self, generated: &[u32]) -> Option<FinishReason> {
+        let &last = generated.last()?;
+        if self.stop_token_ids.contains(&last) {
+            return Some(FinishReason::StopToken(last));
+        }
+
+        let decoder = self.decoder.as_ref()?;
+        // Every token decodes to at least one byte, so a stop string of `n` bytes
+        // cannot span more than `n` tokens; one more covers a partial first token.
+        let window = self.stop_strings.iter().map(String::len).max()? + 1;
+        let tail = &generated[generated.len().saturating_sub(window)..];
+        let text = decoder.decode(tail);
+
+        self.stop_strings
+            .iter()
+            .find(|stop| text.contains(stop.as_str()))
+            .map(|stop| FinishReason::StopString(stop.clone()))
+    }
+}
+
+impl std::fmt::Debug for StopConditions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StopConditions")
+            .field("stop_token_ids", &self.stop_token_ids)
+            .field("stop_strings", &self.stop_strings)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes token `t` as the ASCII letter `'a' + t`
+    struct Letters;
+
+    impl TokenDecoder for Letters {
+        fn decode(&self, tokens: &[u32]) -> String {
+            tokens.iter().map(|&t| (b'a' + t as u8) as char).collect()
+        }
+    }
+
+    #[test]
+    fn test_stop_tokens_and_strings() {
+        let stop = StopConditions::qwen_chat().with_stop_strings(["cab"], Arc::new(Letters));
+
+        assert_eq!(stop.check(&[]), None);
+        assert_eq!(stop.check(&[0, 1]), None);
+        assert_eq!(
+            stop.check(&[3, QWEN_IM_END]),
+            Some(FinishReason::StopToken(QWEN_IM_END))
+        );
+        assert_eq!(
+            stop.check(&[3, 3, 2, 0, 1]),
+            Some(FinishReason::StopString("cab".to_string()))
+        );
+    }
+}
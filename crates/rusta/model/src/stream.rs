@@ -0,0 +1,383 @@
+//! Streaming token generation
+//!
+//! [`TokenStream`] owns the KV-cache decode loop. It runs one forward pass per step and
+//! yields a [`StreamEvent`] for every token as soon as it is sampled, so a UI can print
+//! text while the model is still generating. Long prompts are prefilled a chunk per step,
+//! with a [`StreamEvent::Prefill`] after each, so the UI can show progress before the
+//! first token. [`crate::inference::generate`] drains the same stream to produce its
+//! batched output.
+
+use std::collections::VecDeque;
+
+use burn::tensor::{Int, Tensor, backend::Backend};
+
+use crate::constrained::GrammarConstraint;
+use crate::inference::{GenerationOutput, token_column};
+use crate::model::{
+    CacheLayout, DEFAULT_PREFILL_CHUNK_SIZE, KeyValueCache, Qwen2Config, Qwen2ForCausalLM,
+};
+use crate::prefix_cache::PrefixCache;
+use crate::sampling::{Sampler, logits_rows};
+use crate::stopping::{FinishReason, StopConditions};
+
+/// An event produced by [`TokenStream`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// `processed` of the `total` prompt tokens are in the cache
+    Prefill { processed: usize, total: usize },
+    /// Sequence `sequence` produced `token_id`
+    Token {
+        sequence: usize,
+        token_id: u32,
+        /// Log-probability of the token under the model, when requested
+        logprob: Option<f32>,
+    },
+    /// Sequence `sequence` will not produce further tokens
+    Finished {
+        sequence: usize,
+        reason: FinishReason,
+    },
+}
+
+/// Iterator over the tokens of an in-progress generation
+///
+/// Created by [`crate::inference::generate_stream`]. Dropping the stream stops the
+/// generation; [`TokenStream::cancel`] does the same while still reporting
+/// [`FinishReason::Cancelled`] for the sequences that were running.
+pub struct TokenStream<'a, B: Backend> {
+    model: &'a Qwen2ForCausalLM<B>,
+    config: Qwen2Config,
+    cache: Vec<KeyValueCache<B>>,
+    sampler: Sampler,
+    stop: StopConditions,
+    device: B::Device,
+    /// Prompt tokens not yet in the cache, fed a chunk per step
+    prompt: Option<Tensor<B, 2, Int>>,
+    prompt_len: usize,
+    prefill_chunk_size: usize,
+    /// Prompt plus generated tokens of every sequence
+    history: Vec<Vec<u32>>,
+    finish_reasons: Vec<Option<FinishReason>>,
+    /// Sequence held by each row of the batch; finished sequences are dropped from it
+    rows: Vec<usize>,
+    max_new_tokens: usize,
+    steps: usize,
+    pad_token_id: u32,
+    logprobs: bool,
+    constraint: Option<GrammarConstraint>,
+    pending: VecDeque<StreamEvent>,
+}
+
+impl<'a, B: Backend> TokenStream<'a, B> {
+    pub(crate) fn new(
+        model: &'a Qwen2ForCausalLM<B>,
+        config: &Qwen2Config,
+        input_ids: Tensor<B, 2, Int>,
+        max_new_tokens: usize,
+        sampler: Sampler,
+        stop: StopConditions,
+        device: &B::Device,
+    ) -> Self {
+        let [batch_size, prompt_len] = input_ids.dims();
+        let pad_token_id = stop.pad_token_id().unwrap_or(config.eos_token_id as u32);
+
+        Self {
+            model,
+            config: config.clone(),
+            cache: model.init_cache(config, batch_size, &CacheLayout::Contiguous, device),
+            sampler,
+            stop,
+            device: device.clone(),
+            history: crate::inference::token_rows(input_ids.clone()),
+            prompt: Some(input_ids),
+            prompt_len,
+            prefill_chunk_size: DEFAULT_PREFILL_CHUNK_SIZE,
+            finish_reasons: vec![None; batch_size],
+            rows: (0..batch_size).collect(),
+            max_new_tokens,
+            steps: 0,
+            pad_token_id,
+            logprobs: false,
+            constraint: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Attach the log-probability of each sampled token to [`StreamEvent::Token`]
+    pub fn with_logprobs(mut self) -> Self {
+        self.logprobs = true;
+        self
+    }
+
+    /// Only sample tokens that keep each sequence within `constraint`'s grammar
+    ///
+    /// The stop tokens become available once a sequence's output is complete, so they
+    /// should include the model's end-of-turn token.
+    pub fn with_constraint(mut self, constraint: GrammarConstraint) -> Self {
+        self.constraint = Some(constraint);
+        self
+    }
+
+    /// Feed the prompt `chunk_size` tokens per step (default
+    /// [`DEFAULT_PREFILL_CHUNK_SIZE`])
+    ///
+    /// # Panics
+    /// If `chunk_size` is zero.
+    pub fn with_prefill_chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "prefill chunk size must be positive");
+        self.prefill_chunk_size = chunk_size;
+        self
+    }
+
+    /// Keep the KV cache in `layout` instead of one contiguous buffer per layer
+    ///
+    /// A [`CacheLayout::Rolling`] buffer lets the generation run past the context
+    /// length. Call it before [`Self::with_prefix_cache`].
+    ///
+    /// # Panics
+    /// If the stream has already processed tokens.
+    pub fn with_cache_layout(mut self, layout: &CacheLayout<B>) -> Self {
+        assert!(
+            self.steps == 0 && self.cache.iter().all(KeyValueCache::is_empty),
+            "the cache layout must be chosen before the stream starts"
+        );
+        let batch_size = self.history.len();
+        self.cache = self
+            .model
+            .init_cache(&self.config, batch_size, layout, &self.device);
+        self
+    }
+
+    /// Resume from the longest prefix of the prompt stored in `prefix_cache`
+    ///
+    /// Only single-sequence generations are resumed, and only before the first step.
+    pub fn with_prefix_cache(mut self, prefix_cache: &mut PrefixCache<B>) -> Self {
+        let Some(prompt) = self.prompt.take() else {
+            return self;
+        };
+        if self.history.len() != 1 {
+            self.prompt = Some(prompt);
+            return self;
+        }
+
+        self.prompt = Some(
+            match prefix_cache.restore(&self.history[0], &mut self.cache) {
+                Ok(0) => prompt,
+                Ok(restored) => {
+                    tracing::debug!("resuming from {restored} cached prompt tokens");
+                    prompt.slice([0..1, restored..self.prompt_len])
+                }
+                Err(err) => {
+                    tracing::warn!("ignoring cached prefix: {err}");
+                    self.cache.iter_mut().for_each(KeyValueCache::reset);
+                    prompt
+                }
+            },
+        );
+        self
+    }
+
+    /// Store the tokens processed so far in `prefix_cache`, for later requests to resume
+    ///
+    /// Only single-sequence generations are stored.
+    pub fn save_prefix(&self, prefix_cache: &mut PrefixCache<B>) {
+        if let [row] = self.history.as_slice() {
+            let len = self.cache.first().map_or(0, KeyValueCache::len);
+            prefix_cache.insert(&row[..len], &self.cache);
+        }
+    }
+
+    /// Stop generating; running sequences finish with [`FinishReason::Cancelled`]
+    pub fn cancel(&mut self) {
+        self.finish_running(FinishReason::Cancelled);
+    }
+
+    /// Whether every sequence has finished
+    pub fn is_finished(&self) -> bool {
+        self.finish_reasons.iter().all(Option::is_some)
+    }
+
+    /// Why each sequence stopped, `None` while it is still running
+    pub fn finish_reasons(&self) -> &[Option<FinishReason>] {
+        &self.finish_reasons
+    }
+
+    /// Sequences still in the batch, in row order
+    pub fn running_sequences(&self) -> &[usize] {
+        &self.rows
+    }
+
+    /// Tokens generated so far by `sequence`, prompt excluded
+    pub fn generated(&self, sequence: usize) -> &[u32] {
+        &self.history[sequence][self.prompt_len..]
+    }
+
+    /// Run the stream to completion and collect the batched result
+    pub fn into_output(mut self) -> GenerationOutput<B> {
+        self.by_ref().for_each(drop);
+
+        let width = self.prompt_len + self.steps;
+        let num_generated = self
+            .history
+            .iter()
+            .map(|row| row.len() - self.prompt_len)
+            .collect();
+
+        let rows: Vec<Tensor<B, 2, Int>> = self
+            .history
+            .iter()
+            .map(|row| {
+                let mut row = row.clone();
+                row.resize(width, self.pad_token_id);
+                token_column::<B>(&row, &self.device).swap_dims(0, 1)
+            })
+            .collect();
+
+        GenerationOutput {
+            tokens: Tensor::cat(rows, 0),
+            num_generated,
+            finish_reasons: self
+                .finish_reasons
+                .into_iter()
+                .map(|reason| reason.unwrap_or(FinishReason::Length))
+                .collect(),
+        }
+    }
+
+    fn finish_running(&mut self, reason: FinishReason) {
+        for (sequence, slot) in self.finish_reasons.iter_mut().enumerate() {
+            if slot.is_none() {
+                *slot = Some(reason.clone());
+                self.pending.push_back(StreamEvent::Finished {
+                    sequence,
+                    reason: reason.clone(),
+                });
+            }
+        }
+    }
+
+    /// Run one decode step, queueing the resulting events
+    fn step(&mut self) {
+        if self.steps == self.max_new_tokens {
+            self.finish_running(FinishReason::Length);
+            return;
+        }
+
+        // The prompt a chunk at a time, then the last token of every running sequence
+        let prompt_chunk = self.prompt.take().map(|prompt| {
+            let [batch_size, remaining] = prompt.dims();
+            let chunk = self.prefill_chunk_size;
+            if remaining > chunk {
+                self.prompt = Some(prompt.clone().slice([0..batch_size, chunk..remaining]));
+                prompt.slice([0..batch_size, 0..chunk])
+            } else {
+                prompt
+            }
+        });
+        let prefilling = prompt_chunk.is_some();
+        let input = prompt_chunk.unwrap_or_else(|| {
+            let last: Vec<u32> = self
+                .rows
+                .iter()
+                .map(|&sequence| *self.history[sequence].last().expect("non-empty prompt"))
+                .collect();
+            token_column::<B>(&last, &self.device)
+        });
+
+        // Running out of context ends the generation like reaching `max_new_tokens`
+        let last_logits = match self.model.forward_last(input, &mut self.cache) {
+            Ok(logits) => logits,
+            Err(err) => {
+                tracing::warn!("stopping generation: {err}");
+                self.finish_running(FinishReason::Length);
+                return;
+            }
+        };
+
+        if prefilling {
+            let remaining = self.prompt.as_ref().map_or(0, |prompt| prompt.dims()[1]);
+            self.pending.push_back(StreamEvent::Prefill {
+                processed: self.prompt_len - remaining,
+                total: self.prompt_len,
+            });
+            // Sample only once the whole prompt has been seen
+            if remaining > 0 {
+                return;
+            }
+        }
+
+        let rows = logits_rows(last_logits);
+        for (&sequence, mut row_logits) in self.rows.iter().zip(rows) {
+            let raw_logits = self.logprobs.then(|| row_logits.clone());
+            if let Some(constraint) = &mut self.constraint {
+                constraint.mask_logits(sequence, &mut row_logits, self.stop.stop_token_ids());
+            }
+            let token_id = self
+                .sampler
+                .sample(&mut row_logits, &self.history[sequence]);
+            if let Some(constraint) = &mut self.constraint
+                && !constraint.accept_token(sequence, token_id)
+            {
+                tracing::warn!("sequence {sequence} left its grammar with token {token_id}");
+            }
+            let logprob = raw_logits.map(|logits| log_softmax_at(&logits, token_id as usize));
+
+            self.history[sequence].push(token_id);
+            self.pending.push_back(StreamEvent::Token {
+                sequence,
+                token_id,
+                logprob,
+            });
+
+            let generated = &self.history[sequence][self.prompt_len..];
+            if let Some(reason) = self.stop.check(generated) {
+                self.finish_reasons[sequence] = Some(reason.clone());
+                self.pending
+                    .push_back(StreamEvent::Finished { sequence, reason });
+            }
+        }
+
+        self.steps += 1;
+        self.drop_finished_rows();
+    }
+
+    /// Remove finished sequences from the batch and the cache, so later steps neither
+    /// feed them nor grow their cache rows
+    fn drop_finished_rows(&mut self) {
+        let live: Vec<usize> = (0..self.rows.len())
+            .filter(|&row| self.finish_reasons[self.rows[row]].is_none())
+            .collect();
+        // Once everything has finished the stream stops, and the cache keeps the last
+        // batch for `save_prefix`
+        if live.len() == self.rows.len() || live.is_empty() {
+            return;
+        }
+        self.cache
+            .iter_mut()
+            .for_each(|cache| cache.fork_sequences(&live));
+        self.rows = live.iter().map(|&row| self.rows[row]).collect();
+    }
+}
+
+impl<B: Backend> Iterator for TokenStream<'_, B> {
+    type Item = StreamEvent;
+
+    fn next(&mut self) -> Option<StreamEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            if self.is_finished() {
+                return None;
+            }
+            self.step();
+        }
+    }
+}
+
+/// `log_softmax(logits)[index]`
+pub fn log_softmax_at(logits: &[f32], index: usize) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|&logit| (logit - max).exp()).sum();
+    logits[index] - max - sum.ln()
+}
@@ -0,0 +1,946 @@
+//! HuggingFace safetensors weight loading and export
+//!
+//! A checkpoint is either a single `model.safetensors` file or a set of shards listed in
+//! `model.safetensors.index.json`. Shards are read one at a time: their tensors are renamed
+//! from the HuggingFace layout onto the [`Qwen2ForCausalLM`] module tree and applied before
+//! the next shard is opened, so peak memory stays at roughly one shard plus the model.
+//! [`save_safetensors`] reverses the mapping to write a model back out in the same layout.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use burn::{
+    config::Config,
+    module::ParamId,
+    store::{ApplyError, ModuleSnapshot, TensorSnapshot, TensorSnapshotError},
+    tensor::{DType, TensorData, backend::Backend, bf16, f16},
+};
+use safetensors::{Dtype, SafeTensors, tensor::TensorView};
+use serde::Deserialize;
+
+use crate::error::{ModelError, Result};
+use crate::model::{Qwen2Config, Qwen2ForCausalLM};
+use crate::quant::QuantConfig;
+
+/// File name of a single-file checkpoint
+pub const SAFETENSORS_FILE: &str = "model.safetensors";
+/// File name of the shard index of a sharded checkpoint
+pub const SAFETENSORS_INDEX_FILE: &str = "model.safetensors.index.json";
+/// File name of the HuggingFace model configuration
+pub const CONFIG_FILE: &str = "config.json";
+
+/// Resolve the safetensors files making up a checkpoint
+///
+/// `path` may be a model directory, a shard index or a single safetensors file.
+pub fn checkpoint_files(path: &Path) -> Result<Vec<PathBuf>> {
+    if path.is_dir() {
+        let index = path.join(SAFETENSORS_INDEX_FILE);
+        if index.is_file() {
+            return shard_files(&index);
+        }
+        return Ok(vec![path.join(SAFETENSORS_FILE)]);
+    }
+
+    if path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".index.json"))
+    {
+        return shard_files(path);
+    }
+
+    Ok(vec![path.to_path_buf()])
+}
+
+#[derive(Deserialize)]
+struct ShardIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// Read `model.safetensors.index.json` and list the distinct shard files it references
+fn shard_files(index_path: &Path) -> Result<Vec<PathBuf>> {
+    let json = std::fs::read_to_string(index_path).map_err(|source| ModelError::Io {
+        path: index_path.to_path_buf(),
+        source,
+    })?;
+    let index: ShardIndex =
+        serde_json::from_str(&json).map_err(|source| ModelError::ShardIndex {
+            path: index_path.to_path_buf(),
+            source,
+        })?;
+
+    let dir = index_path.parent().unwrap_or_else(|| Path::new("."));
+    let shards: BTreeSet<&String> = index.weight_map.values().collect();
+    Ok(shards.into_iter().map(|shard| dir.join(shard)).collect())
+}
+
+/// Load HuggingFace safetensors weights into `model`
+///
+/// Every parameter of the model must be covered by the checkpoint and every checkpoint
+/// tensor must map onto a parameter; otherwise [`ModelError::MissingWeights`] or
+/// [`ModelError::UnexpectedWeights`] is returned.
+///
+/// With `quantize`, the linear layers loaded from each shard are quantized before the
+/// next shard is read, so the full-precision weights never all reside in memory.
+pub fn load_safetensors<B: Backend>(
+    mut model: Qwen2ForCausalLM<B>,
+    config: &Qwen2Config,
+    path: &Path,
+    quantize: Option<&QuantConfig>,
+) -> Result<Qwen2ForCausalLM<B>> {
+    let mut loader = WeightLoader::new(config, quantize);
+
+    for shard in checkpoint_files(path)? {
+        tracing::debug!("loading weights from {}", shard.display());
+
+        let bytes = Rc::new(std::fs::read(&shard).map_err(|source| ModelError::Io {
+            path: shard.clone(),
+            source,
+        })?);
+        let (header_len, metadata) =
+            SafeTensors::read_metadata(&bytes).map_err(|source| ModelError::Safetensors {
+                path: shard.clone(),
+                source,
+            })?;
+        let data_start = 8 + header_len;
+
+        let mut tensors = Vec::new();
+        for (name, info) in metadata.tensors() {
+            let Some(dtype) = burn_dtype(info.dtype) else {
+                // Report names the model does not know as unexpected rather than failing
+                if !map_hf_name(&name, config).is_empty() {
+                    return Err(ModelError::UnsupportedDtype {
+                        name,
+                        dtype: format!("{:?}", info.dtype),
+                    });
+                }
+                tensors.push(CheckpointTensor::unreadable(name, info.shape.clone()));
+                continue;
+            };
+            let (start, end) = info.data_offsets;
+            let range = data_start + start..data_start + end;
+
+            let bytes = bytes.clone();
+            let shape = info.shape.clone();
+            tensors.push(CheckpointTensor {
+                name,
+                shape: info.shape.clone(),
+                data: Rc::new(move || {
+                    Ok(TensorData::from_bytes_vec(
+                        bytes[range.clone()].to_vec(),
+                        shape.clone(),
+                        dtype,
+                    )
+                    .convert::<f32>())
+                }),
+            });
+        }
+
+        model = loader.apply(model, tensors)?;
+    }
+
+    loader.finish()?;
+    Ok(model)
+}
+
+/// A checkpoint tensor under its HuggingFace name and `[out, in]` layout, read on demand
+pub(crate) struct CheckpointTensor {
+    pub name: String,
+    pub shape: Vec<usize>,
+    /// Produces the values as f32
+    pub data: Rc<dyn Fn() -> Result<TensorData, TensorSnapshotError>>,
+}
+
+impl CheckpointTensor {
+    /// A tensor whose data cannot be read; fine as long as the model does not use it
+    fn unreadable(name: String, shape: Vec<usize>) -> Self {
+        let message = format!("tensor `{name}` cannot be read");
+        Self {
+            name,
+            shape,
+            data: Rc::new(move || Err(TensorSnapshotError::DataError(message.clone()))),
+        }
+    }
+}
+
+/// Applies batches of checkpoint tensors to a model and checks that, once every batch
+/// is in, each parameter was covered exactly by the checkpoint
+pub(crate) struct WeightLoader<'a> {
+    config: &'a Qwen2Config,
+    quantize: Option<&'a QuantConfig>,
+    visited: HashSet<String>,
+    applied: HashSet<String>,
+    unexpected: Vec<String>,
+}
+
+impl<'a> WeightLoader<'a> {
+    pub(crate) fn new(config: &'a Qwen2Config, quantize: Option<&'a QuantConfig>) -> Self {
+        Self {
+            config,
+            quantize,
+            visited: HashSet::new(),
+            applied: HashSet::new(),
+            unexpected: Vec::new(),
+        }
+    }
+
+    /// Rename `tensors` onto the module tree and load them into `model`
+    ///
+    /// With quantization enabled, the linear layers loaded by this batch are quantized
+    /// before returning.
+    pub(crate) fn apply<B: Backend>(
+        &mut self,
+        mut model: Qwen2ForCausalLM<B>,
+        tensors: Vec<CheckpointTensor>,
+    ) -> Result<Qwen2ForCausalLM<B>> {
+        let head_dim = self.config.head_dim();
+
+        // Module path -> checkpoint name, to report errors in checkpoint terms
+        let mut origin = HashMap::new();
+        let mut snapshots = Vec::new();
+
+        for tensor in tensors {
+            let targets = map_hf_name(&tensor.name, self.config);
+            if targets.is_empty() {
+                if !is_ignored(&tensor.name) {
+                    self.unexpected.push(tensor.name);
+                }
+                continue;
+            }
+
+            for target in targets {
+                let shape = target.shape(&tensor.shape);
+                let path_stack: Vec<String> = target.path.split('.').map(String::from).collect();
+                origin.insert(target.path.clone(), tensor.name.clone());
+
+                let data = tensor.data.clone();
+                let data_fn = Rc::new(move || target.apply(data()?, head_dim));
+
+                snapshots.push(TensorSnapshot::from_closure(
+                    data_fn,
+                    DType::F32,
+                    shape,
+                    path_stack,
+                    Vec::new(),
+                    ParamId::new(),
+                ));
+            }
+        }
+
+        let result = model.apply(snapshots, None, None);
+
+        if let Some(error) = result.errors.into_iter().next() {
+            return Err(match error {
+                ApplyError::ShapeMismatch {
+                    path,
+                    expected,
+                    found,
+                } => ModelError::ShapeMismatch {
+                    name: origin.remove(&path).unwrap_or(path),
+                    expected,
+                    found,
+                },
+                other => ModelError::Tensor(other.to_string()),
+            });
+        }
+
+        self.unexpected.extend(
+            result
+                .unused
+                .into_iter()
+                .map(|path| origin.remove(&path).unwrap_or(path)),
+        );
+        if let Some(quant) = self.quantize {
+            let loaded: HashSet<&str> = result.applied.iter().map(String::as_str).collect();
+            model = model.quantize_where(quant, |path| loaded.contains(path));
+        }
+
+        // Codes and scales of layers quantized by an earlier batch are not checkpoint tensors
+        let missing = result.missing.into_iter();
+        self.visited
+            .extend(missing.filter(|path| !is_quantized_param(path)));
+        self.visited.extend(result.applied.iter().cloned());
+        self.applied.extend(result.applied);
+        Ok(model)
+    }
+
+    /// Report parameters no batch covered and tensors the model does not use
+    pub(crate) fn finish(self) -> Result<()> {
+        let mut missing: Vec<String> = self.visited.difference(&self.applied).cloned().collect();
+        let mut unexpected = self.unexpected;
+        missing.sort();
+        unexpected.sort();
+        if !missing.is_empty() {
+            return Err(ModelError::MissingWeights {
+                missing,
+                unexpected,
+            });
+        }
+        if !unexpected.is_empty() {
+            return Err(ModelError::UnexpectedWeights { unexpected });
+        }
+        Ok(())
+    }
+}
+
+/// Element type of exported weights
+#[derive(Config, Debug, Copy, PartialEq, Eq)]
+pub enum ExportDtype {
+    F32,
+    F16,
+    BF16,
+}
+
+impl ExportDtype {
+    fn size(&self) -> usize {
+        match self {
+            ExportDtype::F32 => 4,
+            ExportDtype::F16 | ExportDtype::BF16 => 2,
+        }
+    }
+
+    fn safetensors(&self) -> Dtype {
+        match self {
+            ExportDtype::F32 => Dtype::F32,
+            ExportDtype::F16 => Dtype::F16,
+            ExportDtype::BF16 => Dtype::BF16,
+        }
+    }
+
+    /// Name of the dtype in `config.json`
+    fn torch_name(&self) -> &'static str {
+        match self {
+            ExportDtype::F32 => "float32",
+            ExportDtype::F16 => "float16",
+            ExportDtype::BF16 => "bfloat16",
+        }
+    }
+
+    fn encode(&self, values: &[f32]) -> Vec<u8> {
+        match self {
+            ExportDtype::F32 => values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ExportDtype::F16 => values
+                .iter()
+                .flat_map(|&v| f16::from_f32(v).to_le_bytes())
+                .collect(),
+            ExportDtype::BF16 => values
+                .iter()
+                .flat_map(|&v| bf16::from_f32(v).to_le_bytes())
+                .collect(),
+        }
+    }
+}
+
+/// Configuration for [`save_safetensors`]
+#[derive(Config, Debug)]
+pub struct ExportConfig {
+    #[config(default = "ExportDtype::F32")]
+    pub dtype: ExportDtype,
+    /// A new shard is started before one would grow past this size
+    #[config(default = "5_000_000_000")]
+    pub max_shard_bytes: usize,
+}
+
+/// Whether `path` is the codes or scales of a quantized linear layer
+fn is_quantized_param(path: &str) -> bool {
+    path.contains(".quantized.")
+}
+
+/// A parameter to export under its HuggingFace name, read on demand
+struct ExportTensor<'a> {
+    name: String,
+    /// `[out, in]` layout
+    shape: Vec<usize>,
+    data: Box<dyn Fn() -> Result<Vec<f32>> + 'a>,
+}
+
+/// Write `model` to `dir` as a HuggingFace checkpoint: `config.json` and either a single
+/// `model.safetensors` or numbered shards with a `model.safetensors.index.json`
+///
+/// Tensor names and layouts are the inverse of [`load_safetensors`], so the output loads
+/// back into an identical model (and into `transformers`). Quantized linear layers are
+/// written dequantized. Shards are materialised one at a time.
+pub fn save_safetensors<B: Backend>(
+    model: &Qwen2ForCausalLM<B>,
+    config: &Qwen2Config,
+    dir: &Path,
+    export: &ExportConfig,
+) -> Result<()> {
+    let io = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| ModelError::Io { path, source }
+    };
+    std::fs::create_dir_all(dir).map_err(io(dir))?;
+
+    let head_dim = config.head_dim();
+    let sources = export_sources(config);
+    let target_of = |path: &str| {
+        sources
+            .get(path)
+            .ok_or_else(|| ModelError::Tensor(format!("no HuggingFace name for `{path}`")))
+    };
+
+    let mut tensors = Vec::new();
+    for snapshot in model.collect(None, None) {
+        let path = snapshot.full_path();
+        if is_quantized_param(&path) {
+            // Written below, dequantized under the dense weight's name
+            continue;
+        }
+        let (name, target) = target_of(&path)?;
+        let target = target.clone();
+        let shape = target.source_shape(&snapshot.shape);
+        tensors.push(ExportTensor {
+            name: name.clone(),
+            shape,
+            data: Box::new(move || {
+                let data = snapshot
+                    .to_data()
+                    .map_err(|e| ModelError::Tensor(e.to_string()))?;
+                let dims = data.shape.clone();
+                let values = data
+                    .convert::<f32>()
+                    .into_vec::<f32>()
+                    .map_err(|e| ModelError::Tensor(format!("{e:?}")))?;
+                Ok(target.revert(values, &dims, head_dim))
+            }),
+        });
+    }
+    for (path, weight) in model.quantized_weights() {
+        let (name, target) = target_of(&path)?;
+        let target = target.clone();
+        let dims = weight.dims();
+        tensors.push(ExportTensor {
+            name: name.clone(),
+            shape: target.source_shape(&dims),
+            data: Box::new(move || {
+                let values = weight
+                    .dequantize()
+                    .into_data()
+                    .convert::<f32>()
+                    .into_vec::<f32>()
+                    .map_err(|e| ModelError::Tensor(format!("{e:?}")))?;
+                Ok(target.revert(values, &dims, head_dim))
+            }),
+        });
+    }
+    tensors.sort_by_cached_key(|tensor| export_order(&tensor.name));
+
+    // Greedily fill shards in checkpoint order
+    let mut shards: Vec<Vec<ExportTensor>> = vec![Vec::new()];
+    let mut shard_bytes = 0;
+    for tensor in tensors {
+        let bytes = tensor.shape.iter().product::<usize>() * export.dtype.size();
+        let current = shards.last_mut().expect("at least one shard");
+        if !current.is_empty() && shard_bytes + bytes > export.max_shard_bytes {
+            shards.push(Vec::new());
+            shard_bytes = 0;
+        }
+        shard_bytes += bytes;
+        shards.last_mut().expect("at least one shard").push(tensor);
+    }
+
+    let num_shards = shards.len();
+    let mut weight_map = HashMap::new();
+    let mut total_size = 0;
+    let metadata = Some(HashMap::from([("format".to_string(), "pt".to_string())]));
+    for (index, shard) in shards.into_iter().enumerate() {
+        let file_name = if num_shards == 1 {
+            SAFETENSORS_FILE.to_string()
+        } else {
+            format!("model-{:05}-of-{num_shards:05}.safetensors", index + 1)
+        };
+        let path = dir.join(&file_name);
+        tracing::debug!("writing {} tensors to {}", shard.len(), path.display());
+
+        let data = shard
+            .iter()
+            .map(|tensor| Ok((tensor.name.clone(), export.dtype.encode(&(tensor.data)()?))))
+            .collect::<Result<Vec<_>>>()?;
+        let views = data
+            .iter()
+            .zip(&shard)
+            .map(|((name, bytes), tensor)| {
+                total_size += bytes.len();
+                weight_map.insert(name.clone(), file_name.clone());
+                let view = TensorView::new(export.dtype.safetensors(), tensor.shape.clone(), bytes)
+                    .map_err(|source| ModelError::Safetensors {
+                        path: path.clone(),
+                        source,
+                    })?;
+                Ok((name.clone(), view))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        safetensors::serialize_to_file(views, &metadata, &path).map_err(|source| {
+            ModelError::Safetensors {
+                path: path.clone(),
+                source,
+            }
+        })?;
+    }
+
+    if num_shards > 1 {
+        let index = serde_json::json!({
+            "metadata": { "total_size": total_size },
+            "weight_map": weight_map.into_iter().collect::<BTreeMap<_, _>>(),
+        });
+        let path = dir.join(SAFETENSORS_INDEX_FILE);
+        let json = serde_json::to_string_pretty(&index).expect("JSON values serialize");
+        std::fs::write(&path, json).map_err(io(&path))?;
+    }
+
+    let path = dir.join(CONFIG_FILE);
+    let json = config.to_hf_config_json(export.dtype.torch_name());
+    std::fs::write(&path, json).map_err(io(&path))
+}
+
+/// Module path -> HuggingFace name and the rewrite applied on load, for every tensor
+/// of the checkpoint
+fn export_sources(config: &Qwen2Config) -> HashMap<String, (String, WeightTarget)> {
+    let mut sources = HashMap::new();
+    for (name, _) in hf_tensor_shapes(config) {
+        for target in map_hf_name(&name, config) {
+            sources.insert(target.path.clone(), (name.clone(), target));
+        }
+    }
+    sources
+}
+
+/// Names and `[out, in]` shapes of the tensors of a HuggingFace checkpoint for `config`
+pub(crate) fn hf_tensor_shapes(config: &Qwen2Config) -> Vec<(String, Vec<usize>)> {
+    let head_dim = config.head_dim();
+    let q = config.num_attention_heads * head_dim;
+    let kv = config.num_key_value_heads * head_dim;
+    let (h, i) = (config.hidden_size, config.intermediate_size);
+
+    let mut tensors = vec![
+        (
+            "model.embed_tokens.weight".to_string(),
+            vec![config.vocab_size, h],
+        ),
+        ("model.norm.weight".to_string(), vec![h]),
+    ];
+    if !config.tie_word_embeddings {
+        tensors.push(("lm_head.weight".to_string(), vec![config.vocab_size, h]));
+    }
+    for layer in 0..config.num_hidden_layers {
+        let p = format!("model.layers.{layer}");
+        tensors.extend([
+            (format!("{p}.input_layernorm.weight"), vec![h]),
+            (format!("{p}.post_attention_layernorm.weight"), vec![h]),
+            (format!("{p}.self_attn.q_proj.weight"), vec![q, h]),
+            (format!("{p}.self_attn.k_proj.weight"), vec![kv, h]),
+            (format!("{p}.self_attn.v_proj.weight"), vec![kv, h]),
+            (format!("{p}.self_attn.o_proj.weight"), vec![h, q]),
+        ]);
+        if config.attention_bias {
+            tensors.extend([
+                (format!("{p}.self_attn.q_proj.bias"), vec![q]),
+                (format!("{p}.self_attn.k_proj.bias"), vec![kv]),
+                (format!("{p}.self_attn.v_proj.bias"), vec![kv]),
+            ]);
+        }
+        if config.qk_norm {
+            tensors.extend([
+                (format!("{p}.self_attn.q_norm.weight"), vec![head_dim]),
+                (format!("{p}.self_attn.k_norm.weight"), vec![head_dim]),
+            ]);
+        }
+        tensors.extend([
+            (format!("{p}.mlp.gate_proj.weight"), vec![i, h]),
+            (format!("{p}.mlp.up_proj.weight"), vec![i, h]),
+            (format!("{p}.mlp.down_proj.weight"), vec![h, i]),
+        ]);
+    }
+    tensors
+}
+
+/// Embeddings first, then the layers in order, then the final norm and head
+fn export_order(name: &str) -> (usize, usize, String) {
+    let layer = name
+        .strip_prefix("model.layers.")
+        .and_then(|rest| rest.split_once('.'))
+        .and_then(|(layer, _)| layer.parse::<usize>().ok());
+    match layer {
+        Some(layer) => (1, layer, name.to_string()),
+        None if name.starts_with("model.embed_tokens") => (0, 0, name.to_string()),
+        None => (2, 0, name.to_string()),
+    }
+}
+
+/// How a checkpoint tensor is rewritten before it is applied to a parameter
+#[derive(Clone)]
+struct WeightTarget {
+    /// Dotted module path of the Burn parameter
+    path: String,
+    /// PyTorch stores linear weights as `[out, in]`, Burn as `[in, out]`
+    transpose: bool,
+    /// Reorder the rotary dimensions of each head from the HuggingFace "rotate half"
+    /// layout to the interleaved pairs used by Burn's `RotaryEncoding`
+    interleave_rotary: bool,
+}
+
+impl WeightTarget {
+    fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            transpose: false,
+            interleave_rotary: false,
+        }
+    }
+
+    fn transposed(mut self) -> Self {
+        self.transpose = true;
+        self
+    }
+
+    fn rotary(mut self) -> Self {
+        self.interleave_rotary = true;
+        self
+    }
+
+    /// Shape of the tensor once rewritten
+    fn shape(&self, source: &[usize]) -> Vec<usize> {
+        let mut shape = source.to_vec();
+        if self.transpose {
+            shape.reverse();
+        }
+        shape
+    }
+
+    /// Checkpoint shape of a parameter of shape `shape`
+    fn source_shape(&self, shape: &[usize]) -> Vec<usize> {
+        // Transposing is its own inverse on shapes
+        self.shape(shape)
+    }
+
+    /// Undo [`Self::apply`] on the values of a parameter of shape `shape`
+    fn revert(&self, mut values: Vec<f32>, shape: &[usize], head_dim: usize) -> Vec<f32> {
+        let rows = shape.first().copied().unwrap_or(1);
+        let cols = values.len() / rows.max(1);
+
+        if self.transpose {
+            values = transpose(&values, rows, cols);
+        }
+        if self.interleave_rotary {
+            let (rows, cols) = if self.transpose {
+                (cols, rows)
+            } else {
+                (rows, cols)
+            };
+            values = deinterleave_rotary_rows(&values, rows, cols, head_dim);
+        }
+        values
+    }
+
+    fn apply(&self, data: TensorData, head_dim: usize) -> Result<TensorData, TensorSnapshotError> {
+        let shape = data.shape.clone();
+        let mut values = data
+            .into_vec::<f32>()
+            .map_err(|e| TensorSnapshotError::DataError(format!("{e:?}")))?;
+
+        let rows = shape.first().copied().unwrap_or(1);
+        let cols = values.len() / rows.max(1);
+
+        if self.interleave_rotary {
+            values = interleave_rotary_rows(&values, rows, cols, head_dim);
+        }
+        if self.transpose {
+            values = transpose(&values, rows, cols);
+        }
+
+        Ok(TensorData::new(values, self.shape(&shape)))
+    }
+}
+
+/// Map a HuggingFace tensor name onto the Burn parameters it initialises
+///
+/// Returns an empty list for names the model does not know.
+fn map_hf_name(name: &str, config: &Qwen2Config) -> Vec<WeightTarget> {
+    match name {
+        "model.embed_tokens.weight" => return vec![WeightTarget::new(name)],
+        "model.norm.weight" => return vec![WeightTarget::new("model.norm.gamma")],
+        // Tied checkpoints sometimes still ship the head; the embedding wins
+        "lm_head.weight" if config.tie_word_embeddings => return Vec::new(),
+        "lm_head.weight" => return vec![WeightTarget::new(name).transposed()],
+        _ => {}
+    }
+
+    let Some(rest) = name.strip_prefix("model.layers.") else {
+        return Vec::new();
+    };
+    let Some((layer, param)) = rest.split_once('.') else {
+        return Vec::new();
+    };
+    if layer.parse::<usize>().is_err() {
+        return Vec::new();
+    }
+    let prefix = format!("model.layers.{layer}");
+
+    let target = match param {
+        "input_layernorm.weight" => WeightTarget::new(format!("{prefix}.input_layernorm.gamma")),
+        "post_attention_layernorm.weight" => {
+            WeightTarget::new(format!("{prefix}.post_attention_layernorm.gamma"))
+        }
+        "self_attn.q_proj.weight" | "self_attn.k_proj.weight" => {
+            WeightTarget::new(format!("{prefix}.{param}"))
+                .rotary()
+                .transposed()
+        }
+        "self_attn.q_proj.bias" | "self_attn.k_proj.bias" => {
+            WeightTarget::new(format!("{prefix}.{param}")).rotary()
+        }
+        "self_attn.v_proj.weight" | "self_attn.o_proj.weight" => {
+            WeightTarget::new(format!("{prefix}.{param}")).transposed()
+        }
+        "self_attn.v_proj.bias" => WeightTarget::new(format!("{prefix}.{param}")),
+        "self_attn.q_norm.weight" => {
+            WeightTarget::new(format!("{prefix}.self_attn.q_norm.gamma")).rotary()
+        }
+        "self_attn.k_norm.weight" => {
+            WeightTarget::new(format!("{prefix}.self_attn.k_norm.gamma")).rotary()
+        }
+        "mlp.gate_proj.weight" => {
+            WeightTarget::new(format!("{prefix}.mlp.swiglu.linear_inner.weight")).transposed()
+        }
+        "mlp.up_proj.weight" => {
+            WeightTarget::new(format!("{prefix}.mlp.swiglu.linear_outer.weight")).transposed()
+        }
+        "mlp.down_proj.weight" => {
+            WeightTarget::new(format!("{prefix}.mlp.down_proj.weight")).transposed()
+        }
+        _ => return Vec::new(),
+    };
+
+    vec![target]
+}
+
+/// Buffers some exporters write that the model recomputes itself
+fn is_ignored(name: &str) -> bool {
+    name.ends_with("rotary_emb.inv_freq")
+}
+
+fn burn_dtype(dtype: Dtype) -> Option<DType> {
+    match dtype {
+        Dtype::F32 => Some(DType::F32),
+        Dtype::F16 => Some(DType::F16),
+        Dtype::BF16 => Some(DType::BF16),
+        Dtype::F64 => Some(DType::F64),
+        _ => None,
+    }
+}
+
+/// Permute rows `[h*d + i, h*d + d/2 + i]` to `[h*d + 2i, h*d + 2i + 1]` for every head
+fn interleave_rotary_rows(values: &[f32], rows: usize, cols: usize, head_dim: usize) -> Vec<f32> {
+    let half = head_dim / 2;
+    let mut out = vec![0.0; values.len()];
+    for row in 0..rows {
+        let (head, offset) = (row / head_dim, row % head_dim);
+        let dest = if offset < half {
+            head * head_dim + 2 * offset
+        } else {
+            head * head_dim + 2 * (offset - half) + 1
+        };
+        out[dest * cols..(dest + 1) * cols].copy_from_slice(&values[row * cols..(row + 1) * cols]);
+    }
+    out
+}
+
+/// Inverse of [`interleave_rotary_rows`]
+fn deinterleave_rotary_rows(values: &[f32], rows: usize, cols: usize, head_dim: usize) -> Vec<f32> {
+    let half = head_dim / 2;
+    let mut out = vec![0.0; values.len()];
+    for row in 0..rows {
+        let (head, offset) = (row / head_dim, row % head_dim);
+        let source = if offset < half {
+            head * head_dim + 2 * offset
+        } else {
+            head * head_dim + 2 * (offset - half) + 1
+        };
+        out[row * cols..(row + 1) * cols]
+            .copy_from_slice(&values[source * cols..(source + 1) * cols]);
+    }
+    out
+}
+
+fn transpose(values: &[f32], rows: usize, cols: usize) -> Vec<f32> {
+    let mut out = vec![0.0; values.len()];
+    for r in 0..rows {
+        for c in 0..cols {
+            out[c * rows + r] = values[r * cols + c];
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use burn::backend::NdArray;
+    use burn::module::Module;
+    use safetensors::tensor::TensorView;
+
+    type Backend = NdArray<f32>;
+
+    pub(crate) fn values(shape: &[usize], seed: usize) -> Vec<f32> {
+        let n: usize = shape.iter().product();
+        (0..n)
+            .map(|j| ((j * 7 + seed * 13) % 17) as f32 / 17.0 - 0.5)
+            .collect()
+    }
+
+    pub(crate) fn write_shard(path: &Path, tensors: &[(String, Vec<usize>)]) {
+        let data: Vec<(String, Vec<u8>, Vec<usize>)> = tensors
+            .iter()
+            .enumerate()
+            .map(|(seed, (name, shape))| {
+                let bytes = values(shape, seed)
+                    .iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect();
+                (name.clone(), bytes, shape.clone())
+            })
+            .collect();
+        let views: Vec<(String, TensorView)> = data
+            .iter()
+            .map(|(name, bytes, shape)| {
+                let view = TensorView::new(Dtype::F32, shape.clone(), bytes).unwrap();
+                (name.clone(), view)
+            })
+            .collect();
+        safetensors::serialize_to_file(views, &None, path).unwrap();
+    }
+
+    #[test]
+    fn test_load_sharded_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Qwen2Config::tiny();
+        let tensors = hf_tensor_shapes(&config);
+        let (first, second) = tensors.split_at(tensors.len() / 2);
+
+        write_shard(&dir.path().join("model-00001-of-00002.safetensors"), first);
+        write_shard(&dir.path().join("model-00002-of-00002.safetensors"), second);
+        let weight_map: HashMap<&str, &str> = first
+            .iter()
+            .map(|(name, _)| (name.as_str(), "model-00001-of-00002.safetensors"))
+            .chain(
+                second
+                    .iter()
+                    .map(|(name, _)| (name.as_str(), "model-00002-of-00002.safetensors")),
+            )
+            .collect();
+        let index = serde_json::json!({ "metadata": {}, "weight_map": weight_map });
+        std::fs::write(dir.path().join(SAFETENSORS_INDEX_FILE), index.to_string()).unwrap();
+
+        let device = Default::default();
+        let model = config.init::<Backend>(&device);
+        let model = load_safetensors(model, &config, dir.path(), None).unwrap();
+        let num_params = model.num_params();
+
+        // Embeddings are copied verbatim, the output head is transposed
+        let record = model.into_record();
+        let embed = record.model.embed_tokens.weight.val().into_data();
+        assert_eq!(embed.to_vec::<f32>().unwrap(), values(&tensors[0].1, 0));
+
+        let lm_head = record.lm_head.expect("untied head").weight.val();
+        assert_eq!(lm_head.dims(), [config.hidden_size, config.vocab_size]);
+        let expected = transpose(
+            &values(&tensors[2].1, 2),
+            config.vocab_size,
+            config.hidden_size,
+        );
+        assert_eq!(lm_head.into_data().to_vec::<f32>().unwrap(), expected);
+
+        // Quantizing on load drops the dense weights of every layer's linears, whichever
+        // shard they came from
+        let quant = QuantConfig::new(crate::quant::QuantFormat::Int4);
+        let model = config.init::<Backend>(&device);
+        let model = load_safetensors(model, &config, dir.path(), Some(&quant)).unwrap();
+        let (h, i) = (config.hidden_size, config.intermediate_size);
+        let kv = h / config.num_attention_heads * config.num_key_value_heads;
+        let linears = config.num_hidden_layers * (2 * h * h + 2 * h * kv + 3 * h * i);
+        // One group of scales per output feature, every layer being narrower than a group
+        let scales = config.num_hidden_layers * (3 * h + 2 * kv + 2 * i);
+        assert_eq!(model.num_params(), num_params - linears + scales);
+    }
+
+    #[test]
+    fn test_reports_missing_and_unexpected_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Qwen2Config::tiny();
+        let mut tensors: Vec<_> = hf_tensor_shapes(&config)
+            .into_iter()
+            .filter(|(name, _)| name != "model.norm.weight")
+            .collect();
+        tensors.push(("model.extra.weight".to_string(), vec![4]));
+        write_shard(&dir.path().join(SAFETENSORS_FILE), &tensors);
+
+        let device = Default::default();
+        let model = config.init::<Backend>(&device);
+        match load_safetensors(model, &config, dir.path(), None) {
+            Err(ModelError::MissingWeights {
+                missing,
+                unexpected,
+            }) => {
+                assert_eq!(missing, vec!["model.norm.gamma".to_string()]);
+                assert_eq!(unexpected, vec!["model.extra.weight".to_string()]);
+            }
+            other => panic!("expected a key mismatch, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_interleave_rotary_rows() {
+        // One head of dim 4: HF pairs (0, 2) and (1, 3) become adjacent
+        let rows = interleave_rotary_rows(&[0.0, 1.0, 2.0, 3.0], 4, 1, 4);
+        assert_eq!(rows, vec![0.0, 2.0, 1.0, 3.0]);
+        assert_eq!(
+            deinterleave_rotary_rows(&rows, 4, 1, 4),
+            vec![0.0, 1.0, 2.0, 3.0]
+        );
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let device = Default::default();
+        let logits = |model: &Qwen2ForCausalLM<Backend>, config: &Qwen2Config| {
+            let input = crate::inference::token_column::<Backend>(&[3, 1, 4, 1, 5], &device)
+                .swap_dims(0, 1);
+            let mut cache = model.init_cache(config, 1, &Default::default(), &device);
+            model.forward(input, &mut cache).unwrap().into_data()
+        };
+
+        for tie_word_embeddings in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let config = Qwen2Config::tiny().with_tie_word_embeddings(tie_word_embeddings);
+            let model = config.init::<Backend>(&device);
+            // Small shards force a sharded checkpoint with an index
+            let export = ExportConfig::new().with_max_shard_bytes(8 * 1024);
+            save_safetensors(&model, &config, dir.path(), &export).unwrap();
+
+            let mut expected: Vec<String> = hf_tensor_shapes(&config)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            let files = checkpoint_files(dir.path()).unwrap();
+            assert!(files.len() > 1);
+            let mut names: Vec<String> = files
+                .iter()
+                .flat_map(|file| {
+                    let bytes = std::fs::read(file).unwrap();
+                    let tensors = SafeTensors::deserialize(&bytes).unwrap();
+                    tensors.names().into_iter().cloned().collect::<Vec<_>>()
+                })
+                .collect();
+            names.sort();
+            expected.sort();
+            assert_eq!(names, expected);
+
+            let (loaded, loaded_config) =
+                crate::inference::load_model_from_dir::<Backend>(dir.path(), &device).unwrap();
+            assert_eq!(loaded_config.tie_word_embeddings, tie_word_embeddings);
+            assert_eq!(logits(&loaded, &loaded_config), logits(&model, &config));
+        }
+    }
+}